@@ -0,0 +1,227 @@
+use crate::math::vec3::{Vec3,Point3,Color};
+use crate::math::mat4x4::Mat4x4;
+use crate::camera::Camera;
+use crate::materials::Material;
+use crate::motion::{AnimatedTransform,Interpolation};
+
+//How a track goes from a key to the next one
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[allow(non_camel_case_types)]
+pub enum Ease {
+    STEP,//Holds the value until the next key
+    LINEAR,
+    EASE_IN,
+    EASE_OUT,
+    EASE_IN_OUT,
+    SPLINE,//Catmull-Rom through the neighbouring keys, smooth paths for fly-throughs. Linear for transforms
+}
+
+impl Ease {
+    pub fn parse(s: &str) -> Option<Self>{
+        return match s {
+            "step" => Some(Ease::STEP),
+            "linear" => Some(Ease::LINEAR),
+            "ease-in" => Some(Ease::EASE_IN),
+            "ease-out" => Some(Ease::EASE_OUT),
+            "ease-in-out" => Some(Ease::EASE_IN_OUT),
+            "spline" => Some(Ease::SPLINE),
+            _ => None,
+        };
+    }
+    //Remaps how far along the segment we are
+    fn apply(&self,a: f32) -> f32{
+        return match self {
+            Ease::STEP => 0.,
+            Ease::LINEAR | Ease::SPLINE => a,
+            Ease::EASE_IN => a*a,
+            Ease::EASE_OUT => a*(2. - a),
+            Ease::EASE_IN_OUT => a*a*(3. - 2.*a),
+        };
+    }
+}
+
+pub trait Animatable: Copy {
+    fn interpolate(a: &Self,b: &Self,t: f32) -> Self;
+    //p1 to p2, p0 and p3 are the keys around them
+    fn catmull_rom(_p0: &Self,p1: &Self,p2: &Self,_p3: &Self,t: f32) -> Self{
+        return Self::interpolate(p1,p2,t);
+    }
+}
+
+impl Animatable for f32 {
+    fn interpolate(a: &Self,b: &Self,t: f32) -> Self{
+        return a + (b - a)*t;
+    }
+    fn catmull_rom(p0: &Self,p1: &Self,p2: &Self,p3: &Self,t: f32) -> Self{
+        let (t2,t3) = (t*t,t*t*t);
+        return 0.5*(2.*p1 + (p2 - p0)*t + (2.*p0 - 5.*p1 + 4.*p2 - p3)*t2 + (3.*p1 - p0 - 3.*p2 + p3)*t3);
+    }
+}
+
+impl Animatable for Vec3 {
+    fn interpolate(a: &Self,b: &Self,t: f32) -> Self{
+        return *a + (*b - *a)*t;
+    }
+    fn catmull_rom(p0: &Self,p1: &Self,p2: &Self,p3: &Self,t: f32) -> Self{
+        let (t2,t3) = (t*t,t*t*t);
+        return 0.5*(2.*(*p1) + (*p2 - *p0)*t + (2.*(*p0) - 5.*(*p1) + 4.*(*p2) - *p3)*t2 + (3.*(*p1) - *p0 - 3.*(*p2) + *p3)*t3);
+    }
+}
+
+//Translation and scale lerped, rotation slerped
+impl Animatable for Mat4x4 {
+    fn interpolate(a: &Self,b: &Self,t: f32) -> Self{
+        return AnimatedTransform::new(a,b,0.,1.,Interpolation::TRS).local_to_world(t);
+    }
+}
+
+//Keys sorted by time, each with the ease of the segment that starts on it. Before the first and after the last key it holds still
+#[derive(Clone, Debug)]
+pub struct Track<T: Animatable> {
+    pub keys: Vec<(f32,T,Ease)>,
+}
+
+impl<T: Animatable> Track<T> {
+    //Starts at time 0, ease is how it goes to the next key
+    pub fn new(value: T,ease: Ease) -> Self{
+        return Self{keys: vec!((0.,value,ease))};
+    }
+    //Keys added later are reached linearly
+    pub fn constant(value: T) -> Self{
+        return Self::new(value,Ease::LINEAR);
+    }
+    //Replaces the key at the same time if there's one
+    pub fn insert(&mut self,time: f32,value: T,ease: Ease){
+        match self.keys.iter().position(|k| k.0 >= time) {
+            Some(i) if self.keys[i].0 == time => self.keys[i] = (time,value,ease),
+            Some(i) => self.keys.insert(i,(time,value,ease)),
+            None => self.keys.push((time,value,ease)),
+        }
+    }
+    pub fn key(mut self,time: f32,value: T,ease: Ease) -> Self{
+        self.insert(time,value,ease);
+        return self;
+    }
+    pub fn at(&self,time: f32) -> T{
+        let n = self.keys.len();
+        assert!(n > 0,"Tracks need at least one key");
+        if !(time > self.keys[0].0) { return self.keys[0].1; }//NaN too
+        if time >= self.keys[n-1].0 { return self.keys[n-1].1; }
+        let i = self.keys.partition_point(|k| k.0 <= time) - 1;
+        let (t0,v0,ease) = &self.keys[i];
+        let (t1,v1,_) = &self.keys[i+1];
+        let a = ease.apply((time - t0)/(t1 - t0));
+        if *ease == Ease::SPLINE {
+            let before = &self.keys[i.saturating_sub(1)].1;
+            let after = &self.keys[(i+2).min(n-1)].1;
+            return T::catmull_rom(before,v0,v1,after,a);
+        }
+        return T::interpolate(v0,v1,a);
+    }
+}
+
+//When a frame is and how long it lasts. Moving objects blur over the part of it the shutter is open
+#[derive(Copy, Clone, Debug)]
+pub struct FrameTime {
+    pub time: f32,//Seconds
+    pub duration: f32,
+}
+
+impl Track<Mat4x4> {
+    //The motion over the frame, in the [0;1] ray time the camera shutter works in. None if it stays still
+    pub fn motion(&self,frame: &FrameTime) -> Option<AnimatedTransform>{
+        let start = self.at(frame.time);
+        let end = self.at(frame.time + frame.duration);
+        if start == end { return None; }
+        return Some(AnimatedTransform::new(&start,&end,0.,1.,Interpolation::TRS));
+    }
+}
+
+//Camera parameters over time, every one of them can be keyed on its own
+#[derive(Clone, Debug)]
+pub struct CameraAnimation {
+    pub lookfrom: Track<Point3>,
+    pub lookat: Track<Point3>,
+    pub vup: Track<Vec3>,
+    pub vfov: Track<f32>,//Degrees
+    pub aperture: Track<f32>,
+    pub focus_dist: Track<f32>,
+}
+
+impl CameraAnimation {
+    pub fn new_still(lookfrom: Point3,lookat: Point3,vup: Vec3,vfov: f32,aperture: f32,focus_dist: f32) -> Self{
+        return Self{lookfrom: Track::constant(lookfrom),lookat: Track::constant(lookat),vup: Track::constant(vup),
+                    vfov: Track::constant(vfov),aperture: Track::constant(aperture),focus_dist: Track::constant(focus_dist)};
+    }
+    pub fn camera_at(&self,time: f32,aspect_ratio: f32) -> Camera{
+        return Camera::new(self.lookfrom.at(time),self.lookat.at(time),self.vup.at(time),self.vfov.at(time),aspect_ratio,
+                           self.aperture.at(time),self.focus_dist.at(time));
+    }
+    //Keys from a text file, one per line: TIME PARAMETER VALUES... [EASE]. The parameters are lookfrom, lookat and vup (3 values)
+    //and vfov, aperture and focus (1 value). # starts a comment. Keyed parameters drop the still value they had
+    pub fn load(&mut self,path: &str) -> Result<(),String>{
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}",path,e))?;
+        let mut cleared: Vec<String> = Vec::new();
+        for (line_idx,line) in text.lines().enumerate(){
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() { continue; }
+            let err = |msg: &str| format!("{}:{}: {}",path,line_idx+1,msg);
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() < 3 { return Err(err("expected TIME PARAMETER VALUES...")); }
+            let time = tokens[0].parse::<f32>().ok().filter(|t| t.is_finite()).ok_or_else(|| err(&format!("bad time '{}'",tokens[0])))?;
+            let param = tokens[1];
+            let count = match param {
+                "lookfrom" | "lookat" | "vup" => 3,
+                "vfov" | "aperture" | "focus" => 1,
+                _ => return Err(err(&format!("unknown parameter '{}'",param))),
+            };
+            if tokens.len() < 2 + count || tokens.len() > 3 + count { return Err(err(&format!("{} takes {} values and an optional ease",param,count))); }
+            let mut values = [0f32;3];
+            for i in 0..count{
+                values[i] = tokens[2+i].parse::<f32>().map_err(|_| err(&format!("bad value '{}'",tokens[2+i])))?;
+            }
+            let ease = match tokens.get(2+count) {
+                Some(e) => Ease::parse(e).ok_or_else(|| err(&format!("unknown ease '{}'",e)))?,
+                None => Ease::LINEAR,
+            };
+            let first = !cleared.iter().any(|c| c == param);
+            if first { cleared.push(param.to_string()); }
+            let v = Vec3::new(values[0],values[1],values[2]);
+            let f = values[0];
+            match param {
+                "lookfrom" => { if first { self.lookfrom.keys.clear(); } self.lookfrom.insert(time,v,ease); },
+                "lookat"   => { if first { self.lookat.keys.clear(); } self.lookat.insert(time,v,ease); },
+                "vup"      => { if first { self.vup.keys.clear(); } self.vup.insert(time,v,ease); },
+                "vfov"     => { if first { self.vfov.keys.clear(); } self.vfov.insert(time,f,ease); },
+                "aperture" => { if first { self.aperture.keys.clear(); } self.aperture.insert(time,f,ease); },
+                _          => { if first { self.focus_dist.keys.clear(); } self.focus_dist.insert(time,f,ease); },
+            }
+        }
+        return Ok(());
+    }
+}
+
+//Material parameters over time, the rest come from base. Every frame gets base's id, which build_scene keeps the same from
+//frame to frame by starting the ids over, so the AOVs don't flicker
+#[derive(Clone)]
+pub struct MaterialAnimation {
+    pub base: Material,
+    pub albedo: Option<Track<Color>>,
+    pub emitted: Option<Track<Color>>,
+    pub fuzz: Option<Track<f32>>,
+    pub ior: Option<Track<f32>>,
+}
+
+impl MaterialAnimation {
+    pub fn new(base: &Material) -> Self{
+        return Self{base: *base,albedo: None,emitted: None,fuzz: None,ior: None};
+    }
+    pub fn at(&self,time: f32) -> Material{
+        let mut m = self.base;
+        if let Some(t) = &self.albedo { m.albedo = t.at(time); }
+        if let Some(t) = &self.emitted { m.emitted = t.at(time); }
+        if let Some(t) = &self.fuzz { m.fuzz = t.at(time); }
+        if let Some(t) = &self.ior { m.ior = t.at(time); }
+        return m;
+    }
+}
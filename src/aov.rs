@@ -0,0 +1,244 @@
+use crate::math::vec3::*;
+use crate::materials::MAX_LIGHT_GROUPS;
+use crate::render_thread::Pixel;
+use crate::utils::{normalize_color,scramble,u64_to_color,clamp};
+use crate::image_io;
+
+//Everything a path found out, not just its color. Geometric values are from the first hit
+#[derive(Copy,Clone)]
+pub struct PathSample{
+    pub color: Color,
+    pub depth: f32,//INFINITY if the camera ray escaped
+    pub obj_id: u64,//0 if it escaped
+    pub normal: UnitVec3,
+    pub position: Point3,
+    pub albedo: Color,
+    pub material_id: u32,
+    pub uv: (f32,f32),
+    pub direct: Color,//Light that got to the camera after at most one scatter
+    pub indirect: Color,
+    pub light_groups: [Color;MAX_LIGHT_GROUPS],//Same light as color, split by who emitted it
+}
+
+impl PathSample{
+    pub fn new() -> Self{
+        Self{color: Color::ZERO,depth: f32::INFINITY,obj_id: 0,normal: Vec3::ZERO,position: Point3::ZERO,albedo: Color::ZERO,
+             material_id: 0,uv: (0.,0.),direct: Color::ZERO,indirect: Color::ZERO,light_groups: [Color::ZERO;MAX_LIGHT_GROUPS]}
+    }
+    pub fn hit(&self) -> bool{
+        return self.depth.is_finite();
+    }
+}
+
+//Ids counted per pixel. Even at edges few objects share a pixel, past this many the least seen one makes room
+//(Space-Saving), so the most common id still wins unless the pixel is a mess of many
+const MAX_PIXEL_IDS: usize = 4;
+
+//The id most of the pixel's samples saw
+#[derive(Copy,Clone)]
+pub struct MajorityId{
+    ids: [u64;MAX_PIXEL_IDS],
+    counts: [u32;MAX_PIXEL_IDS],
+}
+impl MajorityId{
+    pub fn new() -> Self{ Self{ids: [0;MAX_PIXEL_IDS],counts: [0;MAX_PIXEL_IDS]} }
+    #[inline]
+    pub fn add(&mut self,id: u64){
+        let mut least = 0;
+        for i in 0..MAX_PIXEL_IDS{
+            if self.counts[i] > 0 && self.ids[i] == id {
+                self.counts[i] += 1;
+                return;
+            }
+            if self.counts[i] < self.counts[least] { least = i; }
+        }
+        self.ids[least] = id;
+        self.counts[least] += 1;
+    }
+    //Ties go to the smaller id, so they don't depend on the order the samples came in
+    pub fn id(&self) -> u64{
+        let mut best = 0;
+        for i in 1..MAX_PIXEL_IDS{
+            if self.counts[i] > self.counts[best] || (self.counts[i] == self.counts[best] && self.ids[i] < self.ids[best]) { best = i; }
+        }
+        return self.ids[best];
+    }
+}
+
+#[derive(Copy,Clone)]
+pub struct AovStats{
+    pub hits: u32,//Geometric AOVs are averaged over the samples that hit something, not over all of them
+    pub normal_sum: Vec3,
+    pub position_sum: Point3,
+    pub albedo_sum: Color,
+    pub uv_sum: Vec3,//z unused
+    pub direct_sum: Color,
+    pub indirect_sum: Color,
+    pub light_groups_sum: [Color;MAX_LIGHT_GROUPS],
+    pub object_id: MajorityId,
+    pub material_id: MajorityId,
+}
+
+impl AovStats{
+    pub fn new() -> Self{
+        Self{hits: 0,normal_sum: Vec3::ZERO,position_sum: Point3::ZERO,albedo_sum: Color::ZERO,uv_sum: Vec3::ZERO,
+             direct_sum: Color::ZERO,indirect_sum: Color::ZERO,light_groups_sum: [Color::ZERO;MAX_LIGHT_GROUPS],
+             object_id: MajorityId::new(),material_id: MajorityId::new()}
+    }
+    #[inline]
+    pub fn add(&mut self,s: &PathSample){
+        self.direct_sum   += s.direct;
+        self.indirect_sum += s.indirect;
+        for i in 0..MAX_LIGHT_GROUPS{
+            self.light_groups_sum[i] += s.light_groups[i];
+        }
+        self.object_id.add(s.obj_id);
+        self.material_id.add(s.material_id as u64);
+        if !s.hit() { return; }
+        self.hits += 1;
+        self.normal_sum   += s.normal;
+        self.position_sum += s.position;
+        self.albedo_sum   += s.albedo;
+        self.uv_sum       += Vec3::new(s.uv.0,s.uv.1,0.);
+    }
+}
+
+//Normal, Position, Albedo, MaterialId, ObjectId, Uv, Direct, Indirect + one per light group
+pub const AOV_COUNT: usize = 8 + MAX_LIGHT_GROUPS;
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Aov{
+    Normal,
+    Position,
+    Albedo,
+    MaterialId,
+    ObjectId,
+    Uv,
+    Direct,
+    Indirect,
+    LightGroup(u32),
+}
+
+impl Aov{
+    pub fn from_index(i: usize) -> Self{
+        assert!(i < AOV_COUNT);
+        return match i {
+            0 => Aov::Normal,
+            1 => Aov::Position,
+            2 => Aov::Albedo,
+            3 => Aov::MaterialId,
+            4 => Aov::ObjectId,
+            5 => Aov::Uv,
+            6 => Aov::Direct,
+            7 => Aov::Indirect,
+            _ => Aov::LightGroup((i - 8) as u32),
+        };
+    }
+    pub fn name(&self) -> String{
+        return match self {
+            Aov::Normal     => "normal".to_string(),
+            Aov::Position   => "position".to_string(),
+            Aov::Albedo     => "albedo".to_string(),
+            Aov::MaterialId => "material_id".to_string(),
+            Aov::ObjectId   => "object_id".to_string(),
+            Aov::Uv         => "uv".to_string(),
+            Aov::Direct     => "direct".to_string(),
+            Aov::Indirect   => "indirect".to_string(),
+            Aov::LightGroup(g) => format!("light_group{}",g),
+        };
+    }
+    pub fn from_name(name: &str) -> Option<Self>{
+        for i in 0..AOV_COUNT{
+            if Self::from_index(i).name() == name { return Some(Self::from_index(i)); }
+        }
+        return None;
+    }
+    pub fn is_id(&self) -> bool{
+        return *self == Aov::MaterialId || *self == Aov::ObjectId;
+    }
+    pub fn id(&self,pixel: &Pixel) -> u64{
+        return match self {
+            Aov::MaterialId => pixel.aovs.material_id.id(),
+            Aov::ObjectId   => pixel.aovs.object_id.id(),
+            _ => 0,
+        };
+    }
+    //Linear, averaged value. Not meaningful for ids
+    pub fn value(&self,pixel: &Pixel) -> Vec3{
+        let a = &pixel.aovs;
+        let hits = a.hits.max(1) as f32;
+        let n = pixel.stats.n.max(1) as f32;
+        return match self {
+            Aov::Normal     => a.normal_sum/hits,
+            Aov::Position   => a.position_sum/hits,
+            Aov::Albedo     => a.albedo_sum/hits,
+            Aov::Uv         => a.uv_sum/hits,
+            Aov::Direct     => a.direct_sum/n,
+            Aov::Indirect   => a.indirect_sum/n,
+            Aov::LightGroup(g) => a.light_groups_sum[*g as usize]/n,
+            Aov::MaterialId | Aov::ObjectId => Vec3::ZERO,
+        };
+    }
+    //How the viewer shows it. position_extent is the biggest abs coordinate in the image, to fit positions in [0;1]
+    pub fn display(&self,pixel: &Pixel,position_extent: f32) -> (u8,u8,u8){
+        if self.is_id() {
+            return u64_to_color(scramble(self.id(pixel)));
+        }
+        let v = self.value(pixel);
+        let c = match self {
+            Aov::Normal   => v*0.5 + Vec3::new(0.5,0.5,0.5),
+            Aov::Position => v/(2.*position_extent) + Vec3::new(0.5,0.5,0.5),
+            Aov::Uv       => Vec3::new(v.x() - v.x().floor(),v.y() - v.y().floor(),0.),
+            _ => return normalize_color(&v).to_u8x3(),
+        };
+        return Vec3::new(clamp(c.x(),0.,0.999),clamp(c.y(),0.,0.999),clamp(c.z(),0.,0.999)).to_u8x3();
+    }
+}
+
+pub fn position_extent(pixels: &Vec<Pixel>) -> f32{
+    let mut ret: f32 = 0.;
+    for p in pixels{
+        if p.aovs.hits > 0 {
+            ret = ret.max(Aov::Position.value(p).abs().max_val());
+        }
+    }
+    return ret.max(1e-6);
+}
+
+//"normal,albedo" or "all"
+pub fn parse_aov_list(list: &str) -> Result<Vec<Aov>,String>{
+    if list == "all" {
+        return Ok((0..AOV_COUNT).map(Aov::from_index).collect());
+    }
+    let mut ret = Vec::new();
+    for name in list.split(','){
+        match Aov::from_name(name.trim()) {
+            Some(aov) => ret.push(aov),
+            None => return Err(format!("Unknown AOV '{}'",name)),
+        }
+    }
+    return Ok(ret);
+}
+
+//Each AOV goes to its own PFM next to the output. Ids are written as they are (0 is "nothing"), they're small scene
+//positions and creation counts that fit exactly in a float and stay the same between frames of an animation
+pub fn write_aovs(pixels: &Vec<Pixel>,image_width: u32,image_height: u32,output: &str,aovs: &Vec<Aov>){
+    for aov in aovs{
+        let path = image_io::sibling_path(output,&aov.name(),"pfm");
+        if aov.is_id() {
+            let data: Vec<f32> = pixels.iter().map(|p| aov.id(p) as f32).collect();
+            image_io::write_pfm(&path,image_width,image_height,1,&data).unwrap();
+        }
+        else {
+            let mut data: Vec<f32> = Vec::with_capacity(pixels.len()*3);
+            for p in pixels{
+                let v = aov.value(p);
+                data.push(v.x());
+                data.push(v.y());
+                data.push(v.z());
+            }
+            image_io::write_pfm(&path,image_width,image_height,3,&data).unwrap();
+        }
+        eprintln!("Wrote {}",path);
+    }
+}
@@ -31,8 +31,14 @@ pub struct BoundingBox3D {
 
 impl BoundingBox3D {
     pub fn draw_always() -> Self {Self::new(&Point3::new(-INF,-INF,-INF),&Point3::new(INF,INF,INF))}
+    //Contains nothing, rays never go through it and it adds nothing to unions
+    pub fn empty() -> Self {Self::new(&Point3::new(INF,INF,INF),&Point3::new(-INF,-INF,-INF))}
     pub fn new(minp: &Point3,maxp: &Point3) -> Self { Self{minp: *minp,maxp: *maxp} }
+    pub fn is_empty(&self) -> bool {
+        return self.minp.x() > self.maxp.x() || self.minp.y() > self.maxp.y() || self.minp.z() > self.maxp.z();
+    }
     pub fn dot(&self,m: &Mat4x4) -> Self {
+        if self.is_empty() { return Self::empty(); }
         let p1 = m.dot_p3(&self.minp);
         let p2 = m.dot_p3(&Point3::new(self.minp.x(),self.minp.y(),self.maxp.z()));
         let p3 = m.dot_p3(&Point3::new(self.minp.x(),self.maxp.y(),self.minp.z()));
@@ -45,23 +51,56 @@ impl BoundingBox3D {
         let maxp = p1.max(&p2.max(&p3.max(&p4.max(&p5.max(&p6.max(&p7.max(&p8)))))));
         return BoundingBox3D{minp,maxp};
     }
-    pub fn project(&self,cam: &Camera) -> BoundingBox {
-        //http://medialab.di.unipi.it/web/IUM/Waterloo/node47.html#SECTION00810000000000000000
-        //(x,y,z) -> (xn/z,yn/z,n)
+    pub fn corners(&self) -> [Point3;8] {
         let p1 = self.minp;
         let p2 = self.maxp;
-        let v1 = cam.to_uv(&(p1));
-        let v2 = cam.to_uv(&(Point3::new(p1.x(),p1.y(),p2.z())));
-        let v3 = cam.to_uv(&(Point3::new(p1.x(),p2.y(),p1.z())));
-        let v4 = cam.to_uv(&(Point3::new(p1.x(),p2.y(),p2.z())));
-        let v5 = cam.to_uv(&(Point3::new(p2.x(),p1.y(),p1.z())));
-        let v6 = cam.to_uv(&(Point3::new(p2.x(),p1.y(),p2.z())));
-        let v7 = cam.to_uv(&(Point3::new(p2.x(),p2.y(),p1.z())));
-        let v8 = cam.to_uv(&(p2));
-        let minu = v1.0.min(v2.0).min(v3.0).min(v4.0).min(v5.0).min(v6.0).min(v7.0).min(v8.0);
-        let minv = v1.1.min(v2.1).min(v3.1).min(v4.1).min(v5.1).min(v6.1).min(v7.1).min(v8.1);
-        let maxu = v1.0.max(v2.0).max(v3.0).max(v4.0).max(v5.0).max(v6.0).max(v7.0).max(v8.0);
-        let maxv = v1.1.max(v2.1).max(v3.1).max(v4.1).max(v5.1).max(v6.1).max(v7.1).max(v8.1);
+        return [p1,Point3::new(p1.x(),p1.y(),p2.z()),Point3::new(p1.x(),p2.y(),p1.z()),Point3::new(p1.x(),p2.y(),p2.z()),
+                Point3::new(p2.x(),p1.y(),p1.z()),Point3::new(p2.x(),p1.y(),p2.z()),Point3::new(p2.x(),p2.y(),p1.z()),p2];
+    }
+    pub fn union(&self,other: &Self) -> Self {
+        return Self::new(&self.minp.min(&other.minp),&self.maxp.max(&other.maxp));
+    }
+    pub fn grow(&self,f: f32) -> Self {
+        let v = Vec3::new(f,f,f);
+        return Self::new(&(self.minp-v),&(self.maxp+v));
+    }
+    //Part of [t_min;t_max] a ray spends inside the box, slab test. inv_dir is 1/dir per axis
+    pub fn ray_interval(&self,orig: &Point3,inv_dir: &Vec3,t_min: f32,t_max: f32) -> Option<(f32,f32)> {
+        if self.is_empty() { return None; }//The slabs would swap its sides into a real box
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        for i in 0..3{
+            let ta = (self.minp[i] - orig[i])*inv_dir[i];
+            let tb = (self.maxp[i] - orig[i])*inv_dir[i];
+            t0 = t0.max(ta.min(tb));//NaNs (0*INF on flat or infinite boxes) get ignored by min/max
+            t1 = t1.min(ta.max(tb));
+        }
+        return if t0 <= t1 { Some((t0,t1)) } else { None };
+    }
+    //Projects a world bounding box into the camera (u,v) space
+    pub fn project(&self,cam: &Camera) -> BoundingBox {
+        if self.is_empty() { return BoundingBox::new(INF,INF,-INF,-INF); }
+        //Camera rays don't start exactly on the origin because of the lens, grow the box by how much they can move away
+        let mut max_dist: f32 = 0.;
+        for p in self.corners(){
+            max_dist = max_dist.max((p - cam.origin()).length());
+        }
+        let bb = self.grow(cam.defocus_radius_at(max_dist));
+        let mut minu = INF;
+        let mut minv = INF;
+        let mut maxu = -INF;
+        let mut maxv = -INF;
+        for p in bb.corners(){
+            //Anything on the back of the camera doesn't project properly, just draw it everywhere
+            if !cam.in_front(&p) || !p.x().is_finite() || !p.y().is_finite() || !p.z().is_finite(){
+                return BoundingBox::draw_always();
+            }
+            let (u,v) = cam.to_uv(&(p - cam.origin()));
+            minu = minu.min(u);
+            minv = minv.min(v);
+            maxu = maxu.max(u);
+            maxv = maxv.max(v);
+        }
         return BoundingBox::new(minu,minv,maxu,maxv);
     }
 }
@@ -0,0 +1,144 @@
+use crate::math::vec3::{Vec3,Point3};
+use crate::utils::INF;
+use crate::ray::Ray;
+use crate::bounding_box::BoundingBox3D;
+
+//Leaves hold at most this many items
+const BVH_LEAF_SIZE: usize = 4;
+const BVH_MAX_DEPTH: usize = 64;
+
+#[derive(Copy,Clone,Debug)]
+struct BvhNode {
+    bbox: BoundingBox3D,
+    //Inner nodes: index of the left child, the right one is always right after its whole left subtree
+    //Leaves: first item in Bvh::order
+    start: u32,
+    right: u32,
+    count: u32,//0 for inner nodes
+}
+
+#[inline]
+fn union(a: &BoundingBox3D,b: &BoundingBox3D) -> BoundingBox3D {
+    return BoundingBox3D::new(&a.minp.min(&b.minp),&a.maxp.max(&b.maxp));
+}
+
+#[inline]
+fn hit_bbox(b: &BoundingBox3D,orig: &Point3,inv_dir: &Vec3,t_min: f32,t_max: f32) -> bool{
+    let mut t0 = t_min;
+    let mut t1 = t_max;
+    for i in 0..3{
+        let ta = (b.minp[i] - orig[i])*inv_dir[i];
+        let tb = (b.maxp[i] - orig[i])*inv_dir[i];
+        t0 = t0.max(ta.min(tb));
+        t1 = t1.min(ta.max(tb));
+    }
+    return t0 <= t1;
+}
+
+//Bounding volume hierarchy over anything with a bounding box, items are referred to by their index
+//Median split on the longest axis of the centroids, good enough for evenly spread things like tessellated surfaces
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    order: Vec<u32>,//Items in leaf order
+}
+
+impl Bvh {
+    pub fn new(bboxes: &Vec<BoundingBox3D>) -> Self{
+        let mut ret = Self{nodes: Vec::with_capacity(2*bboxes.len()/BVH_LEAF_SIZE + 1),order: (0..bboxes.len() as u32).collect()};
+        let centroids: Vec<Point3> = bboxes.iter().map(|b| (b.minp + b.maxp)*0.5).collect();
+        ret.build_node(bboxes,&centroids,0,bboxes.len(),0);
+        return ret;
+    }
+    fn build_node(&mut self,bboxes: &Vec<BoundingBox3D>,centroids: &Vec<Point3>,start: usize,end: usize,depth: usize) -> u32{
+        let node_idx = self.nodes.len();
+        let mut bbox = BoundingBox3D::new(&Point3::new(INF,INF,INF),&Point3::new(-INF,-INF,-INF));
+        let mut cbox = bbox;
+        for i in start..end{
+            let t = self.order[i] as usize;
+            bbox = union(&bbox,&bboxes[t]);
+            cbox = union(&cbox,&BoundingBox3D::new(&centroids[t],&centroids[t]));
+        }
+        self.nodes.push(BvhNode{bbox: bbox,start: start as u32,right: 0,count: (end - start) as u32});
+        if end - start <= BVH_LEAF_SIZE || depth >= BVH_MAX_DEPTH - 1 {
+            return node_idx as u32;
+        }
+        let extent = cbox.maxp - cbox.minp;
+        let axis = if extent.x() > extent.y() && extent.x() > extent.z() { 0 } else if extent.y() > extent.z() { 1 } else { 2 };
+        if !(extent[axis] > 0.) {//Every centroid in the same place, can't split
+            return node_idx as u32;
+        }
+        let mid = (start + end)/2;
+        self.order[start..end].select_nth_unstable_by(mid - start,|a,b| {
+            centroids[*a as usize][axis].partial_cmp(&centroids[*b as usize][axis]).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        self.build_node(bboxes,centroids,start,mid,depth+1);
+        let right = self.build_node(bboxes,centroids,mid,end,depth+1);
+        self.nodes[node_idx].count = 0;
+        self.nodes[node_idx].start = (node_idx + 1) as u32;
+        self.nodes[node_idx].right = right;
+        return node_idx as u32;
+    }
+    pub fn bbox(&self) -> Option<BoundingBox3D>{
+        return if self.order.is_empty() { None } else { Some(self.nodes[0].bbox) };
+    }
+    //Calls hit_item(item,closest) for the items in every leaf the ray goes through before closest,
+    //it returns the item's t if it was hit closer than that. Returns the closest t
+    pub fn traverse<F: FnMut(u32,f32) -> Option<f32>>(&self,r: &Ray,t_min: f32,t_max: f32,mut hit_item: F) -> f32{
+        let mut closest = t_max;
+        if self.order.is_empty() { return closest; }
+        let inv_dir = Vec3::new(1./r.dir.x(),1./r.dir.y(),1./r.dir.z());
+        let mut stack = [0u32;BVH_MAX_DEPTH+1];
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let node = &self.nodes[stack[stack_len] as usize];
+            if !hit_bbox(&node.bbox,&r.orig,&inv_dir,t_min,closest) { continue; }
+            if node.count > 0 {
+                for i in node.start..(node.start + node.count){
+                    if let Some(t) = hit_item(self.order[i as usize],closest) {
+                        closest = t;
+                    }
+                }
+            }
+            else {
+                stack[stack_len]   = node.start;
+                stack[stack_len+1] = node.right;
+                stack_len += 2;
+            }
+        }
+        return closest;
+    }
+    //Closest item to p, item_dist2(item) returns its squared distance. Nodes farther than the best so far are skipped
+    //and the nearest child is visited first. Returns the item and its squared distance
+    pub fn nearest<F: FnMut(u32) -> f32>(&self,p: &Point3,mut item_dist2: F) -> Option<(u32,f32)>{
+        if self.order.is_empty() { return None; }
+        let box_dist2 = |b: &BoundingBox3D| (b.minp - *p).max(&(*p - b.maxp)).max(&Vec3::ZERO).length_squared();
+        let mut best: Option<(u32,f32)> = None;
+        let mut best_dist2 = INF;
+        let mut stack = [0u32;BVH_MAX_DEPTH+1];
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let node = &self.nodes[stack[stack_len] as usize];
+            if box_dist2(&node.bbox) >= best_dist2 { continue; }
+            if node.count > 0 {
+                for i in node.start..(node.start + node.count){
+                    let item = self.order[i as usize];
+                    let d2 = item_dist2(item);
+                    if d2 < best_dist2 {
+                        best_dist2 = d2;
+                        best = Some((item,d2));
+                    }
+                }
+            }
+            else {
+                let (l,r) = (node.start,node.right);
+                let near_left = box_dist2(&self.nodes[l as usize].bbox) <= box_dist2(&self.nodes[r as usize].bbox);
+                stack[stack_len]   = if near_left { r } else { l };
+                stack[stack_len+1] = if near_left { l } else { r };
+                stack_len += 2;
+            }
+        }
+        return best;
+    }
+}
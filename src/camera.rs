@@ -3,7 +3,7 @@ use crate::math::vec4::*;
 use crate::math::mat3x3::*;
 use crate::math::mat4x4::*;
 use crate::ray::*;
-use crate::utils::degrees_to_radians;
+use crate::utils::{degrees_to_radians,MyRandom};
 use crate::bounding_box::BoundingBox3D;
 
 #[derive(Copy,Clone)]
@@ -26,6 +26,8 @@ pub struct Camera{
     pub viewport_width: f32,
     #[allow(dead_code)]
     pub viewport_height: f32,
+    pub shutter_open: f32,//Rays get a random time in [shutter_open;shutter_close]
+    pub shutter_close: f32,
 }
 
 impl Camera{
@@ -55,13 +57,20 @@ impl Camera{
         let llc = o - h/2. - v/2. - focus_dist*w_of_plane;
         return Camera{ origin: o, horizontal: h, vertical: v, lower_left_corner: llc,
             u_of_plane: u_of_plane,v_of_plane: v_of_plane,w_of_plane: w_of_plane, lens_radius: aperture / 2.0, aspect_ratio: aspect_ratio,
-            focus_dist: focus_dist,viewport_width: viewport_width,viewport_height: viewport_height};
+            focus_dist: focus_dist,viewport_width: viewport_width,viewport_height: viewport_height,shutter_open: 0.,shutter_close: 0.};
+    }
+    //Moving objects are keyframed at times 0 and 1, so [0;1] blurs their whole motion
+    pub fn with_shutter(mut self,open: f32,close: f32) -> Self{
+        self.shutter_open = open;
+        self.shutter_close = close.max(open);
+        return self;
     }
     pub fn get_ray(&self,u: f32,v: f32) -> Ray{
         let rand_in_lens = self.lens_radius * Vec3::rand_in_unit_disc();
         let offset = self.u_of_plane*rand_in_lens.x() + self.v_of_plane*rand_in_lens.y();
         let direction = self.uv_to_dir().dot(&Vec4::new(u,v,0.,1.)).xyz();        
-        return Ray::new(&(self.origin+offset),&(direction-offset).unit());
+        let time = self.shutter_open + f32::rand()*(self.shutter_close - self.shutter_open);
+        return Ray::new_at(&(self.origin+offset),&(direction-offset).unit(),time);
     }
     #[inline]
     pub fn uv_to_dir(&self) -> Mat4x4 {
@@ -99,12 +108,23 @@ impl Camera{
         //println!("vport3d {:?}",ret);//Seems about right
         return ret;
     }
+    //dir is a world direction from the origin of the camera
     pub fn to_uv(&self,dir: &Vec3) -> (f32,f32) {
-        //println!("{} dir",dir);
+        //origin + s*dir = lower_left_corner + u*horizontal + v*vertical
         let to_uvs = Mat3x3::new_3vec_vert(
             &self.horizontal,&self.vertical,&dir
         ).inverse();
         let uvs = to_uvs.dot(&(self.origin-self.lower_left_corner));
-        return (1.0-uvs.x(),1.0-uvs.y());
+        return (uvs.x(),uvs.y());
+    }
+    pub fn origin(&self) -> Point3 {
+        return self.origin;
+    }
+    pub fn in_front(&self,p: &Point3) -> bool {
+        return (*p - self.origin).dot(-self.w_of_plane) > 0.;
     }
-}
\ No newline at end of file
+    //Upper bound of how far a ray that went through the lens is from the pinhole ray at distance dist
+    pub fn defocus_radius_at(&self,dist: f32) -> f32 {
+        return self.lens_radius*(1. + dist/self.focus_dist);
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+use crate::math::vec3::{Vec3,UnitVec3,Point3};
+use crate::math::vec4::Vec4;
+use crate::materials::Material;
+use crate::bounding_box::*;
+use crate::hits::HitRecord;
+use crate::marched::{Marched,sdf_gradient,normal_eps};
+use crate::utils::clamp;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[allow(non_camel_case_types)]
+pub enum CsgOp {
+    UNION,
+    INTERSECTION,
+    SUBTRACTION,//a with b carved out of it
+}
+
+//Combines the distance fields of two marched objects (or other nodes) into one, so they are marched as a single surface.
+//Works in world space, the leaves already apply their own transforms in sdf()
+pub struct MarchedCsg {
+    pub op: CsgOp,
+    pub a: Arc<dyn Marched + Send + Sync>,
+    pub b: Arc<dyn Marched + Send + Sync>,
+    pub blend: f32,//Radius of the polynomial smooth min/max, 0 is a sharp seam
+    pub material: Material,//a's, the actual one at a point comes from material_at
+}
+
+impl MarchedCsg {
+    pub fn new(op: CsgOp,a: &Arc<dyn Marched + Send + Sync>,b: &Arc<dyn Marched + Send + Sync>,blend: f32) -> Self{
+        return Self{op: op,a: a.clone(),b: b.clone(),blend: blend.max(0.),material: *a.material()};
+    }
+    #[allow(dead_code)]
+    pub fn union(a: &Arc<dyn Marched + Send + Sync>,b: &Arc<dyn Marched + Send + Sync>,blend: f32) -> Self{
+        return Self::new(CsgOp::UNION,a,b,blend);
+    }
+    #[allow(dead_code)]
+    pub fn intersection(a: &Arc<dyn Marched + Send + Sync>,b: &Arc<dyn Marched + Send + Sync>,blend: f32) -> Self{
+        return Self::new(CsgOp::INTERSECTION,a,b,blend);
+    }
+    #[allow(dead_code)]
+    pub fn subtraction(a: &Arc<dyn Marched + Send + Sync>,b: &Arc<dyn Marched + Send + Sync>,blend: f32) -> Self{
+        return Self::new(CsgOp::SUBTRACTION,a,b,blend);
+    }
+    //Distance and how much of a's material there is, 1 on a's surface and 0 on b's (the carved faces for a subtraction)
+    fn eval(&self,p: &Point3,time: f32) -> (f32,f32){
+        let da = self.a.sdf_at(p,time);
+        let db = self.b.sdf_at(p,time);
+        let db = if self.op == CsgOp::SUBTRACTION { -db } else { db };
+        let k = self.blend;
+        if k <= 0. {
+            let a_wins = match self.op {
+                CsgOp::UNION => da <= db,
+                _ => da >= db,
+            };
+            return if a_wins { (da,1.) } else { (db,0.) };
+        }
+        //https://iquilezles.org/articles/smin/ the blend gets pulled in (min) or pushed out (max) by up to k/4
+        return match self.op {
+            CsgOp::UNION => {
+                let h = clamp(0.5 + 0.5*(db - da)/k,0.,1.);
+                (db*(1. - h) + da*h - k*h*(1. - h),h)
+            },
+            _ => {
+                let h = clamp(0.5 - 0.5*(db - da)/k,0.,1.);
+                (db*(1. - h) + da*h + k*h*(1. - h),h)
+            },
+        };
+    }
+    fn normal_at(&self,p: &Point3,eps: f32,time: f32) -> UnitVec3{
+        return sdf_gradient(|q| self.eval(q,time).0,p,eps);
+    }
+    fn blended_material(&self,p: &Point3,time: f32) -> Material{
+        let w = self.eval(p,time).1;
+        if w >= 1. { return self.a.material_at_time(p,time); }
+        if w <= 0. { return self.b.material_at_time(p,time); }
+        return self.a.material_at_time(p,time).mix(&self.b.material_at_time(p,time),w);
+    }
+}
+
+impl Marched for MarchedCsg {
+    fn local_sdf(&self,p: &Point3) -> f32 {
+        return self.eval(p,0.).0;
+    }
+    fn sdf_at(&self,p: &Point3,time: f32) -> f32{
+        return self.eval(p,time).0;
+    }
+    fn material(&self) -> &Material{
+        return &self.material;
+    }
+    fn material_at(&self,p: &Point3) -> Material{
+        return self.blended_material(p,0.);
+    }
+    fn material_at_time(&self,p: &Point3,time: f32) -> Material{
+        return self.blended_material(p,time);
+    }
+    fn to_local(&self,p: &Vec4) -> Vec4{
+        return *p;
+    }
+    fn to_world(&self,p: &Vec4) -> Vec4{
+        return *p;
+    }
+    fn to_world_f(&self,f: f32) -> f32{
+        return f;
+    }
+    fn uv(&self,p: &Point3) -> (f32,f32){
+        return if self.eval(p,0.).1 >= 0.5 { self.a.uv(p) } else { self.b.uv(p) };
+    }
+    fn get_outward_normal(&self,p: &Point3,eps: f32) -> UnitVec3{
+        return self.normal_at(p,eps,0.);
+    }
+    fn hit_record_at(&self,t: f32,p: &Point3,time: f32) -> HitRecord{
+        let normal = self.normal_at(p,normal_eps(t,p),time);
+        return HitRecord{t: t,point: *p,normal: normal,geometric_normal: normal,material: self.blended_material(p,time),obj_id: 0,
+                         uv: self.uv(p),tangent: Vec3::ZERO,fiber: false};
+    }
+}
+
+impl Bounded for MarchedCsg {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let a = self.a.build_world_bounding_box();
+        let b = self.b.build_world_bounding_box();
+        return match self.op {
+            CsgOp::UNION => a.union(&b).grow(0.25*self.blend),
+            CsgOp::INTERSECTION => {//Children that don't overlap leave nothing
+                let bb = BoundingBox3D::new(&a.minp.max(&b.minp),&a.maxp.min(&b.maxp));
+                if bb.is_empty() { BoundingBox3D::empty() } else { bb }
+            },
+            CsgOp::SUBTRACTION => a,//Smooth max only ever shrinks it
+        };
+    }
+}
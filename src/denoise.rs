@@ -0,0 +1,155 @@
+use crate::math::vec3::*;
+use crate::render_thread::Pixel;
+use crate::aov::Aov;
+use crate::utils::luminance;
+
+//Edge-avoiding À-trous wavelet filter (Dammertz et al. 2010) with the variance guided
+//luminance weight from SVGF (Schied et al. 2017), on a single frame
+//https://jo.dreggn.org/home/2010_atrous.pdf
+#[derive(Copy,Clone,Debug)]
+pub struct DenoiseSettings{
+    //Each iteration doubles the distance between taps, 5 iterations reach 2^5*2 = 64 pixels away
+    pub iterations: u32,
+    //Bigger is blurrier, in standard deviations of the pixel's luminance
+    pub sigma_color: f32,
+    //Exponent of the normals dot product
+    pub sigma_normal: f32,
+    //Relative depth difference allowed per pixel of distance
+    pub sigma_depth: f32,
+    pub sigma_albedo: f32,
+}
+
+impl DenoiseSettings{
+    pub fn new() -> Self{
+        Self{iterations: 5,sigma_color: 4.,sigma_normal: 64.,sigma_depth: 0.02,sigma_albedo: 0.1}
+    }
+}
+
+//B3 spline, separable
+const KERNEL: [f32;5] = [1./16.,1./4.,3./8.,1./4.,1./16.];
+//So black albedos don't blow up the demodulated color
+const ALBEDO_EPS: f32 = 0.01;
+
+#[derive(Copy,Clone)]
+struct Guide{
+    normal: UnitVec3,
+    depth: f32,//INFINITY for the sky
+    albedo: Color,
+}
+
+//Returns the denoised color of every pixel, linear and in the same order as pixels
+pub fn denoise(pixels: &Vec<Pixel>,image_width: u32,image_height: u32,settings: &DenoiseSettings) -> Vec<Color>{
+    let w = image_width as i32;
+    let h = image_height as i32;
+    let size = pixels.len();
+    assert!(size == (image_width*image_height) as usize);
+
+    let mut guides: Vec<Guide> = Vec::with_capacity(size);
+    let mut color: Vec<Color> = Vec::with_capacity(size);
+    let mut variance: Vec<f32> = Vec::with_capacity(size);
+    let mut max_variance: f32 = 0.;
+    for p in pixels{
+        let hit = p.aovs.hits > 0;
+        let albedo = if hit { Aov::Albedo.value(p) } else { Color::new(1.,1.,1.) };
+        let divisor = albedo.max(&Color::new(ALBEDO_EPS,ALBEDO_EPS,ALBEDO_EPS));
+        let c = p.stats.estimate();
+        //Filter the lighting only, texture detail is put back at the end. The variance is scaled the same way
+        color.push(c/divisor);
+        let v = p.stats.variance_of_mean()/luminance(&divisor).powi(2);
+        if v.is_finite() { max_variance = max_variance.max(v); }
+        variance.push(v);
+        //Renormalized, the average of the normals at an edge can be quite short and powf would then kill even the center tap
+        let normal = if hit { Aov::Normal.value(p) } else { Vec3::ZERO };
+        let normal = if normal.length_squared() > 0. { normal.unit() } else { normal };
+        let depth = if hit { p.stats.avg_depth } else { f32::INFINITY };
+        guides.push(Guide{normal: normal,depth: depth,albedo: albedo});
+    }
+    for v in variance.iter_mut(){//Pixels with less than 2 samples know nothing, assume the worst
+        if !v.is_finite() { *v = max_variance; }
+    }
+
+    let mut next_color = color.clone();
+    let mut next_variance = variance.clone();
+    for iteration in 0..settings.iterations{
+        let step = 1i32 << iteration;
+        let blurred_variance = blur_variance(&variance,w,h);
+        for y in 0..h{
+            for x in 0..w{
+                let p = (x + y*w) as usize;
+                let gp = &guides[p];
+                let lp = luminance(&color[p]);
+                let color_scale = settings.sigma_color*blurred_variance[p].sqrt() + 1e-6;
+                let mut sum_w = 0.;
+                let mut sum_c = Color::ZERO;
+                let mut sum_v = 0.;
+                for ky in 0..5{
+                    let qy = y + (ky as i32 - 2)*step;
+                    if qy < 0 || qy >= h { continue; }
+                    for kx in 0..5{
+                        let qx = x + (kx as i32 - 2)*step;
+                        if qx < 0 || qx >= w { continue; }
+                        let q = (qx + qy*w) as usize;
+                        let gq = &guides[q];
+                        let dist = (((kx as i32 - 2).abs() + (ky as i32 - 2).abs())*step) as f32;
+                        let wgt = KERNEL[kx]*KERNEL[ky]
+                            *depth_weight(gp.depth,gq.depth,dist,settings.sigma_depth)
+                            *normal_weight(&gp.normal,&gq.normal,settings.sigma_normal)
+                            *(-(gp.albedo - gq.albedo).length()/settings.sigma_albedo).exp()
+                            *(-(lp - luminance(&color[q])).abs()/color_scale).exp();
+                        sum_w += wgt;
+                        sum_c += wgt*color[q];
+                        sum_v += wgt*wgt*variance[q];
+                    }
+                }
+                //The center tap always has weight KERNEL[2]^2 so sum_w > 0
+                next_color[p] = sum_c/sum_w;
+                next_variance[p] = sum_v/(sum_w*sum_w);
+            }
+        }
+        ::std::mem::swap(&mut color,&mut next_color);
+        ::std::mem::swap(&mut variance,&mut next_variance);
+    }
+
+    for p in 0..size{
+        color[p] *= guides[p].albedo.max(&Color::new(ALBEDO_EPS,ALBEDO_EPS,ALBEDO_EPS));
+    }
+    return color;
+}
+
+#[inline]
+fn depth_weight(dp: f32,dq: f32,dist: f32,sigma_depth: f32) -> f32{
+    if dp.is_infinite() || dq.is_infinite() {//Sky only blurs with sky
+        return (dp.is_infinite() == dq.is_infinite()) as u32 as f32;
+    }
+    return (-(dp - dq).abs()/(sigma_depth*dist*dp + 1e-6)).exp();
+}
+
+#[inline]
+fn normal_weight(np: &UnitVec3,nq: &UnitVec3,sigma_normal: f32) -> f32{
+    if np.length_squared() == 0. && nq.length_squared() == 0. { return 1.; }
+    return np.dot(*nq).max(0.).powf(sigma_normal);
+}
+
+//3x3 gaussian, a single pixel's variance estimate is too noisy to use as is
+fn blur_variance(variance: &Vec<f32>,w: i32,h: i32) -> Vec<f32>{
+    const K: [f32;3] = [0.25,0.5,0.25];
+    let mut ret = vec!(0.;variance.len());
+    for y in 0..h{
+        for x in 0..w{
+            let mut sum = 0.;
+            let mut sum_w = 0.;
+            for ky in 0..3{
+                let qy = y + ky as i32 - 1;
+                if qy < 0 || qy >= h { continue; }
+                for kx in 0..3{
+                    let qx = x + kx as i32 - 1;
+                    if qx < 0 || qx >= w { continue; }
+                    sum   += K[kx]*K[ky]*variance[(qx + qy*w) as usize];
+                    sum_w += K[kx]*K[ky];
+                }
+            }
+            ret[(x + y*w) as usize] = sum/sum_w;
+        }
+    }
+    return ret;
+}
@@ -0,0 +1,176 @@
+use crate::math::vec3::*;
+use crate::utils::{clamp,srgb_oetf};
+
+//Scene linear Rec.709 -> what gets shown/written. Order is exposure, white balance, tone curve, encoding
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum ToneCurve{
+    Clamp,
+    Reinhard,
+    Hable,
+    Aces,
+}
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum OutputSpace{
+    Srgb,//Tone mapped, sRGB OETF, for the viewer and 8 bit files
+    LinearRec709,//Scene linear, just exposure and white balance
+    AcesCg,//Same but with AP1 primaries
+}
+
+type Mat3 = [[f32;3];3];
+
+#[inline]
+fn mul3(m: &Mat3,v: &Vec3) -> Vec3{
+    return Vec3::new(
+        m[0][0]*v.x() + m[0][1]*v.y() + m[0][2]*v.z(),
+        m[1][0]*v.x() + m[1][1]*v.y() + m[1][2]*v.z(),
+        m[2][0]*v.x() + m[2][1]*v.y() + m[2][2]*v.z(),
+    );
+}
+
+fn matmul3(a: &Mat3,b: &Mat3) -> Mat3{
+    let mut ret = [[0.;3];3];
+    for i in 0..3{
+        for j in 0..3{
+            ret[i][j] = a[i][0]*b[0][j] + a[i][1]*b[1][j] + a[i][2]*b[2][j];
+        }
+    }
+    return ret;
+}
+
+const IDENTITY3: Mat3 = [[1.,0.,0.],[0.,1.,0.],[0.,0.,1.]];
+const REC709_TO_XYZ: Mat3 = [[0.4124564,0.3575761,0.1804375],[0.2126729,0.7151522,0.0721750],[0.0193339,0.1191920,0.9503041]];
+const XYZ_TO_REC709: Mat3 = [[3.2404542,-1.5371385,-0.4985314],[-0.9692660,1.8760108,0.0415560],[0.0556434,-0.2040259,1.0572252]];
+const BRADFORD: Mat3 = [[0.8951,0.2664,-0.1614],[-0.7502,1.7135,0.0367],[0.0389,-0.0685,1.0296]];
+const BRADFORD_INV: Mat3 = [[0.9869929,-0.1470543,0.1599627],[0.4323053,0.5183603,0.0492912],[-0.0085287,0.0400428,0.9684867]];
+const D65_XYZ: [f32;3] = [0.95047,1.,1.08883];
+//Linear Rec.709 (D65) to ACEScg (AP1, D60), Bradford adapted
+const REC709_TO_ACESCG: Mat3 = [[0.6130974,0.3395231,0.0473795],[0.0701937,0.9163539,0.0134524],[0.0206156,0.1095698,0.8698151]];
+//Stephen Hill's fit of the ACES RRT+ODT, sRGB primaries in and out
+//https://github.com/TheRealMJP/BakingLab/blob/master/BakingLab/ACES.hlsl
+const ACES_INPUT: Mat3 = [[0.59719,0.35458,0.04823],[0.07600,0.90834,0.01566],[0.02840,0.13383,0.83777]];
+const ACES_OUTPUT: Mat3 = [[1.60475,-0.53108,-0.07367],[-0.10208,1.10813,-0.00605],[-0.00327,-0.07276,1.07602]];
+
+//Planckian locus in CIE xy, Kim et al. cubic spline, valid from 1667K to 25000K
+fn planckian_xy(kelvin: f32) -> (f32,f32){
+    let t = clamp(kelvin,1667.,25000.);
+    let (t2,t3) = (t*t,t*t*t);
+    let x = if t <= 4000. {
+        -0.2661239e9/t3 - 0.2343589e6/t2 + 0.8776956e3/t + 0.179910
+    } else {
+        -3.0258469e9/t3 + 2.1070379e6/t2 + 0.2226347e3/t + 0.240390
+    };
+    let (x2,x3) = (x*x,x*x*x);
+    let y = if t <= 2222. {
+        -1.1063814*x3 - 1.34811020*x2 + 2.18555832*x - 0.20219683
+    } else if t <= 4000. {
+        -0.9549476*x3 - 1.37418593*x2 + 2.09137015*x - 0.16748867
+    } else {
+        3.0817580*x3 - 5.87338670*x2 + 3.75112997*x - 0.37001483
+    };
+    return (x,y);
+}
+
+//Von Kries adaptation in Bradford space so something lit by a blackbody of that temperature looks white
+fn white_balance_matrix(kelvin: f32) -> Mat3{
+    let (x,y) = planckian_xy(kelvin);
+    let src = mul3(&BRADFORD,&Vec3::new(x/y,1.,(1.-x-y)/y));
+    let dst = mul3(&BRADFORD,&Vec3::new(D65_XYZ[0],D65_XYZ[1],D65_XYZ[2]));
+    let scale: Mat3 = [[dst.x()/src.x(),0.,0.],[0.,dst.y()/src.y(),0.],[0.,0.,dst.z()/src.z()]];
+    let xyz = matmul3(&BRADFORD_INV,&matmul3(&scale,&BRADFORD));
+    return matmul3(&XYZ_TO_REC709,&matmul3(&xyz,&REC709_TO_XYZ));
+}
+
+#[inline]
+fn hable_partial(x: f32) -> f32{//http://filmicworlds.com/blog/filmic-tonemapping-operators/
+    const A: f32 = 0.15; const B: f32 = 0.50; const C: f32 = 0.10;
+    const D: f32 = 0.20; const E: f32 = 0.02; const F: f32 = 0.30;
+    return ((x*(A*x + C*B) + D*E)/(x*(A*x + B) + D*F)) - E/F;
+}
+
+#[derive(Copy,Clone,Debug)]
+pub struct DisplayTransform{
+    pub curve: ToneCurve,
+    pub output: OutputSpace,
+    //Exposure and white balance baked together
+    pre: Mat3,
+}
+
+impl DisplayTransform{
+    //white_balance is the Kelvin of the light that should look white, None leaves colors alone
+    pub fn new(exposure_ev: f32,white_balance: Option<f32>,curve: ToneCurve,output: OutputSpace) -> Self{
+        let wb = white_balance.map_or(IDENTITY3,white_balance_matrix);
+        let gain = 2f32.powf(exposure_ev);
+        let mut pre = wb;
+        for row in pre.iter_mut(){
+            for v in row.iter_mut(){
+                *v *= gain;
+            }
+        }
+        return Self{curve: curve,output: output,pre: pre};
+    }
+    //Scene linear, exposed and balanced, in the output primaries. What the linear outputs are
+    pub fn linear(&self,c: &Color) -> Color{
+        let c = mul3(&self.pre,c);
+        return match self.output {
+            OutputSpace::AcesCg => mul3(&REC709_TO_ACESCG,&c),
+            _ => c,
+        };
+    }
+    //Tone mapped and sRGB encoded in [0;1), what the viewer and the 8 bit files show
+    pub fn display(&self,c: &Color) -> Color{
+        let c = mul3(&self.pre,c);
+        let c = Vec3::new(c.x().max(0.),c.y().max(0.),c.z().max(0.));
+        let mapped = match self.curve {
+            ToneCurve::Clamp    => c,
+            ToneCurve::Reinhard => Vec3::new(c.x()/(1. + c.x()),c.y()/(1. + c.y()),c.z()/(1. + c.z())),
+            ToneCurve::Hable    => {
+                const EXPOSURE_BIAS: f32 = 2.;
+                const WHITE: f32 = 11.2;
+                let w = 1./hable_partial(WHITE);
+                Vec3::new(hable_partial(c.x()*EXPOSURE_BIAS)*w,hable_partial(c.y()*EXPOSURE_BIAS)*w,hable_partial(c.z()*EXPOSURE_BIAS)*w)
+            },
+            ToneCurve::Aces     => {
+                let v = mul3(&ACES_INPUT,&c);
+                let rrt_odt = |x: f32| (x*(x + 0.0245786) - 0.000090537)/(x*(0.983729*x + 0.4329510) + 0.238081);
+                mul3(&ACES_OUTPUT,&Vec3::new(rrt_odt(v.x()),rrt_odt(v.y()),rrt_odt(v.z())))
+            },
+        };
+        return Vec3::new(
+            clamp(srgb_oetf(clamp(mapped.x(),0.,1.)),0.,0.999),
+            clamp(srgb_oetf(clamp(mapped.y(),0.,1.)),0.,0.999),
+            clamp(srgb_oetf(clamp(mapped.z(),0.,1.)),0.,0.999),
+        );
+    }
+    #[inline]
+    pub fn to_u8x3(&self,c: &Color) -> (u8,u8,u8){
+        return self.display(c).to_u8x3();
+    }
+    //Suffix of the linear output file, None for sRGB which has no linear output
+    pub fn linear_suffix(&self) -> Option<&'static str>{
+        return match self.output {
+            OutputSpace::Srgb => None,
+            OutputSpace::LinearRec709 => Some("rec709"),
+            OutputSpace::AcesCg => Some("acescg"),
+        };
+    }
+}
+
+pub fn parse_tone_curve(s: &str) -> Option<ToneCurve>{
+    return match s {
+        "clamp"    => Some(ToneCurve::Clamp),
+        "reinhard" => Some(ToneCurve::Reinhard),
+        "hable"    => Some(ToneCurve::Hable),
+        "aces"     => Some(ToneCurve::Aces),
+        _ => None,
+    };
+}
+
+pub fn parse_output_space(s: &str) -> Option<OutputSpace>{
+    return match s {
+        "srgb"   => Some(OutputSpace::Srgb),
+        "linear" => Some(OutputSpace::LinearRec709),
+        "acescg" => Some(OutputSpace::AcesCg),
+        _ => None,
+    };
+}
@@ -0,0 +1,170 @@
+use std::sync::Arc;
+use crate::math::vec3::{Vec3,UnitVec3,Point3};
+use crate::math::vec4::Vec4;
+use crate::math::mat4x4::Mat4x4;
+use crate::materials::Material;
+use crate::bounding_box::*;
+use crate::hits::HitRecord;
+use crate::marched::{Marched,sdf_gradient,normal_eps};
+
+//Warps of the space an object is evaluated in. The object is given in the local space of the node
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[allow(non_camel_case_types)]
+#[allow(dead_code)]
+pub enum DomainOp {
+    REPEAT{period: Vec3},//Infinite copies, the object should fit in a cell around the origin
+    REPEAT_LIMITED{period: Vec3,count: Vec3},//Copies from -count to count on each axis
+    MIRROR{axes: Vec3},//Axes with a non zero value get reflected onto their positive side
+    TWIST{rate: f32},//Radians per unit around y
+    BEND{rate: f32},//Radians per unit along x, bends up towards y
+    ELONGATE{half: Vec3},//Stretches the object from its origin, without scaling its features
+    ROUND{radius: f32},
+    ONION{thickness: f32},//Shell of the surface
+}
+
+impl DomainOp {
+    //How much faster than 1 the warped distance can change around an object with bounds bb.
+    //Sphere tracing divides by it so it never steps through the surface
+    pub fn lipschitz(&self,bb: &BoundingBox3D) -> f32{
+        return match self {
+            //The twisted/bent point moves rate*r per unit on top of the rigid part, r being how far it is from the axis
+            DomainOp::TWIST{rate} => 1. + rate.abs()*axis_reach(bb,1),
+            DomainOp::BEND{rate} => 1. + rate.abs()*axis_reach(bb,2),
+            _ => 1.,
+        };
+    }
+}
+
+//How far the box goes from the axis through the origin along axis, the farthest point of a box is one of its corners
+fn axis_reach(bb: &BoundingBox3D,axis: usize) -> f32{
+    return bb.corners().iter().map(|c| {
+        let mut c = *c;
+        c[axis] = 0.;
+        c.length()
+    }).fold(0.,f32::max);
+}
+
+#[inline]
+fn component(v: &Vec3,f: &dyn Fn(f32,usize) -> f32) -> Vec3{
+    return Vec3::new(f(v.x(),0),f(v.y(),1),f(v.z(),2));
+}
+
+pub struct MarchedDomain {
+    pub object: Arc<dyn Marched + Send + Sync>,
+    pub op: DomainOp,
+    pub m_local_to_world: Mat4x4,//Rigid, scale with a MarchedInstance around the node
+    pub m_word_to_local: Mat4x4,
+    pub material: Material,
+    lipschitz: f32,
+}
+
+impl MarchedDomain {
+    pub fn new(object: &Arc<dyn Marched + Send + Sync>,op: DomainOp,m_local_to_world: &Mat4x4) -> Self{
+        let lipschitz = op.lipschitz(&object.build_world_bounding_box());
+        assert!(lipschitz.is_finite(),"Twisted and bent objects need finite bounds");
+        return Self{object: object.clone(),op: op,m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),
+                    material: *object.material(),lipschitz: lipschitz};
+    }
+    //Where in the object's space a local point ends up
+    fn warp(&self,p: &Point3) -> Point3{
+        return match self.op {
+            DomainOp::REPEAT{period} => component(p,&|x,i| if period[i] > 0. { x - period[i]*(x/period[i]).round() } else { x }),
+            DomainOp::REPEAT_LIMITED{period,count} => component(p,&|x,i| if period[i] > 0. {
+                x - period[i]*(x/period[i]).round().max(-count[i]).min(count[i])
+            } else { x }),
+            DomainOp::MIRROR{axes} => component(p,&|x,i| if axes[i] != 0. { x.abs() } else { x }),
+            DomainOp::TWIST{rate,..} => {
+                let (s,c) = (rate*p.y()).sin_cos();
+                Point3::new(c*p.x() - s*p.z(),p.y(),s*p.x() + c*p.z())
+            },
+            DomainOp::BEND{rate,..} => {
+                let (s,c) = (rate*p.x()).sin_cos();
+                Point3::new(c*p.x() - s*p.y(),s*p.x() + c*p.y(),p.z())
+            },
+            DomainOp::ELONGATE{half} => *p - p.max(&-half.abs()).min(&half.abs()),
+            DomainOp::ROUND{..} | DomainOp::ONION{..} => *p,
+        };
+    }
+    fn eval(&self,local_p: &Point3,time: f32) -> f32{
+        let d = self.object.sdf_at(&self.warp(local_p),time);
+        return match self.op {
+            DomainOp::ROUND{radius} => d - radius,
+            DomainOp::ONION{thickness} => d.abs() - thickness,
+            _ => d,
+        };
+    }
+    fn world_eval(&self,p: &Point3,time: f32) -> f32{
+        return self.eval(&self.m_word_to_local.dot_p3(p),time)/self.lipschitz;
+    }
+}
+
+impl Marched for MarchedDomain {
+    fn local_sdf(&self,p: &Point3) -> f32 {
+        return self.eval(p,0.);
+    }
+    fn sdf_at(&self,p: &Point3,time: f32) -> f32{
+        return self.world_eval(p,time);
+    }
+    fn material(&self) -> &Material{
+        return &self.material;
+    }
+    fn material_at(&self,p: &Point3) -> Material{
+        return self.object.material_at(&self.warp(&self.m_word_to_local.dot_p3(p)));
+    }
+    fn material_at_time(&self,p: &Point3,time: f32) -> Material{
+        return self.object.material_at_time(&self.warp(&self.m_word_to_local.dot_p3(p)),time);
+    }
+    fn to_local(&self,p: &Vec4) -> Vec4{
+        return self.m_word_to_local.dot(p);
+    }
+    fn to_world(&self,p: &Vec4) -> Vec4{
+        return self.m_local_to_world.dot(p);
+    }
+    fn to_world_f(&self,f: f32) -> f32{
+        return f/self.lipschitz;
+    }
+    fn uv(&self,p: &Point3) -> (f32,f32){
+        return self.object.uv(&self.warp(&self.m_word_to_local.dot_p3(p)));
+    }
+    fn get_outward_normal(&self,p: &Point3,eps: f32) -> UnitVec3{
+        return sdf_gradient(|q| self.world_eval(q,0.),p,eps);
+    }
+    fn hit_record_at(&self,t: f32,p: &Point3,time: f32) -> HitRecord{
+        let normal = sdf_gradient(|q| self.world_eval(q,time),p,normal_eps(t,p));
+        return HitRecord{t: t,point: *p,normal: normal,geometric_normal: normal,material: self.material_at_time(p,time),obj_id: 0,
+                         uv: self.uv(p),tangent: Vec3::ZERO,fiber: false};
+    }
+}
+
+impl Bounded for MarchedDomain {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let bb = self.object.build_world_bounding_box();
+        let finite = bb.minp.x().is_finite() && bb.maxp.x().is_finite() && bb.minp.y().is_finite()
+                  && bb.maxp.y().is_finite() && bb.minp.z().is_finite() && bb.maxp.z().is_finite();
+        if !finite { return bb; }
+        let local = match self.op {
+            DomainOp::REPEAT{..} => return BoundingBox3D::draw_always(),
+            DomainOp::REPEAT_LIMITED{period,count} => {
+                let reach = component(&period,&|p,i| p.max(0.)*count[i]);
+                BoundingBox3D::new(&(bb.minp - reach),&(bb.maxp + reach))
+            },
+            DomainOp::MIRROR{axes} => {
+                let mirrored = BoundingBox3D::new(&component(&bb.maxp,&|x,i| if axes[i] != 0. { -x } else { bb.minp[i] }),
+                                                  &component(&bb.minp,&|x,i| if axes[i] != 0. { -x } else { bb.maxp[i] }));
+                bb.union(&mirrored)
+            },
+            DomainOp::TWIST{..} => {//Turns around y, staying as far from it as it was
+                let r = axis_reach(&bb,1);
+                BoundingBox3D::new(&Point3::new(-r,bb.minp.y(),-r),&Point3::new(r,bb.maxp.y(),r))
+            },
+            DomainOp::BEND{..} => {//Everything stays about as far from the origin as it was
+                let r = bb.corners().iter().map(|c| c.length()).fold(0.,f32::max);
+                BoundingBox3D::new(&Point3::new(-r,-r,bb.minp.z()),&Point3::new(r,r,bb.maxp.z()))
+            },
+            DomainOp::ELONGATE{half} => BoundingBox3D::new(&(bb.minp - half.abs()),&(bb.maxp + half.abs())),
+            DomainOp::ROUND{radius} => bb.grow(radius.max(0.)),
+            DomainOp::ONION{thickness} => bb.grow(thickness.max(0.)),
+        };
+        return local.dot(&self.m_local_to_world);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::math::vec3::{Vec3,UnitVec3,Point3};
+use crate::math::mat4x4::Mat4x4;
+use crate::utils::INF;
+use crate::ray::Ray;
+use crate::hits::HitRecord;
+use crate::materials::Material;
+use crate::bounding_box::*;
+use crate::traced::Traced;
+
+//Max levels of the min/max pyramid, enough for 2^31 cells a side
+const MAX_LEVELS: usize = 32;
+
+//Grid of heights in the unit square, x and z in [0;1] and y the height, placed in the world by m_local_to_world.
+//Each cell is two triangles. Rays walk a min/max pyramid of the cells front to back (Tevs et al. 2008, maximum mipmaps)
+//so they only test the triangles of the cells whose height range they go through
+pub struct Heightfield {
+    pub m_local_to_world: Mat4x4,
+    pub m_word_to_local: Mat4x4,
+    pub width: u32,//Samples along x
+    pub depth: u32,//Samples along z
+    pub heights: Vec<f32>,//Row by row along x, z = 0 first
+    pub material: Material,
+    normals: Vec<UnitVec3>,//Local, per sample
+    levels: Vec<(u32,u32,Vec<(f32,f32)>)>,//(cells along x, cells along z, min/max) per level, level 0 is per cell
+}
+
+impl Heightfield {
+    pub fn new(width: u32,depth: u32,heights: Vec<f32>,m_local_to_world: &Mat4x4,mat: &Material) -> Self{
+        assert!(width >= 2 && depth >= 2 && heights.len() as u64 == width as u64*depth as u64);
+        let mut ret = Self{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),
+                           width: width,depth: depth,heights: heights,material: *mat,normals: Vec::new(),levels: Vec::new()};
+        ret.build_normals();
+        ret.build_levels();
+        return ret;
+    }
+    //Top row of the image is z = 0
+    pub fn load(path: &str,m_local_to_world: &Mat4x4,mat: &Material) -> Result<Self,String>{
+        let (w,h,values) = crate::image_io::read_heightmap(path)?;
+        if w < 2 || h < 2 { return Err(format!("{}: heightmaps need at least 2x2 samples",path)); }
+        return Ok(Self::new(w,h,values,m_local_to_world,mat));
+    }
+    #[inline]
+    fn height(&self,x: u32,z: u32) -> f32{
+        return self.heights[z as usize*self.width as usize + x as usize];
+    }
+    #[inline]
+    fn sample_point(&self,x: u32,z: u32) -> Point3{
+        return Point3::new(x as f32/(self.width - 1) as f32,self.height(x,z),z as f32/(self.depth - 1) as f32);
+    }
+    //Central differences, one sided on the border
+    fn build_normals(&mut self){
+        let (dx,dz) = (1./(self.width - 1) as f32,1./(self.depth - 1) as f32);
+        self.normals = Vec::with_capacity(self.heights.len());
+        for z in 0..self.depth{
+            for x in 0..self.width{
+                let (x0,x1) = (x.saturating_sub(1),(x+1).min(self.width-1));
+                let (z0,z1) = (z.saturating_sub(1),(z+1).min(self.depth-1));
+                let dhdx = (self.height(x1,z) - self.height(x0,z))/(dx*(x1 - x0) as f32);
+                let dhdz = (self.height(x,z1) - self.height(x,z0))/(dz*(z1 - z0) as f32);
+                self.normals.push(Vec3::new(-dhdx,1.,-dhdz).unit());
+            }
+        }
+    }
+    fn build_levels(&mut self){
+        let (cx,cz) = (self.width - 1,self.depth - 1);
+        let mut level = Vec::with_capacity(cx as usize*cz as usize);
+        for z in 0..cz{
+            for x in 0..cx{
+                let h = [self.height(x,z),self.height(x+1,z),self.height(x,z+1),self.height(x+1,z+1)];
+                level.push((h[0].min(h[1]).min(h[2]).min(h[3]),h[0].max(h[1]).max(h[2]).max(h[3])));
+            }
+        }
+        self.levels = vec!((cx,cz,level));
+        while self.levels.len() < MAX_LEVELS {
+            let (pw,pd,prev) = self.levels.last().unwrap();
+            if *pw == 1 && *pd == 1 { break; }
+            let (w,d) = ((pw + 1)/2,(pd + 1)/2);
+            let mut next = Vec::with_capacity(w as usize*d as usize);
+            for z in 0..d{
+                for x in 0..w{
+                    let mut mm = (INF,-INF);
+                    for (ox,oz) in [(0,0),(1,0),(0,1),(1,1)]{
+                        let (px,pz) = (2*x + ox,2*z + oz);
+                        if px >= *pw || pz >= *pd { continue; }
+                        let c = prev[pz as usize*(*pw as usize) + px as usize];
+                        mm = (mm.0.min(c.0),mm.1.max(c.1));
+                    }
+                    next.push(mm);
+                }
+            }
+            self.levels.push((w,d,next));
+        }
+    }
+    //Local box of node (x,z) of a level, None if the ray misses it, else where it enters
+    #[inline]
+    fn node_entry(&self,level: usize,x: u32,z: u32,orig: &Point3,inv_dir: &Vec3,t_min: f32,t_max: f32) -> Option<f32>{
+        let (w,_d,mm) = &self.levels[level];
+        let (lo,hi) = mm[z as usize*(*w as usize) + x as usize];
+        let cells = 1u32 << level;
+        let (cx,cz) = ((self.width - 1) as f32,(self.depth - 1) as f32);
+        let minp = Point3::new((x*cells) as f32/cx,lo,(z*cells) as f32/cz);
+        let maxp = Point3::new((((x+1)*cells) as f32/cx).min(1.),hi,(((z+1)*cells) as f32/cz).min(1.));
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        for i in 0..3{
+            let ta = (minp[i] - orig[i])*inv_dir[i];
+            let tb = (maxp[i] - orig[i])*inv_dir[i];
+            t0 = t0.max(ta.min(tb));
+            t1 = t1.min(ta.max(tb));
+        }
+        return if t0 <= t1 { Some(t0) } else { None };
+    }
+    //Both triangles of a cell, split along the (x,z)-(x+1,z+1) diagonal. Returns t and the barycentric weights of its 4 corners
+    fn hit_cell(&self,r: &Ray,x: u32,z: u32,t_min: f32,t_max: f32) -> Option<(f32,[f32;4])>{
+        let p = [self.sample_point(x,z),self.sample_point(x+1,z),self.sample_point(x,z+1),self.sample_point(x+1,z+1)];
+        let mut best: Option<(f32,[f32;4])> = None;
+        for (a,b,c) in [(0,3,1),(0,2,3)]{
+            let closest = best.map_or(t_max,|h| h.0);
+            if let Some((t,b1,b2)) = hit_triangle(r,&p[a],&p[b],&p[c],t_min,closest) {
+                let mut w = [0.;4];
+                w[a] = 1. - b1 - b2;
+                w[b] = b1;
+                w[c] = b2;
+                best = Some((t,w));
+            }
+        }
+        return best;
+    }
+}
+
+//Möller–Trumbore, two sided
+#[inline]
+fn hit_triangle(r: &Ray,p0: &Point3,p1: &Point3,p2: &Point3,t_min: f32,t_max: f32) -> Option<(f32,f32,f32)>{
+    let e1 = *p1 - *p0;
+    let e2 = *p2 - *p0;
+    let pv = r.dir.cross(e2);
+    let det = e1.dot(pv);
+    if det.abs() < 1e-12 { return None; }
+    let inv_det = 1./det;
+    let tv = r.orig - *p0;
+    let b1 = tv.dot(pv)*inv_det;
+    if b1 < 0. || b1 > 1. { return None; }
+    let qv = tv.cross(e1);
+    let b2 = r.dir.dot(qv)*inv_det;
+    if b2 < 0. || b1 + b2 > 1. { return None; }
+    let t = e2.dot(qv)*inv_det;
+    if t < t_min || t > t_max { return None; }
+    return Some((t,b1,b2));
+}
+
+impl Traced for Heightfield {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let new_r = r.transform(&self.m_word_to_local);
+        let inv_dir = Vec3::new(1./new_r.dir.x(),1./new_r.dir.y(),1./new_r.dir.z());
+        let top = self.levels.len() - 1;
+        let mut closest = t_max;
+        let mut best: Option<(u32,u32,[f32;4])> = None;
+        //(level,x,z,entry t). Children are pushed far to near so the near ones come out first
+        let mut stack = [(0usize,0u32,0u32,0f32);4*MAX_LEVELS];
+        let mut stack_len = 0;
+        if let Some(t) = self.node_entry(top,0,0,&new_r.orig,&inv_dir,t_min,closest) {
+            stack[0] = (top,0,0,t);
+            stack_len = 1;
+        }
+        while stack_len > 0 {
+            stack_len -= 1;
+            let (level,x,z,entry) = stack[stack_len];
+            if entry > closest { continue; }
+            if level == 0 {
+                if let Some((t,w)) = self.hit_cell(&new_r,x,z,t_min,closest) {
+                    closest = t;
+                    best = Some((x,z,w));
+                }
+                continue;
+            }
+            let (w,d,_mm) = &self.levels[level-1];
+            let mut children = [(0u32,0u32,0f32);4];
+            let mut n = 0;
+            for (ox,oz) in [(0,0),(1,0),(0,1),(1,1)]{
+                let (cx,cz) = (2*x + ox,2*z + oz);
+                if cx >= *w || cz >= *d { continue; }
+                if let Some(t) = self.node_entry(level-1,cx,cz,&new_r.orig,&inv_dir,t_min,closest) {
+                    children[n] = (cx,cz,t);
+                    n += 1;
+                }
+            }
+            children[..n].sort_unstable_by(|a,b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
+            for c in &children[..n]{
+                stack[stack_len] = (level-1,c.0,c.1,c.2);
+                stack_len += 1;
+            }
+        }
+        let (x,z,w) = best?;
+        let corners = [(x,z),(x+1,z),(x,z+1),(x+1,z+1)];
+        let mut local_normal = Vec3::ZERO;
+        let mut local_point = Point3::ZERO;
+        for i in 0..4{
+            local_normal += w[i]*self.normals[corners[i].1 as usize*self.width as usize + corners[i].0 as usize];
+            local_point  += w[i]*self.sample_point(corners[i].0,corners[i].1);
+        }
+        //The face of the triangle that got hit, w is 0 for the corner it doesn't use
+        let (ia,ib,ic) = if w[2] == 0. { (0,3,1) } else { (0,2,3) };
+        let p = corners.map(|c| self.sample_point(c.0,c.1));
+        let mut local_face = (p[ib] - p[ia]).cross(p[ic] - p[ia]);
+        if local_face.y() < 0. { local_face = -local_face; }
+        let normal_matrix = self.m_word_to_local.transpose();
+        return Some(HitRecord{t: closest,point: self.m_local_to_world.dot_p3(&local_point),normal: normal_matrix.dot_v3(&local_normal).unit(),
+                              geometric_normal: normal_matrix.dot_v3(&local_face).unit(),material: self.material,obj_id: 0,
+                              uv: (local_point.x(),local_point.z()),tangent: self.m_local_to_world.dot_v3(&Vec3::new(1.,0.,0.)).unit(),fiber: false});
+    }
+}
+
+impl Bounded for Heightfield {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let (lo,hi) = self.levels[self.levels.len() - 1].2[0];
+        return BoundingBox3D::new(&Point3::new(0.,lo,0.),&Point3::new(1.,hi,1.)).dot(&self.m_local_to_world);
+    }
+}
@@ -1,19 +1,25 @@
 use crate::math::vec3::{Vec3,UnitVec3,Point3};
-use crate::utils::{INF,get_id,VecIndexes};
+use crate::utils::{INF,VecIndexes};
 use crate::ray::Ray;
 use crate::materials::Material;
 use crate::traced::*;
 use crate::marched::*;
 use crate::camera::Camera;
-use crate::bounding_box::Bounded;
+use crate::bounding_box::{Bounded,BoundingBox3D};
 use crate::camera_hash::*;
+use crate::report::RenderCounters;
+use crate::texture::Texture;
 
 pub struct HitRecord {
     pub point: Point3,
-    pub normal: UnitVec3,//Always outward from the surface
+    pub normal: UnitVec3,//Always outward from the surface. The shading normal, interpolated or perturbed by normal/bump maps
+    pub geometric_normal: UnitVec3,//Of the actual surface, on the same side as normal. What rays leaving it get offset along
     pub material: Material,
     pub t: f32,
     pub obj_id: u64,
+    pub uv: (f32,f32),//Surface parametrization, usually [0;1] but some primitives (planes) are unbounded
+    pub tangent: UnitVec3,//dP/du, along the fiber for curves. Zero when the primitive doesn't provide it
+    pub fiber: bool,//Hit a curve, hair materials only shade those as fibers
 }
 
 use std::sync::Arc;
@@ -22,7 +28,6 @@ use std::sync::Arc;
 macro_rules! set_hash_index {
     ($camera_hash:expr,$bb:expr,$idx:expr,$ident:ident) => {
         let (mini,maxi,minj,maxj) = $camera_hash.get_indexes(&$bb);
-        println!("indexes {},{} - {},{}",mini,minj,maxi,maxj);
         for i in mini..=maxi{
             for j in minj..=maxj{
                 $camera_hash.cells[i][j].$ident.add($idx);
@@ -51,6 +56,14 @@ struct CameraHashCell {
     $(pub $marched_ident: VecIndexes<MAX_INDEXES_HASH>,)*
 }
 
+impl CameraHashCell {
+    fn overflow(&self) -> bool {
+        return self.traced_objects.overflow || self.marched_objects.overflow
+        $(|| self.$traced_ident.overflow)*
+        $(|| self.$marched_ident.overflow)*;
+    }
+}
+
 impl CellEmptyInitializable for CameraHashCell {
     fn empty(&mut self) -> () {
         self.traced_objects.empty();
@@ -60,12 +73,31 @@ impl CellEmptyInitializable for CameraHashCell {
     }
 }
 
+//World bounds of the marched objects grown by HIT_SIZE, same order as their lists
+struct MarchedBounds {
+    marched_objects: Vec<BoundingBox3D>,
+    $($marched_ident: Vec<BoundingBox3D>,)*
+}
+
+//Object ids are 1 + where the object is in the scene, counting list after list and every object inside groups. Building the
+//same scene gives the same ids no matter where the objects end up in memory, so the id AOVs of an animation don't change
+//from frame to frame. Hits give their id relative to the list entry (see Traced::hit), the entry's first id is added to it
+struct FirstIds {
+    traced_objects: Vec<u64>,//Per object, they can take more than one id
+    marched_objects: u64,
+    $($traced_ident: u64,)*
+    $($marched_ident: u64,)*
+}
+
 pub struct FrozenHittableList{
     traced_objects: Vec<Arc<dyn Traced + Send + Sync>>,
     marched_objects: Vec<Arc<dyn Marched + Send + Sync>>,
     $($traced_ident: Vec<$traced>,)*
     $($marched_ident: Vec<$marched>,)*
+    marched_bounds: MarchedBounds,
+    first_ids: FirstIds,
     camera_hash: Box<CameraHash<CameraHashCell>>, 
+    pub textures: Arc<[Texture]>,//What the materials' texture ids index, minus one
 }
 
 impl HittableList {
@@ -84,8 +116,8 @@ impl HittableList {
         $(self.$traced_ident.clear();)*
         $(self.$marched_ident.clear();)*
     }
-    pub fn freeze(&mut self,cam: &Camera) -> FrozenHittableList{
-        return FrozenHittableList::new(self,cam);
+    pub fn freeze(&mut self,cam: &Camera,textures: &Arc<[Texture]>) -> FrozenHittableList{
+        return FrozenHittableList::new(self,cam,textures);
     }
 }
 impl std::ops::AddAssign<Arc<dyn Traced + Send + Sync>> for HittableList {
@@ -111,38 +143,37 @@ $(impl std::ops::AddAssign<&$marched> for HittableList{
 
 
 const HIT_SIZE: f32 = 0.001;
+const MAX_MARCH_ITER: u32 = 1024;
 
 impl FrozenHittableList{
-    pub fn new(hl: &mut HittableList,cam: &Camera) -> Self{
+    pub fn new(hl: &mut HittableList,cam: &Camera,textures: &Arc<[Texture]>) -> Self{
+        let mut next_id: u64 = 1;
+        let mut first_id = |len: usize| { next_id += len as u64; next_id - len as u64 };
+        let first_ids = FirstIds{traced_objects: hl.traced_objects.iter().map(|o| first_id(o.id_count() as usize)).collect(),
+            marched_objects: first_id(hl.marched_objects.len()),
+            $($traced_ident: first_id(hl.$traced_ident.len()),)*
+            $($marched_ident: first_id(hl.$marched_ident.len()),)*
+        };
         let mut ret = Self{
             traced_objects: hl.traced_objects.clone(),
             marched_objects: hl.marched_objects.clone(),
             $($traced_ident: hl.$traced_ident.clone(),)*
             $($marched_ident: hl.$marched_ident.clone(),)*
+            marched_bounds: MarchedBounds{marched_objects: Vec::new(),$($marched_ident: Vec::new(),)*},
+            first_ids: first_ids,
             camera_hash: unsafe {//Rust is just awful, all this mess just to init on the heap
                 let layout = std::alloc::Layout::new::<CameraHash<CameraHashCell>>();
                 let ptr = std::alloc::alloc(layout) as *mut CameraHash<CameraHashCell>;
                 (*ptr).empty(); 
                 Box::from_raw(ptr)
             },
+            textures: textures.clone(),
         };
 
-        let camera_hash = &ret.camera_hash;
-        let world_to_camera = cam.world_to_camera();
-        /*let bb = cam.viewport_world_bounding_box();
-        let bb = bb.dot(&world_to_camera);
-        let bb = bb.project(cam);
-        println!("{:?}",bb);*/
         $({
             let mut idx = 0;
-            for obj in &mut ret.$traced_ident{
-                let bb = obj.build_world_bounding_box();
-                println!("{:?}",bb);
-                let bb = bb.dot(&world_to_camera);
-                println!("{:?}",bb);
-                let bb = bb.project(cam);
-                println!("{:?}",bb);
-                //println!("{:?}",bb);
+            for obj in &ret.$traced_ident{
+                let bb = obj.build_world_bounding_box().project(cam);
                 set_hash_index!(ret.camera_hash,bb,idx,$traced_ident);
                 idx += 1;
             }
@@ -150,10 +181,8 @@ impl FrozenHittableList{
 
         {
             let mut idx = 0;
-            for obj in &mut ret.traced_objects{//This modifies the base object rather than clone it, not sure how I feel about it
-                let bb = Arc::get_mut(obj).unwrap().build_world_bounding_box();
-                let bb = bb.dot(&world_to_camera);
-                let bb = bb.project(cam);
+            for obj in &ret.traced_objects{
+                let bb = obj.build_world_bounding_box().project(cam);
                 set_hash_index!(ret.camera_hash,bb,idx,traced_objects);
                 idx += 1;
             }
@@ -161,22 +190,22 @@ impl FrozenHittableList{
 
         $({
             let mut idx = 0;
-            for obj in &mut ret.$marched_ident{
-                let bb = obj.build_world_bounding_box();
-                let bb = bb.dot(&world_to_camera);
-                let bb = bb.project(cam);
-                set_hash_index!(ret.camera_hash,bb,idx,traced_objects);
+            for obj in &ret.$marched_ident{
+                let wbb = obj.build_world_bounding_box();
+                ret.marched_bounds.$marched_ident.push(wbb.grow(HIT_SIZE));
+                let bb = wbb.project(cam);
+                set_hash_index!(ret.camera_hash,bb,idx,$marched_ident);
                 idx += 1;
             }
         })*
 
         {
             let mut idx = 0;
-            for obj in &mut ret.marched_objects {
-                let bb = Arc::get_mut(obj).unwrap().build_world_bounding_box();
-                let bb = bb.dot(&world_to_camera);
-                let bb = bb.project(cam);
-                set_hash_index!(ret.camera_hash,bb,idx,traced_objects);
+            for obj in &ret.marched_objects {
+                let wbb = obj.build_world_bounding_box();
+                ret.marched_bounds.marched_objects.push(wbb.grow(HIT_SIZE));
+                let bb = wbb.project(cam);
+                set_hash_index!(ret.camera_hash,bb,idx,marched_objects);
                 idx += 1;
             }
         }
@@ -184,18 +213,22 @@ impl FrozenHittableList{
         return ret;
     }
 
-    pub fn first_hit(&self,r: &Ray,t_min: f32,t_max: f32,u: f32,v: f32) -> Option<HitRecord> {
+    pub fn first_hit(&self,r: &Ray,t_min: f32,t_max: f32,u: f32,v: f32,counters: &mut RenderCounters) -> Option<HitRecord> {
         //Ray tracing section
         let mut closest_so_far = t_max;
         let mut rec: Option<HitRecord>  = None;
         //println!("{},{} -> {},{}",u,v,r.dir.to_z1().x(),r.dir.to_z1().y());
         let cell = self.camera_hash.at(u,v);
+        if cell.overflow() {//Too many objects to index here, check everything
+            return self.hit(r,t_min,t_max,counters);
+        }
         $({
             let arr = &cell.$traced_ident;
             for idx_idx in 0..arr.count{
                 let idx = arr.arr[idx_idx];
-                if let Some(hr) = self.$traced_ident[idx].hit(r,t_min,closest_so_far) {
+                if let Some(mut hr) = self.$traced_ident[idx].hit(r,t_min,closest_so_far) {
                     closest_so_far = hr.t;
+                    hr.obj_id += self.first_ids.$traced_ident + idx as u64;
                     rec = Some(hr);
                 }
             }
@@ -204,171 +237,204 @@ impl FrozenHittableList{
             let arr = &cell.traced_objects;
             for idx_idx in 0..arr.count{
                 let idx = arr.arr[idx_idx];
-                if let Some(hr) = self.traced_objects[idx].hit(r,t_min,closest_so_far) {
+                if let Some(mut hr) = self.traced_objects[idx].hit(r,t_min,closest_so_far) {
                     closest_so_far = hr.t;
+                    hr.obj_id += self.first_ids.traced_objects[idx];
                     rec = Some(hr);
                 }
             }
         }
 
-        let mut t = self.unstuck(t_min,r);//If we started stuck in a object... unstuck ourselves
-        if t.is_infinite() {//No marched objects in the scene. return raycasted result
-            return rec;
-        }
-        let mut max_march_iter = 1024;
-
-        while t < t_max && t < closest_so_far && max_march_iter > 0 {
-            max_march_iter-=1;
-            let point = r.at(t);
-            let mut distance = INF;
-            let mut normal   = Vec3::ZERO;
-            let mut id       = 0;
-            let mut material: Option<Material> = None;
-            $({
-                let arr = cell.$marched_ident;
-                for idx_idx in 0..arr.count{
-                    let idx = arr.arr[idx_idx];
-                    let obj = &self.$marched_ident[idx];
-                    let d = obj.sdf(&point).abs();//Not an actual vtable call, just a normal fast function call
-                    if d < distance {
-                        distance = d;
-                        normal = obj.get_outward_normal(&point);//Not an actual vtable call, just a normal fast function call
-                        material = Some(obj.material);
-                        id = get_id(obj);
-                    }
-                }
-            })*
-            {
-                let arr = cell.marched_objects;
-                for idx_idx in 0..arr.count{
-                    let idx = arr.arr[idx_idx];
-                    let obj = &self.marched_objects[idx];
-                    let d = obj.sdf(&point).abs();//Not an actual vtable call, just a normal fast function call
-                    if d < distance {
-                        distance = d;
-                        normal = obj.get_outward_normal(&point);//Not an actual vtable call, just a normal fast function call
-                        material = Some(*obj.material());
-                        id = get_id(obj.as_ref());
-                    }
+        //Ray marching section, only what the ray goes through the bounds of before the closest traced hit
+        let inv_dir = Vec3::new(1./r.dir.x(),1./r.dir.y(),1./r.dir.z());
+        let mut candidates = MarchCandidates::new();
+        $({
+            let arr = &cell.$marched_ident;
+            for idx_idx in 0..arr.count{
+                let idx = arr.arr[idx_idx];
+                if let Some((t0,t1)) = self.marched_bounds.$marched_ident[idx].ray_interval(&r.orig,&inv_dir,t_min,closest_so_far) {
+                    candidates.push(MarchCandidate{obj: &self.$marched_ident[idx],id: self.first_ids.$marched_ident + idx as u64,t0: t0,t1: t1});
                 }
             }
-
-            //Should never happen the only raymarched object gets deleted mid transition between unstucking and raymarching
-            if material.is_none() { return rec; }
-
-            if distance < HIT_SIZE {//We hit something
-                rec = Some(HitRecord{t: t,point: point,normal: normal, material: material.unwrap(),obj_id: id});
-                break;
-            }
-            else { //Move forward
-                t += distance;//This only works if our direction in our Ray is unit length!!!
+        })*
+        {
+            let arr = &cell.marched_objects;
+            for idx_idx in 0..arr.count{
+                let idx = arr.arr[idx_idx];
+                if let Some((t0,t1)) = self.marched_bounds.marched_objects[idx].ray_interval(&r.orig,&inv_dir,t_min,closest_so_far) {
+                    candidates.push(MarchCandidate{obj: self.marched_objects[idx].as_ref(),id: self.first_ids.marched_objects + idx as u64,t0: t0,t1: t1});
+                }
             }
         }
-        return rec; 
+        return sphere_trace(&candidates,r,t_min,closest_so_far,counters).or(rec);
     }
 
-    pub fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+    pub fn hit(&self,r: &Ray,t_min: f32,t_max: f32,counters: &mut RenderCounters) -> Option<HitRecord> {
         //Ray tracing section
         let mut closest_so_far = t_max;
         let mut rec: Option<HitRecord>  = None;
-        $(for obj in &self.$traced_ident{
-            if let Some(hr) = obj.hit(r,t_min,closest_so_far) {
+        $(for (idx,obj) in self.$traced_ident.iter().enumerate(){
+            if let Some(mut hr) = obj.hit(r,t_min,closest_so_far) {
                 closest_so_far = hr.t;
+                hr.obj_id += self.first_ids.$traced_ident + idx as u64;
                 rec = Some(hr);
             }
         })*
-        for obj in &self.traced_objects{
-            if let Some(hr) = obj.hit(r,t_min,closest_so_far) {
+        for (idx,obj) in self.traced_objects.iter().enumerate(){
+            if let Some(mut hr) = obj.hit(r,t_min,closest_so_far) {
                 closest_so_far = hr.t;
+                hr.obj_id += self.first_ids.traced_objects[idx];
                 rec = Some(hr);
             }
         }
 
         //Ray marching section
-        let mut t = self.unstuck(t_min,r);//If we started stuck in a object... unstuck ourselves
-        if t.is_infinite() {//No marched objects in the scene. return raycasted result
-            return rec;
-        }
-        let mut max_march_iter = 1024;
-
-        while t < t_max && t < closest_so_far && max_march_iter > 0 {
-            max_march_iter-=1;
-            let point = r.at(t);
-            let mut distance = INF;
-            let mut normal   = Vec3::ZERO;
-            let mut id       = 0;
-            let mut material: Option<Material> = None;
-            $(
-                for obj in &self.$marched_ident{
-                    let d = obj.sdf(&point).abs();//Not an actual vtable call, just a normal fast function call
-                    if d < distance {
-                        distance = d;
-                        normal = obj.get_outward_normal(&point);//Not an actual vtable call, just a normal fast function call
-                        material = Some(obj.material);
-                        id = get_id(obj);
-                    }
-                }
-            )*
-            for obj in &self.marched_objects{
-                let d = obj.sdf(&point).abs();
-                if d < distance {
-                    distance = d;
-                    normal = obj.get_outward_normal(&point);
-                    material = Some(*obj.material());
-                    id = get_id(obj.as_ref());
-                }
+        let inv_dir = Vec3::new(1./r.dir.x(),1./r.dir.y(),1./r.dir.z());
+        let mut candidates = MarchCandidates::new();
+        $(for (idx,(obj,bb)) in self.$marched_ident.iter().zip(&self.marched_bounds.$marched_ident).enumerate(){
+            if let Some((t0,t1)) = bb.ray_interval(&r.orig,&inv_dir,t_min,closest_so_far) {
+                candidates.push(MarchCandidate{obj: obj,id: self.first_ids.$marched_ident + idx as u64,t0: t0,t1: t1});
+            }
+        })*
+        for (idx,(obj,bb)) in self.marched_objects.iter().zip(&self.marched_bounds.marched_objects).enumerate(){
+            if let Some((t0,t1)) = bb.ray_interval(&r.orig,&inv_dir,t_min,closest_so_far) {
+                candidates.push(MarchCandidate{obj: obj.as_ref(),id: self.first_ids.marched_objects + idx as u64,t0: t0,t1: t1});
             }
+        }
+        return sphere_trace(&candidates,r,t_min,closest_so_far,counters).or(rec);
+    }
+}
+ 
+};}
 
-            //Should never happen the only raymarched object gets deleted mid transition between unstucking and raymarching
-            if material.is_none() { return rec; }
+hittable_list!(spheres;Sphere, cubes;Cube, triangles;Triangle,infinite_planes;InfinitePlane,parallelograms;Parallelogram,
+               cylinders;Cylinder,cones;Cone,disks;Disk,capsules;Capsule,tori;Torus,quadrics;Quadric,curves;Curve
+              |marched_spheres;MarchedSphere,marched_boxes;MarchedBox,marched_torus;MarchedTorus,
+               mandelbulbs;MarchedMandelbulb,menger_sponges;MarchedMengerSponge,mandelboxes;MarchedMandelbox,sierpinskis;MarchedSierpinski);
+
+//Relaxed steps go this many times the distance, see sphere_trace
+const MARCH_RELAXATION: f32 = 1.5;
+
+//A marched object and the part of the ray inside its bounds
+#[derive(Copy,Clone)]
+struct MarchCandidate<'a> {
+    obj: &'a (dyn Marched + Send + Sync),
+    id: u64,
+    t0: f32,
+    t1: f32,
+}
 
-            if distance < HIT_SIZE {//We hit something
-                rec = Some(HitRecord{t: t,point: point,normal: normal, material: material.unwrap(),obj_id: id});
-                break;
-            }
-            else { //Move forward
-                t += distance;//This only works if our direction in our Ray is unit length!!!
-            }
+//Candidates kept on the stack, this runs for every ray. Past MAX_CANDIDATES the rest go to the heap
+const MAX_CANDIDATES: usize = 32;
+struct MarchCandidates<'a> {
+    arr: [Option<MarchCandidate<'a>>;MAX_CANDIDATES],
+    count: usize,
+    spilled: Vec<MarchCandidate<'a>>,//Doesn't allocate until something is pushed
+}
+
+impl<'a> MarchCandidates<'a> {
+    #[inline]
+    fn new() -> Self{
+        return Self{arr: [None;MAX_CANDIDATES],count: 0,spilled: Vec::new()};
+    }
+    #[inline]
+    fn push(&mut self,c: MarchCandidate<'a>){
+        if self.count < MAX_CANDIDATES {
+            self.arr[self.count] = Some(c);
+            self.count += 1;
+        }
+        else {
+            self.spilled.push(c);
+        }
+    }
+    #[inline]
+    fn is_empty(&self) -> bool{
+        return self.count == 0;
+    }
+    #[inline]
+    fn iter(&self) -> impl Iterator<Item = &MarchCandidate<'a>>{
+        return self.arr[..self.count].iter().flatten().chain(self.spilled.iter());
+    }
+}
+
+//Closest distance among the candidates whose bounds contain t, where the first of those bounds ends
+//and where the next bounds after t start
+#[inline]
+fn closest_candidate<'a,'b>(candidates: &'b MarchCandidates<'a>,r: &Ray,t: f32) -> (f32,Option<&'b MarchCandidate<'a>>,f32,f32){
+    let point = r.at(t);
+    let mut distance = INF;
+    let mut closest: Option<&MarchCandidate> = None;
+    let mut exit = INF;
+    let mut next_entry = INF;
+    for c in candidates.iter(){
+        if c.t0 > t { next_entry = next_entry.min(c.t0); continue; }
+        if c.t1 < t { continue; }
+        exit = exit.min(c.t1);
+        let d = c.obj.sdf_at(&point,r.time).abs();
+        if d < distance {
+            distance = d;
+            closest = Some(c);
         }
-        return rec; 
     }
+    return (distance,closest,exit,next_entry);
+}
 
-    pub fn unstuck(&self,t: f32,r: &Ray) -> f32{
+//Enhanced sphere tracing (Keinert et al. 2014). Steps get stretched by MARCH_RELAXATION as long as the unbounding spheres
+//of consecutive points overlap. Once they don't there could be a surface in the gap, so we go back to the plain step and
+//stop relaxing for the rest of the ray. Outside every bounds we jump straight to the next one
+fn sphere_trace(candidates: &MarchCandidates,r: &Ray,t_min: f32,t_max: f32,counters: &mut RenderCounters) -> Option<HitRecord>{
+    if candidates.is_empty() { return None; }//Nothing marched in the way
+    let mut t = t_min;
+    {//If we started stuck in a object... unstuck ourselves
         const MIN_STEP_SIZE: f32 = HIT_SIZE/2.;
-        let mut new_t = t;
-        let mut d = f32::INFINITY;
-        let p = r.at(t);
-        let mut marched: Option<&(dyn Marched + Send + Sync)> = None;
-
-        $(for obj in &self.$marched_ident{
-            let nd = obj.sdf(&p).abs();//Not an actual vtable call, just a normal fast function call
-            if nd < d {
-                d = nd;
-                marched = Some(obj);
-            }
-        })*
-        for obj in &self.marched_objects {
-            let nd = obj.sdf(&p).abs();
-            if nd < d {
-                d = nd;
-                marched = Some(obj.as_ref());
+        let (d,stuck,_,_) = closest_candidate(candidates,r,t);
+        if let Some(c) = stuck {
+            let mut aux = d;
+            while aux < HIT_SIZE {
+                t += MIN_STEP_SIZE;
+                aux = c.obj.sdf_at(&r.at(t),r.time).abs();
             }
         }
-
-        let mut aux = d;
-        if marched.is_none() { return f32::INFINITY; }//No marched objects on the Scene... return what we already
-        while aux < HIT_SIZE {
-            new_t += MIN_STEP_SIZE;
-            aux = marched.unwrap().sdf(&r.at(new_t)).abs();
+    }
+    let mut iters = 0;
+    let mut relaxation = MARCH_RELAXATION;
+    let mut prev_distance = 0.;
+    let mut step: f32 = 0.;
+    let mut rec: Option<HitRecord> = None;
+    while t < t_max && iters < MAX_MARCH_ITER {
+        let (distance,closest,exit,next_entry) = closest_candidate(candidates,r,t);
+        if closest.is_none() {//Between bounds, nothing to evaluate until the next one
+            t = next_entry;
+            prev_distance = 0.;
+            step = 0.;
+            continue;
+        }
+        iters += 1;
+        if relaxation > 1. && distance + prev_distance < step {//Overstepped, back to where a plain step would've landed
+            t += prev_distance - step;
+            step = prev_distance;
+            relaxation = 1.;
+            continue;
+        }
+        if distance < HIT_SIZE {//We hit something
+            //Only now that we know what we hit we bother with the normal, uv, etc
+            let c = closest.unwrap();
+            let mut hr = c.obj.hit_record_at(t,&r.at(t),r.time);
+            hr.obj_id = c.id;
+            rec = Some(hr);
+            break;
         }
-        return new_t;
+        //Relaxed steps land inside every bounds they were measured in, otherwise the overstep check above would be made
+        //with other objects' distances or skipped. Plain steps are safe anywhere.
+        //Never past the start of other bounds, those objects didn't bound the step. Only works with unit length ray directions!!!
+        prev_distance = distance;
+        step = (relaxation*distance).min(exit - t).max(distance).min(next_entry - t);
+        t += step;
     }
+    let exhausted = rec.is_none() && iters == MAX_MARCH_ITER && t < t_max;
+    counters.add_march(iters,exhausted);
+    return rec;
 }
- 
-};}
-
-hittable_list!(spheres;Sphere, cubes;Cube, triangles;Triangle,infinite_planes;InfinitePlane,parallelograms;Parallelogram
-              |marched_spheres;MarchedSphere,marched_boxes;MarchedBox,marched_torus;MarchedTorus);
 
 //Only tested with abs(obj.sdf(r.at(t))) < HIT_SIZE
 #[allow(dead_code)]
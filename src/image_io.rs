@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{BufWriter,Write};
+
+//Binary PPM (P6), rgb is expected to be width*height*3 bytes, top row first
+pub fn write_ppm(path: &str,width: u32,height: u32,rgb: &[u8]) -> std::io::Result<()>{
+    assert!(rgb.len() == (width*height*3) as usize);
+    let mut f = BufWriter::new(File::create(path)?);
+    write!(f,"P6\n{} {}\n255\n",width,height)?;
+    f.write_all(rgb)?;
+    return f.flush();
+}
+
+//Little endian PFM, channels is 1 (Pf) or 3 (PF). Values are written as is, no tonemapping
+//PFM stores rows bottom to top, data is expected top row first like everything else
+pub fn write_pfm(path: &str,width: u32,height: u32,channels: u32,data: &[f32]) -> std::io::Result<()>{
+    assert!(channels == 1 || channels == 3);
+    assert!(data.len() == (width*height*channels) as usize);
+    let mut f = BufWriter::new(File::create(path)?);
+    write!(f,"{}\n{} {}\n-1.0\n",["Pf","PF"][(channels == 3) as usize],width,height)?;
+    let row_len = (width*channels) as usize;
+    for row in (0..height as usize).rev(){
+        for v in &data[row*row_len..(row+1)*row_len]{
+            f.write_all(&v.to_le_bytes())?;
+        }
+    }
+    return f.flush();
+}
+
+//"out.ppm" + "spp" + "pfm" -> "out_spp.pfm"
+pub fn sibling_path(path: &str,suffix: &str,extension: &str) -> String{
+    let stem = match path.rfind('.') {
+        Some(dot) if dot > path.rfind('/').map_or(0,|s| s+1) => &path[..dot],
+        _ => path,
+    };
+    return format!("{}_{}.{}",stem,suffix,extension);
+}
+
+//Header tokens of PGM/PFM files, skips whitespace and # comments. Returns the token and where it ended
+fn header_token(data: &[u8],mut pos: usize) -> Result<(String,usize),String>{
+    loop {
+        while pos < data.len() && data[pos].is_ascii_whitespace() { pos += 1; }
+        if pos < data.len() && data[pos] == b'#' {
+            while pos < data.len() && data[pos] != b'\n' { pos += 1; }
+            continue;
+        }
+        break;
+    }
+    let start = pos;
+    while pos < data.len() && !data[pos].is_ascii_whitespace() { pos += 1; }
+    if start == pos { return Err("Truncated header".to_string()); }
+    return Ok((String::from_utf8_lossy(&data[start..pos]).to_string(),pos));
+}
+
+fn parse_header_value<T: std::str::FromStr>(data: &[u8],pos: usize,what: &str) -> Result<(T,usize),String>{
+    let (tok,pos) = header_token(data,pos)?;
+    return tok.parse::<T>().map(|v| (v,pos)).map_err(|_| format!("Bad {} '{}'",what,tok));
+}
+
+//Image as (width,height,channels,values) top row first, channels interleaved. PGM/PPM (binary or ascii, 8 or 16 bit)
+//are normalized to [0;1], PFM is read as is
+pub fn read_image(path: &str) -> Result<(u32,u32,u32,Vec<f32>),String>{
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}",path,e))?;
+    let (magic,pos) = header_token(&data,0)?;
+    let (width,pos) = parse_header_value::<usize>(&data,pos,"width")?;
+    let (height,pos) = parse_header_value::<usize>(&data,pos,"height")?;
+    let channels = match magic.as_str() {
+        "P2" | "P5" | "Pf" => 1,
+        "P3" | "P6" | "PF" => 3,
+        _ => return Err(format!("{}: unknown format '{}', expected PGM, PPM or PFM",path,magic)),
+    };
+    if width == 0 || height == 0 || width > u32::MAX as usize || height > u32::MAX as usize {
+        return Err(format!("{}: bad size {}x{}",path,width,height));
+    }
+    let n = width.checked_mul(height).and_then(|n| n.checked_mul(channels)).ok_or_else(|| format!("{}: {}x{} is too big",path,width,height))?;
+    //Every value takes at least a byte of the file, so a bad header can't make us reserve more than that
+    let mut values = Vec::with_capacity(n.min(data.len()));
+    match magic.as_str() {
+        "P2" | "P3" => {
+            let (maxval,mut pos) = parse_header_value::<u32>(&data,pos,"maxval")?;
+            if maxval == 0 || maxval > 65535 { return Err(format!("{}: maxval {} isn't in [1;65535]",path,maxval)); }
+            for _i in 0..n{
+                let (v,next) = parse_header_value::<f32>(&data,pos,"value")?;
+                values.push(v/maxval as f32);
+                pos = next;
+            }
+        },
+        "P5" | "P6" => {
+            let (maxval,pos) = parse_header_value::<u32>(&data,pos,"maxval")?;
+            if maxval == 0 || maxval > 65535 { return Err(format!("{}: maxval {} isn't in [1;65535]",path,maxval)); }
+            let bytes = if maxval > 255 { 2 } else { 1 };
+            let body = &data[(pos+1).min(data.len())..];//A single whitespace after the header
+            if body.len()/bytes < n { return Err(format!("{}: expected {} bytes of pixels, got {}",path,n.saturating_mul(bytes),body.len())); }
+            for i in 0..n{//16 bit is big endian
+                let v = if bytes == 2 { u16::from_be_bytes([body[2*i],body[2*i+1]]) as u32 } else { body[i] as u32 };
+                values.push(v as f32/maxval as f32);
+            }
+        },
+        _ => {
+            let (scale,pos) = parse_header_value::<f32>(&data,pos,"scale")?;
+            let body = &data[(pos+1).min(data.len())..];
+            if body.len()/4 < n { return Err(format!("{}: expected {} bytes of pixels, got {}",path,n.saturating_mul(4),body.len())); }
+            values.resize(n,0.);
+            let row_len = width*channels;
+            for row in 0..height{//Bottom row first
+                for i in 0..row_len{
+                    let j = 4*(row*row_len + i);
+                    let b = [body[j],body[j+1],body[j+2],body[j+3]];
+                    values[(height - 1 - row)*row_len + i] = if scale < 0. { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) };
+                }
+            }
+        },
+    }
+    return Ok((width as u32,height as u32,channels as u32,values));
+}
+
+//Grayscale image as (width,height,values) top row first, the first channel of read_image or a square 16 bit little endian .raw/.r16
+pub fn read_heightmap(path: &str) -> Result<(u32,u32,Vec<f32>),String>{
+    let lower = path.to_lowercase();
+    if lower.ends_with(".raw") || lower.ends_with(".r16") {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}",path,e))?;
+        let n = data.len()/2;
+        let side = (n as f64).sqrt().round() as usize;
+        if side*side != n || data.len() % 2 != 0 { return Err(format!("{}: raw 16 bit heightmaps must be square",path)); }
+        let values = data.chunks_exact(2).map(|b| u16::from_le_bytes([b[0],b[1]]) as f32/65535.).collect();
+        return Ok((side as u32,side as u32,values));
+    }
+    let (w,h,channels,values) = read_image(path)?;
+    return Ok((w,h,values.into_iter().step_by(channels as usize).collect()));
+}
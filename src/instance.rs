@@ -0,0 +1,203 @@
+use crate::math::vec3::{Vec3,UnitVec3,Point3};
+use crate::math::vec4::Vec4;
+use crate::math::mat4x4::Mat4x4;
+use crate::ray::Ray;
+use crate::hits::HitRecord;
+use crate::materials::Material;
+use crate::bounding_box::*;
+use crate::traced::Traced;
+use crate::marched::{Marched,MarchedTransform,normal_eps};
+use crate::bvh::Bvh;
+use crate::motion::{AnimatedTransform,transforms_at};
+use std::sync::Arc;
+
+//Shared geometry placed with its own transform, the object is in local coordinates
+//Every instance has its own object ids, the material override replaces whatever the object had
+#[derive(Clone)]
+pub struct Instance {
+    pub object: Arc<dyn Traced + Send + Sync>,
+    pub m_local_to_world: Mat4x4,
+    pub m_word_to_local: Mat4x4,
+    pub material: Option<Material>,
+    pub motion: Option<AnimatedTransform>,//If set, the transforms above are the ones at the first keyframe
+}
+
+impl Instance {
+    pub fn new(object: &Arc<dyn Traced + Send + Sync>,m_local_to_world: &Mat4x4,material: Option<Material>) -> Self{
+        Self{object: object.clone(),m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),
+             material: material,motion: None}
+    }
+    //How meshes (or anything traced) move
+    #[allow(dead_code)]
+    pub fn new_moving(object: &Arc<dyn Traced + Send + Sync>,motion: &AnimatedTransform,material: Option<Material>) -> Self{
+        let mut ret = Self::new(object,&motion.start,material);
+        ret.motion = Some(*motion);
+        return ret;
+    }
+}
+
+impl Traced for Instance {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let (m_local_to_world,m_word_to_local) = transforms_at(&self.motion,&self.m_local_to_world,&self.m_word_to_local,r.time);
+        let local_r = r.transform(&m_word_to_local);
+        //Some objects need unit directions, t gets scaled back after
+        let len = local_r.dir.length();
+        if len == 0. { return None; }
+        let local_r = Ray{orig: local_r.orig,dir: local_r.dir/len,time: r.time};
+        let mut hr = self.object.hit(&local_r,t_min*len,t_max*len)?;
+        hr.t /= len;
+        hr.point = m_local_to_world.dot_p3(&hr.point);
+        let normal_matrix = m_word_to_local.transpose();
+        hr.normal = normal_matrix.dot_v3(&hr.normal).unit();
+        hr.geometric_normal = normal_matrix.dot_v3(&hr.geometric_normal).unit();
+        if hr.tangent.length_squared() > 0. {
+            hr.tangent = m_local_to_world.dot_v3(&hr.tangent).unit();
+        }
+        if let Some(m) = &self.material {
+            hr.material = *m;
+        }
+        return Some(hr);
+    }
+    fn id_count(&self) -> u64 {
+        return self.object.id_count();
+    }
+}
+
+impl Bounded for Instance {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let bb = self.object.build_world_bounding_box();
+        if let Some(motion) = &self.motion { return motion.motion_bounds(&bb); }
+        return bb.dot(&self.m_local_to_world);
+    }
+}
+
+//Traced objects behind their own BVH, so thousands of instances don't get tested one by one by every ray
+//It's an object itself so it can be instanced too
+//Each object keeps its own ids inside the group's, in the order they were given
+pub struct TracedGroup {
+    pub objects: Vec<Arc<dyn Traced + Send + Sync>>,
+    first_ids: Vec<u64>,//Where each object's ids start, relative to the group's
+    id_count: u64,
+    bvh: Bvh,
+}
+
+impl TracedGroup {
+    pub fn new(objects: Vec<Arc<dyn Traced + Send + Sync>>) -> Self{
+        let bboxes: Vec<BoundingBox3D> = objects.iter().map(|o| o.build_world_bounding_box()).collect();
+        let mut first_ids: Vec<u64> = Vec::with_capacity(objects.len());
+        let mut id_count: u64 = 0;
+        for o in &objects{
+            first_ids.push(id_count);
+            id_count += o.id_count();
+        }
+        return Self{objects: objects,first_ids: first_ids,id_count: id_count,bvh: Bvh::new(&bboxes)};
+    }
+}
+
+impl Traced for TracedGroup {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let mut best: Option<HitRecord> = None;
+        self.bvh.traverse(r,t_min,t_max,|i,closest| {
+            let mut hr = self.objects[i as usize].hit(r,t_min,closest)?;
+            hr.obj_id += self.first_ids[i as usize];
+            let t = hr.t;
+            best = Some(hr);
+            return Some(t);
+        });
+        return best;
+    }
+    fn id_count(&self) -> u64 {
+        return self.id_count;
+    }
+}
+
+impl Bounded for TracedGroup {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        return self.bvh.bbox().unwrap_or(BoundingBox3D::new(&Point3::ZERO,&Point3::ZERO));
+    }
+}
+
+//Same for marched objects, with any affine transform. See MarchedTransform for how distances and normals are kept right
+#[derive(Clone)]
+pub struct MarchedInstance {
+    pub object: Arc<dyn Marched + Send + Sync>,
+    pub transform: MarchedTransform,
+    pub material: Option<Material>,//Replaces whatever the object had, even blended or orbit trap materials
+    pub motion: Option<AnimatedTransform>,//If set, the transform above is the one at the first keyframe
+}
+
+impl MarchedInstance {
+    pub fn new(object: &Arc<dyn Marched + Send + Sync>,m_local_to_world: &Mat4x4,material: Option<Material>) -> Self{
+        Self{object: object.clone(),transform: MarchedTransform::new(m_local_to_world),material: material,motion: None}
+    }
+    //How marched objects move, the sdf and normals are evaluated with the transform at the ray's time
+    #[allow(dead_code)]
+    pub fn new_moving(object: &Arc<dyn Marched + Send + Sync>,motion: &AnimatedTransform,material: Option<Material>) -> Self{
+        let mut ret = Self::new(object,&motion.start,material);
+        ret.motion = Some(*motion);
+        return ret;
+    }
+    fn transform_at(&self,time: f32) -> MarchedTransform{
+        let (m_local_to_world,m_world_to_local) = transforms_at(&self.motion,&self.transform.m_local_to_world,&self.transform.m_world_to_local,time);
+        return MarchedTransform{m_local_to_world: m_local_to_world,m_world_to_local: m_world_to_local,min_scale: m_local_to_world.min_singular_value()};
+    }
+}
+
+impl Marched for MarchedInstance {
+    fn local_sdf(&self,p: &Point3) -> f32 {
+        return self.object.sdf(p);
+    }
+    fn material(&self) -> &Material{
+        return self.material.as_ref().unwrap_or(self.object.material());
+    }
+    fn material_at(&self,p: &Point3) -> Material{
+        if let Some(m) = &self.material { return *m; }
+        return self.object.material_at(&self.to_local(&Vec4::new_p3(p)).xyz());
+    }
+    fn material_at_time(&self,p: &Point3,time: f32) -> Material{
+        if let Some(m) = &self.material { return *m; }
+        if self.motion.is_none() { return self.object.material_at_time(&self.to_local(&Vec4::new_p3(p)).xyz(),time); }
+        return self.object.material_at_time(&self.transform_at(time).m_world_to_local.dot_p3(p),time);
+    }
+    fn to_local(&self,p: &Vec4) -> Vec4{
+        return self.transform.to_local(p);
+    }
+    fn to_world(&self,p: &Vec4) -> Vec4{
+        return self.transform.to_world(p);
+    }
+    fn to_world_f(&self,f: f32) -> f32{
+        return self.transform.to_world_f(f);
+    }
+    fn uv(&self,p: &Point3) -> (f32,f32){
+        return self.object.uv(&self.to_local(&Vec4::new_p3(p)).xyz());
+    }
+    fn normal_to_world(&self,n: &Vec3) -> Vec3{
+        return self.transform.normal_to_world(n);
+    }
+    fn get_outward_normal(&self,p: &Point3,eps: f32) -> UnitVec3{
+        let local_p = self.to_local(&Vec4::new_p3(p)).xyz();
+        return self.normal_to_world(&self.object.get_outward_normal(&local_p,eps/self.transform.min_scale)).unit();
+    }
+    fn sdf_at(&self,p: &Point3,time: f32) -> f32{
+        if self.motion.is_none() { return self.sdf(p); }
+        let transform = self.transform_at(time);
+        return transform.to_world_f(self.object.sdf(&transform.m_world_to_local.dot_p3(p)));
+    }
+    fn hit_record_at(&self,t: f32,p: &Point3,time: f32) -> HitRecord{
+        if self.motion.is_none() { return self.hit_record(t,p); }
+        let transform = self.transform_at(time);
+        let local_p = transform.m_world_to_local.dot_p3(p);
+        let normal = transform.normal_to_world(&self.object.get_outward_normal(&local_p,normal_eps(t,p)/transform.min_scale)).unit();
+        return HitRecord{t: t,point: *p,normal: normal,geometric_normal: normal,material: self.material_at_time(p,time),obj_id: 0,
+                         uv: self.object.uv(&local_p),tangent: Vec3::ZERO,fiber: false};
+    }
+}
+
+impl Bounded for MarchedInstance {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let bb = self.object.build_world_bounding_box();
+        if !bb.minp.x().is_finite() || !bb.maxp.x().is_finite() { return bb; }
+        if let Some(motion) = &self.motion { return motion.motion_bounds(&bb); }
+        return self.transform.bounds(&bb);
+    }
+}
@@ -27,6 +27,27 @@ use materials::*;
 mod render_thread;
 mod bounding_box;
 mod camera_hash;
+mod options;
+mod image_io;
+mod report;
+mod aov;
+mod denoise;
+mod display;
+mod post;
+mod bvh;
+mod mesh;
+mod instance;
+mod patch;
+mod heightfield;
+mod texture;
+mod motion;
+mod animation;
+mod csg;
+mod domain;
+mod sdf_grid;
+mod sdf_expr;
+use display::DisplayTransform;
+use report::{ThreadReport,RenderReport};
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
@@ -75,7 +96,7 @@ fn random_scene() -> HittableList{
         Material::new_dielectric(1.5);
         //world+=Arc::new(MarchedTorus{center: Point3::new(0.,1.,0.), sizes: Vec3::new(0.5,0.1,0.1), material: mat}) as Arc<dyn Marched + Send + Sync>;
         let local_to_world = m4x4!(TR 0.,1.,0.)
-        //^m4x4!(SC 1.,2.,1.)//@BUG: Not rendering properly, maybe the algoritm doesn't handle scaling after rotating(?)
+        ^m4x4!(SC 1.,2.,1.)//Stretched after rotating, sheared in the torus' own axes
         ^m4x4!(RX 0.6)^m4x4!(RZ 1.33*2.*PI);
         world+=&MarchedTorus::new(&local_to_world,&Vec3::new(0.5,0.1,0.1),&mat);
     }
@@ -109,6 +130,240 @@ fn basic_scene() -> HittableList{
     return world;
 }
 
+//Procedural mountains when no heightmap is given
+#[allow(dead_code)]
+fn terrain_scene(heightmap: &Option<String>) -> HittableList{
+    let mut world = HittableList::new();
+    let m = m4x4!(TR -10.,-2.5,-10.)^m4x4!(SC 20.,4.,20.);
+    let rock = Material::new_lambertian(Color::new(0.45,0.4,0.35));
+    let terrain = match heightmap {
+        Some(file) => match heightfield::Heightfield::load(file,&m,&rock) {
+            Ok(t) => t,
+            Err(e) => { eprintln!("Error reading heightmap {}",e); std::process::exit(1); },
+        },
+        None => {
+            const N: u32 = 513;
+            let mut heights = Vec::with_capacity((N*N) as usize);
+            for z in 0..N{
+                for x in 0..N{
+                    let (fx,fz) = (x as f32/(N-1) as f32,z as f32/(N-1) as f32);
+                    let mut h = 0.;
+                    let mut amplitude = 0.5;
+                    let mut frequency = 3.;
+                    for octave in 0..7{//Ridges: folded sines at growing frequencies, rotated a bit each octave
+                        let a = 0.7*(octave as f32);
+                        let (u,v) = (fx*a.cos() - fz*a.sin(),fx*a.sin() + fz*a.cos());
+                        h += amplitude*(1. - ((frequency*u + 1.3*octave as f32).sin()*(frequency*v).cos()).abs());
+                        amplitude *= 0.45;
+                        frequency *= 2.1;
+                    }
+                    heights.push(h);
+                }
+            }
+            heightfield::Heightfield::new(N,N,heights,&m,&rock)
+        },
+    };
+    world+=Arc::new(terrain) as Arc<dyn Traced + Send + Sync>;
+    world+=&Sphere::new_with_radius(&Point3::new(4.,1.2,0.),0.4,&Material::new_metal(Color::new(0.8,0.8,0.85)));
+    return world;
+}
+
+//Distance estimated fractals in a row, colored by their orbit traps
+#[allow(dead_code)]
+fn fractal_scene() -> HittableList{
+    let mut world = HittableList::new();
+    world+=&Sphere::new_with_radius(&Point3::new(0., -1000.,0.),1000.0,&Material::new_lambertian(Color::new(0.5,0.5,0.5)));
+    let diffuse = Material::new_lambertian(Color::new(0.8,0.8,0.8));
+    world+=&MarchedMandelbulb{center: Point3::new(0.,0.85,2.4),size: 0.7,power: 8.,iterations: 10,material: diffuse,
+                              orbit_colors: [Color::new(0.9,0.3,0.1),Color::new(0.95,0.9,0.6)]};
+    world+=&MarchedMengerSponge{center: Point3::new(0.,0.6,0.8),size: 0.6,iterations: 4,material: diffuse,
+                                orbit_colors: [Color::new(0.8,0.8,0.8),Color::new(0.1,0.4,0.8)]};
+    world+=&MarchedMandelbox{center: Point3::new(0.,0.7,-0.8),size: 0.7,scale: 2.,iterations: 12,material: Material::new_metal_fuzz(Color::ZERO,0.2),
+                             orbit_colors: [Color::new(0.3,0.8,0.4),Color::new(0.9,0.9,0.9)]};
+    world+=&MarchedSierpinski{center: Point3::new(0.,0.6,-2.4),size: 0.6,iterations: 8,material: diffuse,
+                              orbit_colors: [Color::new(0.9,0.8,0.2),Color::new(0.6,0.1,0.5)]};
+    return world;
+}
+
+//What the mechanical scene needs that is too slow or too global to redo every frame, made once before the frame loop
+struct MechanicalAssets {
+    studs: u32,//Texture ids
+    ripples: u32,
+    rounded_cube: Arc<sdf_grid::SdfGrid>,
+}
+
+impl MechanicalAssets {
+    fn new(sdf_cache: &Option<String>) -> Self{
+        //Stud heights for a bump map and ripples for a normal map
+        const TEX: u32 = 128;
+        let mut studs = Vec::with_capacity((TEX*TEX) as usize);
+        let mut ripples = Vec::with_capacity((3*TEX*TEX) as usize);
+        for y in 0..TEX{
+            for x in 0..TEX{
+                let (fx,fy) = ((x as f32 + 0.5)/TEX as f32,(y as f32 + 0.5)/TEX as f32);
+                let (cx,cy) = ((fx*8.).fract() - 0.5,(fy*8.).fract() - 0.5);
+                studs.push((1. - (cx*cx + cy*cy).sqrt()/0.35).max(0.).sqrt());
+                let n = Vec3::new(0.25*(fx*40.).sin(),0.,1.).unit();
+                ripples.extend_from_slice(&[0.5*n.x() + 0.5,0.5*n.y() + 0.5,0.5*n.z() + 0.5]);
+            }
+        }
+        //Rounded cube baked into a distance field, the slow part
+        let cube = mesh::PolyMesh::new_cube().catmull_clark(2).to_mesh(&Material::new_lambertian(Color::new(1.,1.,1.)));
+        return Self{studs: texture::register(texture::Texture::new(TEX,TEX,1,studs)),
+                    ripples: texture::register(texture::Texture::new(TEX,TEX,3,ripples)),
+                    rounded_cube: Arc::new(sdf_grid::SdfGrid::load_or_bake(sdf_cache.as_deref(),"rounded_cube",&cube,48,true))};
+    }
+}
+
+#[allow(dead_code)]
+fn mechanical_scene(subdivision: u32,frame: &animation::FrameTime,assets: &MechanicalAssets) -> HittableList{
+    let mut world = HittableList::new();
+    let mat_ground = Material::new_lambertian(Color::new(0.5,0.5,0.5));
+    world+=&Sphere::new_with_radius(&Point3::new(0., -1000.,0.),1000.0,&mat_ground);
+    let steel  = Material::new_metal_fuzz(Color::new(0.8,0.8,0.85),0.1);
+    let brass  = Material::new_metal(Color::new(0.8,0.6,0.3));
+    let paint  = Material::new_lambertian(Color::new(0.7,0.15,0.1));
+    let rubber = Material::new_lambertian(Color::new(0.1,0.1,0.1));
+    //Shaft with a flange and a tube slid over it
+    world+=&Cylinder::new_with_ends(&Point3::new(0.,0.6,-3.),&Point3::new(0.,0.6,3.),0.25,true,&steel);
+    world+=&Cylinder::new_with_ends(&Point3::new(0.,0.6,1.),&Point3::new(0.,0.6,2.),0.4,false,&paint);
+    world+=&Disk::new_with_normal(&Point3::new(0.,0.6,-1.),&Vec3::new(0.,0.,1.),0.6,0.25,&brass);
+    //Cone and frustum standing on the ground
+    world+=&Cone::new_with_ends(&Point3::new(2.,0.,-1.5),0.5,&Point3::new(2.,1.2,-1.5),0.,true,&paint);
+    world+=&Cone::new_with_ends(&Point3::new(2.,0.,1.5),0.6,&Point3::new(2.,0.8,1.5),0.3,true,&brass);
+    //Capsules lying around
+    world+=&Capsule::new_with_ends(&Point3::new(3.,0.2,-0.5),&Point3::new(3.,0.2,0.5),0.2,&rubber);
+    world+=&Capsule::new_with_ends(&Point3::new(-1.,0.3,-2.),&Point3::new(-1.5,1.3,-1.),0.3,&Material::new_dielectric(1.5));
+    world+=&Disk::new_with_normal(&Point3::new(1.,0.001,0.),&Vec3::new(0.,1.,0.),0.8,0.,&rubber);
+    //Quartic and quadric surfaces
+    world+=&Torus::new_with_radii(&(m4x4!(TR 1.,0.15,-2.8)),0.6,0.15,&brass);
+    world+=&Torus::new_with_radii(&(m4x4!(TR -1.,1.6,2.)^m4x4!(RX 1.2)^m4x4!(SC 1.,2.,1.)),0.5,0.1,&Material::new_dielectric(1.5));
+    world+=&Quadric::new_ellipsoid(&(m4x4!(TR 3.5,0.3,2.5)^m4x4!(RY 0.5)^m4x4!(SC 0.5,0.3,0.25)),&paint);
+    world+=&Quadric::new_paraboloid(&(m4x4!(TR 1.5,1.9,-0.8)^m4x4!(RX PI)^m4x4!(SC 0.3,0.6,0.3)),&steel);
+    world+=&Quadric::new_hyperboloid(&(m4x4!(TR -2.,1.,-0.5)^m4x4!(SC 0.3,0.5,0.3)),true,&brass);
+    //Subdivided cube, a sphere-ish blob at high levels
+    let mut blob = mesh::PolyMesh::new_cube().catmull_clark(subdivision).to_mesh(&paint);
+    blob.transform(&(m4x4!(TR -3.,0.5,1.)^m4x4!(RY 0.6)^m4x4!(SC 0.5,0.5,0.5)));
+    world+=Arc::new(blob) as Arc<dyn Traced + Send + Sync>;
+    //Wavy sheet from a single Bézier patch
+    let mut p = [Point3::ZERO;16];
+    for j in 0..4{
+        for i in 0..4{
+            let h = if (i == 1 || i == 2) && (j == 1 || j == 2) { 1.2 } else if (i + j) % 2 == 0 { 0.2 } else { 0.5 };
+            p[j*4 + i] = Point3::new(5. + 0.4*i as f32,0.5*h,-0.4 + 0.4*j as f32);
+        }
+    }
+    world+=Arc::new(patch::tessellate(&vec!(patch::BezierPatch::new(p)),0.005,&brass)) as Arc<dyn Traced + Send + Sync>;
+    //Ring of instances of one shared mesh, behind their own BVH. Every other one has its material overridden
+    let bead: Arc<dyn Traced + Send + Sync> = Arc::new(mesh::PolyMesh::new_cube().catmull_clark(2).to_mesh(&steel));
+    let mut beads: Vec<Arc<dyn Traced + Send + Sync>> = Vec::new();
+    for i in 0..24{
+        let a = 2.*PI*(i as f32)/24.;
+        let m = m4x4!(TR 1. + 0.95*a.cos(),0.07,0.95*a.sin())^m4x4!(RY -a)^m4x4!(SC 0.1,0.07,0.05);
+        beads.push(Arc::new(instance::Instance::new(&bead,&m,if i % 2 == 0 { Some(paint) } else { None })));
+    }
+    world+=Arc::new(instance::TracedGroup::new(beads)) as Arc<dyn Traced + Send + Sync>;
+    //Marched instances of a single shared unit sphere, squashed into pebbles
+    let pebble: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedSphere{center: Point3::ZERO,radius: 1.,material: rubber});
+    world+=Arc::new(instance::MarchedInstance::new(&pebble,&(m4x4!(TR 4.4,0.08,-1.1)^m4x4!(RY 0.4)^m4x4!(SC 0.2,0.08,0.12)),None)) as Arc<dyn Marched + Send + Sync>;
+    world+=Arc::new(instance::MarchedInstance::new(&pebble,&(m4x4!(TR 4.8,0.06,-0.8)^m4x4!(RY 1.3)^m4x4!(SC 0.15,0.06,0.1)),Some(brass))) as Arc<dyn Marched + Send + Sync>;
+    //Plate with studs from a bump map, and a flat tile that shades like a cushion (vertex normals) with ripples (normal map)
+    world+=&Parallelogram::new3points(&Point3::new(-4.,0.,-4.),&Point3::new(-4.,0.,-2.8),&Point3::new(-4.,1.2,-4.),
+        &Material::new_lambertian(Color::new(0.8,0.8,0.75)).with_bump_map(assets.studs,0.02));
+    let corner_normals = [Vec3::new(1.,-0.6,-0.6).unit(),Vec3::new(1.,-0.6,0.6).unit(),Vec3::new(1.,0.6,-0.6).unit(),Vec3::new(1.,0.6,0.6).unit()];
+    world+=&Parallelogram::new3points_with_normals(&Point3::new(-4.,0.,-5.4),&Point3::new(-4.,0.,-4.2),&Point3::new(-4.,1.2,-5.4),
+        &corner_normals,&Material::new_lambertian(Color::new(0.2,0.3,0.7)).with_normal_map(assets.ripples));
+    //Things in motion, they blur with --shutter: a ball thrown across, a spinning cube, a sliding bead and a hopping pebble
+    world+=&Sphere::new_moving(&motion::AnimatedTransform::new_linear(&(m4x4!(TR 2.6,1.9,-0.2)^m4x4!(SC 0.2,0.2,0.2)),
+                                                                     &(m4x4!(TR 2.6,1.8,-1.4)^m4x4!(SC 0.2,0.2,0.2))),
+                               &Material::new_lambertian(Color::new(0.9,0.8,0.1)));
+    world+=&Cube::new_moving(&motion::AnimatedTransform::new_trs(&(m4x4!(TR 3.4,0.2,-0.9)^m4x4!(SC 0.3,0.3,0.3)),
+                                                                 &(m4x4!(TR 3.4,0.2,-0.9)^m4x4!(RY PI/3.)^m4x4!(SC 0.3,0.3,0.3))),&paint);
+    world+=Arc::new(instance::Instance::new_moving(&bead,&motion::AnimatedTransform::new_trs(
+        &(m4x4!(TR 3.,0.07,1.)^m4x4!(SC 0.1,0.07,0.05)),&(m4x4!(TR 3.,0.07,1.6)^m4x4!(RY PI/2.)^m4x4!(SC 0.1,0.07,0.05))),Some(brass))) as Arc<dyn Traced + Send + Sync>;
+    world+=Arc::new(instance::MarchedInstance::new_moving(&pebble,&motion::AnimatedTransform::new_linear(
+        &(m4x4!(TR 4.,0.08,-0.3)^m4x4!(SC 0.12,0.08,0.12)),&(m4x4!(TR 4.,0.5,-0.3)^m4x4!(SC 0.12,0.08,0.12))),None)) as Arc<dyn Marched + Send + Sync>;
+    //Distance field CSG: a floating die with smoothly carved pips and a paint/brass blob where two spheres melt together
+    {
+        use csg::MarchedCsg;
+        let block: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedBox{center: Point3::new(-4.,1.25,2.9),sizes: Vec3::new(0.4,0.4,0.4),material: steel});
+        let rounded: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedCsg::intersection(&block,
+            &(Arc::new(MarchedSphere{center: Point3::new(-4.,1.25,2.9),radius: 0.55,material: steel}) as Arc<dyn Marched + Send + Sync>),0.05));
+        let mut die = rounded;
+        for (dy,dz) in [(0.,0.),(0.2,0.2),(-0.2,-0.2),(0.2,-0.2),(-0.2,0.2)]{
+            let pip: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedSphere{center: Point3::new(-3.6,1.25 + dy,2.9 + dz),radius: 0.09,material: paint});
+            die = Arc::new(MarchedCsg::subtraction(&die,&pip,0.03));
+        }
+        world+=die;
+        let left: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedSphere{center: Point3::new(-4.,1.8,-3.7),radius: 0.35,material: paint});
+        let right: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedSphere{center: Point3::new(-4.,1.85,-4.4),radius: 0.3,material: brass});
+        world+=Arc::new(MarchedCsg::union(&left,&right,0.3)) as Arc<dyn Marched + Send + Sync>;
+    }
+    //Domain warps, hanging in the sky: a twisted bar, a row of repeated beads, a cut open onion of two shells and a bent rounded bar
+    {
+        use domain::{MarchedDomain,DomainOp};
+        let at = |x: Arc<dyn Marched + Send + Sync>,op: DomainOp,m: &Mat4x4| Arc::new(MarchedDomain::new(&x,op,m)) as Arc<dyn Marched + Send + Sync>;
+        let bar: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedBox{center: Point3::ZERO,sizes: Vec3::new(0.07,0.5,0.07),material: brass});
+        world+=at(bar,DomainOp::TWIST{rate: 4.},&(m4x4!(TR -4.,2.25,0.7)^m4x4!(RX PI/2.)));
+        let bead: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedSphere{center: Point3::ZERO,radius: 0.06,material: steel});
+        world+=at(bead,DomainOp::REPEAT_LIMITED{period: Vec3::new(0.,0.,0.2),count: Vec3::new(0.,0.,2.)},&m4x4!(TR -4.,1.75,0.95));
+        let ball: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedSphere{center: Point3::ZERO,radius: 0.2,material: paint});
+        let shells = at(at(ball,DomainOp::ONION{thickness: 0.03},&Mat4x4::IDENTITY),DomainOp::ONION{thickness: 0.012},&m4x4!(TR -4.,1.85,0.2));
+        let cut: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedBox{center: Point3::new(-3.75,1.85,0.2),sizes: Vec3::new(0.25,0.3,0.3),material: paint});
+        world+=Arc::new(csg::MarchedCsg::subtraction(&shells,&cut,0.)) as Arc<dyn Marched + Send + Sync>;
+        let rod: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedBox{center: Point3::ZERO,sizes: Vec3::new(0.25,0.02,0.02),material: rubber});
+        let rod = at(rod,DomainOp::ROUND{radius: 0.02},&Mat4x4::IDENTITY);
+        world+=at(rod,DomainOp::BEND{rate: 2.},&(m4x4!(TR -4.,2.1,-0.4)^m4x4!(RY -PI/2.)));
+    }
+    //A mesh baked into a distance field blends like any other marched object: a rounded cube melting into a ball
+    {
+        let cube: Arc<dyn Marched + Send + Sync> = Arc::new(sdf_grid::MarchedSdfGrid::new(&assets.rounded_cube,&paint));
+        let cube: Arc<dyn Marched + Send + Sync> = Arc::new(instance::MarchedInstance::new(&cube,&(m4x4!(TR 6.6,0.2,2.6)^m4x4!(RY 0.5)^m4x4!(SC 0.25,0.25,0.25)),None));
+        let ball: Arc<dyn Marched + Send + Sync> = Arc::new(MarchedSphere{center: Point3::new(6.6,0.45,2.35),radius: 0.15,material: brass});
+        world+=Arc::new(csg::MarchedCsg::union(&cube,&ball,0.15)) as Arc<dyn Marched + Send + Sync>;
+    }
+    //Keyframed over seconds, shows up with --frames: a ball bouncing along the shaft, changing color as it goes
+    {
+        use animation::{Track,Ease,MaterialAnimation};
+        let hop = |z: f32,y: f32| m4x4!(TR 0.5,y,z)^m4x4!(SC 0.2,0.2,0.2);
+        //Slowing down on the way up and speeding up on the way down
+        let bounce = Track::new(hop(1.2,1.2),Ease::EASE_OUT)
+            .key(0.5,hop(0.4,2.),Ease::EASE_IN).key(1.,hop(-0.4,1.2),Ease::EASE_OUT)
+            .key(1.5,hop(-1.2,2.),Ease::EASE_IN).key(2.,hop(-2.,1.2),Ease::LINEAR);
+        let mut ball = MaterialAnimation::new(&Material::new_lambertian(Color::new(0.1,0.3,0.9)));
+        ball.albedo = Some(Track::new(Color::new(0.1,0.3,0.9),Ease::SPLINE).key(2.,Color::new(0.1,0.8,0.3),Ease::LINEAR));
+        let mat = ball.at(frame.time);
+        match bounce.motion(frame) {
+            Some(m) => world+=&Sphere::new_moving(&m,&mat),
+            None => world+=&Sphere::new(&bounce.at(frame.time),&mat),
+        }
+    }
+    //Grass blades as ribbons and a fur ball
+    let grass = Material::new_lambertian(Color::new(0.2,0.5,0.1));
+    for _i in 0..200{
+        let base = Point3::new(f32::rand_range(6.,7.),0.,f32::rand_range(0.8,2.2));
+        let lean = Vec3::new(f32::rand_range(-0.1,0.1),0.,f32::rand_range(-0.1,0.1));
+        let height = f32::rand_range(0.2,0.4);
+        let up = Vec3::new(0.,height,0.);
+        let cp = [base,base + up/3.,base + 2.*up/3. + 0.4*lean,base + up + lean];
+        let angle = f32::rand_range(0.,PI);
+        let n = Vec3::new(angle.cos(),0.,angle.sin());
+        world+=&Curve::new_ribbon(&cp,0.03,0.003,&n,&n,&grass);
+    }
+    let fur_center = Point3::new(4.5,0.35,1.6);
+    world+=&Sphere::new_with_radius(&fur_center,0.22,&Material::new_lambertian(Color::new(0.1,0.06,0.03)));
+    let fur = Material::new_hair_melanin(1.3,0.2,0.25,0.3);
+    for _i in 0..600{
+        let d = Vec3::rand_unit_vector();
+        let droop = Vec3::new(0.,-0.06,0.);
+        let root = fur_center + 0.2*d;
+        for c in Curve::new_strand(&[root,root + 0.08*d,root + 0.16*d + droop,root + 0.22*d + 2.5*droop],0.008,0.002,&fur){
+            world+=&c;
+        }
+    }
+    return world;
+}
+
 fn print_progress(progress: f64) -> (){
     let progress100 = round_n(100.0*progress,2);
     let frac = progress100 % 1.;
@@ -117,57 +372,103 @@ fn print_progress(progress: f64) -> (){
 }
 
 use std::thread;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+//Scenes with random layouts are built from this seed, so every frame of an animation gets the same one
+const SCENE_SEED: u64 = 0x07703a4c;
+
+//Read and compiled once for every frame, the interval bounds of the expressions are slow
+fn load_shapes(file: &str) -> Vec<sdf_expr::MarchedExpr>{
+    return match std::fs::read_to_string(file).map_err(|e| e.to_string()).and_then(|t| sdf_expr::load_shapes(&t)) {
+        Ok(s) => s,
+        Err(e) => { eprintln!("Error reading shapes from {}: {}",file,e); std::process::exit(1); },
+    };
+}
+
+fn build_scene(options: &options::RenderOptions,frame: &animation::FrameTime,mechanical: &Option<MechanicalAssets>,
+    shapes: &[sdf_expr::MarchedExpr]) -> HittableList{
+    reset_material_ids();
+    let mut world = match options.scene.as_str() {
+        "basic"      => basic_scene(),
+        "mechanical" => mechanical_scene(options.subdivision,frame,mechanical.as_ref().unwrap()),
+        "terrain"    => terrain_scene(&options.heightmap),
+        "fractal"    => fractal_scene(),
+        _            => random_scene(),
+    };
+    if let Some(file) = &options.patches {
+        let patches = match std::fs::read_to_string(file).map_err(|e| e.to_string()).and_then(|t| patch::load_patches(&t)) {
+            Ok(p) => p,
+            Err(e) => { eprintln!("Error reading patches from {}: {}",file,e); std::process::exit(1); },
+        };
+        let mut m = patch::tessellate(&patches,options.patch_tolerance,&Material::new_lambertian(Color::new(0.8,0.8,0.8)));
+        m.transform(&m4x4!(RX -PI/2.));//The teapot format is Z up
+        world+=Arc::new(m) as Arc<dyn Traced + Send + Sync>;
+    }
+    for s in shapes {
+        let mut s = s.clone();
+        s.material = s.material.with_new_id();//Numbered after the scene's like when they were loaded every frame
+        world+=Arc::new(s) as Arc<dyn Marched + Send + Sync>;
+    }
+    return world;
+}
 
 fn main() {
-    //IMAGE
+    let options = options::RenderOptions::from_args();
     let aspect_ratio: f32 = 3.0 / 2.0;
-    let image_width:    u32 = 1000;
+    //camera = Camera::world_camera(90.,aspect_ratio);
+    //camera = Camera::new(Point3::new(0.,2.,-2.),Point3::new(0.,0.,-2.),Point3::new(0.,0.,-1.),130.,aspect_ratio,20.,2.);
+    let mut camera_animation = animation::CameraAnimation::new_still(Point3::new(13.,2.,3.),Point3::new(0.,0.,0.),Vec3::new(0.,1.,0.),20.,0.1,10.);
+    if let Some(file) = &options.animation {
+        if let Err(e) = camera_animation.load(file) {
+            eprintln!("Error reading animation {}",e);
+            std::process::exit(1);
+        }
+    }
+    //Each frame rebuilds and refreezes the scene at its time, ray times [0;1] span the frame
+    let frame_duration = 1./options.fps;
+    let frames = options.frames.unwrap_or(1);
+    let mechanical = if options.scene == "mechanical" { Some(MechanicalAssets::new(&options.sdf_cache)) } else { None };
+    let shapes = options.shapes.as_deref().map(load_shapes).unwrap_or_default();
+    let textures = texture::freeze();
+    for frame in 0..frames{
+        let frame_time = animation::FrameTime{time: options.start_time + (frame as f32)*frame_duration,duration: frame_duration};
+        seed_rng(SCENE_SEED);
+        let mut world = build_scene(&options,&frame_time,&mechanical,&shapes);
+        let camera = camera_animation.camera_at(frame_time.time,aspect_ratio).with_shutter(0.,options.shutter);
+        let output = match options.frames {
+            Some(_) => {
+                eprintln!("Frame {}/{} at {}s",frame + 1,frames,frame_time.time);
+                options.output.as_ref().map(|o| image_io::sibling_path(o,&format!("{:04}",frame),"ppm"))
+            },
+            None => options.output.clone(),
+        };
+        render_frame(&options,&camera,&mut world,&textures,&output);
+    }
+}
+
+fn render_frame(options: &options::RenderOptions,camera: &Camera,world: &mut HittableList,textures: &Arc<[texture::Texture]>,output: &Option<String>){
+    //IMAGE
+    let image_width:    u32 = options.image_width;
     let image_width_f:  f32 = image_width as f32;
-    let image_height_f: f32 = image_width_f/ aspect_ratio;
+    let image_height_f: f32 = image_width_f/ camera.aspect_ratio;
     let image_height:   u32 = image_height_f as u32;
     let image_size:     u32 = image_width*image_height;
+    let camera = *camera;
 
-    let camera: Camera;
-    {
-        //camera = Camera::world_camera(90.,aspect_ratio);
-        //camera = Camera::new(Point3::new(0.,2.,-2.),Point3::new(0.,0.,-2.),Point3::new(0.,0.,-1.),130.,aspect_ratio,20.,2.);
-        let lookfrom = Point3::new(13.,2.,3.);
-        let lookat   = Point3::new(0.,0.,0.);
-        let vup      =   Vec3::new(0.,1.,0.);
-        let vfov = 20.;
-        let aperture = 0.1;
-        let dist_to_focus = 10.;
-        camera = Camera::new(lookfrom,lookat,vup,vfov,aspect_ratio,aperture,dist_to_focus);
-    }
-
-    let samples_per_pixel: u32 = 200;
-    let max_depth: u32 = 50;
-    let mut world = random_scene();
+    let samples_per_pixel: u32 = options.samples_per_pixel;
+    let path_settings = render_thread::PathSettings{max_depth: options.max_depth,rr_min_depth: options.rr_min_depth,
+        tmin: 0.001,tmax: 100.0,clamp_sample: options.clamp_sample,clamp_indirect: options.clamp_indirect};//@TODO: You could find tmin/tmax from bounding boxes from the scene
+    let display = options.display_transform();
+    let time_budget = options.time_budget;
     let samples_atomic = AtomicU64::new(0);
     let arc_samples_atomic = Arc::new(samples_atomic);
-    
-    {//Log thread
-        let smpls_atom = arc_samples_atomic.clone();
-        thread::spawn(move || {
-            let total_samples = (image_size as u64)*(samples_per_pixel as u64);
-            let total_samples_f = total_samples as f64;
-            let start = std::time::Instant::now();
-            loop {
-                let progress = smpls_atom.load(Ordering::Relaxed);
-                print_progress((progress as f64)/total_samples_f);
-                if total_samples == progress{ 
-                    print_progress(1.0);
-                    break;
-                }
-                ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 2));
-            }
-            eprintln!("{} seconds",start.elapsed().as_secs());
-        });
-    }
-
-    let num_threads = num_cpus::get() as u32 - 1;
+    let arc_threads_done = Arc::new(AtomicU32::new(0));
+    let num_threads = options.num_threads;
+    let pixels_box = render_thread::PixelsBox{pixels: &mut vec!(render_thread::Pixel::new(options.median_of_means);image_size as usize)};
+    //This DOES NOT work idk why @CompilerBug
+    //let pixels_box = render_thread::PixelsBox::new(image_size as usize);
+    //for some reason the bottom statement messes ups the len() of pixels in each thread if I do new()
 
     let mut assigned_thread: Vec<u32> = Vec::with_capacity(image_size as usize);
     {
@@ -189,35 +490,166 @@ fn main() {
     }
     assigned_thread.shrink_to_fit();
     let arc_assigned_thread = Arc::new(assigned_thread);
-    let pixels_box = render_thread::PixelsBox{pixels: &mut vec!(render_thread::Pixel::new();image_size as usize)};
-    //This DOES NOT work idk why @CompilerBug
-    //let pixels_box = render_thread::PixelsBox::new(image_size as usize);
-    //for some reason the bottom statement messes ups the len() of pixels in each thread if I do new()
     let mut handlers: Vec<thread::JoinHandle<()>> = Vec::with_capacity(num_threads as usize);
     let arc_camera = Arc::new(camera);
-    let arc_world = Arc::new(world.freeze(&camera));
+    let freeze_start = std::time::Instant::now();
+    let arc_world = Arc::new(world.freeze(&camera,textures));
+    let freeze_seconds = freeze_start.elapsed().as_secs_f64();
+    let arc_thread_reports: Arc<Mutex<Vec<ThreadReport>>> = Arc::new(Mutex::new(Vec::with_capacity(num_threads as usize)));
     eprintln!("Running {} threads",num_threads);
+    //Taken after freezing so the budget is spent only on sampling
+    let deadline = time_budget.map(|budget| std::time::Instant::now() + budget);
+    let log_thread = {//Log thread
+        let smpls_atom = arc_samples_atomic.clone();
+        let threads_done = arc_threads_done.clone();
+        let pxls_box = pixels_box.clone();
+        let thread_reports = arc_thread_reports.clone();
+        let headless = options.headless;
+        let report_path = options.report.clone();
+        thread::spawn(move || {
+            let total_samples = (image_size as u64)*(samples_per_pixel as u64);
+            let total_samples_f = total_samples as f64;
+            let start = std::time::Instant::now();
+            loop {
+                let progress = match (time_budget,deadline) {
+                    (Some(budget),Some(deadline)) => 1. - deadline.saturating_duration_since(std::time::Instant::now()).as_secs_f64()/budget.as_secs_f64(),
+                    _ => (smpls_atom.load(Ordering::Relaxed) as f64)/total_samples_f,
+                };
+                print_progress(progress.min(1.0));
+                if threads_done.load(Ordering::Relaxed) == num_threads{
+                    print_progress(1.0);
+                    break;
+                }
+                ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 2));
+            }
+            let render_seconds = start.elapsed().as_secs_f64();
+            eprintln!("{} seconds",start.elapsed().as_secs());
+            let pixels = unsafe{&*pxls_box.pixels};
+            let (min,avg,max) = render_thread::spp_summary(pixels);
+            eprintln!("Samples per pixel: min {} avg {:.2} max {} ({} samples total)",min,avg,max,smpls_atom.load(Ordering::Relaxed));
+            if !headless {//Headless reports after writing the output, to time it too
+                let phases = vec!(("freeze",freeze_seconds),("render",render_seconds));
+                finish_report(pixels,image_width,image_height,&path_settings,phases,&thread_reports.lock().unwrap(),&report_path);
+            }
+            render_seconds
+        })
+    };
+
     for i in 0..num_threads {
         let cam = arc_camera.clone();
         let wrld = arc_world.clone();
         let smpls_atom = arc_samples_atomic.clone();
+        let threads_done = arc_threads_done.clone();
+        let thread_reports = arc_thread_reports.clone();
         let assgn_th = arc_assigned_thread.clone();
-        let tmin = 0.001;
-        let tmax = 100.0;//@TODO: You could find these from bounding boxes from the scene
         let draw_thread = move || {
-            return render_thread::render(&cam,&wrld,max_depth,tmin,tmax,
+            let report = render_thread::render(&cam,&wrld,&path_settings,&display,
                 samples_per_pixel,image_width,image_height,
                 pixels_box,
-                i,&assgn_th,&smpls_atom);
+                i,&assgn_th,&smpls_atom,deadline);
+            thread_reports.lock().unwrap().push(report);
+            threads_done.fetch_add(1,Ordering::Relaxed);
         };
         handlers.push(thread::spawn(draw_thread));
     }
-    draw_to_sdl(pixels_box.clone(),samples_per_pixel,image_width,image_height);
+    if !options.headless {
+        draw_to_sdl(pixels_box.clone(),&display,&options.post,samples_per_pixel,image_width,image_height);
+        std::process::exit(0);//The render threads still write to the pixels
+    }
+    for h in handlers{
+        h.join().unwrap();
+    }
+    let render_seconds = log_thread.join().unwrap();
+    let pixels = unsafe{&*pixels_box.pixels};
+    let output = output.clone().unwrap();
+    let output_start = std::time::Instant::now();
+    write_output(pixels,&hdr_image(pixels,image_width,image_height,&options.post),&display,image_width,image_height,&output);
+    aov::write_aovs(pixels,image_width,image_height,&output,&options.aovs);
+    let mut phases = vec!(("freeze",freeze_seconds),("render",render_seconds),("output",output_start.elapsed().as_secs_f64()));
+    if options.denoise {
+        let denoise_start = std::time::Instant::now();
+        let mut denoised = denoise::denoise(pixels,image_width,image_height,&denoise::DenoiseSettings::new());
+        if options.post.enabled() {
+            denoised = post::apply(&denoised,image_width,image_height,&options.post);
+        }
+        let mut rgb: Vec<u8> = Vec::with_capacity(denoised.len()*3);
+        for c in &denoised{
+            let c = display.to_u8x3(c);
+            rgb.push(c.0);
+            rgb.push(c.1);
+            rgb.push(c.2);
+        }
+        let denoised_path = image_io::sibling_path(&output,"denoised","ppm");
+        image_io::write_ppm(&denoised_path,image_width,image_height,&rgb).unwrap();
+        eprintln!("Wrote {}",denoised_path);
+        phases.push(("denoise",denoise_start.elapsed().as_secs_f64()));
+    }
+    let report_path = options.report.clone().or(Some(image_io::sibling_path(&output,"report","json")));
+    finish_report(pixels,image_width,image_height,&path_settings,phases,&arc_thread_reports.lock().unwrap(),&report_path);
+}
+
+fn finish_report(pixels: &Vec<render_thread::Pixel>,image_width: u32,image_height: u32,path_settings: &render_thread::PathSettings,
+    phases: Vec<(&'static str,f64)>,thread_reports: &Vec<ThreadReport>,report_path: &Option<String>){
+    let mut threads = thread_reports.clone();
+    threads.sort_by_key(|t| t.tid);
+    let median_of_means = pixels.first().map_or(false,|p| p.stats.median_of_means);
+    let mut mean_lum: f64 = 0.;
+    let mut estimate_lum: f64 = 0.;
+    for p in pixels{
+        mean_lum     += luminance(&p.stats.mean()) as f64;
+        estimate_lum += luminance(&p.stats.estimate()) as f64;
+    }
+    let mom_rejected = if mean_lum > 0. { 1. - estimate_lum/mean_lum } else { 0. };
+    let report = RenderReport{image_width: image_width,image_height: image_height,
+        spp: render_thread::spp_summary(pixels),phases: phases,threads: threads,
+        clamp_sample: path_settings.clamp_sample,clamp_indirect: path_settings.clamp_indirect,
+        median_of_means: median_of_means,mom_rejected: mom_rejected};
+    report.print_summary();
+    if let Some(path) = report_path {
+        report.write_json(path).unwrap();
+        eprintln!("Wrote {}",path);
+    }
+}
+
+//The pixel estimates with the post effects on top, still linear
+fn hdr_image(pixels: &Vec<render_thread::Pixel>,image_width: u32,image_height: u32,post_settings: &post::PostSettings) -> Vec<Color>{
+    let hdr: Vec<Color> = pixels.iter().map(|p| p.stats.estimate()).collect();
+    if !post_settings.enabled() { return hdr; }
+    return post::apply(&hdr,image_width,image_height,post_settings);
+}
+
+fn write_output(pixels: &Vec<render_thread::Pixel>,hdr: &Vec<Color>,display: &DisplayTransform,image_width: u32,image_height: u32,output: &str){
+    let mut rgb: Vec<u8> = Vec::with_capacity(pixels.len()*3);
+    let mut spp: Vec<f32> = Vec::with_capacity(pixels.len());
+    for (p,c) in pixels.iter().zip(hdr){
+        let c = display.to_u8x3(c);
+        rgb.push(c.0);
+        rgb.push(c.1);
+        rgb.push(c.2);
+        spp.push(p.stats.n as f32);
+    }
+    image_io::write_ppm(output,image_width,image_height,&rgb).unwrap();
+    //Achieved samples per pixel, mostly interesting with --budget
+    let spp_path = image_io::sibling_path(output,"spp","pfm");
+    image_io::write_pfm(&spp_path,image_width,image_height,1,&spp).unwrap();
+    eprintln!("Wrote {} and {}",output,spp_path);
+    if let Some(suffix) = display.linear_suffix() {
+        let mut linear: Vec<f32> = Vec::with_capacity(pixels.len()*3);
+        for c in hdr{
+            let c = display.linear(c);
+            linear.push(c.x());
+            linear.push(c.y());
+            linear.push(c.z());
+        }
+        let linear_path = image_io::sibling_path(output,suffix,"pfm");
+        image_io::write_pfm(&linear_path,image_width,image_height,3,&linear).unwrap();
+        eprintln!("Wrote {}",linear_path);
+    }
 }
 
 
 #[inline]
-fn apply_box_filter_ij_samples(pixels: &Vec<crate::render_thread::Pixel>,image_width: u32,sdlpixels: &mut Vec<u8>,
+fn apply_box_filter_ij_samples(pixels: &Vec<crate::render_thread::Pixel>,display: &DisplayTransform,image_width: u32,sdlpixels: &mut Vec<u8>,
     i: u32,j: u32,min_x: i32,max_x: i32,min_y: i32,max_y: i32){
     let mut total_weight = 0.;
     let mut color = Color::ZERO;
@@ -234,14 +666,14 @@ fn apply_box_filter_ij_samples(pixels: &Vec<crate::render_thread::Pixel>,image_w
     }
     let aux = i as usize + j as usize*image_width as usize;
     let p = aux*3;
-    let c = normalize_color(&(color/total_weight)).to_u8x3();
+    let c = display.to_u8x3(&(color/total_weight));
     sdlpixels[p+0] = c.0;
     sdlpixels[p+1] = c.1;
     sdlpixels[p+2] = c.2;
 }
 
 #[inline]
-fn apply_box_filter_ij_depth(pixels: &Vec<crate::render_thread::Pixel>,image_width: u32,sdlpixels: &mut Vec<u8>,
+fn apply_box_filter_ij_depth(pixels: &Vec<crate::render_thread::Pixel>,display: &DisplayTransform,image_width: u32,sdlpixels: &mut Vec<u8>,
     i: u32,j: u32,min_x: i32,max_x: i32,min_y: i32,max_y: i32){
     let mut total_weight = 0.;
     let mut color = Color::ZERO;
@@ -266,21 +698,21 @@ fn apply_box_filter_ij_depth(pixels: &Vec<crate::render_thread::Pixel>,image_wid
             let is_diagonal = (x != 0 && y != 0) as u32 as f32;
             let diag_w = w*(1. - (1. - SQRT2_INV)*is_diagonal);
             total_weight += diag_w;
-            let c = pixels[idx as usize].stats.sum/(pixels[idx as usize].stats.n as f32);
+            let c = pixels[idx as usize].stats.estimate();
             color += diag_w*c;
         }
     }
 
     let aux = i as usize + j as usize*image_width as usize;
     let p = aux*3;
-    let c = normalize_color(&(color/total_weight)).to_u8x3();
+    let c = display.to_u8x3(&(color/total_weight));
     sdlpixels[p+0] = c.0;
     sdlpixels[p+1] = c.1;
     sdlpixels[p+2] = c.2;
 }
 
 #[inline]
-fn apply_box_filter_ij_id(pixels: &Vec<crate::render_thread::Pixel>,image_width: u32,sdlpixels: &mut Vec<u8>,
+fn apply_box_filter_ij_id(pixels: &Vec<crate::render_thread::Pixel>,display: &DisplayTransform,image_width: u32,sdlpixels: &mut Vec<u8>,
     i: u32,j: u32,min_x: i32,max_x: i32,min_y: i32,max_y: i32){
     let mut total_weight = 0.;
     let mut color = Color::ZERO;
@@ -294,55 +726,55 @@ fn apply_box_filter_ij_id(pixels: &Vec<crate::render_thread::Pixel>,image_width:
             let is_diagonal = (x != 0 && y != 0) as u32 as f32;
             let diag_w = w*(1. - (1. - SQRT2_INV)*is_diagonal);
             total_weight += diag_w;
-            let c = pixels[idx as usize].stats.sum/(pixels[idx as usize].stats.n as f32);
+            let c = pixels[idx as usize].stats.estimate();
             color +=diag_w*c;
         }
     }
 
     let aux = i as usize + j as usize*image_width as usize;
     let p = aux*3;
-    let c = normalize_color(&(color/total_weight)).to_u8x3();
+    let c = display.to_u8x3(&(color/total_weight));
     sdlpixels[p+0] = c.0;
     sdlpixels[p+1] = c.1;
     sdlpixels[p+2] = c.2;
 }
 
 #[inline]
-fn apply_box_filter_ij<const MODE: usize>(pixels: &Vec<crate::render_thread::Pixel>,image_width: u32,sdlpixels: &mut Vec<u8>,
+fn apply_box_filter_ij<const MODE: usize>(pixels: &Vec<crate::render_thread::Pixel>,display: &DisplayTransform,image_width: u32,sdlpixels: &mut Vec<u8>,
     i: u32,j: u32,min_x: i32,max_x: i32,min_y: i32,max_y: i32){
     if MODE == 0{
-        return apply_box_filter_ij_samples(pixels,image_width,sdlpixels,i,j,min_x,max_x,min_y,max_y);
+        return apply_box_filter_ij_samples(pixels,display,image_width,sdlpixels,i,j,min_x,max_x,min_y,max_y);
     }
     else if MODE == 1{
-        return apply_box_filter_ij_depth(pixels,image_width,sdlpixels,i,j,min_x,max_x,min_y,max_y);
+        return apply_box_filter_ij_depth(pixels,display,image_width,sdlpixels,i,j,min_x,max_x,min_y,max_y);
     }
     else if MODE == 2{
-        return apply_box_filter_ij_id(pixels,image_width,sdlpixels,i,j,min_x,max_x,min_y,max_y);
+        return apply_box_filter_ij_id(pixels,display,image_width,sdlpixels,i,j,min_x,max_x,min_y,max_y);
     }
     return;
 }
 
-fn apply_box_filter<const MODE: usize>(pixels: &Vec<crate::render_thread::Pixel>,image_height: u32,image_width: u32,sdlpixels: &mut Vec<u8>){
+fn apply_box_filter<const MODE: usize>(pixels: &Vec<crate::render_thread::Pixel>,display: &DisplayTransform,image_height: u32,image_width: u32,sdlpixels: &mut Vec<u8>){
     for j in 1..(image_height-1){
         for i in 1..(image_width-1){
             if j == 1{//Top-Bottom lines
-                apply_box_filter_ij::<MODE>(pixels,image_width,sdlpixels,i,             0,-1,1, 0,1);
-                apply_box_filter_ij::<MODE>(pixels,image_width,sdlpixels,i,image_height-1,-1,1,-1,0);
+                apply_box_filter_ij::<MODE>(pixels,display,image_width,sdlpixels,i,             0,-1,1, 0,1);
+                apply_box_filter_ij::<MODE>(pixels,display,image_width,sdlpixels,i,image_height-1,-1,1,-1,0);
             }
-            apply_box_filter_ij::<MODE>(pixels,image_width,sdlpixels,i,j,-1,1,-1,1);
+            apply_box_filter_ij::<MODE>(pixels,display,image_width,sdlpixels,i,j,-1,1,-1,1);
         }
         //Left-Right lines
-        apply_box_filter_ij::<MODE>(pixels,image_width,sdlpixels,            0,j, 0,1,-1,1);
-        apply_box_filter_ij::<MODE>(pixels,image_width,sdlpixels,image_width-1,j,-1,0,-1,1);
+        apply_box_filter_ij::<MODE>(pixels,display,image_width,sdlpixels,            0,j, 0,1,-1,1);
+        apply_box_filter_ij::<MODE>(pixels,display,image_width,sdlpixels,image_width-1,j,-1,0,-1,1);
     }
     //Corners
-    apply_box_filter_ij::<MODE>(pixels,image_width,sdlpixels,            0,             0, 0,1, 0,1);
-    apply_box_filter_ij::<MODE>(pixels,image_width,sdlpixels,image_width-1,             0,-1,0, 0,1);
-    apply_box_filter_ij::<MODE>(pixels,image_width,sdlpixels,            0,image_height-1, 0,1,-1,0);
-    apply_box_filter_ij::<MODE>(pixels,image_width,sdlpixels,image_width-1,image_height-1,-1,0,-1,0);
+    apply_box_filter_ij::<MODE>(pixels,display,image_width,sdlpixels,            0,             0, 0,1, 0,1);
+    apply_box_filter_ij::<MODE>(pixels,display,image_width,sdlpixels,image_width-1,             0,-1,0, 0,1);
+    apply_box_filter_ij::<MODE>(pixels,display,image_width,sdlpixels,            0,image_height-1, 0,1,-1,0);
+    apply_box_filter_ij::<MODE>(pixels,display,image_width,sdlpixels,image_width-1,image_height-1,-1,0,-1,0);
 }
 
-fn draw_to_sdl(pixels_box: render_thread::PixelsBox,_samples_per_pixel: u32,image_width: u32,image_height: u32){
+fn draw_to_sdl(pixels_box: render_thread::PixelsBox,display: &DisplayTransform,post_settings: &post::PostSettings,_samples_per_pixel: u32,image_width: u32,image_height: u32){
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
@@ -364,7 +796,10 @@ fn draw_to_sdl(pixels_box: render_thread::PixelsBox,_samples_per_pixel: u32,imag
     const MODE_DEPTH_WEIGHTED_BLUR: u32  = 4;
     const MODE_SHOW_IDS: u32             = 5;
     const MODE_ID_WEIGHTED_BLUR: u32     = 6;
-    const MODE_COUNT: u32 = 7;//Rust enums fucking suck
+    const MODE_DENOISED: u32             = 7;
+    const MODE_POST: u32                 = 8;
+    const MODE_AOV_FIRST: u32            = 9;//One mode per AOV from here on, see aov::Aov::from_index
+    const MODE_COUNT: u32 = MODE_AOV_FIRST + aov::AOV_COUNT as u32;//Rust enums fucking suck
     let mut mode: u32 = MODE_NORMAL;
 
     let mut sdlpixels = vec!(0 as u8;(image_width*image_height*3) as usize);
@@ -418,13 +853,34 @@ fn draw_to_sdl(pixels_box: render_thread::PixelsBox,_samples_per_pixel: u32,imag
             }
         }
         else if mode == MODE_SAMPLE_WEIGHTED_BLUR {
-            apply_box_filter::<0>(pixels,image_height,image_width,&mut sdlpixels);
+            apply_box_filter::<0>(pixels,display,image_height,image_width,&mut sdlpixels);
         }
         else if mode == MODE_DEPTH_WEIGHTED_BLUR {
-            apply_box_filter::<1>(pixels,image_height,image_width,&mut sdlpixels);
+            apply_box_filter::<1>(pixels,display,image_height,image_width,&mut sdlpixels);
         }
         else if mode == MODE_ID_WEIGHTED_BLUR {
-            apply_box_filter::<2>(pixels,image_height,image_width,&mut sdlpixels);
+            apply_box_filter::<2>(pixels,display,image_height,image_width,&mut sdlpixels);
+        }
+        else if mode == MODE_DENOISED {//@SPEED: Redone every frame even if the render is finished
+            let denoised = denoise::denoise(pixels,image_width,image_height,&denoise::DenoiseSettings::new());
+            for pos in 0..image_width*image_height{
+                let aux = (pos*3) as usize;
+                let c = display.to_u8x3(&denoised[pos as usize]);
+                sdlpixels[aux+0] = c.0;
+                sdlpixels[aux+1] = c.1;
+                sdlpixels[aux+2] = c.2;
+            }
+        }
+        else if mode == MODE_POST {//Whatever was asked for in the command line, or a bit of everything
+            let settings = if post_settings.enabled() { *post_settings } else { post::PostSettings::preview() };
+            let hdr = hdr_image(pixels,image_width,image_height,&settings);
+            for pos in 0..image_width*image_height{
+                let aux = (pos*3) as usize;
+                let c = display.to_u8x3(&hdr[pos as usize]);
+                sdlpixels[aux+0] = c.0;
+                sdlpixels[aux+1] = c.1;
+                sdlpixels[aux+2] = c.2;
+            }
         }
         else if mode == MODE_SHOW_IDS {
             for pos in 0..image_width*image_height{
@@ -435,6 +891,17 @@ fn draw_to_sdl(pixels_box: render_thread::PixelsBox,_samples_per_pixel: u32,imag
                 sdlpixels[aux+2] = c.2;
             }
         }
+        else if mode >= MODE_AOV_FIRST {
+            let aov = aov::Aov::from_index((mode - MODE_AOV_FIRST) as usize);
+            let extent = if aov == aov::Aov::Position { aov::position_extent(pixels) } else { 1. };
+            for pos in 0..image_width*image_height{
+                let aux = (pos*3) as usize;
+                let c = aov.display(&pixels[pos as usize],extent);
+                sdlpixels[aux+0] = c.0;
+                sdlpixels[aux+1] = c.1;
+                sdlpixels[aux+2] = c.2;
+            }
+        }
         //pitch = row in bytes. 1 byte per color -> 3*width
         let surface = sdl2::surface::Surface::from_data(sdlpixels.as_mut_slice(), image_width, image_height, image_width*3, sdl2::pixels::PixelFormatEnum::RGB24)
         .unwrap();
@@ -470,6 +937,18 @@ fn draw_to_sdl(pixels_box: render_thread::PixelsBox,_samples_per_pixel: u32,imag
                 Event::KeyDown { keycode: Some(Keycode::Kp6), ..} => {
                     mode = 6;
                 }
+                Event::KeyDown { keycode: Some(Keycode::Kp7), ..} |
+                Event::KeyDown { keycode: Some(Keycode::D), ..} => {
+                    mode = MODE_DENOISED;
+                }
+                Event::KeyDown { keycode: Some(Keycode::Kp8), ..} |
+                Event::KeyDown { keycode: Some(Keycode::P), ..} => {
+                    mode = MODE_POST;
+                }
+                Event::KeyDown { keycode: Some(Keycode::A), ..} => {//Cycle through the AOVs only
+                    mode = if mode < MODE_AOV_FIRST || mode + 1 == MODE_COUNT { MODE_AOV_FIRST } else { mode + 1 };
+                    eprintln!("Showing AOV {}",aov::Aov::from_index((mode - MODE_AOV_FIRST) as usize).name());
+                }
                 Event::KeyDown { keycode: Some(Keycode::F12), ..} => {
                     let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
                     surface.save_bmp(timestamp.as_secs().to_string() + ".bmp").unwrap();
@@ -1,10 +1,11 @@
 
-use crate::math::vec3::{Vec3,UnitVec3,Point3};
+use crate::math::vec3::{Vec3,UnitVec3,Point3,Color};
 use crate::math::mat4x4::Mat4x4;
 use crate::math::vec4::Vec4;
-use crate::ray::Ray;
 use crate::materials::Material;
 use crate::bounding_box::*;
+use crate::hits::HitRecord;
+use crate::utils::{clamp,lerp};
 pub trait Marched: Bounded {
     fn material(&self) -> &Material;
     fn to_local(&self,p: &Vec4) -> Vec4;
@@ -16,35 +17,100 @@ pub trait Marched: Bounded {
         let local_sdf = self.local_sdf(&p);
         return self.to_world_f(local_sdf);
     }
-    fn get_outward_normal(&self,p: &Point3) -> UnitVec3{
+    //Spherical mapping around the local origin by default
+    fn uv(&self,p: &Point3) -> (f32,f32){
+        return self.to_local(&Vec4::new_p3(p)).xyz().spherical_uv();
+    }
+    //Material at a world point, the same everywhere unless it's blended from several objects
+    fn material_at(&self,_p: &Point3) -> Material{
+        return *self.material();
+    }
+    //Where the ray is at time, only objects with moving parts care
+    fn material_at_time(&self,p: &Point3,_time: f32) -> Material{
+        return self.material_at(p);
+    }
+    fn hit_record(&self,t: f32,p: &Point3) -> HitRecord{
+        let normal = self.get_outward_normal(p,normal_eps(t,p));
+        return HitRecord{t: t,point: *p,normal: normal,geometric_normal: normal,material: self.material_at(p),obj_id: 0,uv: self.uv(p),tangent: Vec3::ZERO,fiber: false};
+    }
+    //Where the ray is at time, only moving objects care
+    fn sdf_at(&self,p: &Point3,_time: f32) -> f32{
+        return self.sdf(p);
+    }
+    fn hit_record_at(&self,t: f32,p: &Point3,_time: f32) -> HitRecord{
+        return self.hit_record(t,p);
+    }
+    //eps is the world distance numerical gradients are taken over, see normal_eps
+    fn get_outward_normal(&self,p: &Point3,eps: f32) -> UnitVec3{
         let p = self.to_local(&Vec4::new_p3(p)).xyz();
-        let n = Vec4::new_v3(&self.get_outward_local_normal(&p));
-        let world_n = self.to_world(&n);
-        return world_n.xyz().unit();
-    }
-    fn get_outward_local_normal(&self,p: &Point3) -> UnitVec3 {
-        let eps = 0.0000001;
-        let ex = Point3::new(eps, 0., 0.);
-        let ey = Point3::new( 0.,eps, 0.);
-        let ez = Point3::new( 0., 0.,eps);
-        let x = self.local_sdf(&(*p+ex)) - self.local_sdf(&(*p-ex));
-        let y = self.local_sdf(&(*p+ey)) - self.local_sdf(&(*p-ey));
-        let z = self.local_sdf(&(*p+ez)) - self.local_sdf(&(*p-ez));
-        let normal = Vec3::new(x,y,z).unit();
-        //Flip the sign so always the SDF grows in the direction of the normal
-        // This should work in local and word coords as long its convex
-        // and there is no inversion or shearing or something like that
-        let test_ray = Ray::new(&Point3::ZERO,&normal);//Ray::new(&self.center(),&normal);
-        let start     = test_ray.at(0.);
-        let start_val = self.local_sdf(&start);
-        let end     = test_ray.at(1.);
-        let end_val = self.local_sdf(&end);
-        let sign = [-1.,1.][(end_val > start_val) as usize];//If it grows, keep the sign. Else flip it
-        return normal*sign;
+        let n = self.get_outward_local_normal(&p,eps/self.to_world_f(1.));
+        return self.normal_to_world(&n).unit();
+    }
+    //Fine for translations, rotations and uniform scales. Anything with non uniform scales needs the inverse transpose
+    fn normal_to_world(&self,n: &Vec3) -> Vec3{
+        return self.to_world(&Vec4::new_v3(n)).xyz();
+    }
+    //Exact gradient of local_sdf if the primitive knows it, otherwise it's taken numerically
+    fn local_gradient(&self,_p: &Point3) -> Option<Vec3>{
+        return None;
+    }
+    //The SDF grows along its gradient, so it already points outwards. No need to guess the side
+    fn get_outward_local_normal(&self,p: &Point3,eps: f32) -> UnitVec3 {
+        if let Some(g) = self.local_gradient(p) { return g.unit(); }
+        return sdf_gradient(|q| self.local_sdf(q),p,eps);
     }
 }
 //For now, just always draw the marched
 
+//Gradients are taken over about a fraction of a pixel at distance t from the ray origin (the camera for primary rays),
+//but never below what f32 can tell apart around p
+const NORMAL_EPS_PER_UNIT: f32 = 1e-4;
+#[inline]
+pub fn normal_eps(t: f32,p: &Point3) -> f32{
+    return (NORMAL_EPS_PER_UNIT*t).max(64.*f32::EPSILON*p.abs().max_val()).max(1e-6);
+}
+
+//Tetrahedral differences, 4 taps instead of 6 of central differences. Unit length, pointing where sdf grows
+pub fn sdf_gradient<F: Fn(&Point3) -> f32>(sdf: F,p: &Point3,eps: f32) -> UnitVec3{
+    let k0 = Vec3::new( 1.,-1.,-1.);
+    let k1 = Vec3::new(-1.,-1., 1.);
+    let k2 = Vec3::new(-1., 1.,-1.);
+    let k3 = Vec3::new( 1., 1., 1.);
+    let g = k0*sdf(&(*p + eps*k0)) + k1*sdf(&(*p + eps*k1)) + k2*sdf(&(*p + eps*k2)) + k3*sdf(&(*p + eps*k3));
+    return g.unit();
+}
+
+
+//Any affine transform for a marched object, non uniform scales after rotations (shears) included.
+//Distances get scaled by the smallest singular value so they never overshoot, normals go through the inverse transpose
+#[derive(Copy, Clone, Debug)]
+pub struct MarchedTransform {
+    pub m_local_to_world: Mat4x4,
+    pub m_world_to_local: Mat4x4,
+    pub min_scale: f32,
+}
+
+impl MarchedTransform {
+    pub fn new(m_local_to_world: &Mat4x4) -> Self{
+        return Self{m_local_to_world: *m_local_to_world,m_world_to_local: m_local_to_world.fast_homogenous_inverse(),
+                    min_scale: m_local_to_world.min_singular_value()};
+    }
+    pub fn to_local(&self,p: &Vec4) -> Vec4{
+        return self.m_world_to_local.dot(p);
+    }
+    pub fn to_world(&self,p: &Vec4) -> Vec4{
+        return self.m_local_to_world.dot(p);
+    }
+    pub fn to_world_f(&self,f: f32) -> f32{
+        return f*self.min_scale;
+    }
+    pub fn normal_to_world(&self,n: &Vec3) -> Vec3{
+        return self.m_world_to_local.transpose().dot_v3(n);
+    }
+    pub fn bounds(&self,local: &BoundingBox3D) -> BoundingBox3D{
+        return local.dot(&self.m_local_to_world);
+    }
+}
 
 #[derive(Copy, Clone)]
 pub struct MarchedSphere {
@@ -52,14 +118,18 @@ pub struct MarchedSphere {
     pub radius: f32,
     pub material: Material
 }
-impl Bounded for MarchedSphere {}
+impl Bounded for MarchedSphere {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let r = Vec3::new(self.radius,self.radius,self.radius).abs();
+        return BoundingBox3D::new(&(self.center - r),&(self.center + r));
+    }
+}
 impl Marched for MarchedSphere {
     fn local_sdf(&self,p: &Point3) -> f32 {
         return p.length() - self.radius;
     }
-    fn get_outward_normal(&self,p: &Point3) -> Vec3 {
-        let normal = (*p - self.center).unit();
-        return normal;
+    fn local_gradient(&self,p: &Point3) -> Option<Vec3>{
+        return Some(*p);
     }
     fn material(&self) -> &Material{
         return &self.material;
@@ -81,12 +151,28 @@ pub struct MarchedBox {
     pub sizes: Vec3,
     pub material: Material
 }
-impl Bounded for MarchedBox {}
+impl Bounded for MarchedBox {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        return BoundingBox3D::new(&(self.center - self.sizes.abs()),&(self.center + self.sizes.abs()));
+    }
+}
 impl Marched for MarchedBox {
     fn local_sdf(&self,p: &Point3) -> f32 {
         let q = p.abs() - self.sizes;
         return q.max(&Vec3::ZERO).length() + q.x().max(q.y().max(q.z())).min(0.);
     }
+    fn local_gradient(&self,p: &Point3) -> Option<Vec3>{
+        let q = p.abs() - self.sizes;
+        let sign = Vec3::new(1_f32.copysign(p.x()),1_f32.copysign(p.y()),1_f32.copysign(p.z()));
+        if q.max_val() > 0. {//Outside, away from the closest point of the box
+            return Some(q.max(&Vec3::ZERO)*sign);
+        }
+        //Inside, towards the closest face
+        let axis = if q.x() >= q.y() && q.x() >= q.z() { 0 } else if q.y() >= q.z() { 1 } else { 2 };
+        let mut g = Vec3::ZERO;
+        g[axis] = sign[axis];
+        return Some(g);
+    }
     fn material(&self) -> &Material{
         return &self.material;
     }
@@ -103,10 +189,7 @@ impl Marched for MarchedBox {
 
 #[derive(Copy, Clone)]
 pub struct MarchedTorus {
-    pub m_local_to_world_translate_rotate: Mat4x4,
-    pub m_world_to_local_translate_rotate: Mat4x4,
-    pub m_local_to_world_scale: Vec4,
-    pub m_world_to_local_scale: Vec4,
+    pub transform: MarchedTransform,
     pub sizes: Vec3,//Vec2... actualy
     pub material: Material,
     pub bounding_box: BoundingBox,
@@ -114,15 +197,8 @@ pub struct MarchedTorus {
 
 impl MarchedTorus {
     pub fn new(m_local_to_world: &Mat4x4,local_sizes: &Vec3,mat: &Material) -> Self{
-        let (t,r,s) = m_local_to_world.decompose_into_translate_rotate_scale();//TRS
-        let translate_rotate = t.dot_mat(&r);//TR
-        let scale = s.diag();//w value should be 1.
-        let scale_inv = Vec4::new(1./scale.x(),1./scale.y(),1./scale.z(),1./scale.w());//w value should be 1.
         Self{
-            m_local_to_world_translate_rotate: translate_rotate,//TR
-            m_world_to_local_translate_rotate: translate_rotate.fast_homogenous_inverse(),//R^-1 T^-1
-            m_local_to_world_scale: scale,
-            m_world_to_local_scale: scale_inv,
+            transform: MarchedTransform::new(m_local_to_world),
             sizes: *local_sizes,
             material: *mat,
             bounding_box: BoundingBox::draw_always(),
@@ -136,28 +212,207 @@ impl Marched for MarchedTorus {
         let q = Vec3::new(Vec3::new(p2.x(),p2.z(),0.).length()-self.sizes.x(),p2.y(),0.);
         return q.length() - self.sizes.y();
     }
+    fn local_gradient(&self,p: &Point3) -> Option<Vec3>{
+        let ring = Vec3::new(p.x(),0.,p.z()).length();
+        if ring == 0. { return None; }//On the axis every direction is as good
+        //From the closest point of the center circle to p
+        let center = Vec3::new(p.x(),0.,p.z())*(self.sizes.x()/ring);
+        return Some(*p - center);
+    }
     fn material(&self) -> &Material{
         return &self.material;
     }
     fn to_local(&self,p: &Vec4) -> Vec4{
-        return self.m_world_to_local_translate_rotate.dot(&(*p*self.m_world_to_local_scale));
+        return self.transform.to_local(p);
     }
     fn to_world(&self,p: &Vec4) -> Vec4{
-        return self.m_local_to_world_translate_rotate.dot(p)*self.m_local_to_world_scale;
+        return self.transform.to_world(p);
     }
     fn to_world_f(&self,f: f32) -> f32{//http://jamie-wong.com/2016/07/15/ray-marching-signed-distance-functions/#non-uniform-scaling-and-beyond
-        return f*self.m_local_to_world_scale.xyz().min_val();
+        return self.transform.to_world_f(f);
+    }
+    fn normal_to_world(&self,n: &Vec3) -> Vec3{
+        return self.transform.normal_to_world(n);
     }
 }
 impl Bounded for MarchedTorus {
     fn build_world_bounding_box(&self) -> BoundingBox3D {
-        let size = self.sizes.x().max(self.sizes.y());
-        let vsize = Vec3::new(size,size,size)*self.m_local_to_world_scale.xyz();
-        let bb = BoundingBox3D::new(&-vsize,&vsize).dot(&self.m_local_to_world_translate_rotate);
-        return bb;
+        let (major,minor) = (self.sizes.x(),self.sizes.y());
+        let v = Vec3::new(major + minor,minor,major + minor);
+        return self.transform.bounds(&BoundingBox3D::new(&-v,&v));
     }
     fn hit_bounding_box(&self,dir: &Vec3) -> bool{ 
         self.bounding_box.hit(dir) 
     }
     fn get_bounding_box(&self) -> BoundingBox { self.bounding_box }
-}
\ No newline at end of file
+}
+//Distance estimated fractals. Their natural extent (about [-extent;extent]^3) is scaled by size and moved to center,
+//orbit_colors[0] to [1] paint the albedo by the orbit trap of the point, the rest of the material is used as is
+macro_rules! marched_fractal {
+    ($fractal:ty,$extent:expr) => {//extent is a function of the fractal giving the half side of its bounds, before size
+        impl Marched for $fractal {
+            fn local_sdf(&self,p: &Point3) -> f32 {
+                return self.de(p).0;
+            }
+            fn material(&self) -> &Material{
+                return &self.material;
+            }
+            fn material_at(&self,p: &Point3) -> Material{
+                let mut m = self.material;
+                m.albedo = lerp(self.de(&self.to_local(&Vec4::new_p3(p)).xyz()).1,self.orbit_colors[0],self.orbit_colors[1]);
+                return m;
+            }
+            fn to_local(&self,p: &Vec4) -> Vec4 {
+                return Vec4::new_v3_w(&((p.xyz() - p.w()*self.center)/self.size),p.w());
+            }
+            fn to_world(&self,p: &Vec4) -> Vec4{
+                return Vec4::new_v3_w(&(p.xyz()*self.size + p.w()*self.center),p.w());
+            }
+            fn to_world_f(&self,f: f32) -> f32{
+                return f*self.size;
+            }
+        }
+        impl Bounded for $fractal {
+            fn build_world_bounding_box(&self) -> BoundingBox3D {
+                let e = ($extent)(self);
+                let e = Vec3::new(e,e,e)*self.size;
+                return BoundingBox3D::new(&(self.center - e),&(self.center + e));
+            }
+        }
+    };
+}
+
+#[derive(Copy, Clone)]
+pub struct MarchedMandelbulb {
+    pub center: Point3,
+    pub size: f32,
+    pub power: f32,//8 is the classic bulb, 2 or more
+    pub iterations: u32,
+    pub material: Material,
+    pub orbit_colors: [Color;2],
+}
+impl MarchedMandelbulb {
+    //Radius the whole bulb fits in: past it |z|^power - |p| > |z| and the orbit escapes. 2 for power 2, about 1.1 for 8
+    fn extent(&self) -> f32{
+        return 2f32.powf(1./(self.power.max(2.) - 1.));
+    }
+    //Distance and orbit trap (how close the orbit got to the origin) in [0;1]
+    fn de(&self,p: &Point3) -> (f32,f32){
+        let mut z = *p;
+        let mut dr: f32 = 1.;
+        let mut r = z.length();
+        //Far away the estimate has nothing to go on and overshoots, the bounding sphere is closer to the truth
+        let extent = self.extent();
+        if r > 1.25*extent { return (r - extent,1.); }
+        let mut trap = r;
+        for _i in 0..self.iterations{
+            if r > 2. { break; }
+            //Spherical coordinates with y up, raised to the power
+            let theta = (z.y()/r.max(1e-12)).max(-1.).min(1.).acos()*self.power;
+            let phi = z.z().atan2(z.x())*self.power;
+            dr = r.powf(self.power - 1.)*self.power*dr + 1.;
+            let zr = r.powf(self.power);
+            z = zr*Vec3::new(theta.sin()*phi.cos(),theta.cos(),theta.sin()*phi.sin()) + *p;
+            r = z.length();
+            trap = trap.min(r);
+        }
+        return (0.5*r.max(1e-12).ln()*r/dr,clamp(trap,0.,1.));
+    }
+}
+marched_fractal!(MarchedMandelbulb,MarchedMandelbulb::extent);
+
+#[derive(Copy, Clone)]
+pub struct MarchedMengerSponge {
+    pub center: Point3,
+    pub size: f32,//Half side of the sponge
+    pub iterations: u32,
+    pub material: Material,
+    pub orbit_colors: [Color;2],
+}
+impl MarchedMengerSponge {
+    //https://iquilezles.org/articles/menger/ the trap is how deep the hole that carved the point is
+    fn de(&self,p: &Point3) -> (f32,f32){
+        let q = p.abs() - Vec3::new(1.,1.,1.);
+        let mut d = q.max(&Vec3::ZERO).length() + q.x().max(q.y().max(q.z())).min(0.);
+        let mut trap = 0.;
+        let mut s = 1.;
+        for m in 0..self.iterations{
+            let a = Vec3::new((p.x()*s).rem_euclid(2.) - 1.,(p.y()*s).rem_euclid(2.) - 1.,(p.z()*s).rem_euclid(2.) - 1.);
+            s *= 3.;
+            let r = (Vec3::new(1.,1.,1.) - 3.*a.abs()).abs();
+            let da = r.x().max(r.y());
+            let db = r.y().max(r.z());
+            let dc = r.z().max(r.x());
+            let c = (da.min(db.min(dc)) - 1.)/s;
+            if c > d {
+                d = c;
+                trap = (m + 1) as f32/(self.iterations as f32);
+            }
+        }
+        return (d,trap);
+    }
+}
+marched_fractal!(MarchedMengerSponge,|_| 1.);
+
+#[derive(Copy, Clone)]
+pub struct MarchedMandelbox {
+    pub center: Point3,
+    pub size: f32,
+    pub scale: f32,//2 gives the boxy one, about -1.5 the organic one
+    pub iterations: u32,
+    pub material: Material,
+    pub orbit_colors: [Color;2],
+}
+impl MarchedMandelbox {
+    //Natural extent of the set, 2(|s|+1)/(|s|-1) for |s| > 1
+    pub fn extent(scale: f32) -> f32{
+        return 2.*(scale.abs() + 1.)/(scale.abs() - 1.).max(1e-3);
+    }
+    //Box fold, sphere fold, scale. Normalized to [-1;1] so size is the half side like the sponge. The trap is the smallest radius the orbit went through
+    fn de(&self,p: &Point3) -> (f32,f32){
+        const MIN_R2: f32 = 0.25;
+        const FIXED_R2: f32 = 1.;
+        let extent = Self::extent(self.scale);
+        let c = *p*extent;
+        let mut z = c;
+        let mut dr: f32 = 1.;
+        let mut trap: f32 = 1e10;
+        for _i in 0..self.iterations{
+            z = Vec3::new(clamp(z.x(),-1.,1.),clamp(z.y(),-1.,1.),clamp(z.z(),-1.,1.))*2. - z;
+            let r2 = z.length_squared();
+            trap = trap.min(r2);
+            let f = if r2 < MIN_R2 { FIXED_R2/MIN_R2 } else if r2 < FIXED_R2 { FIXED_R2/r2 } else { 1. };
+            z = z*(f*self.scale) + c;
+            dr = dr*f*self.scale.abs() + 1.;
+            if z.length_squared() > 1e4 { break; }
+        }
+        return (z.length()/dr/extent,clamp(trap.sqrt(),0.,1.));
+    }
+}
+marched_fractal!(MarchedMandelbox,|_| 1.);
+
+#[derive(Copy, Clone)]
+pub struct MarchedSierpinski {
+    pub center: Point3,
+    pub size: f32,//Corners at (1,1,1),(-1,-1,1),(-1,1,-1),(1,-1,-1) times size
+    pub iterations: u32,
+    pub material: Material,
+    pub orbit_colors: [Color;2],
+}
+impl MarchedSierpinski {
+    //Folds everything into the (1,1,1) corner and halves towards it. The trap is how many folds the point went through
+    fn de(&self,p: &Point3) -> (f32,f32){
+        let mut z = *p;
+        let mut folds = 0;
+        for _i in 0..self.iterations{
+            if z.x() + z.y() < 0. { z = Vec3::new(-z.y(),-z.x(),z.z()); folds += 1; }
+            if z.x() + z.z() < 0. { z = Vec3::new(-z.z(),z.y(),-z.x()); folds += 1; }
+            if z.y() + z.z() < 0. { z = Vec3::new(z.x(),-z.z(),-z.y()); folds += 1; }
+            z = z*2. - Vec3::new(1.,1.,1.);
+        }
+        //Distance to the tetrahedron left at the bottom, scaled back up
+        let tetra = ((-z.x() - z.y() - z.z()).max(z.x() + z.y() - z.z()).max((-z.x() + z.y() + z.z()).max(z.x() - z.y() + z.z())) - 1.)/(3 as f32).sqrt();
+        return (tetra*(0.5 as f32).powi(self.iterations as i32),folds as f32/(3*self.iterations.max(1)) as f32);
+    }
+}
+marched_fractal!(MarchedSierpinski,|_| 1.);
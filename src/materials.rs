@@ -1,7 +1,16 @@
 use crate::ray::Ray;
 use crate::math::vec3::*;
 use crate::hits::HitRecord;
-use crate::utils::MyRandom;
+use crate::utils::{MyRandom,PI,clamp,luminance};
+use std::sync::atomic::{AtomicU32,Ordering};
+
+//Starts the ray a bit off the geometric surface on the side it's going to, so it doesn't hit it again.
+//The shading normal can't be used for this, it may be tilted away from the actual surface
+#[inline]
+fn spawn_ray(hr: &HitRecord,dir: &Vec3) -> Ray{
+    let offset = (1e-4 as f32).copysign(dir.dot(hr.geometric_normal));
+    return Ray::new(&(hr.point + offset*hr.geometric_normal),dir);
+}
 
 pub struct MaterialScatterResult {
     pub attenuation: Color,
@@ -9,10 +18,25 @@ pub struct MaterialScatterResult {
 }
 
 #[derive(Copy, Clone, PartialEq)]
+#[allow(non_camel_case_types)]
 pub enum MaterialType {
     LAMBERTIAN,
     METAL,
     DIELECTRIC,
+    DIFFUSE_LIGHT,
+    HAIR,
+}
+
+//Light group 0 is always the sky
+pub const MAX_LIGHT_GROUPS: usize = 4;
+
+static NEXT_MATERIAL_ID: AtomicU32 = AtomicU32::new(1);
+fn next_material_id() -> u32{
+    return NEXT_MATERIAL_ID.fetch_add(1,Ordering::Relaxed);
+}
+//Ids start over, so a scene built the same way every frame gets the same material ids every frame
+pub fn reset_material_ids(){
+    NEXT_MATERIAL_ID.store(1,Ordering::Relaxed);
 }
 
 #[derive(Copy, Clone)]
@@ -20,21 +44,118 @@ pub struct Material {//Used in:
     pub albedo: Color,//Lambertian, Metal
     pub fuzz: f32,//Metal
     pub ior: f32,//Dielectric
+    pub emitted: Color,//Diffuse light
+    pub light_group: u32,//Diffuse light
+    pub sigma_a: Color,//Hair, absorption per unit of fiber diameter
+    pub beta_m: f32,//Hair, longitudinal roughness in [0;1]
+    pub beta_n: f32,//Hair, azimuthal roughness in [0;1]
+    pub alpha: f32,//Hair, tilt of the cuticle scales in radians
+    pub normal_map: u32,//Any, texture id in crate::texture. 0 is none
+    pub bump_map: u32,//Any, texture id, heights in the first channel
+    pub bump_scale: f32,//Any, height of the bump map at 1
     pub mat_type: MaterialType, //Tag
+    pub id: u32,//Unique per constructed material, 0 is reserved for "nothing"
 }
 
 impl Material{
     pub fn new_lambertian(albedo: Color) -> Self{
-        return Self{albedo: albedo,fuzz: 0.,ior: 0.,mat_type: MaterialType::LAMBERTIAN};
+        return Self{albedo: albedo,fuzz: 0.,ior: 0.,mat_type: MaterialType::LAMBERTIAN,emitted: Color::ZERO,light_group: 0,
+                    sigma_a: Color::ZERO,beta_m: 0.,beta_n: 0.,alpha: 0.,normal_map: 0,bump_map: 0,bump_scale: 0.,id: next_material_id()};
     }
     pub fn new_metal(albedo: Color) -> Self{
-        return Self{albedo: albedo,fuzz: 0.,ior: 0.,mat_type: MaterialType::METAL};
+        return Self{albedo: albedo,fuzz: 0.,ior: 0.,mat_type: MaterialType::METAL,emitted: Color::ZERO,light_group: 0,
+                    sigma_a: Color::ZERO,beta_m: 0.,beta_n: 0.,alpha: 0.,normal_map: 0,bump_map: 0,bump_scale: 0.,id: next_material_id()};
     }
     pub fn new_metal_fuzz(albedo: Color,fuzz: f32) -> Self{
-        return Self{albedo: albedo,fuzz: fuzz,ior: 0.,mat_type: MaterialType::METAL};
+        return Self{albedo: albedo,fuzz: fuzz,ior: 0.,mat_type: MaterialType::METAL,emitted: Color::ZERO,light_group: 0,
+                    sigma_a: Color::ZERO,beta_m: 0.,beta_n: 0.,alpha: 0.,normal_map: 0,bump_map: 0,bump_scale: 0.,id: next_material_id()};
     }
     pub fn new_dielectric(index_of_refraction: f32) -> Self{
-        return Self{albedo: Color::ZERO,fuzz: 0.,ior: index_of_refraction,mat_type: MaterialType::DIELECTRIC};
+        return Self{albedo: Color::ZERO,fuzz: 0.,ior: index_of_refraction,mat_type: MaterialType::DIELECTRIC,emitted: Color::ZERO,light_group: 0,
+                    sigma_a: Color::ZERO,beta_m: 0.,beta_n: 0.,alpha: 0.,normal_map: 0,bump_map: 0,bump_scale: 0.,id: next_material_id()};
+    }
+    //light_group should be in [1;MAX_LIGHT_GROUPS), 0 is the sky
+    #[allow(dead_code)]
+    pub fn new_diffuse_light(emitted: Color,light_group: u32) -> Self{
+        assert!(light_group > 0 && (light_group as usize) < MAX_LIGHT_GROUPS);
+        return Self{albedo: Color::ZERO,fuzz: 0.,ior: 0.,mat_type: MaterialType::DIFFUSE_LIGHT,emitted: emitted,light_group: light_group,
+                    sigma_a: Color::ZERO,beta_m: 0.,beta_n: 0.,alpha: 0.,normal_map: 0,bump_map: 0,bump_scale: 0.,id: next_material_id()};
+    }
+    //Chiang et al. 2016 hair, cuticle scales tilted alpha_degrees (2 is typical for human hair)
+    #[allow(dead_code)]
+    pub fn new_hair(sigma_a: Color,beta_m: f32,beta_n: f32,alpha_degrees: f32) -> Self{
+        let beta_n = clamp(beta_n,0.,1.);
+        //The AOVs get the color that absorption ends up looking like
+        let f = hair_color_factor(beta_n);
+        let albedo = Color::new((-sigma_a.x().sqrt()*f).exp(),(-sigma_a.y().sqrt()*f).exp(),(-sigma_a.z().sqrt()*f).exp());
+        return Self{albedo: albedo,fuzz: 0.,ior: 1.55,mat_type: MaterialType::HAIR,emitted: Color::ZERO,light_group: 0,
+                    sigma_a: sigma_a,beta_m: clamp(beta_m,0.,1.),beta_n: beta_n,alpha: alpha_degrees.to_radians(),
+                    normal_map: 0,bump_map: 0,bump_scale: 0.,id: next_material_id()};
+    }
+    //Eumelanin goes from blonde (~0.3) to brown (~1.3) and black (~8), pheomelanin makes it red
+    #[allow(dead_code)]
+    pub fn new_hair_melanin(eumelanin: f32,pheomelanin: f32,beta_m: f32,beta_n: f32) -> Self{
+        let sigma_a = eumelanin*Color::new(0.419,0.697,1.37) + pheomelanin*Color::new(0.187,0.4,1.05);
+        return Self::new_hair(sigma_a,beta_m,beta_n,2.);
+    }
+    //Absorption picked so after all the scattering the hair looks roughly color
+    #[allow(dead_code)]
+    pub fn new_hair_color(color: Color,beta_m: f32,beta_n: f32) -> Self{
+        let f = hair_color_factor(clamp(beta_n,0.,1.));
+        let sigma_a = |c: f32| (c.max(0.0001).min(1.).ln()/f).powi(2);
+        return Self::new_hair(Color::new(sigma_a(color.x()),sigma_a(color.y()),sigma_a(color.z())),beta_m,beta_n,2.);
+    }
+    #[allow(dead_code)]
+    pub fn with_normal_map(mut self,texture_id: u32) -> Self{
+        self.normal_map = texture_id;
+        return self;
+    }
+    #[allow(dead_code)]
+    pub fn with_bump_map(mut self,texture_id: u32,scale: f32) -> Self{
+        self.bump_map = texture_id;
+        self.bump_scale = scale;
+        return self;
+    }
+    //Same material numbered as if it was constructed now, for materials kept across reset_material_ids()
+    pub fn with_new_id(mut self) -> Self{
+        self.id = next_material_id();
+        return self;
+    }
+    #[inline]
+    pub fn has_normal_perturbation(&self) -> bool{
+        return self.normal_map != 0 || self.bump_map != 0;
+    }
+    //Self at w = 1, other at w = 0. Parameters are lerped if both are the same type, otherwise each hit picks
+    //one with probability w, which averages out to the blend over the samples of a pixel
+    pub fn mix(&self,other: &Self,w: f32) -> Self{
+        if w >= 1. { return *self; }
+        if w <= 0. { return *other; }
+        if self.mat_type != other.mat_type {
+            return if f32::rand() < w { *self } else { *other };
+        }
+        let mut ret = if w >= 0.5 { *self } else { *other };//Textures, light group and id from the closest
+        let l = |a: f32,b: f32| a*w + b*(1. - w);
+        ret.albedo  = self.albedo*w + other.albedo*(1. - w);
+        ret.emitted = self.emitted*w + other.emitted*(1. - w);
+        ret.sigma_a = self.sigma_a*w + other.sigma_a*(1. - w);
+        ret.fuzz = l(self.fuzz,other.fuzz);
+        ret.ior  = l(self.ior,other.ior);
+        ret.beta_m = l(self.beta_m,other.beta_m);
+        ret.beta_n = l(self.beta_n,other.beta_n);
+        ret.alpha  = l(self.alpha,other.alpha);
+        return ret;
+    }
+    #[inline]
+    pub fn is_light(&self) -> bool{
+        return self.mat_type == MaterialType::DIFFUSE_LIGHT;
+    }
+    //Albedo as reported to the AOVs, clear dielectrics are white
+    pub fn aov_albedo(&self) -> Color{
+        match &self.mat_type{
+            MaterialType::DIELECTRIC => Color::new(1.,1.,1.),
+            MaterialType::DIFFUSE_LIGHT => self.emitted,
+            _ => self.albedo,
+        }
     }
     pub fn scatter(&self,r_in: &Ray,hr: &HitRecord) -> MaterialScatterResult {
         match &self.mat_type{
@@ -47,6 +168,12 @@ impl Material{
             MaterialType::DIELECTRIC => {
                 return self.scatter_dielectric(r_in,hr);
             }
+            MaterialType::HAIR => {
+                return self.scatter_hair(r_in,hr);
+            }
+            MaterialType::DIFFUSE_LIGHT => {//Lights absorb everything, the path should have ended before calling this
+                return MaterialScatterResult{attenuation: Color::ZERO,ray: *r_in};
+            }
         }
     }
     pub fn scatter_lambertian(&self,_r_in: &Ray,hr: &HitRecord) -> MaterialScatterResult {
@@ -56,12 +183,17 @@ impl Material{
             new_dir = hr.normal;
         }
         //let new_dir = Vec3::rand_in_hemisphere(&hr.normal);
-        let new_ray = Ray::new(&hr.point, &new_dir);
+        //A perturbed shading normal can send it into the actual surface, mirror it back out
+        let g = hr.geometric_normal;
+        if new_dir.dot(g)*hr.normal.dot(g) < 0. {
+            new_dir = new_dir - 2.*new_dir.dot(g)*g;
+        }
+        let new_ray = spawn_ray(hr,&new_dir);
         return MaterialScatterResult{attenuation: self.albedo,ray: new_ray};
     }
     pub fn scatter_metal(&self,r_in: &Ray,hr: &HitRecord) -> MaterialScatterResult {
         let reflected: Vec3 = reflect(&r_in.dir, &hr.normal);
-        let new_ray = Ray::new(&hr.point, &(reflected + self.fuzz*Vec3::rand_in_unit_sphere()));
+        let new_ray = spawn_ray(hr,&(reflected + self.fuzz*Vec3::rand_in_unit_sphere()));
         return MaterialScatterResult{attenuation: self.albedo,ray: new_ray};
     }
 
@@ -91,7 +223,28 @@ impl Material{
             new_dir = refract(&dir_unit,&normal,refraction_ratio);
         }
 
-        return MaterialScatterResult{attenuation:  Color::new(1.0,1.0,1.0),ray: Ray::new(&hr.point,&new_dir)};
+        return MaterialScatterResult{attenuation:  Color::new(1.0,1.0,1.0),ray: spawn_ray(hr,&new_dir)};
+    }
+
+    //Samples a lobe and a direction like pbrt-v3's HairBSDF::Sample_f, the attenuation is f*cos/pdf
+    //https://benedikt-bitterli.me/pchfm/
+    pub fn scatter_hair(&self,r_in: &Ray,hr: &HitRecord) -> MaterialScatterResult {
+        //Frame: x along the fiber, y across it (the way h grows), z facing the incoming ray
+        let x = hr.tangent;
+        let y = x.cross(r_in.dir);
+        if !hr.fiber || y.length_squared() < 1e-12 {//Not a curve or looking down the fiber, just bounce back
+            return MaterialScatterResult{attenuation: self.albedo,ray: Ray::new(&hr.point,&-r_in.dir)};
+        }
+        let y = y.unit();
+        let wo_world = -r_in.dir;
+        let z = (wo_world - x*wo_world.dot(x)).unit();
+        let wo = Vec3::new(wo_world.dot(x),wo_world.dot(y),wo_world.dot(z));
+        let bsdf = HairBsdf::new(self,2.*hr.uv.1 - 1.);
+        let wi = bsdf.sample(&wo,[f32::rand(),f32::rand(),f32::rand(),f32::rand()]);
+        let (f,pdf) = bsdf.eval(&wo,&wi);
+        let attenuation = if pdf > 0. && pdf.is_finite() { f/pdf } else { Color::ZERO };
+        let new_dir = wi.x()*x + wi.y()*y + wi.z()*z;
+        return MaterialScatterResult{attenuation: attenuation,ray: Ray::new(&hr.point,&new_dir)};
     }
 }
 
@@ -113,4 +266,219 @@ fn reflectance(cos: f32, ref_idx: f32) -> f32{
     let r0_2 = r0*r0;
     let cos_5 = (1.-cos)*(1.-cos)*(1.-cos)*(1.-cos)*(1.-cos);
     return r0_2 + (1.-r0_2)*cos_5;
-}
\ No newline at end of file
+}
+//Maps the azimuthal roughness to how much the absorption darkens multiply scattered light
+#[inline]
+fn hair_color_factor(beta_n: f32) -> f32{
+    return 5.969 - 0.215*beta_n + 2.532*beta_n.powi(2) - 10.73*beta_n.powi(3) + 5.574*beta_n.powi(4) + 0.245*beta_n.powi(5);
+}
+
+//Exact Fresnel for unpolarized light going into a dielectric
+fn fresnel_dielectric(cos_i: f32,eta: f32) -> f32{
+    let cos_i = clamp(cos_i,0.,1.);
+    let sin_t = (1. - cos_i*cos_i).max(0.).sqrt()/eta;
+    if sin_t >= 1. { return 1.; }
+    let cos_t = (1. - sin_t*sin_t).max(0.).sqrt();
+    let r_parallel = (eta*cos_i - cos_t)/(eta*cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta*cos_t)/(cos_i + eta*cos_t);
+    return 0.5*(r_parallel*r_parallel + r_perpendicular*r_perpendicular);
+}
+
+#[inline]
+fn safe_sqrt(x: f32) -> f32{
+    return x.max(0.).sqrt();
+}
+
+//Lobes R, TT, TRT and everything after lumped together
+const HAIR_P_MAX: usize = 3;
+
+//Modified Bessel function of the first kind
+fn bessel_i0(x: f32) -> f32{
+    let mut ret = 0.;
+    let mut x2i = 1.;
+    let mut ifact = 1.;
+    let mut i4 = 1.;
+    for i in 0..10{
+        if i > 1 { ifact *= i as f32; }
+        ret += x2i/(i4*ifact*ifact);
+        x2i *= x*x;
+        i4 *= 4.;
+    }
+    return ret;
+}
+
+fn log_bessel_i0(x: f32) -> f32{
+    if x > 12. { return x + 0.5*(-(2.*PI).ln() + (1./x).ln() + 1./(8.*x)); }
+    return bessel_i0(x).ln();
+}
+
+//Longitudinal scattering
+fn hair_mp(cos_i: f32,cos_o: f32,sin_i: f32,sin_o: f32,v: f32) -> f32{
+    let a = cos_i*cos_o/v;
+    let b = sin_i*sin_o/v;
+    if v <= 0.1 {//The direct formula overflows for narrow lobes
+        return (log_bessel_i0(a) - b - 1./v + std::f32::consts::LN_2 + (1./(2.*v)).ln()).exp();
+    }
+    return ((-b).exp()*bessel_i0(a))/((1./v).sinh()*2.*v);
+}
+
+#[inline]
+fn hair_phi(p: usize,gamma_o: f32,gamma_t: f32) -> f32{
+    return 2.*(p as f32)*gamma_t - 2.*gamma_o + (p as f32)*PI;
+}
+
+#[inline]
+fn logistic(x: f32,s: f32) -> f32{
+    let x = x.abs();
+    return (-x/s).exp()/(s*(1. + (-x/s).exp()).powi(2));
+}
+
+#[inline]
+fn logistic_cdf(x: f32,s: f32) -> f32{
+    return 1./(1. + (-x/s).exp());
+}
+
+#[inline]
+fn trimmed_logistic(x: f32,s: f32,a: f32,b: f32) -> f32{
+    return logistic(x,s)/(logistic_cdf(b,s) - logistic_cdf(a,s));
+}
+
+fn sample_trimmed_logistic(u: f32,s: f32,a: f32,b: f32) -> f32{
+    let k = logistic_cdf(b,s) - logistic_cdf(a,s);
+    let x = -s*(1./(u*k + logistic_cdf(a,s)) - 1.).ln();
+    return clamp(x,a,b);
+}
+
+//Azimuthal scattering
+fn hair_np(phi: f32,p: usize,s: f32,gamma_o: f32,gamma_t: f32) -> f32{
+    let mut dphi = phi - hair_phi(p,gamma_o,gamma_t);
+    while dphi > PI { dphi -= 2.*PI; }
+    while dphi < -PI { dphi += 2.*PI; }
+    return trimmed_logistic(dphi,s,-PI,PI);
+}
+
+//Everything that depends on where the fiber got hit, in the fiber's frame (x along it)
+struct HairBsdf{
+    h: f32,
+    gamma_o: f32,
+    eta: f32,
+    sigma_a: Color,
+    v: [f32;HAIR_P_MAX+1],//Longitudinal variance per lobe
+    s: f32,//Azimuthal logistic scale
+    sin_2k_alpha: [f32;3],
+    cos_2k_alpha: [f32;3],
+}
+
+impl HairBsdf{
+    fn new(m: &Material,h: f32) -> Self{
+        let h = clamp(h,-1.,1.);
+        let (bm,bn) = (m.beta_m,m.beta_n);
+        let mut v = [0.;HAIR_P_MAX+1];
+        v[0] = (0.726*bm + 0.812*bm*bm + 3.7*bm.powi(20)).powi(2);
+        v[1] = 0.25*v[0];
+        v[2] = 4.*v[0];
+        v[3] = v[2];
+        const SQRT_PI_OVER_8: f32 = 0.626657069;
+        let s = SQRT_PI_OVER_8*(0.265*bn + 1.194*bn*bn + 5.372*bn.powi(22));
+        let mut sin_2k_alpha = [0.;3];
+        let mut cos_2k_alpha = [0.;3];
+        sin_2k_alpha[0] = m.alpha.sin();
+        cos_2k_alpha[0] = safe_sqrt(1. - sin_2k_alpha[0]*sin_2k_alpha[0]);
+        for i in 1..3{
+            sin_2k_alpha[i] = 2.*cos_2k_alpha[i-1]*sin_2k_alpha[i-1];
+            cos_2k_alpha[i] = cos_2k_alpha[i-1]*cos_2k_alpha[i-1] - sin_2k_alpha[i-1]*sin_2k_alpha[i-1];
+        }
+        return Self{h: h,gamma_o: h.asin(),eta: m.ior,sigma_a: m.sigma_a,v: v,s: s,sin_2k_alpha: sin_2k_alpha,cos_2k_alpha: cos_2k_alpha};
+    }
+    //The scales tilt each lobe a different way, R by -2 alpha, TT by alpha and TRT by 4 alpha
+    #[inline]
+    fn tilt(&self,p: usize,sin_o: f32,cos_o: f32) -> (f32,f32){
+        let (sin_a,cos_a,sign) = match p {
+            0 => (self.sin_2k_alpha[1],self.cos_2k_alpha[1],-1.),
+            1 => (self.sin_2k_alpha[0],self.cos_2k_alpha[0],1.),
+            2 => (self.sin_2k_alpha[2],self.cos_2k_alpha[2],1.),
+            _ => return (sin_o,cos_o),
+        };
+        return (sin_o*cos_a + sign*cos_o*sin_a,(cos_o*cos_a - sign*sin_o*sin_a).abs());
+    }
+    #[inline]
+    fn gamma_t(&self,sin_o: f32,cos_o: f32) -> f32{
+        let etap = (self.eta*self.eta - sin_o*sin_o).sqrt()/cos_o;
+        return clamp(self.h/etap,-1.,1.).asin();
+    }
+    //Fraction of the light that leaves through each lobe
+    fn ap(&self,sin_o: f32,cos_o: f32) -> [Color;HAIR_P_MAX+1]{
+        let sin_t = sin_o/self.eta;
+        let cos_t = safe_sqrt(1. - sin_t*sin_t);
+        let cos_gamma_t = self.gamma_t(sin_o,cos_o).cos();
+        let d = 2.*cos_gamma_t/cos_t;
+        let t = Color::new((-self.sigma_a.x()*d).exp(),(-self.sigma_a.y()*d).exp(),(-self.sigma_a.z()*d).exp());
+        let f = fresnel_dielectric(cos_o*safe_sqrt(1. - self.h*self.h),self.eta);
+        let one = Color::new(1.,1.,1.);
+        let mut ap = [Color::ZERO;HAIR_P_MAX+1];
+        ap[0] = Color::new(f,f,f);
+        ap[1] = (1. - f)*(1. - f)*t;
+        ap[2] = ap[1]*t*f;
+        ap[3] = ap[2]*t*f/(one - t*f);
+        return ap;
+    }
+    fn ap_pdf(ap: &[Color;HAIR_P_MAX+1]) -> [f32;HAIR_P_MAX+1]{
+        let mut ret = [0.;HAIR_P_MAX+1];
+        let sum: f32 = ap.iter().map(|a| luminance(a)).sum();
+        for i in 0..ret.len(){
+            ret[i] = if sum > 0. { luminance(&ap[i])/sum } else { 0.25 };
+        }
+        return ret;
+    }
+    //f*cos and the pdf of sampling wi
+    fn eval(&self,wo: &Vec3,wi: &Vec3) -> (Color,f32){
+        let sin_o = wo.x();
+        let cos_o = safe_sqrt(1. - sin_o*sin_o);
+        let phi_o = wo.z().atan2(wo.y());
+        let sin_i = wi.x();
+        let cos_i = safe_sqrt(1. - sin_i*sin_i);
+        let phi_i = wi.z().atan2(wi.y());
+        let gamma_t = self.gamma_t(sin_o,cos_o);
+        let ap = self.ap(sin_o,cos_o);
+        let ap_pdf = Self::ap_pdf(&ap);
+        let phi = phi_i - phi_o;
+        let mut f = Color::ZERO;
+        let mut pdf = 0.;
+        for p in 0..HAIR_P_MAX{
+            let (sin_op,cos_op) = self.tilt(p,sin_o,cos_o);
+            let mn = hair_mp(cos_i,cos_op,sin_i,sin_op,self.v[p])*hair_np(phi,p,self.s,self.gamma_o,gamma_t);
+            f += mn*ap[p];
+            pdf += mn*ap_pdf[p];
+        }
+        let m = hair_mp(cos_i,cos_o,sin_i,sin_o,self.v[HAIR_P_MAX])/(2.*PI);
+        f += m*ap[HAIR_P_MAX];
+        pdf += m*ap_pdf[HAIR_P_MAX];
+        return (f,pdf);
+    }
+    fn sample(&self,wo: &Vec3,u: [f32;4]) -> Vec3{
+        let sin_o = wo.x();
+        let cos_o = safe_sqrt(1. - sin_o*sin_o);
+        let phi_o = wo.z().atan2(wo.y());
+        let ap_pdf = Self::ap_pdf(&self.ap(sin_o,cos_o));
+        let mut u0 = u[0];
+        let mut p = 0;
+        while p < HAIR_P_MAX && u0 >= ap_pdf[p] {
+            u0 -= ap_pdf[p];
+            p += 1;
+        }
+        let (sin_op,cos_op) = self.tilt(p,sin_o,cos_o);
+        let u1 = u[1].max(1e-5);
+        let cos_theta = 1. + self.v[p]*(u1 + (1. - u1)*(-2./self.v[p]).exp()).ln();
+        let sin_theta = safe_sqrt(1. - cos_theta*cos_theta);
+        let cos_phi = (2.*PI*u[2]).cos();
+        let sin_i = clamp(-cos_theta*sin_op + sin_theta*cos_phi*cos_op,-1.,1.);
+        let cos_i = safe_sqrt(1. - sin_i*sin_i);
+        let dphi = if p < HAIR_P_MAX {
+            hair_phi(p,self.gamma_o,self.gamma_t(sin_o,cos_o)) + sample_trimmed_logistic(u[3],self.s,-PI,PI)
+        } else {
+            2.*PI*u[3]
+        };
+        let phi_i = phi_o + dphi;
+        return Vec3::new(sin_i,cos_i*phi_i.cos(),cos_i*phi_i.sin());
+    }
+}
@@ -1,4 +1,6 @@
 pub mod vec3;
 pub mod vec4;
 pub mod mat3x3;
-pub mod mat4x4;
\ No newline at end of file
+pub mod mat4x4;
+pub mod quat;
+pub mod roots;
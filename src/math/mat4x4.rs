@@ -148,6 +148,24 @@ impl Mat4x4 {
     pub fn diag(&self) -> Vec4{
         return Vec4::new(self.at_row(0).x(),self.at_row(1).y(),self.at_row(2).z(),self.at_row(3).w());
     }
+    //Smallest singular value of the 3x3 part, the least it can stretch a unit vector (shears and scales after rotations included)
+    //Square root of the smallest eigenvalue of M^T M https://en.wikipedia.org/wiki/Eigenvalue_algorithm#3%C3%973_matrices
+    pub fn min_singular_value(&self) -> f32{
+        let c = [self.at_col(0).xyz(),self.at_col(1).xyz(),self.at_col(2).xyz()];
+        let a = |i: usize,j: usize| c[i].dot(c[j]) as f64;//f64, the cancellations below eat f32 precision quickly
+        let p1 = a(0,1)*a(0,1) + a(0,2)*a(0,2) + a(1,2)*a(1,2);
+        if p1 == 0. {//Already diagonal
+            return a(0,0).min(a(1,1)).min(a(2,2)).sqrt() as f32;
+        }
+        let q = (a(0,0) + a(1,1) + a(2,2))/3.;
+        let p2 = (a(0,0) - q).powi(2) + (a(1,1) - q).powi(2) + (a(2,2) - q).powi(2) + 2.*p1;
+        let p = (p2/6.).sqrt();
+        let b = |i: usize,j: usize| (a(i,j) - if i == j { q } else { 0. })/p;
+        let det = b(0,0)*(b(1,1)*b(2,2) - b(1,2)*b(2,1)) - b(0,1)*(b(1,0)*b(2,2) - b(1,2)*b(2,0)) + b(0,2)*(b(1,0)*b(2,1) - b(1,1)*b(2,0));
+        let phi = (det/2.).max(-1.).min(1.).acos()/3.;
+        let smallest = q + 2.*p*(phi + 2.*std::f64::consts::PI/3.).cos();
+        return smallest.max(0.).sqrt() as f32;
+    }
 }
 
 use std::ops::{Mul,Div,BitXor};
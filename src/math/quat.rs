@@ -0,0 +1,61 @@
+use super::vec4::Vec4;
+use super::mat4x4::Mat4x4;
+
+//Unit quaternion for rotations, (x,y,z) is the imaginary part and w the real one
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quat {
+    pub e: Vec4
+}
+
+impl Quat {
+    #[allow(dead_code)]
+    pub const IDENTITY : Self = Self{ e: Vec4{e: [0.,0.,0.,1.]}};
+    //From the upper 3x3 of m, which should be a rotation (orthonormal, determinant 1)
+    //Shoemake's method, picks the biggest component to divide by so it never loses precision
+    pub fn from_rotation(m: &Mat4x4) -> Self{
+        let trace = m.at(0,0) + m.at(1,1) + m.at(2,2);
+        let e = if trace > 0. {
+            let s = 0.5/(trace + 1.).sqrt();
+            Vec4::new((m.at(2,1) - m.at(1,2))*s,(m.at(0,2) - m.at(2,0))*s,(m.at(1,0) - m.at(0,1))*s,0.25/s)
+        }
+        else if m.at(0,0) > m.at(1,1) && m.at(0,0) > m.at(2,2) {
+            let s = 2.*(1. + m.at(0,0) - m.at(1,1) - m.at(2,2)).sqrt();
+            Vec4::new(0.25*s,(m.at(0,1) + m.at(1,0))/s,(m.at(0,2) + m.at(2,0))/s,(m.at(2,1) - m.at(1,2))/s)
+        }
+        else if m.at(1,1) > m.at(2,2) {
+            let s = 2.*(1. + m.at(1,1) - m.at(0,0) - m.at(2,2)).sqrt();
+            Vec4::new((m.at(0,1) + m.at(1,0))/s,0.25*s,(m.at(1,2) + m.at(2,1))/s,(m.at(0,2) - m.at(2,0))/s)
+        }
+        else {
+            let s = 2.*(1. + m.at(2,2) - m.at(0,0) - m.at(1,1)).sqrt();
+            Vec4::new((m.at(0,2) + m.at(2,0))/s,(m.at(1,2) + m.at(2,1))/s,0.25*s,(m.at(1,0) - m.at(0,1))/s)
+        };
+        return Self{e: e.unit()};
+    }
+    pub fn to_mat4x4(&self) -> Mat4x4{
+        let (x,y,z,w) = (self.e.x(),self.e.y(),self.e.z(),self.e.w());
+        return Mat4x4::new_16f(1. - 2.*(y*y + z*z),     2.*(x*y - z*w),     2.*(x*z + y*w),0.,
+                                   2.*(x*y + z*w), 1. - 2.*(x*x + z*z),     2.*(y*z - x*w),0.,
+                                   2.*(x*z - y*w),     2.*(y*z + x*w), 1. - 2.*(x*x + y*y),0.,
+                                               0.,                  0.,                  0.,1.);
+    }
+    //Angle between the two rotations, in radians
+    pub fn angle_to(&self,other: &Self) -> f32{
+        return 2.*self.e.dot(other.e).abs().min(1.).acos();
+    }
+    //Constant angular speed from self (a = 0) to other (a = 1), the short way around
+    pub fn slerp(&self,other: &Self,a: f32) -> Self{
+        let mut cos_theta = self.e.dot(other.e);
+        let mut to = other.e;
+        if cos_theta < 0. {//q and -q are the same rotation, go to the closest one
+            cos_theta = -cos_theta;
+            to = -to;
+        }
+        if cos_theta > 0.9995 {//Almost the same, lerp is fine and avoids dividing by sin(theta) ~ 0
+            return Self{e: (self.e*(1. - a) + to*a).unit()};
+        }
+        let theta = cos_theta.acos();
+        let sin_theta = theta.sin();
+        return Self{e: (self.e*(((1. - a)*theta).sin()/sin_theta) + to*((a*theta).sin()/sin_theta)).unit()};
+    }
+}
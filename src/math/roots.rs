@@ -0,0 +1,94 @@
+//Real roots of polynomials up to degree 4, in f64 since the quartic loses a lot of precision on the way
+//Coefficients go from the highest degree down, roots are returned unsorted
+
+#[inline]
+fn eval(c: &[f64],x: f64) -> f64{
+    let mut ret = 0.;
+    for ci in c{
+        ret = ret*x + ci;
+    }
+    return ret;
+}
+
+#[inline]
+fn eval_derivative(c: &[f64],x: f64) -> f64{
+    let n = c.len() - 1;
+    let mut ret = 0.;
+    for (i,ci) in c[..n].iter().enumerate(){
+        ret = ret*x + ci*((n - i) as f64);
+    }
+    return ret;
+}
+
+pub fn solve_quadratic(a: f64,b: f64,c: f64) -> Vec<f64>{
+    if a == 0. {
+        return if b == 0. { vec!() } else { vec!(-c/b) };
+    }
+    let discriminant = b*b - 4.*a*c;
+    if discriminant < 0. { return vec!(); }
+    //Numerically stable form, avoids subtracting two close numbers
+    let q = -0.5*(b + discriminant.sqrt().copysign(b));
+    if q == 0. { return vec!(0.,0.); }
+    return vec!(q/a,c/q);
+}
+
+pub fn solve_cubic(a: f64,b: f64,c: f64,d: f64) -> Vec<f64>{
+    if a == 0. { return solve_quadratic(b,c,d); }
+    let (b,c,d) = (b/a,c/a,d/a);
+    //x = y - b/3 -> y^3 + p y + q = 0
+    let p = c - b*b/3.;
+    let q = 2.*b*b*b/27. - b*c/3. + d;
+    let shift = -b/3.;
+    let discriminant = q*q/4. + p*p*p/27.;
+    if discriminant > 0. {//One real root, Cardano
+        let s = discriminant.sqrt();
+        return vec!((-q/2. + s).cbrt() + (-q/2. - s).cbrt() + shift);
+    }
+    if p == 0. { return vec!(shift); }
+    //Three real roots, trigonometric form
+    let m = 2.*(-p/3.).sqrt();
+    let theta = ((3.*q/(p*m)).max(-1.).min(1.)).acos()/3.;
+    let mut ret = Vec::with_capacity(3);
+    for k in 0..3{
+        ret.push(m*(theta - 2.*std::f64::consts::PI*(k as f64)/3.).cos() + shift);
+    }
+    return ret;
+}
+
+//Ferrari's method, roots polished with a couple of Newton steps on the original polynomial
+pub fn solve_quartic(a: f64,b: f64,c: f64,d: f64,e: f64) -> Vec<f64>{
+    if a == 0. { return solve_cubic(b,c,d,e); }
+    let (b,c,d,e) = (b/a,c/a,d/a,e/a);
+    //x = y - b/4 -> y^4 + p y^2 + q y + r = 0
+    let b2 = b*b;
+    let p = c - 3.*b2/8.;
+    let q = d - b*c/2. + b2*b/8.;
+    let r = e - b*d/4. + b2*c/16. - 3.*b2*b2/256.;
+    let shift = -b/4.;
+    let mut ys: Vec<f64> = Vec::with_capacity(4);
+    if q.abs() < 1e-12 {//Biquadratic
+        for z in solve_quadratic(1.,p,r){
+            if z < 0. { continue; }
+            ys.push(z.sqrt());
+            ys.push(-z.sqrt());
+        }
+    }
+    else {
+        //Resolvent cubic, any positive root m lets it factor into two quadratics
+        let m = solve_cubic(1.,p,p*p/4. - r,-q*q/8.).into_iter().fold(f64::MIN,f64::max);
+        if m <= 0. { return vec!(); }
+        let s = (2.*m).sqrt();
+        ys.extend(solve_quadratic(1.,-s,m + p/2. + q/(2.*s)));
+        ys.extend(solve_quadratic(1., s,m + p/2. - q/(2.*s)));
+    }
+    let coefs = [1.,b,c,d,e];
+    return ys.into_iter().map(|y| {
+        let mut x = y + shift;
+        for _i in 0..2{
+            let der = eval_derivative(&coefs,x);
+            if der == 0. { break; }
+            x -= eval(&coefs,x)/der;
+        }
+        x
+    }).collect();
+}
@@ -70,6 +70,22 @@ impl Vec3{
         let eps = 1e-8;
         return (self.e[0].abs() < eps) && (self.e[1].abs() < eps) && (self.e[2].abs() < eps);
     }
+    //Two unit vectors that together with self (assumed unit) make an orthonormal basis
+    //https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+    pub fn orthonormal_basis(&self) -> (Self,Self){
+        let sign = (1. as f32).copysign(self.z());
+        let a = -1./(sign + self.z());
+        let b = self.x()*self.y()*a;
+        return (Vec3::new(1. + sign*self.x()*self.x()*a,sign*b,-sign*self.x()),
+                Vec3::new(b,sign + self.y()*self.y()*a,-self.y()));
+    }
+    //Spherical (longitude,latitude) coordinates of a direction, both in [0;1]
+    pub fn spherical_uv(&self) -> (f32,f32){
+        let d = self.unit();
+        let theta = (-d.y()).max(-1.).min(1.).acos();
+        let phi = (-d.z()).atan2(d.x()) + utils::PI;
+        return (phi/(2.*utils::PI),theta/utils::PI);
+    }
     #[inline]
     pub fn to_u8x3(&self) -> (u8,u8,u8){
         return ((self.e[0]*256.0) as u8,(self.e[1]*256.0) as u8,(self.e[2]*256.0) as u8);
@@ -0,0 +1,277 @@
+use crate::math::vec3::{Vec3,UnitVec3,Point3};
+use crate::math::mat4x4::Mat4x4;
+use crate::ray::Ray;
+use crate::hits::HitRecord;
+use crate::materials::Material;
+use crate::bounding_box::*;
+use crate::traced::Traced;
+use crate::bvh::Bvh;
+use std::collections::HashMap;
+
+//Triangle mesh in world space with per vertex normals, added to the world as an Arc<dyn Traced>
+//Its triangles are only tested through its own BVH
+pub struct Mesh {
+    pub positions: Vec<Point3>,
+    pub normals: Vec<UnitVec3>,
+    pub uvs: Vec<(f32,f32)>,//Empty means barycentric uvs
+    pub triangles: Vec<[u32;3]>,
+    pub material: Material,
+    bvh: Bvh,
+}
+
+//Real-Time Collision Detection 5.1.5, by the Voronoi region of the triangle p is in
+fn closest_point_on_triangle(p: &Point3,t: &[Point3;3]) -> Point3 {
+    let (a,b,c) = (t[0],t[1],t[2]);
+    let (ab,ac,ap) = (b - a,c - a,*p - a);
+    let (d1,d2) = (ab.dot(ap),ac.dot(ap));
+    if d1 <= 0. && d2 <= 0. { return a; }
+    let bp = *p - b;
+    let (d3,d4) = (ab.dot(bp),ac.dot(bp));
+    if d3 >= 0. && d4 <= d3 { return b; }
+    let vc = d1*d4 - d3*d2;
+    if vc <= 0. && d1 >= 0. && d3 <= 0. { return a + ab*(d1/(d1 - d3)); }
+    let cp = *p - c;
+    let (d5,d6) = (ab.dot(cp),ac.dot(cp));
+    if d6 >= 0. && d5 <= d6 { return c; }
+    let vb = d5*d2 - d1*d6;
+    if vb <= 0. && d2 >= 0. && d6 <= 0. { return a + ac*(d2/(d2 - d6)); }
+    let va = d3*d6 - d5*d4;
+    if va <= 0. && (d4 - d3) >= 0. && (d5 - d6) >= 0. { return b + (c - b)*((d4 - d3)/((d4 - d3) + (d5 - d6))); }
+    let denom = va + vb + vc;
+    if denom == 0. { return a; }//Degenerate
+    return a + ab*(vb/denom) + ac*(vc/denom);
+}
+
+#[inline]
+fn triangle_bbox(p: &[Point3;3]) -> BoundingBox3D {
+    return BoundingBox3D::new(&p[0].min(&p[1].min(&p[2])),&p[0].max(&p[1].max(&p[2])));
+}
+
+impl Mesh {
+    //normals can be empty, they are then averaged from the faces around each vertex
+    pub fn new(positions: Vec<Point3>,normals: Vec<UnitVec3>,uvs: Vec<(f32,f32)>,triangles: Vec<[u32;3]>,material: &Material) -> Self{
+        assert!(normals.is_empty() || normals.len() == positions.len());
+        assert!(uvs.is_empty() || uvs.len() == positions.len());
+        let mut ret = Self{positions: positions,normals: normals,uvs: uvs,triangles: triangles,material: *material,
+                           bvh: Bvh::new(&Vec::new())};
+        if ret.normals.is_empty() {
+            ret.normals = ret.smooth_normals();
+        }
+        ret.build_bvh();
+        return ret;
+    }
+    pub fn transform(&mut self,m_local_to_world: &Mat4x4){
+        let normal_matrix = m_local_to_world.fast_homogenous_inverse().transpose();
+        for p in self.positions.iter_mut(){
+            *p = m_local_to_world.dot_p3(p);
+        }
+        for n in self.normals.iter_mut(){
+            *n = normal_matrix.dot_v3(n).unit();
+        }
+        self.build_bvh();
+    }
+    //Area weighted average of the faces around each vertex (the cross product's length is twice the area)
+    fn smooth_normals(&self) -> Vec<UnitVec3>{
+        let mut ret = vec!(Vec3::ZERO;self.positions.len());
+        for tri in &self.triangles{
+            let p = self.triangle_points(tri);
+            let n = (p[1] - p[0]).cross(p[2] - p[0]);
+            for i in tri{
+                ret[*i as usize] += n;
+            }
+        }
+        for n in ret.iter_mut(){
+            if n.length_squared() > 0. { *n = n.unit(); }
+        }
+        return ret;
+    }
+    fn build_bvh(&mut self){
+        let bboxes: Vec<BoundingBox3D> = self.triangles.iter().map(|t| triangle_bbox(&self.triangle_points(t))).collect();
+        self.bvh = Bvh::new(&bboxes);
+    }
+    //Unsigned distance from p to the surface
+    pub fn distance(&self,p: &Point3) -> f32{
+        let nearest = self.bvh.nearest(p,|t_idx| (closest_point_on_triangle(p,&self.triangle_points(&self.triangles[t_idx as usize])) - *p).length_squared());
+        return nearest.map_or(f32::INFINITY,|(_,d2)| d2.sqrt());
+    }
+    #[inline]
+    pub fn triangle_points(&self,tri: &[u32;3]) -> [Point3;3]{
+        return [self.positions[tri[0] as usize],self.positions[tri[1] as usize],self.positions[tri[2] as usize]];
+    }
+    //Möller–Trumbore, returns (t,b1,b2)
+    #[inline]
+    fn hit_triangle(&self,r: &Ray,tri: &[u32;3],t_min: f32,t_max: f32) -> Option<(f32,f32,f32)>{
+        let p = self.triangle_points(tri);
+        let e1 = p[1] - p[0];
+        let e2 = p[2] - p[0];
+        let pv = r.dir.cross(e2);
+        let det = e1.dot(pv);
+        if det.abs() < 1e-12 { return None; }
+        let inv_det = 1./det;
+        let tv = r.orig - p[0];
+        let b1 = tv.dot(pv)*inv_det;
+        if b1 < 0. || b1 > 1. { return None; }
+        let qv = tv.cross(e1);
+        let b2 = r.dir.dot(qv)*inv_det;
+        if b2 < 0. || b1 + b2 > 1. { return None; }
+        let t = e2.dot(qv)*inv_det;
+        if t < t_min || t > t_max { return None; }
+        return Some((t,b1,b2));
+    }
+}
+
+impl Traced for Mesh {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let mut best: Option<(u32,f32,f32)> = None;//Triangle and barycentrics
+        let closest = self.bvh.traverse(r,t_min,t_max,|t_idx,closest| {
+            let (t,b1,b2) = self.hit_triangle(r,&self.triangles[t_idx as usize],t_min,closest)?;
+            best = Some((t_idx,b1,b2));
+            return Some(t);
+        });
+        let (t_idx,b1,b2) = best?;
+        let tri = &self.triangles[t_idx as usize];
+        let b0 = 1. - b1 - b2;
+        let (i0,i1,i2) = (tri[0] as usize,tri[1] as usize,tri[2] as usize);
+        let normal = (b0*self.normals[i0] + b1*self.normals[i1] + b2*self.normals[i2]).unit();
+        let uv = if self.uvs.is_empty() { (b1,b2) } else {
+            (b0*self.uvs[i0].0 + b1*self.uvs[i1].0 + b2*self.uvs[i2].0,
+             b0*self.uvs[i0].1 + b1*self.uvs[i1].1 + b2*self.uvs[i2].1)
+        };
+        let p = self.triangle_points(tri);
+        let (e1,e2) = (p[1] - p[0],p[2] - p[0]);
+        let face = e1.cross(e2).unit();
+        let geometric_normal = if face.dot(normal) < 0. { -face } else { face };
+        //dP/du from how the uvs change along the edges, the edge itself when the uvs are the barycentrics
+        let mut tangent = e1;
+        if !self.uvs.is_empty() {
+            let (du1,dv1) = (self.uvs[i1].0 - self.uvs[i0].0,self.uvs[i1].1 - self.uvs[i0].1);
+            let (du2,dv2) = (self.uvs[i2].0 - self.uvs[i0].0,self.uvs[i2].1 - self.uvs[i0].1);
+            let det = du1*dv2 - dv1*du2;
+            if det.abs() > 1e-12 { tangent = (dv2*e1 - dv1*e2)/det; }
+        }
+        return Some(HitRecord{t: closest,point: r.at(closest),normal: normal,geometric_normal: geometric_normal,material: self.material,
+                              obj_id: 0,uv: uv,tangent: tangent.unit(),fiber: false});
+    }
+}
+
+impl Bounded for Mesh {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        return self.bvh.bbox().unwrap_or(BoundingBox3D::new(&Point3::ZERO,&Point3::ZERO));
+    }
+}
+
+//Polygon control cage, faces can have any number of vertices, in counter clockwise order seen from outside
+#[derive(Clone)]
+pub struct PolyMesh {
+    pub positions: Vec<Point3>,
+    pub faces: Vec<Vec<u32>>,
+}
+
+impl PolyMesh {
+    pub fn new_cube() -> Self{
+        let mut positions = Vec::with_capacity(8);
+        for i in 0..8{
+            positions.push(Point3::new([-1.,1.][i&1],[-1.,1.][(i>>1)&1],[-1.,1.][(i>>2)&1]));
+        }
+        let faces = vec!(vec!(0,2,3,1),vec!(4,5,7,6),vec!(0,1,5,4),vec!(2,6,7,3),vec!(0,4,6,2),vec!(1,3,7,5));
+        return Self{positions: positions,faces: faces};
+    }
+    //Catmull-Clark, every level turns each n-gon into n quads. Boundary edges use the cubic B-spline rules so open
+    //cages keep their border
+    pub fn catmull_clark(&self,levels: u32) -> Self{
+        let mut ret = self.clone();
+        for _l in 0..levels{
+            ret = ret.subdivide();
+        }
+        return ret;
+    }
+    fn subdivide(&self) -> Self{
+        let nv = self.positions.len();
+        let face_points: Vec<Point3> = self.faces.iter().map(|f| {
+            let mut c = Point3::ZERO;
+            for v in f { c += self.positions[*v as usize]; }
+            c/(f.len() as f32)
+        }).collect();
+        //Edges by their sorted vertices, with the faces on each side
+        let mut edges: HashMap<(u32,u32),(u32,Vec<u32>)> = HashMap::new();
+        for (fi,f) in self.faces.iter().enumerate(){
+            for i in 0..f.len(){
+                let (a,b) = (f[i],f[(i+1)%f.len()]);
+                let key = (a.min(b),a.max(b));
+                let next = edges.len() as u32;
+                edges.entry(key).or_insert((next,Vec::new())).1.push(fi as u32);
+            }
+        }
+        let mut positions = vec!(Point3::ZERO;nv + self.faces.len() + edges.len());
+        let face_base = nv;
+        let edge_base = nv + self.faces.len();
+        for (fi,fp) in face_points.iter().enumerate(){
+            positions[face_base + fi] = *fp;
+        }
+        let mut boundary = vec!(false;nv);
+        let mut vert_faces = vec!(0u32;nv);
+        let mut vert_edges = vec!(0u32;nv);
+        let mut vert_f_sum = vec!(Point3::ZERO;nv);
+        let mut vert_r_sum = vec!(Point3::ZERO;nv);
+        let mut vert_boundary_sum = vec!(Point3::ZERO;nv);
+        for (&(a,b),(ei,faces)) in &edges{
+            let (pa,pb) = (self.positions[a as usize],self.positions[b as usize]);
+            let mid = (pa + pb)*0.5;
+            positions[edge_base + *ei as usize] = if faces.len() == 2 {
+                (pa + pb + face_points[faces[0] as usize] + face_points[faces[1] as usize])*0.25
+            } else {
+                mid
+            };
+            for v in [a,b]{
+                vert_edges[v as usize] += 1;
+                vert_r_sum[v as usize] += mid;
+            }
+            if faces.len() != 2 {
+                boundary[a as usize] = true;
+                boundary[b as usize] = true;
+                vert_boundary_sum[a as usize] += pb;
+                vert_boundary_sum[b as usize] += pa;
+            }
+        }
+        for (fi,f) in self.faces.iter().enumerate(){
+            for v in f{
+                vert_faces[*v as usize] += 1;
+                vert_f_sum[*v as usize] += face_points[fi];
+            }
+        }
+        for v in 0..nv{
+            let p = self.positions[v];
+            positions[v] = if vert_faces[v] == 0 { p }
+            else if boundary[v] {
+                p*0.75 + vert_boundary_sum[v]*0.125
+            }
+            else {
+                let n = vert_edges[v] as f32;
+                let f = vert_f_sum[v]/(vert_faces[v] as f32);
+                let r = vert_r_sum[v]/n;
+                (f + 2.*r + (n - 3.)*p)/n
+            };
+        }
+        let mut faces = Vec::with_capacity(self.faces.len()*4);
+        for (fi,f) in self.faces.iter().enumerate(){
+            let edge_point = |a: u32,b: u32| (edge_base as u32) + edges[&(a.min(b),a.max(b))].0;
+            for i in 0..f.len(){
+                let prev = f[(i + f.len() - 1)%f.len()];
+                let curr = f[i];
+                let next = f[(i+1)%f.len()];
+                faces.push(vec!(curr,edge_point(curr,next),(face_base + fi) as u32,edge_point(prev,curr)));
+            }
+        }
+        return Self{positions: positions,faces: faces};
+    }
+    //Fan triangulation, the normals are averaged so the result shades smooth
+    pub fn to_mesh(&self,material: &Material) -> Mesh{
+        let mut triangles = Vec::with_capacity(self.faces.len()*2);
+        for f in &self.faces{
+            for i in 1..f.len()-1{
+                triangles.push([f[0],f[i],f[i+1]]);
+            }
+        }
+        return Mesh::new(self.positions.clone(),Vec::new(),Vec::new(),triangles,material);
+    }
+}
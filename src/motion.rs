@@ -0,0 +1,99 @@
+use crate::math::vec3::Vec3;
+use crate::math::mat4x4::Mat4x4;
+use crate::math::quat::Quat;
+use crate::utils::clamp;
+use crate::bounding_box::BoundingBox3D;
+
+//Steps the rotating bounds get sampled at, the bulge between them is padded
+const MOTION_BOUND_STEPS: u32 = 32;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[allow(non_camel_case_types)]
+pub enum Interpolation {
+    LINEAR,//Every element of the matrix on its own. Cheap, but rotations shrink halfway through
+    TRS,//Translation and scale lerped, rotation slerped. What rigid motion looks like
+}
+
+//m_local_to_world keyframed at time0 and time1, held still before and after them
+#[derive(Copy, Clone, Debug)]
+pub struct AnimatedTransform {
+    pub start: Mat4x4,
+    pub end: Mat4x4,
+    pub time0: f32,
+    pub time1: f32,
+    pub interpolation: Interpolation,
+    translate: [Vec3;2],
+    rotate: [Quat;2],
+    scale: [Vec3;2],
+}
+
+impl AnimatedTransform {
+    pub fn new(start: &Mat4x4,end: &Mat4x4,time0: f32,time1: f32,interpolation: Interpolation) -> Self{
+        let (t0,r0,s0) = start.decompose_into_translate_rotate_scale();
+        let (t1,r1,s1) = end.decompose_into_translate_rotate_scale();
+        return Self{start: *start,end: *end,time0: time0,time1: time1,interpolation: interpolation,
+                    translate: [t0.at_col(3).xyz(),t1.at_col(3).xyz()],rotate: [Quat::from_rotation(&r0),Quat::from_rotation(&r1)],
+                    scale: [s0.diag().xyz(),s1.diag().xyz()]};
+    }
+    #[allow(dead_code)]
+    pub fn new_linear(start: &Mat4x4,end: &Mat4x4) -> Self{
+        return Self::new(start,end,0.,1.,Interpolation::LINEAR);
+    }
+    #[allow(dead_code)]
+    pub fn new_trs(start: &Mat4x4,end: &Mat4x4) -> Self{
+        return Self::new(start,end,0.,1.,Interpolation::TRS);
+    }
+    #[inline]
+    fn alpha(&self,time: f32) -> f32{
+        if self.time1 <= self.time0 { return 0.; }
+        return clamp((time - self.time0)/(self.time1 - self.time0),0.,1.);
+    }
+    pub fn local_to_world(&self,time: f32) -> Mat4x4{
+        let a = self.alpha(time);
+        if a <= 0. { return self.start; }
+        if a >= 1. { return self.end; }
+        match self.interpolation {
+            Interpolation::LINEAR => {
+                let (s,e) = (self.start,self.end);
+                return Mat4x4::new_4vec(&(s.e[0]*(1.-a) + e.e[0]*a),&(s.e[1]*(1.-a) + e.e[1]*a),
+                                        &(s.e[2]*(1.-a) + e.e[2]*a),&(s.e[3]*(1.-a) + e.e[3]*a));
+            },
+            Interpolation::TRS => {
+                let t = self.translate[0]*(1.-a) + self.translate[1]*a;
+                let r = self.rotate[0].slerp(&self.rotate[1],a);
+                let s = self.scale[0]*(1.-a) + self.scale[1]*a;
+                return Mat4x4::new_translate(&t).dot_mat(&r.to_mat4x4()).dot_mat(&Mat4x4::new_scale(&s));
+            },
+        }
+    }
+    //World box of everywhere a local box goes to between the keyframes
+    pub fn motion_bounds(&self,local: &BoundingBox3D) -> BoundingBox3D{
+        let start = local.dot(&self.start);
+        let end = local.dot(&self.end);
+        let bb = start.union(&end);
+        //Lerped corners move in a straight line, the boxes at the ends already have them
+        if self.interpolation == Interpolation::LINEAR { return bb; }
+        let mut bb = bb;
+        let mut max_radius: f32 = 0.;
+        for i in 1..MOTION_BOUND_STEPS{
+            let m = self.local_to_world(self.time0 + (self.time1 - self.time0)*(i as f32)/(MOTION_BOUND_STEPS as f32));
+            bb = bb.union(&local.dot(&m));
+            let center = m.at_col(3).xyz();
+            for c in local.corners(){
+                max_radius = max_radius.max((m.dot_p3(&c) - center).length());
+            }
+        }
+        //Corners sweep arcs around the origin, between two samples they go out of the chord by at most r*(1 - cos(step angle/2))
+        let step_angle = self.rotate[0].angle_to(&self.rotate[1])/(MOTION_BOUND_STEPS as f32);
+        return bb.grow(max_radius*(1. - (0.5*step_angle).cos()));
+    }
+}
+
+//Transforms (local to world, world to local) at time, the fixed ones if there's no motion
+#[inline]
+pub fn transforms_at(motion: &Option<AnimatedTransform>,m_local_to_world: &Mat4x4,m_word_to_local: &Mat4x4,time: f32) -> (Mat4x4,Mat4x4){
+    return match motion {
+        Some(m) => { let l2w = m.local_to_world(time); (l2w,l2w.fast_homogenous_inverse()) },
+        None => (*m_local_to_world,*m_word_to_local),
+    };
+}
@@ -0,0 +1,204 @@
+use std::time::Duration;
+use crate::aov::{Aov,AOV_COUNT,parse_aov_list};
+use crate::post::PostSettings;
+use crate::display::{DisplayTransform,ToneCurve,OutputSpace,parse_tone_curve,parse_output_space};
+
+#[derive(Clone,Debug)]
+pub struct RenderOptions{
+    pub image_width: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    pub rr_min_depth: u32,
+    pub num_threads: u32,
+    //If set, samples_per_pixel is ignored and we keep sampling until the time runs out
+    pub time_budget: Option<Duration>,
+    //Don't open the SDL window, wait for the render to finish and write the output instead
+    pub headless: bool,
+    pub output: Option<String>,
+    //JSON render report, headless renders write one next to the output if not set
+    pub report: Option<String>,
+    //Extra buffers written next to the output in headless mode
+    pub aovs: Vec<Aov>,
+    //Also write a denoised copy of the output in headless mode
+    pub denoise: bool,
+    //Firefly suppression, all off by default since they trade noise for bias
+    pub clamp_sample: Option<f32>,
+    pub clamp_indirect: Option<f32>,
+    pub median_of_means: bool,
+    pub exposure_ev: f32,
+    pub white_balance: Option<f32>,
+    pub tone_curve: ToneCurve,
+    pub output_space: OutputSpace,
+    pub post: PostSettings,
+    pub scene: String,
+    //Catmull-Clark levels of the mechanical scene's cage
+    pub subdivision: u32,
+    //Bézier patches added to the scene and how far their triangles may stray from the surface
+    pub patches: Option<String>,
+    pub patch_tolerance: f32,
+    //Heightmap of the terrain scene, procedural if None
+    pub heightmap: Option<String>,
+    //Where meshes baked into SDFs are kept, so later runs load them instead of baking again
+    pub sdf_cache: Option<String>,
+    //Marched shapes written as SDF expressions, added to the scene
+    pub shapes: Option<String>,
+    //The camera sees [0;shutter], moving objects go through their whole motion between 0 and 1
+    pub shutter: f32,
+    //Batch mode, renders this many frames as FILE_0000.ppm, FILE_0001.ppm... next to the output
+    pub frames: Option<u32>,
+    pub fps: f32,
+    //Seconds into the animation of the first (or only) frame
+    pub start_time: f32,
+    //Camera keyframes, see animation::CameraAnimation::load
+    pub animation: Option<String>,
+}
+
+impl RenderOptions{
+    pub fn new() -> Self{
+        //Leave one core for the viewer/log threads
+        let num_threads = (num_cpus::get() as u32).saturating_sub(1).max(1);
+        return Self{image_width: 1000,samples_per_pixel: 200,max_depth: 50,rr_min_depth: 3,num_threads: num_threads,
+            time_budget: None,headless: false,output: None,report: None,aovs: Vec::new(),denoise: false,
+            clamp_sample: None,clamp_indirect: None,median_of_means: false,
+            exposure_ev: 0.,white_balance: None,tone_curve: ToneCurve::Clamp,output_space: OutputSpace::Srgb,post: PostSettings::new(),scene: "random".to_string(),
+            subdivision: 3,patches: None,patch_tolerance: 0.01,heightmap: None,sdf_cache: None,shapes: None,shutter: 0.,
+            frames: None,fps: 24.,start_time: 0.,animation: None};
+    }
+    pub fn from_args() -> Self{
+        let mut ret = Self::new();
+        let args: Vec<String> = std::env::args().collect();
+        let mut i = 1;
+        while i < args.len(){
+            let arg = args[i].as_str();
+            //Every option but the flags takes exactly one value
+            let is_flag = arg == "--headless" || arg == "--denoise" || arg == "--median-of-means" || arg == "--help";
+            let value = if is_flag { "" } else {
+                i += 1;
+                match args.get(i) {
+                    Some(v) => v.as_str(),
+                    None => Self::usage_and_exit(&format!("Missing value for {}",arg)),
+                }
+            };
+            match arg {
+                "--width"    => ret.image_width       = Self::parse(arg,value),
+                "--spp"      => ret.samples_per_pixel = Self::parse(arg,value),
+                "--depth"    => ret.max_depth         = Self::parse(arg,value),
+                "--rr-depth" => ret.rr_min_depth      = Self::parse(arg,value),
+                "--threads"  => ret.num_threads       = Self::parse::<u32>(arg,value).max(1),
+                "--budget"   => ret.time_budget       = Some(Duration::from_secs_f64(Self::parse(arg,value))),
+                "--output"   => ret.output            = Some(value.to_string()),
+                "--report"   => ret.report            = Some(value.to_string()),
+                "--aov"      => ret.aovs              = match parse_aov_list(value) {
+                    Ok(aovs) => aovs,
+                    Err(msg) => Self::usage_and_exit(&msg),
+                },
+                "--exposure" => ret.exposure_ev       = Self::parse(arg,value),
+                "--white-balance" => ret.white_balance = Some(Self::parse(arg,value)),
+                "--tonemap"  => ret.tone_curve        = match parse_tone_curve(value) {
+                    Some(c) => c,
+                    None => Self::usage_and_exit(&format!("Unknown tone curve '{}'",value)),
+                },
+                "--output-space" => ret.output_space  = match parse_output_space(value) {
+                    Some(o) => o,
+                    None => Self::usage_and_exit(&format!("Unknown output space '{}'",value)),
+                },
+                "--bloom"    => ret.post.bloom_strength = Self::parse(arg,value),
+                "--bloom-threshold" => ret.post.bloom_threshold = Self::parse(arg,value),
+                "--glare"    => ret.post.glare_strength = Self::parse(arg,value),
+                "--glare-blades" => ret.post.glare_blades = Self::parse::<u32>(arg,value).max(2),
+                "--vignette" => ret.post.vignette       = Self::parse(arg,value),
+                "--chromatic-aberration" => ret.post.chromatic_aberration = Self::parse(arg,value),
+                "--scene"    => ret.scene             = match value {
+                    "random" | "basic" | "mechanical" | "terrain" | "fractal" => value.to_string(),
+                    _ => Self::usage_and_exit(&format!("Unknown scene '{}'",value)),
+                },
+                "--subdivision" => ret.subdivision    = Self::parse::<u32>(arg,value).min(8),
+                "--patches"  => ret.patches           = Some(value.to_string()),
+                "--heightmap" => ret.heightmap        = Some(value.to_string()),
+                "--sdf-cache" => ret.sdf_cache        = Some(value.to_string()),
+                "--shapes"   => ret.shapes            = Some(value.to_string()),
+                "--patch-tolerance" => ret.patch_tolerance = Self::parse(arg,value),
+                "--shutter"  => ret.shutter           = Self::parse::<f32>(arg,value).max(0.),
+                "--frames"   => ret.frames            = Some(Self::parse::<u32>(arg,value).max(1)),
+                "--fps"      => ret.fps               = Self::parse::<f32>(arg,value).max(1e-3),
+                "--time"     => ret.start_time        = Self::parse(arg,value),
+                "--animation" => ret.animation        = Some(value.to_string()),
+                "--headless" => ret.headless          = true,
+                "--denoise"  => ret.denoise           = true,
+                "--clamp"    => ret.clamp_sample      = Some(Self::parse_positive(arg,value)),
+                "--clamp-indirect"  => ret.clamp_indirect  = Some(Self::parse_positive(arg,value)),
+                "--median-of-means" => ret.median_of_means = true,
+                "--help"     => Self::usage_and_exit(""),
+                _ => Self::usage_and_exit(&format!("Unknown option {}",arg)),
+            }
+            i += 1;
+        }
+        if ret.frames.is_some(){//No window for sequences
+            ret.headless = true;
+        }
+        if ret.headless && ret.output.is_none(){
+            ret.output = Some("output.ppm".to_string());
+        }
+        return ret;
+    }
+    pub fn display_transform(&self) -> DisplayTransform{
+        return DisplayTransform::new(self.exposure_ev,self.white_balance,self.tone_curve,self.output_space);
+    }
+    fn parse<T: std::str::FromStr>(arg: &str,value: &str) -> T{
+        match value.parse::<T>() {
+            Ok(v) => v,
+            Err(_) => Self::usage_and_exit(&format!("Invalid value '{}' for {}",value,arg)),
+        }
+    }
+    //Limits and such, where 0, negatives, inf or NaN make no sense
+    fn parse_positive(arg: &str,value: &str) -> f32{
+        let v = Self::parse::<f32>(arg,value);
+        if !(v > 0. && v.is_finite()) {
+            Self::usage_and_exit(&format!("{} must be a positive number, got '{}'",arg,value));
+        }
+        return v;
+    }
+    fn usage_and_exit(msg: &str) -> !{
+        if !msg.is_empty(){
+            eprintln!("{}",msg);
+        }
+        eprintln!("Usage: raytracer [options]");
+        eprintln!("  --scene NAME     random (default), basic, mechanical, terrain or fractal");
+        eprintln!("  --subdivision N  Catmull-Clark levels of the mechanical scene's subdivided cube (default 3)");
+        eprintln!("  --patches FILE   Add Bézier patches in the Utah teapot format (Z up) to the scene");
+        eprintln!("  --patch-tolerance X  Max distance between the patches and their triangles (default 0.01)");
+        eprintln!("  --heightmap FILE Heightmap of the terrain scene, PGM (8/16 bit), PFM or square 16 bit .raw");
+        eprintln!("  --sdf-cache DIR  Keep the meshes baked into SDFs here and load them on later runs instead of baking again");
+        eprintln!("  --shapes FILE    Add marched shapes defined as SDF expressions to the scene");
+        eprintln!("  --shutter X      Shutter open from time 0 to X, moving objects blur over it. They move from 0 to 1, one frame (default 0, no motion blur)");
+        eprintln!("  --frames N       Render N frames of the animation as FILE_0000.ppm, FILE_0001.ppm... next to the output, implies --headless");
+        eprintln!("  --fps F          Frames per second of the animation (default 24)");
+        eprintln!("  --time T         Seconds into the animation of the first frame (default 0)");
+        eprintln!("  --animation FILE Camera keyframes, lines of 'TIME lookfrom|lookat|vup X Y Z [EASE]' or 'TIME vfov|aperture|focus X [EASE]'");
+        eprintln!("                   EASE is step, linear (default), ease-in, ease-out, ease-in-out or spline");
+        eprintln!("  --width N        Image width in pixels (height follows the 3:2 aspect ratio)");
+        eprintln!("  --spp N          Samples per pixel");
+        eprintln!("  --depth N        Max bounces per path");
+        eprintln!("  --rr-depth N     Bounces before paths start being ended by Russian roulette, not counting the primary ray");
+        eprintln!("  --threads N      Render threads");
+        eprintln!("  --budget SECS    Render for SECS wall-clock seconds instead of a fixed spp");
+        eprintln!("  --headless       Don't open a window, write the image to --output when done");
+        eprintln!("  --output FILE    Output image (.ppm), defaults to output.ppm when headless");
+        eprintln!("  --report FILE    Write the render statistics as JSON (headless defaults to FILE_report.json next to the output)");
+        eprintln!("  --exposure EV    Exposure in stops, applied before the tone curve");
+        eprintln!("  --white-balance K  Color temperature in Kelvin that should look white");
+        eprintln!("  --tonemap CURVE  clamp (default), reinhard, hable or aces");
+        eprintln!("  --output-space S srgb (default), linear or acescg. The last two also write the scene linear image as FILE_rec709.pfm/FILE_acescg.pfm");
+        eprintln!("  --bloom X        Strength of the bloom of whatever is brighter than --bloom-threshold (default 1)");
+        eprintln!("  --glare X        Strength of the star streaks, one per --glare-blades (default 6) of the aperture");
+        eprintln!("  --vignette X     cos^4 vignetting, X is the tan of the angle at the corners");
+        eprintln!("  --chromatic-aberration X  Red is scaled up and blue down by X around the center");
+        eprintln!("  --clamp X        Scale down samples so no channel is over X");
+        eprintln!("  --clamp-indirect X  Same for light that bounced at least twice, the limit is X/(bounces-1)");
+        eprintln!("  --median-of-means   Show the median of 5 bucket means per pixel instead of the mean, rejects fireflies");
+        eprintln!("  --denoise        Also write the output denoised with the normal/albedo/depth guides as FILE_denoised.ppm");
+        eprintln!("  --aov LIST       Comma separated AOVs to write next to the output as FILE_<aov>.pfm, or 'all':");
+        eprintln!("                   {}",(0..AOV_COUNT).map(|i| Aov::from_index(i).name()).collect::<Vec<String>>().join(","));
+        std::process::exit((!msg.is_empty()) as i32);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::math::vec3::{Vec3,Point3};
+use crate::materials::Material;
+use crate::mesh::Mesh;
+use std::collections::HashMap;
+
+//Bicubic Bézier patch, control points row by row, p[v*4 + u]
+#[derive(Copy,Clone,Debug)]
+pub struct BezierPatch {
+    pub p: [Point3;16],
+}
+
+//Bernstein basis and its derivative
+#[inline]
+fn bernstein(t: f32) -> ([f32;4],[f32;4]){
+    let s = 1. - t;
+    return ([s*s*s,3.*s*s*t,3.*s*t*t,t*t*t],
+            [-3.*s*s,3.*s*s - 6.*s*t,6.*s*t - 3.*t*t,3.*t*t]);
+}
+
+impl BezierPatch {
+    pub fn new(p: [Point3;16]) -> Self{
+        return Self{p: p};
+    }
+    //Returns the point and the partial derivatives along u and v
+    pub fn eval(&self,u: f32,v: f32) -> (Point3,Vec3,Vec3){
+        let (bu,dbu) = bernstein(u);
+        let (bv,dbv) = bernstein(v);
+        let mut p  = Point3::ZERO;
+        let mut du = Vec3::ZERO;
+        let mut dv = Vec3::ZERO;
+        for j in 0..4{
+            for i in 0..4{
+                let c = self.p[j*4 + i];
+                p  += bu[i]*bv[j]*c;
+                du += dbu[i]*bv[j]*c;
+                dv += bu[i]*dbv[j]*c;
+            }
+        }
+        return (p,du,dv);
+    }
+    //Normal at (u,v). Degenerate corners (the teapot's lid and bottom collapse a whole row to a point)
+    //have a zero derivative so it gets sampled a bit inside
+    pub fn normal(&self,u: f32,v: f32) -> Vec3{
+        const NUDGE: f32 = 1e-3;
+        let (_p,du,dv) = self.eval(u,v);
+        let n = du.cross(dv);
+        if n.length_squared() > 1e-12 { return n.unit(); }
+        let (_p,du,dv) = self.eval(u + (0.5 - u)*NUDGE*2.,v + (0.5 - v)*NUDGE*2.);
+        let n = du.cross(dv);
+        return if n.length_squared() > 0. { n.unit() } else { Vec3::new(0.,1.,0.) };
+    }
+    //How far the control net strays from a bilinear patch through its corners, what a uniform grid of
+    //that many segments has to get under the tolerance
+    fn flatness(&self) -> f32{
+        let corners = [self.p[0],self.p[3],self.p[12],self.p[15]];
+        let mut ret: f32 = 0.;
+        for j in 0..4{
+            for i in 0..4{
+                let (u,v) = (i as f32/3.,j as f32/3.);
+                let bilinear = (1.-v)*((1.-u)*corners[0] + u*corners[1]) + v*((1.-u)*corners[2] + u*corners[3]);
+                ret = ret.max((self.p[j*4 + i] - bilinear).length());
+            }
+        }
+        return ret;
+    }
+}
+
+//Newell's teapot format: patch count, then 16 1-based comma separated indexes per line, vertex count,
+//then x,y,z per line
+pub fn load_patches(text: &str) -> Result<Vec<BezierPatch>,String>{
+    let mut lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+    let mut next_line = |what: &str| lines.next().ok_or(format!("Unexpected end of file reading {}",what));
+    let parse_count = |l: &str,what: &str| l.parse::<usize>().map_err(|_| format!("Bad {} count '{}'",what,l));
+    let num_patches = parse_count(next_line("patch count")?,"patch")?;
+    let mut indexes: Vec<[usize;16]> = Vec::with_capacity(num_patches);
+    for _pi in 0..num_patches{
+        let l = next_line("patch")?;
+        let idx: Vec<usize> = l.split(',').map(|s| s.trim().parse::<usize>()).collect::<Result<_,_>>()
+                               .map_err(|_| format!("Bad patch '{}'",l))?;
+        if idx.len() != 16 { return Err(format!("Patch with {} indexes instead of 16 '{}'",idx.len(),l)); }
+        let mut arr = [0;16];
+        arr.copy_from_slice(&idx);
+        indexes.push(arr);
+    }
+    let num_vertices = parse_count(next_line("vertex count")?,"vertex")?;
+    let mut vertices: Vec<Point3> = Vec::with_capacity(num_vertices);
+    for _vi in 0..num_vertices{
+        let l = next_line("vertex")?;
+        let xyz: Vec<f32> = l.split(',').map(|s| s.trim().parse::<f32>()).collect::<Result<_,_>>()
+                             .map_err(|_| format!("Bad vertex '{}'",l))?;
+        if xyz.len() != 3 { return Err(format!("Vertex with {} coordinates '{}'",xyz.len(),l)); }
+        vertices.push(Point3::new(xyz[0],xyz[1],xyz[2]));
+    }
+    let mut ret = Vec::with_capacity(num_patches);
+    for idx in indexes{
+        let mut p = [Point3::ZERO;16];
+        for i in 0..16{
+            if idx[i] == 0 || idx[i] > num_vertices {
+                return Err(format!("Vertex index {} out of range 1..{}",idx[i],num_vertices));
+            }
+            p[i] = vertices[idx[i]-1];
+        }
+        ret.push(BezierPatch::new(p));
+    }
+    return Ok(ret);
+}
+
+//Cubic through 4 control points
+#[inline]
+fn eval_curve(c: &[Point3;4],t: f32) -> Point3{
+    let (b,_db) = bernstein(t);
+    return b[0]*c[0] + b[1]*c[1] + b[2]*c[2] + b[3]*c[3];
+}
+
+//Patch boundaries as seen from either side: the control points in whichever order compares lower
+fn edge_key(c: &[Point3;4]) -> ([u32;12],bool){
+    let bits = |c: &[Point3;4]| {
+        let mut k = [0u32;12];
+        for i in 0..4{
+            for a in 0..3{
+                k[i*3 + a] = c[i][a].to_bits();
+            }
+        }
+        k
+    };
+    let reversed = [c[3],c[2],c[1],c[0]];
+    let (k,r) = (bits(c),bits(&reversed));
+    return if k <= r { (k,false) } else { (r,true) };
+}
+
+//Points along a boundary with segments+1 points. They're always evaluated in the key's order, so both patches
+//sharing the edge get the exact same floats
+fn edge_points(c: &[Point3;4],segments: usize) -> Vec<Point3>{
+    let (_key,reversed) = edge_key(c);
+    let canonical = if reversed { [c[3],c[2],c[1],c[0]] } else { *c };
+    let mut ret: Vec<Point3> = (0..=segments).map(|k| eval_curve(&canonical,k as f32/segments as f32)).collect();
+    if reversed { ret.reverse(); }
+    return ret;
+}
+
+//Each patch gets its own grid, fine enough for its own flatness to be within tolerance of the surface (the chordal
+//error of a cubic is at most 3/4 of the net's deviation over n^2 segments). Shared edges would crack between grids
+//of different rates, so every boundary curve is cut at the finest rate of the patches on it and evaluated the same way
+//from both sides. Each patch then zips the points on its edges to the first ring of its own grid
+pub fn tessellate(patches: &Vec<BezierPatch>,tolerance: f32,material: &Material) -> Mesh{
+    const MAX_SEGMENTS: usize = 64;
+    let rates: Vec<usize> = patches.iter().map(|p| ((0.75*p.flatness()/tolerance.max(1e-6)).sqrt().ceil() as usize).max(1).min(MAX_SEGMENTS)).collect();
+    //Sides walked with the patch on their left (counterclockwise in uv), and where along them a parameter t is
+    let sides = |p: &BezierPatch| [[p.p[0],p.p[1],p.p[2],p.p[3]],[p.p[3],p.p[7],p.p[11],p.p[15]],
+                                   [p.p[15],p.p[14],p.p[13],p.p[12]],[p.p[12],p.p[8],p.p[4],p.p[0]]];
+    let side_uv = |side: usize,t: f32| match side { 0 => (t,0.), 1 => (1.,t), 2 => (1. - t,1.), _ => (0.,1. - t) };
+    let mut edge_rates: HashMap<[u32;12],usize> = HashMap::new();
+    for (patch,rate) in patches.iter().zip(&rates){
+        for c in sides(patch){
+            let r = edge_rates.entry(edge_key(&c).0).or_insert(1);
+            *r = (*r).max(*rate);
+        }
+    }
+    let mut positions = Vec::new();
+    let mut normals   = Vec::new();
+    let mut uvs       = Vec::new();
+    let mut triangles = Vec::new();
+    for (patch,&n) in patches.iter().zip(&rates){
+        let mut vertex = |p: Point3,u: f32,v: f32| {
+            positions.push(p);
+            normals.push(patch.normal(u,v));
+            uvs.push((u,v));
+            return (positions.len() - 1) as u32;
+        };
+        //Grid points off the boundary, a single one in the middle when the grid has none
+        let mut inner: Vec<u32> = Vec::new();
+        if n < 2 { inner.push(vertex(patch.eval(0.5,0.5).0,0.5,0.5)); }
+        for j in 1..n{
+            for i in 1..n{
+                let (u,v) = (i as f32/n as f32,j as f32/n as f32);
+                inner.push(vertex(patch.eval(u,v).0,u,v));
+            }
+        }
+        let at = |i: usize,j: usize| if n < 2 { inner[0] } else { inner[(j - 1)*(n - 1) + i - 1] };
+        for j in 1..n.saturating_sub(1){
+            for i in 1..n.saturating_sub(1){
+                let (a,b,c,d) = (at(i,j),at(i+1,j),at(i,j+1),at(i+1,j+1));
+                triangles.push([a,b,d]);
+                triangles.push([a,d,c]);
+            }
+        }
+        for (side,c) in sides(patch).iter().enumerate(){
+            //The edge's points and the grid ring next to it, both walked the same way and with where they are along it
+            let edge = edge_points(c,if c.iter().all(|p| *p == c[0]) { 1 } else { edge_rates[&edge_key(c).0] });
+            let m = edge.len() - 1;
+            let outer: Vec<(u32,f32)> = edge.iter().enumerate().map(|(k,p)| {
+                let t = k as f32/m as f32;
+                let (u,v) = side_uv(side,t);
+                (vertex(*p,u,v),t)
+            }).collect();
+            let ring: Vec<(u32,f32)> = if n < 2 { vec!((inner[0],0.5)) } else {
+                (1..n).map(|k| {
+                    let idx = match side { 0 => at(k,1), 1 => at(n-1,k), 2 => at(n-k,n-1), _ => at(1,n-k) };
+                    (idx,k as f32/n as f32)
+                }).collect()
+            };
+            //Zipper, always stepping along whichever of the two is behind
+            let (mut k,mut i) = (0,0);
+            while k < m || i + 1 < ring.len(){
+                if k < m && (i + 1 == ring.len() || outer[k+1].1 <= ring[i+1].1) {
+                    triangles.push([outer[k].0,outer[k+1].0,ring[i].0]);
+                    k += 1;
+                }
+                else {
+                    triangles.push([outer[k].0,ring[i+1].0,ring[i].0]);
+                    i += 1;
+                }
+            }
+        }
+    }
+    return Mesh::new(positions,normals,uvs,triangles,material);
+}
@@ -0,0 +1,231 @@
+use crate::math::vec3::*;
+use crate::utils::{luminance,PI};
+
+//Lens effects on the HDR image, before the display transform. Strengths at 0 turn each one off
+#[derive(Copy,Clone,Debug)]
+pub struct PostSettings{
+    pub bloom_strength: f32,
+    //Luminance above which light blooms
+    pub bloom_threshold: f32,
+    //Pyramid levels, each one half the size of the previous
+    pub bloom_levels: u32,
+    pub glare_strength: f32,
+    //Aperture blades, even counts give that many streaks and odd counts twice as many
+    pub glare_blades: u32,
+    //Fraction of the glare kept per pixel of streak
+    pub glare_falloff: f32,
+    //tan of the field of view angle at the corners, for the cos^4 falloff. 0 is no vignetting
+    pub vignette: f32,
+    //Red is scaled up and blue down by this fraction around the center
+    pub chromatic_aberration: f32,
+}
+
+impl PostSettings{
+    pub fn new() -> Self{
+        Self{bloom_strength: 0.,bloom_threshold: 1.,bloom_levels: 6,glare_strength: 0.,glare_blades: 6,glare_falloff: 0.94,
+             vignette: 0.,chromatic_aberration: 0.}
+    }
+    //What the viewer shows when nothing was asked for in the command line
+    pub fn preview() -> Self{
+        let mut ret = Self::new();
+        ret.bloom_strength = 0.05;
+        ret.glare_strength = 0.02;
+        ret.vignette = 0.5;
+        ret.chromatic_aberration = 0.002;
+        return ret;
+    }
+    pub fn enabled(&self) -> bool{
+        return self.bloom_strength > 0. || self.glare_strength > 0. || self.vignette > 0. || self.chromatic_aberration > 0.;
+    }
+}
+
+#[derive(Clone)]
+struct Image{
+    w: i32,
+    h: i32,
+    data: Vec<Color>,
+}
+
+impl Image{
+    fn new(w: i32,h: i32) -> Self{
+        Self{w: w,h: h,data: vec!(Color::ZERO;(w*h) as usize)}
+    }
+    #[inline]
+    fn get(&self,x: i32,y: i32) -> Color{//Black outside
+        if x < 0 || y < 0 || x >= self.w || y >= self.h { return Color::ZERO; }
+        return self.data[(x + y*self.w) as usize];
+    }
+    #[inline]
+    fn get_clamped(&self,x: i32,y: i32) -> Color{
+        return self.data[(x.max(0).min(self.w-1) + y.max(0).min(self.h-1)*self.w) as usize];
+    }
+    #[inline]
+    fn bilinear(&self,x: f32,y: f32) -> Color{//Pixel centers are at integer coordinates
+        let (x0,y0) = (x.floor(),y.floor());
+        let (fx,fy) = (x - x0,y - y0);
+        let (x0,y0) = (x0 as i32,y0 as i32);
+        return (1.-fy)*((1.-fx)*self.get(x0,y0)   + fx*self.get(x0+1,y0))
+              +    fy *((1.-fx)*self.get(x0,y0+1) + fx*self.get(x0+1,y0+1));
+    }
+    //Edges repeat instead of fading to black
+    #[inline]
+    fn bilinear_clamped(&self,x: f32,y: f32) -> Color{
+        return self.bilinear(x.max(0.).min((self.w-1) as f32),y.max(0.).min((self.h-1) as f32));
+    }
+    //2x2 box, edges repeat
+    fn downsample(&self) -> Self{
+        let mut ret = Self::new((self.w/2).max(1),(self.h/2).max(1));
+        for y in 0..ret.h{
+            for x in 0..ret.w{
+                ret.data[(x + y*ret.w) as usize] = 0.25*(self.get_clamped(2*x,2*y) + self.get_clamped(2*x+1,2*y)
+                                                        + self.get_clamped(2*x,2*y+1) + self.get_clamped(2*x+1,2*y+1));
+            }
+        }
+        return ret;
+    }
+    //Separable 5 tap binomial
+    fn blur(&self) -> Self{
+        const K: [f32;5] = [1./16.,4./16.,6./16.,4./16.,1./16.];
+        let mut tmp = Self::new(self.w,self.h);
+        for y in 0..self.h{
+            for x in 0..self.w{
+                let mut c = Color::ZERO;
+                for k in 0..5{
+                    c += K[k]*self.get_clamped(x + k as i32 - 2,y);
+                }
+                tmp.data[(x + y*self.w) as usize] = c;
+            }
+        }
+        let mut ret = Self::new(self.w,self.h);
+        for y in 0..self.h{
+            for x in 0..self.w{
+                let mut c = Color::ZERO;
+                for k in 0..5{
+                    c += K[k]*tmp.get_clamped(x,y + k as i32 - 2);
+                }
+                ret.data[(x + y*self.w) as usize] = c;
+            }
+        }
+        return ret;
+    }
+}
+
+//Only what's above the threshold, scaled so the hue is kept
+fn bright_pass(img: &Image,threshold: f32) -> Image{
+    let mut ret = img.clone();
+    for c in ret.data.iter_mut(){
+        let l = luminance(c);
+        *c = if l > threshold { *c*((l - threshold)/l) } else { Color::ZERO };
+    }
+    return ret;
+}
+
+//Gaussian pyramid of the bright pass, every level blurred and added back at full resolution
+//Wider levels weigh less so it looks like the long tail of a lens' point spread function
+fn bloom(bright: &Image,levels: u32) -> Image{
+    let mut ret = Image::new(bright.w,bright.h);
+    let mut level = bright.blur();
+    let mut weight_sum = 0.;
+    let mut weight = 1.;
+    for l in 0..levels{
+        let scale = (1u32 << l) as f32;
+        for y in 0..ret.h{
+            for x in 0..ret.w{
+                let c = level.bilinear_clamped((x as f32 + 0.5)/scale - 0.5,(y as f32 + 0.5)/scale - 0.5);
+                ret.data[(x + y*ret.w) as usize] += weight*c;
+            }
+        }
+        weight_sum += weight;
+        weight *= 0.7;
+        if level.w == 1 && level.h == 1 { break; }
+        level = level.downsample().blur();
+    }
+    for c in ret.data.iter_mut(){
+        *c /= weight_sum;
+    }
+    return ret;
+}
+
+//Kawase's streak filter, every pass samples 4 taps further apart so 3 passes reach 64 pixels
+//https://www.chrisoat.com/papers/Oat-SteerableStreakFilter.pdf
+fn glare(bright: &Image,blades: u32,falloff: f32) -> Image{
+    const PASSES: u32 = 3;
+    const TAPS: u32 = 4;
+    let streaks = if blades % 2 == 0 { blades } else { 2*blades };
+    let mut ret = Image::new(bright.w,bright.h);
+    for s in 0..streaks{
+        let angle = PI/8. + 2.*PI*(s as f32)/(streaks as f32);//Slightly tilted, perfectly horizontal streaks look fake
+        let (dx,dy) = (angle.cos(),angle.sin());
+        let mut streak = bright.clone();
+        for pass in 0..PASSES{
+            let b = TAPS.pow(pass) as f32;
+            let mut next = Image::new(bright.w,bright.h);
+            let mut weights = [0.;TAPS as usize];
+            let mut total = 0.;
+            for t in 0..TAPS as usize{
+                weights[t] = falloff.powf(b*t as f32);
+                total += weights[t];
+            }
+            for y in 0..bright.h{
+                for x in 0..bright.w{
+                    let mut c = Color::ZERO;
+                    for t in 0..TAPS as usize{
+                        let d = b*t as f32;
+                        c += weights[t]*streak.bilinear(x as f32 + dx*d,y as f32 + dy*d);
+                    }
+                    next.data[(x + y*bright.w) as usize] = c/total;
+                }
+            }
+            streak = next;
+        }
+        for i in 0..ret.data.len(){
+            ret.data[i] += streak.data[i]/(streaks as f32);
+        }
+    }
+    return ret;
+}
+
+pub fn apply(hdr: &Vec<Color>,image_width: u32,image_height: u32,settings: &PostSettings) -> Vec<Color>{
+    let mut img = Image{w: image_width as i32,h: image_height as i32,data: hdr.clone()};
+    if settings.bloom_strength > 0. || settings.glare_strength > 0. {
+        let bright = bright_pass(&img,settings.bloom_threshold);
+        if settings.bloom_strength > 0. {
+            let b = bloom(&bright,settings.bloom_levels);
+            for i in 0..img.data.len(){
+                img.data[i] += settings.bloom_strength*b.data[i];
+            }
+        }
+        if settings.glare_strength > 0. {
+            let g = glare(&bright,settings.glare_blades,settings.glare_falloff);
+            for i in 0..img.data.len(){
+                img.data[i] += settings.glare_strength*g.data[i];
+            }
+        }
+    }
+    let (cx,cy) = ((img.w as f32 - 1.)/2.,(img.h as f32 - 1.)/2.);
+    let corner = (cx*cx + cy*cy).sqrt();
+    if settings.chromatic_aberration > 0. {//Lateral only, red focuses further out than blue
+        let src = img.clone();
+        let ca = settings.chromatic_aberration;
+        for y in 0..img.h{
+            for x in 0..img.w{
+                let (ox,oy) = (x as f32 - cx,y as f32 - cy);
+                let r = src.bilinear_clamped(cx + ox/(1. + ca),cy + oy/(1. + ca)).x();
+                let b = src.bilinear_clamped(cx + ox/(1. - ca),cy + oy/(1. - ca)).z();
+                let i = (x + y*img.w) as usize;
+                img.data[i] = Color::new(r,src.data[i].y(),b);
+            }
+        }
+    }
+    if settings.vignette > 0. {//Natural vignetting, cos^4 of the angle off axis
+        for y in 0..img.h{
+            for x in 0..img.w{
+                let (ox,oy) = (x as f32 - cx,y as f32 - cy);
+                let tan = settings.vignette*(ox*ox + oy*oy).sqrt()/corner;
+                let cos2 = 1./(1. + tan*tan);
+                img.data[(x + y*img.w) as usize] *= cos2*cos2;
+            }
+        }
+    }
+    return img.data;
+}
@@ -4,17 +4,21 @@ use crate::math::mat4x4::{Mat4x4};
 #[derive(Copy,Clone)]
 pub struct Ray{
     pub orig: Point3,
-    pub dir: Vec3
+    pub dir: Vec3,
+    pub time: f32,//When in the shutter interval it was shot, moving objects are hit where they were then
 }
 
 impl Ray{
     pub fn new(o: &Point3,d: &UnitVec3) -> Self{
-        return Ray{ orig: *o, dir: d.unit() };
-    } 
+        return Ray{ orig: *o, dir: d.unit(), time: 0. };
+    }
+    pub fn new_at(o: &Point3,d: &UnitVec3,time: f32) -> Self{
+        return Ray{ orig: *o, dir: d.unit(), time: time };
+    }
     pub fn at(&self,t: f32) -> Point3{
         return self.orig + t*self.dir;
     }
     pub fn transform(&self,m: &Mat4x4) -> Ray{
-        return Ray{orig: m.dot_p3(&self.orig), dir: m.dot_v3(&self.dir)};
+        return Ray{orig: m.dot_p3(&self.orig), dir: m.dot_v3(&self.dir), time: self.time};
     }
 }
\ No newline at end of file
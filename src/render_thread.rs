@@ -4,7 +4,14 @@ use crate::hits::*;
 use crate::camera::*;
 use crate::ray::*;
 use std::sync::atomic::{AtomicU64, Ordering};
-use crate::utils::{lerp,MyRandom,normalize_color,BloomFilter};
+use crate::utils::{lerp,MyRandom,luminance,BloomFilter,INF};
+use crate::display::DisplayTransform;
+use std::time::Instant;
+use crate::report::{RenderCounters,ThreadReport};
+use crate::aov::{PathSample,AovStats};
+
+//Median of means buckets, odd so there's a middle one
+pub const MOM_BUCKETS: usize = 5;
 
 #[derive(Copy,Clone)]
 pub struct Stats{//https://en.wikipedia.org/wiki/Algorithms_for_calculating_variance#Welford's_online_algorithm
@@ -14,26 +21,67 @@ pub struct Stats{//https://en.wikipedia.org/wiki/Algorithms_for_calculating_vari
     pub bad_avgs: u32,
     pub avg_depth: f32,
     pub bloom_filter: BloomFilter,
+    //Welford running mean/M2 of the sample luminance
+    pub mean_lum: f32,
+    pub m2_lum: f32,
+    //Sample i goes to bucket i % MOM_BUCKETS, the pixel shows the bucket mean with the median luminance
+    //A single firefly can only ruin one bucket, at the cost of a bit of bias
+    pub median_of_means: bool,
+    pub bucket_sum: [Color;MOM_BUCKETS],
+    pub bucket_n: [u32;MOM_BUCKETS],
 }
 impl Stats{
-    pub fn new() -> Self {
-        Self{sum:Color::ZERO,n:0,color:(0,0,0),bad_avgs: 0,avg_depth: 0.,bloom_filter: BloomFilter::new()}
+    pub fn new(median_of_means: bool) -> Self {
+        Self{sum:Color::ZERO,n:0,color:(0,0,0),bad_avgs: 0,avg_depth: 0.,bloom_filter: BloomFilter::new(),mean_lum: 0.,m2_lum: 0.,
+             median_of_means: median_of_means,bucket_sum: [Color::ZERO;MOM_BUCKETS],bucket_n: [0;MOM_BUCKETS]}
+    }
+    #[inline]
+    pub fn mean(&self) -> Color{
+        if self.n == 0 { return Color::ZERO; }
+        return self.sum / self.n as f32;
+    }
+    //What the pixel is, the mean or the median of means depending on the mode
+    pub fn estimate(&self) -> Color{
+        if !self.median_of_means || (self.n as usize) < MOM_BUCKETS { return self.mean(); }
+        let mut means = [Color::ZERO;MOM_BUCKETS];
+        for b in 0..MOM_BUCKETS{
+            means[b] = self.bucket_sum[b] / self.bucket_n[b] as f32;
+        }
+        means.sort_by(|a,b| luminance(a).partial_cmp(&luminance(b)).unwrap_or(std::cmp::Ordering::Equal));
+        return means[MOM_BUCKETS/2];
+    }
+    #[inline]
+    pub fn variance(&self) -> f32{
+        if self.n < 2 { return INF; }
+        return self.m2_lum/(self.n as f32 - 1.);
+    }
+    //How uncertain the pixel average still is, what we try to lower when we have time left
+    #[inline]
+    pub fn variance_of_mean(&self) -> f32{
+        return self.variance()/(self.n as f32);
     }
     #[inline]
-    pub fn add(&mut self,x: &Color,depth: f32,obj_id: u64) -> bool{
+    pub fn add(&mut self,x: &Color,depth: f32,obj_id: u64,display: &DisplayTransform) -> bool{
         let old_color = self.color;
+        if self.median_of_means {
+            let b = (self.n as usize) % MOM_BUCKETS;
+            self.bucket_sum[b] += x;
+            self.bucket_n[b]   += 1;
+        }
         self.sum   += x;
         self.n     += 1;
-        self.color = {            
-            let avg    = self.sum / self.n as f32;
-            normalize_color(&avg).to_u8x3()
-        };
+        //Converged means the displayed color stopped changing, so it has to be the same transform the viewer uses
+        self.color = display.to_u8x3(&self.estimate());
         let bad_run = (old_color.0 == self.color.0) 
                    && (old_color.1 == self.color.1) 
                    && (old_color.2 == self.color.2) ;// && self.n > 30;
         self.bad_avgs += bad_run as u32;
         self.bad_avgs *= bad_run as u32;
         self.avg_depth = ((self.n as f32-1.)*self.avg_depth+depth)/self.n as f32;
+        let lum = luminance(x);
+        let delta = lum - self.mean_lum;
+        self.mean_lum += delta/self.n as f32;
+        self.m2_lum   += delta*(lum - self.mean_lum);
         self.bloom_filter.set(obj_id);
         return self.bad_avgs >= 5;
     }
@@ -42,11 +90,12 @@ impl Stats{
 #[derive(Copy,Clone)]
 pub struct Pixel{
     pub c: Color,
-    pub stats: Stats
+    pub stats: Stats,
+    pub aovs: AovStats,
 }
 impl Pixel{
-    pub fn new() -> Self{
-        Self{c: Color::ZERO,stats: Stats::new()}
+    pub fn new(median_of_means: bool) -> Self{
+        Self{c: Color::ZERO,stats: Stats::new(median_of_means),aovs: AovStats::new()}
     }
 }
 
@@ -60,7 +109,7 @@ unsafe impl Send for PixelsBox{}
 impl PixelsBox{
     #[allow(dead_code)]
     pub fn new(image_size: usize) -> Self{
-        Self{pixels: &mut vec!(Pixel::new();image_size as usize)}
+        Self{pixels: &mut vec!(Pixel::new(false);image_size as usize)}
     }
 }
 
@@ -103,52 +152,161 @@ impl ThreadPixels{
 
 
 #[inline]
-fn handle_hit(hit_record: &mut Option<HitRecord>,curr_ray: &mut Ray,curr_color: &mut Color) -> (f32,u64){
-    let depth: f32;
-    let obj_id: u64;
-    match hit_record {
-        Some(hr) => {
-            let rslt = hr.material.scatter(&curr_ray,&hr);
-            *curr_color *= rslt.attenuation;
-            *curr_ray = rslt.ray;
-            depth = hr.t;
-            obj_id = hr.obj_id;
-        },
-        None => {
-            let t: f32 = 0.5*(curr_ray.dir.y() + 1.0);//r.dir.y() + 1.0);
-            let lerped_sky_color = lerp(t,Color::new(1.0,1.0,1.0),Color::new(0.5,0.7,1.0));
-            *curr_color *= lerped_sky_color;
-            depth = f32::INFINITY;
-            obj_id = 0;
+fn sky_color(dir: &Vec3) -> Color{
+    let t: f32 = 0.5*(dir.y() + 1.0);
+    return lerp(t,Color::new(1.0,1.0,1.0),Color::new(0.5,0.7,1.0));
+}
+
+//Scales the color down so no channel is over limit, keeps the hue
+#[inline]
+fn clamp_radiance(c: &Color,limit: f32) -> Option<Color>{
+    let m = c.max_val();
+    if m <= limit { return None; }
+    return Some(*c*(limit/m));
+}
+
+//segment is how many scatters the light went through before getting to the camera
+#[inline]
+fn add_light(sample: &mut PathSample,segment: u32,light_group: u32,light: &Color,path: &PathSettings,counters: &mut RenderCounters){
+    let mut light = *light;
+    if segment <= 1 {
+        sample.direct += light;
+    }
+    else {
+        //Deeper bounces get a tighter limit, they are the rare caustic paths that make fireflies
+        if let Some(clamped) = path.clamp_indirect.and_then(|c| clamp_radiance(&light,c/(segment-1) as f32)) {
+            light = clamped;
+            counters.clamped_indirect += 1;
         }
+        sample.indirect += light;
     }
-    return (depth,obj_id);
+    sample.color += light;
+    sample.light_groups[light_group as usize] += light;
 }
 
-fn ray_color(r: &Ray,world: &FrozenHittableList, depth: u32,tmin: f32,tmax: f32,u: f32,v: f32) -> (Color,f32,u64){
-    let mut curr_color = Color::new(1.,1.,1.);
+//Settings of how each path is traced, the same for every thread
+#[derive(Copy,Clone,Debug)]
+pub struct PathSettings{
+    pub max_depth: u32,
+    //Bounce rays always traced, after these paths survive with a probability proportional to their throughput
+    pub rr_min_depth: u32,
+    pub tmin: f32,
+    pub tmax: f32,
+    //Max channel value of a whole sample
+    pub clamp_sample: Option<f32>,
+    //Max channel value of light that bounced at least twice, divided by (bounces-1)
+    pub clamp_indirect: Option<f32>,
+}
+
+//Max probability of a path surviving Russian roulette, so even white paths eventually end
+const RR_MAX_SURVIVAL: f32 = 0.95;
+
+fn ray_color(r: &Ray,world: &FrozenHittableList,path: &PathSettings,u: f32,v: f32,counters: &mut RenderCounters) -> PathSample{
+    let mut sample = PathSample::new();
+    let mut throughput = Color::new(1.,1.,1.);
     let mut curr_ray: Ray = *r;
-    //Get the depth with the first hit
-    let (depthf,obj_id) = handle_hit(&mut world.first_hit(&curr_ray,tmin,tmax,u,v),&mut curr_ray,&mut curr_color);
-    if depthf.is_infinite(){
-        return (curr_color,f32::INFINITY,0);
-    }
-    for _i in 1..depth{
-        let h = handle_hit(&mut world.hit(&curr_ray,tmin,tmax),&mut curr_ray,&mut curr_color);
-        if h.0.is_infinite(){
-            return (curr_color,depthf,obj_id);//Return the depth & ID of the first hit!!!!
+    for i in 0..path.max_depth{//i is the number of bounces so far, 0 is the primary ray
+        if i > path.rr_min_depth {
+            //Russian roulette: end the path with probability 1-p and boost the survivors by 1/p
+            //On average it's the same contribution, so the estimate stays unbiased
+            let p = throughput.max_val().min(RR_MAX_SURVIVAL);
+            if f32::rand() >= p {
+                counters.add_path(i,false);
+                counters.roulette_terminated += 1;
+                return sample;
+            }
+            throughput /= p;
+        }
+        let hit_record = if i == 0 {
+            counters.primary_rays += 1;
+            world.first_hit(&curr_ray,path.tmin,path.tmax,u,v,counters)
+        } else {
+            counters.bounce_rays += 1;
+            world.hit(&curr_ray,path.tmin,path.tmax,counters)
+        };
+        match hit_record {
+            None => {
+                add_light(&mut sample,i,0,&(throughput*sky_color(&curr_ray.dir)),path,counters);
+                counters.add_path(i+1,false);
+                return sample;
+            },
+            Some(mut hr) => {
+                if hr.material.has_normal_perturbation() {
+                    crate::texture::perturb_normal(&mut hr,&world.textures);
+                }
+                if i == 0 {//The depth, ID, etc of the first hit is what the pixel shows
+                    sample.depth       = hr.t;
+                    sample.obj_id      = hr.obj_id;
+                    sample.normal      = hr.normal;
+                    sample.position    = hr.point;
+                    sample.albedo      = hr.material.aov_albedo();
+                    sample.material_id = hr.material.id;
+                    sample.uv          = hr.uv;
+                }
+                if hr.material.is_light() {
+                    add_light(&mut sample,i,hr.material.light_group,&(throughput*hr.material.emitted),path,counters);
+                    counters.add_path(i+1,false);
+                    return sample;
+                }
+                let rslt = hr.material.scatter(&curr_ray,&hr);
+                throughput *= rslt.attenuation;
+                curr_ray = Ray{time: curr_ray.time,..rslt.ray};//The whole path happens at the same instant
+            }
         }
     }
-    return (-Color::ZERO,depthf,obj_id);//If we run out of depth return -black
+    //Ran out of depth without reaching a light, no light gathered
+    counters.add_path(path.max_depth,true);
+    return sample;
 }
 
-pub fn render(camera: &Camera,world: &FrozenHittableList,max_depth: u32,tmin: f32,tmax: f32,
+#[inline]
+fn render_sample(camera: &Camera,world: &FrozenHittableList,path: &PathSettings,display: &DisplayTransform,
+    image_width: u32,image_height: u32,jitters: &Vec<(f32,f32)>,pixel: &mut Pixel,pxl_idx: usize,counters: &mut RenderCounters) -> bool
+{
+    let line = (pxl_idx as u32) / image_width;
+    let col  = (pxl_idx as u32) - image_width*line;
+    let j_f = line as f32;
+    let i_f = col as f32;
+
+    let jitter = jitters[(pixel.stats.n as usize) % jitters.len()];
+    let i_rand = (f32::rand() + jitter.0)/2.;
+    let j_rand = (f32::rand() + jitter.1)/2.;
+    let u = (i_f+i_rand)/(image_width as f32-1.);
+    let v = 1.0 - (j_f+j_rand)/(image_height as f32-1.);
+    let ray = camera.get_ray(u,v);
+    let mut sample = ray_color(&ray,&world,path,u,v,counters);
+    if let Some(limit) = path.clamp_sample {
+        let m = sample.color.max_val();
+        if m > limit {//Scale everything the same so the AOVs still add up to the color
+            let scale = limit/m;
+            sample.color    *= scale;
+            sample.direct   *= scale;
+            sample.indirect *= scale;
+            for g in 0..sample.light_groups.len(){
+                sample.light_groups[g] *= scale;
+            }
+            counters.clamped_samples += 1;
+        }
+    }
+    pixel.aovs.add(&sample);
+    return pixel.stats.add(&sample.color,sample.depth,sample.obj_id,display);
+}
+
+//Passes where every pixel gets a sample before we trust the variance estimates
+const BUDGET_WARMUP_PASSES: u32 = 4;
+//Every so often sample everything anyway, so pixels with an unlucky low variance don't starve
+const BUDGET_FULL_PASS_EVERY: u32 = 8;
+//How many pixels we render between checks of the clock
+const BUDGET_CLOCK_CHECK: usize = 256;
+
+pub fn render(camera: &Camera,world: &FrozenHittableList,path: &PathSettings,display: &DisplayTransform,
     samples_per_pixel: u32,image_width: u32,image_height: u32,
-    pixels_box: PixelsBox,tid: u32,assigned_thread: &Vec<u32>,samples_atom: &AtomicU64)
+    pixels_box: PixelsBox,tid: u32,assigned_thread: &Vec<u32>,samples_atom: &AtomicU64,
+    deadline: Option<Instant>) -> ThreadReport
 {
+    let start = Instant::now();
+    let mut counters = RenderCounters::new();
     //println!("len is {}",unsafe{&*pixels_box.pixels}.len());
-    let image_width_f  = image_width as f32;
-    let image_height_f = image_height as f32;
     let image_size = (image_width*image_height) as usize;
 
     let mut thread_pixels = ThreadPixels::new(image_size);
@@ -163,7 +321,7 @@ pub fn render(camera: &Camera,world: &FrozenHittableList,max_depth: u32,tmin: f3
     //https://en.wikipedia.org/wiki/Low-discrepancy_sequence#Construction_of_low-discrepancy_sequences
     let jitters: Vec<(f32,f32)> = {
         let mut ret: Vec<(f32,f32)> = Vec::with_capacity(samples_per_pixel as usize);
-        for s in 0..samples_per_pixel{
+        for s in 0..samples_per_pixel.max(4){
             let sdiv = s / 2;
             let jitteri = (sdiv&1) as f32;//mod 2
             let jitterj = (s&1) as f32;//mod 2
@@ -173,6 +331,12 @@ pub fn render(camera: &Camera,world: &FrozenHittableList,max_depth: u32,tmin: f3
         ret
     };
 
+    if let Some(deadline) = deadline {
+        render_budget(camera,world,path,display,image_width,image_height,
+            pixels_box,&thread_pixels,&jitters,samples_atom,deadline,&mut counters);
+        return ThreadReport{tid: tid,seconds: start.elapsed().as_secs_f64(),counters: counters};
+    }
+
     for _sample in 0..samples_per_pixel{
         //idx is a double indirection... indexes[pos_idx] is the pixel index in the main memory buffer
         for idx in 0..thread_pixels.len{
@@ -180,18 +344,7 @@ pub fn render(camera: &Camera,world: &FrozenHittableList,max_depth: u32,tmin: f3
             let pixel: &mut Pixel = &mut unsafe{&mut *pixels_box.pixels}[pxl_idx];
             //Should never happen since we upkeep undone pixels with a backbuffer
             //assert!(curr_samples < samples_per_pixel);
-            let line = (pxl_idx as u32) / image_width;
-            let col  = (pxl_idx as u32) - image_width*line;
-            let j_f = line as f32;
-            let i_f = col as f32;
-
-            let i_rand = (f32::rand() + jitters[pixel.stats.n as usize].0)/2.;
-            let j_rand = (f32::rand() + jitters[pixel.stats.n as usize].1)/2.;
-            let u = (i_f+i_rand)/(image_width_f-1.);
-            let v = 1.0 - (j_f+j_rand)/(image_height_f-1.);
-            let ray = camera.get_ray(u,v);
-            let (pixel_color,depth,obj_id) = ray_color(&ray,&world,max_depth,tmin,tmax,u,v);
-            let done = pixel.stats.add(&pixel_color,depth,obj_id);
+            let done = render_sample(camera,world,path,display,image_width,image_height,&jitters,pixel,pxl_idx,&mut counters);
             thread_pixels.add_run(idx,done);
             let log_samples = (done as u32)*(samples_per_pixel-pixel.stats.n) + 1;//+1 cause is done post increment
             //Inform left over samples or 1
@@ -199,4 +352,52 @@ pub fn render(camera: &Camera,world: &FrozenHittableList,max_depth: u32,tmin: f3
         }
         thread_pixels.swap_buffers();
     }
-}
\ No newline at end of file
+    return ThreadReport{tid: tid,seconds: start.elapsed().as_secs_f64(),counters: counters};
+}
+
+//Keep sampling until the deadline. After the warmup, each pass only samples the pixels whose
+//variance of the mean is above the average of the thread, so the remaining time goes to the noisiest ones
+fn render_budget(camera: &Camera,world: &FrozenHittableList,path: &PathSettings,display: &DisplayTransform,
+    image_width: u32,image_height: u32,pixels_box: PixelsBox,thread_pixels: &ThreadPixels,
+    jitters: &Vec<(f32,f32)>,samples_atom: &AtomicU64,deadline: Instant,counters: &mut RenderCounters)
+{
+    let pixels = unsafe{&mut *pixels_box.pixels};
+    let mut pass: u32 = 0;
+    'passes: loop {
+        let full_pass = pass < BUDGET_WARMUP_PASSES || pass % BUDGET_FULL_PASS_EVERY == 0;
+        let threshold = if full_pass { 0. } else {
+            let mut total = 0.;
+            for idx in 0..thread_pixels.len{
+                total += pixels[thread_pixels.list[idx]].stats.variance_of_mean();
+            }
+            total/(thread_pixels.len.max(1) as f32)
+        };
+        for idx in 0..thread_pixels.len{
+            if idx % BUDGET_CLOCK_CHECK == 0 && Instant::now() >= deadline {
+                break 'passes;
+            }
+            let pxl_idx = thread_pixels.list[idx];
+            let pixel: &mut Pixel = &mut pixels[pxl_idx];
+            if pixel.stats.variance_of_mean() < threshold {
+                continue;
+            }
+            //Convergence is ignored, the variance already decides who gets sampled
+            render_sample(camera,world,path,display,image_width,image_height,jitters,pixel,pxl_idx,counters);
+            samples_atom.fetch_add(1,Ordering::Relaxed);
+        }
+        pass += 1;
+    }
+}
+
+//Min, average and max samples per pixel
+pub fn spp_summary(pixels: &Vec<Pixel>) -> (u32,f64,u32){
+    let mut min = u32::MAX;
+    let mut max = 0;
+    let mut total: u64 = 0;
+    for p in pixels{
+        min = min.min(p.stats.n);
+        max = max.max(p.stats.n);
+        total += p.stats.n as u64;
+    }
+    return (min,(total as f64)/(pixels.len().max(1) as f64),max);
+}
@@ -0,0 +1,165 @@
+use std::fs::File;
+use std::io::Write;
+
+//Bucket i counts marches of [2^(i-1),2^i) steps, bucket 0 is marches that ended before stepping
+pub const MARCH_HISTOGRAM_BUCKETS: usize = 12;
+
+//Per thread counters, merged at the end of the render. Kept as plain integers so counting is just an add
+#[derive(Copy,Clone,Debug)]
+pub struct RenderCounters{
+    pub primary_rays: u64,
+    pub bounce_rays: u64,
+    pub shadow_rays: u64,//No light sampling yet so it stays at 0, kept so the report doesn't change shape
+    pub paths: u64,
+    pub path_segments: u64,
+    pub max_depth_paths: u64,
+    pub roulette_terminated: u64,
+    pub clamped_samples: u64,
+    pub clamped_indirect: u64,
+    pub marches: u64,
+    pub march_steps: u64,
+    pub march_exhausted: u64,
+    pub march_histogram: [u64;MARCH_HISTOGRAM_BUCKETS],
+}
+
+impl RenderCounters{
+    pub fn new() -> Self{
+        Self{primary_rays: 0,bounce_rays: 0,shadow_rays: 0,paths: 0,path_segments: 0,max_depth_paths: 0,roulette_terminated: 0,clamped_samples: 0,clamped_indirect: 0,
+             marches: 0,march_steps: 0,march_exhausted: 0,march_histogram: [0;MARCH_HISTOGRAM_BUCKETS]}
+    }
+    #[inline]
+    pub fn add_march(&mut self,steps: u32,exhausted: bool){
+        let bucket = ((32 - steps.leading_zeros()) as usize).min(MARCH_HISTOGRAM_BUCKETS-1);
+        self.marches += 1;
+        self.march_steps += steps as u64;
+        self.march_exhausted += exhausted as u64;
+        self.march_histogram[bucket] += 1;
+    }
+    #[inline]
+    pub fn add_path(&mut self,segments: u32,hit_max_depth: bool){
+        self.paths += 1;
+        self.path_segments += segments as u64;
+        self.max_depth_paths += hit_max_depth as u64;
+    }
+    pub fn rays(&self) -> u64{
+        return self.primary_rays + self.bounce_rays + self.shadow_rays;
+    }
+    pub fn merge(&mut self,other: &Self){
+        self.primary_rays    += other.primary_rays;
+        self.bounce_rays     += other.bounce_rays;
+        self.shadow_rays     += other.shadow_rays;
+        self.paths           += other.paths;
+        self.path_segments   += other.path_segments;
+        self.max_depth_paths += other.max_depth_paths;
+        self.roulette_terminated += other.roulette_terminated;
+        self.clamped_samples += other.clamped_samples;
+        self.clamped_indirect += other.clamped_indirect;
+        self.marches         += other.marches;
+        self.march_steps     += other.march_steps;
+        self.march_exhausted += other.march_exhausted;
+        for i in 0..MARCH_HISTOGRAM_BUCKETS{
+            self.march_histogram[i] += other.march_histogram[i];
+        }
+    }
+}
+
+#[derive(Copy,Clone,Debug)]
+pub struct ThreadReport{
+    pub tid: u32,
+    pub seconds: f64,
+    pub counters: RenderCounters,
+}
+
+pub struct RenderReport{
+    pub image_width: u32,
+    pub image_height: u32,
+    pub spp: (u32,f64,u32),//min,avg,max
+    pub phases: Vec<(&'static str,f64)>,//Name and seconds, in order
+    pub threads: Vec<ThreadReport>,
+    pub clamp_sample: Option<f32>,
+    pub clamp_indirect: Option<f32>,
+    pub median_of_means: bool,
+    //Fraction of the image luminance median of means threw away compared to the plain mean
+    pub mom_rejected: f64,
+}
+
+fn div_or_zero(a: f64,b: f64) -> f64{
+    return if b > 0. { a/b } else { 0. };
+}
+
+//JSON has no inf or NaN
+fn number_json(v: f64) -> String{
+    return if v.is_finite() { v.to_string() } else { "null".to_string() };
+}
+
+fn option_json(v: Option<f32>) -> String{
+    return v.map_or("null".to_string(),|v| number_json(v as f64));
+}
+
+fn march_bucket_label(i: usize) -> String{
+    if i <= 1 { return i.to_string(); }
+    if i == MARCH_HISTOGRAM_BUCKETS-1 { return format!("{}+",1u32 << (i-1)); }
+    return format!("{}-{}",1u32 << (i-1),(1u32 << i)-1);
+}
+
+impl RenderReport{
+    pub fn totals(&self) -> RenderCounters{
+        let mut ret = RenderCounters::new();
+        for t in &self.threads{
+            ret.merge(&t.counters);
+        }
+        return ret;
+    }
+    pub fn print_summary(&self){
+        let c = self.totals();
+        eprintln!("---- Render report ----");
+        for (name,secs) in &self.phases{
+            eprintln!("{:>8}: {:.3}s",name,secs);
+        }
+        eprintln!("Rays: {} primary, {} bounce, {} shadow",c.primary_rays,c.bounce_rays,c.shadow_rays);
+        for t in &self.threads{
+            eprintln!("  thread {:>3}: {:.0} rays/s",t.tid,div_or_zero(t.counters.rays() as f64,t.seconds));
+        }
+        eprintln!("Average path length: {:.3} ({} of {} paths hit max depth, {} ended by russian roulette)",
+            div_or_zero(c.path_segments as f64,c.paths as f64),c.max_depth_paths,c.paths,c.roulette_terminated);
+        eprintln!("Clamped: {} samples (limit {}), {} indirect contributions (limit {})",
+            c.clamped_samples,option_json(self.clamp_sample),c.clamped_indirect,option_json(self.clamp_indirect));
+        if self.median_of_means {
+            eprintln!("Median of means rejected {:.3}% of the luminance",100.*self.mom_rejected);
+        }
+        eprintln!("Marches: {}, average {:.2} steps, {} ran out of iterations",
+            c.marches,div_or_zero(c.march_steps as f64,c.marches as f64),c.march_exhausted);
+        for i in 0..MARCH_HISTOGRAM_BUCKETS{
+            if c.march_histogram[i] == 0 { continue; }
+            eprintln!("  {:>9} steps: {}",march_bucket_label(i),c.march_histogram[i]);
+        }
+    }
+    pub fn to_json(&self) -> String{
+        let c = self.totals();
+        let mut s = String::new();
+        s += "{\n";
+        s += &format!("  \"image\": {{\"width\": {}, \"height\": {}}},\n",self.image_width,self.image_height);
+        s += &format!("  \"spp\": {{\"min\": {}, \"avg\": {}, \"max\": {}}},\n",self.spp.0,number_json(self.spp.1),self.spp.2);
+        let phases: Vec<String> = self.phases.iter().map(|(name,secs)| format!("\"{}\": {}",name,number_json(*secs))).collect();
+        s += &format!("  \"phases_seconds\": {{{}}},\n",phases.join(", "));
+        s += &format!("  \"rays\": {{\"primary\": {}, \"bounce\": {}, \"shadow\": {}}},\n",c.primary_rays,c.bounce_rays,c.shadow_rays);
+        let threads: Vec<String> = self.threads.iter().map(|t| format!(
+            "    {{\"id\": {}, \"seconds\": {}, \"rays\": {}, \"rays_per_second\": {}}}",
+            t.tid,number_json(t.seconds),t.counters.rays(),number_json(div_or_zero(t.counters.rays() as f64,t.seconds)))).collect();
+        s += &format!("  \"threads\": [\n{}\n  ],\n",threads.join(",\n"));
+        s += &format!("  \"paths\": {{\"count\": {}, \"average_length\": {}, \"max_depth_reached\": {}, \"roulette_terminated\": {}}},\n",
+            c.paths,number_json(div_or_zero(c.path_segments as f64,c.paths as f64)),c.max_depth_paths,c.roulette_terminated);
+        s += &format!("  \"fireflies\": {{\"clamp_sample\": {}, \"clamped_samples\": {}, \"clamp_indirect\": {}, \"clamped_indirect\": {}, \"median_of_means\": {}, \"mom_rejected\": {}}},\n",
+            option_json(self.clamp_sample),c.clamped_samples,option_json(self.clamp_indirect),c.clamped_indirect,self.median_of_means,number_json(self.mom_rejected));
+        let buckets: Vec<String> = (0..MARCH_HISTOGRAM_BUCKETS).map(|i| format!(
+            "{{\"steps\": \"{}\", \"count\": {}}}",march_bucket_label(i),c.march_histogram[i])).collect();
+        s += &format!("  \"march\": {{\"count\": {}, \"steps\": {}, \"max_iter_exhausted\": {}, \"histogram\": [{}]}}\n",
+            c.marches,c.march_steps,c.march_exhausted,buckets.join(", "));
+        s += "}\n";
+        return s;
+    }
+    pub fn write_json(&self,path: &str) -> std::io::Result<()>{
+        let mut f = File::create(path)?;
+        return f.write_all(self.to_json().as_bytes());
+    }
+}
@@ -0,0 +1,730 @@
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use crate::math::vec3::{Point3,Color};
+use crate::math::vec4::Vec4;
+use crate::materials::{Material,MAX_LIGHT_GROUPS};
+use crate::bounding_box::*;
+use crate::marched::Marched;
+use crate::utils::{INF,clamp};
+
+//Longest tape an expression can compile to, the registers live on the stack while evaluating
+const MAX_OPS: usize = 256;
+//Bounds are searched for inside [-BOUNDS_SEARCH;BOUNDS_SEARCH]^3, anything reaching its sides is drawn always
+const BOUNDS_SEARCH: f32 = 1024.;
+const BOUNDS_LEVELS: u32 = 16;
+const MAX_BOUNDS_BOXES: usize = 4096;
+
+//Scalar instructions, each one writes the register with its own index and reads earlier ones. Vectors are 3 registers
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[allow(non_camel_case_types)]
+enum Op {
+    CONST(f32),
+    X,Y,Z,
+    ADD(u32,u32),
+    SUB(u32,u32),
+    MUL(u32,u32),
+    DIV(u32,u32),
+    NEG(u32),
+    ABS(u32),
+    SQR(u32),//x*x, its interval can't go below 0
+    SQRT(u32),
+    SIN(u32),
+    COS(u32),
+    FLOOR(u32),
+    MIN(u32,u32),
+    MAX(u32,u32),
+    SMIN(u32,u32,u32),//a,b,k
+    NOISE(u32,u32,u32),
+}
+
+impl Op {
+    //For deduplicating, floats by their bits
+    fn key(&self) -> (u8,[u32;3]){
+        return match *self {
+            Op::CONST(v) => (0,[v.to_bits(),0,0]),
+            Op::X => (1,[0;3]),
+            Op::Y => (2,[0;3]),
+            Op::Z => (3,[0;3]),
+            Op::ADD(a,b) => (4,[a,b,0]),
+            Op::SUB(a,b) => (5,[a,b,0]),
+            Op::MUL(a,b) => (6,[a,b,0]),
+            Op::DIV(a,b) => (7,[a,b,0]),
+            Op::NEG(a) => (8,[a,0,0]),
+            Op::ABS(a) => (9,[a,0,0]),
+            Op::SQR(a) => (10,[a,0,0]),
+            Op::SQRT(a) => (11,[a,0,0]),
+            Op::SIN(a) => (12,[a,0,0]),
+            Op::COS(a) => (13,[a,0,0]),
+            Op::FLOOR(a) => (14,[a,0,0]),
+            Op::MIN(a,b) => (15,[a,b,0]),
+            Op::MAX(a,b) => (16,[a,b,0]),
+            Op::SMIN(a,b,k) => (17,[a,b,k]),
+            Op::NOISE(a,b,c) => (18,[a,b,c]),
+        };
+    }
+    //Same instruction reading other registers
+    fn map_args(&self,m: &dyn Fn(u32) -> u32) -> Self{
+        return match *self {
+            Op::ADD(a,b) => Op::ADD(m(a),m(b)),
+            Op::SUB(a,b) => Op::SUB(m(a),m(b)),
+            Op::MUL(a,b) => Op::MUL(m(a),m(b)),
+            Op::DIV(a,b) => Op::DIV(m(a),m(b)),
+            Op::NEG(a) => Op::NEG(m(a)),
+            Op::ABS(a) => Op::ABS(m(a)),
+            Op::SQR(a) => Op::SQR(m(a)),
+            Op::SQRT(a) => Op::SQRT(m(a)),
+            Op::SIN(a) => Op::SIN(m(a)),
+            Op::COS(a) => Op::COS(m(a)),
+            Op::FLOOR(a) => Op::FLOOR(m(a)),
+            Op::MIN(a,b) => Op::MIN(m(a),m(b)),
+            Op::MAX(a,b) => Op::MAX(m(a),m(b)),
+            Op::SMIN(a,b,k) => Op::SMIN(m(a),m(b),m(k)),
+            Op::NOISE(a,b,c) => Op::NOISE(m(a),m(b),m(c)),
+            op => op,
+        };
+    }
+    fn args(&self) -> Vec<u32>{
+        return match *self {
+            Op::CONST(_) | Op::X | Op::Y | Op::Z => vec!(),
+            Op::NEG(a) | Op::ABS(a) | Op::SQR(a) | Op::SQRT(a) | Op::SIN(a) | Op::COS(a) | Op::FLOOR(a) => vec!(a),
+            Op::ADD(a,b) | Op::SUB(a,b) | Op::MUL(a,b) | Op::DIV(a,b) | Op::MIN(a,b) | Op::MAX(a,b) => vec!(a,b),
+            Op::SMIN(a,b,c) | Op::NOISE(a,b,c) => vec!(a,b,c),
+        };
+    }
+}
+
+//Polynomial smooth min, same as the CSG nodes
+#[inline]
+fn smin(a: f32,b: f32,k: f32) -> f32{
+    if k <= 0. { return a.min(b); }
+    let h = clamp(0.5 + 0.5*(b - a)/k,0.,1.);
+    return b*(1. - h) + a*h - k*h*(1. - h);
+}
+
+#[inline]
+fn hash3(x: i32,y: i32,z: i32) -> f32{
+    let mut h = (x as u32).wrapping_mul(0x8da6b343) ^ (y as u32).wrapping_mul(0xd8163841) ^ (z as u32).wrapping_mul(0xcb1ab31f);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1e995);
+    h ^= h >> 15;
+    return (h as f32/u32::MAX as f32)*2. - 1.;
+}
+
+//Value noise in [-1;1], smoothstepped between random values on the integer lattice
+fn noise(x: f32,y: f32,z: f32) -> f32{
+    let (fx,fy,fz) = (x.floor(),y.floor(),z.floor());
+    let (ix,iy,iz) = (fx as i32,fy as i32,fz as i32);
+    let fade = |t: f32| t*t*(3. - 2.*t);
+    let (u,v,w) = (fade(x - fx),fade(y - fy),fade(z - fz));
+    let lerp = |a: f32,b: f32,t: f32| a + (b - a)*t;
+    let plane = |dz: i32| lerp(lerp(hash3(ix,iy,iz+dz),hash3(ix+1,iy,iz+dz),u),lerp(hash3(ix,iy+1,iz+dz),hash3(ix+1,iy+1,iz+dz),u),v);
+    return lerp(plane(0),plane(1),w);
+}
+
+//Every value the expression can take over a box, for the bounds
+#[derive(Copy, Clone, Debug)]
+pub struct Interval {
+    pub lo: f32,
+    pub hi: f32,
+}
+
+impl Interval {
+    const ANY: Self = Self{lo: -INF,hi: INF};
+    pub fn new(lo: f32,hi: f32) -> Self{
+        if lo.is_nan() || hi.is_nan() { return Self::ANY; }
+        return Self{lo: lo,hi: hi};
+    }
+    fn mul(&self,o: &Self) -> Self{
+        let p = [self.lo*o.lo,self.lo*o.hi,self.hi*o.lo,self.hi*o.hi];
+        if p.iter().any(|v| v.is_nan()) { return Self::ANY; }//0*INF
+        return Self::new(p[0].min(p[1]).min(p[2]).min(p[3]),p[0].max(p[1]).max(p[2]).max(p[3]));
+    }
+    fn sqr(&self) -> Self{
+        let (a,b) = (self.lo*self.lo,self.hi*self.hi);
+        if self.lo <= 0. && self.hi >= 0. { return Self::new(0.,a.max(b)); }
+        return Self::new(a.min(b),a.max(b));
+    }
+    fn sin(&self) -> Self{
+        if !(self.hi - self.lo < 2.*PI) { return Self::new(-1.,1.); }
+        let (a,b) = (self.lo.sin(),self.hi.sin());
+        let mut ret = Self::new(a.min(b),a.max(b));
+        //Peaks and troughs inside
+        if PI/2. + 2.*PI*((self.lo - PI/2.)/(2.*PI)).ceil() <= self.hi { ret.hi = 1.; }
+        if -PI/2. + 2.*PI*((self.lo + PI/2.)/(2.*PI)).ceil() <= self.hi { ret.lo = -1.; }
+        return ret;
+    }
+}
+
+//An expression of the point p compiled into a tape of scalar instructions. Repeated subexpressions are computed once
+//and constant ones are folded while compiling
+pub struct SdfExpr {
+    ops: Vec<Op>,
+}
+
+impl SdfExpr {
+    pub fn eval(&self,p: &Point3) -> f32{
+        let mut r = [0f32;MAX_OPS];
+        for (i,op) in self.ops.iter().enumerate(){
+            r[i] = match *op {
+                Op::CONST(v) => v,
+                Op::X => p.x(),
+                Op::Y => p.y(),
+                Op::Z => p.z(),
+                Op::ADD(a,b) => r[a as usize] + r[b as usize],
+                Op::SUB(a,b) => r[a as usize] - r[b as usize],
+                Op::MUL(a,b) => r[a as usize]*r[b as usize],
+                Op::DIV(a,b) => r[a as usize]/r[b as usize],
+                Op::NEG(a) => -r[a as usize],
+                Op::ABS(a) => r[a as usize].abs(),
+                Op::SQR(a) => r[a as usize]*r[a as usize],
+                Op::SQRT(a) => r[a as usize].max(0.).sqrt(),
+                Op::SIN(a) => r[a as usize].sin(),
+                Op::COS(a) => r[a as usize].cos(),
+                Op::FLOOR(a) => r[a as usize].floor(),
+                Op::MIN(a,b) => r[a as usize].min(r[b as usize]),
+                Op::MAX(a,b) => r[a as usize].max(r[b as usize]),
+                Op::SMIN(a,b,k) => smin(r[a as usize],r[b as usize],r[k as usize]),
+                Op::NOISE(a,b,c) => noise(r[a as usize],r[b as usize],r[c as usize]),
+            };
+        }
+        return r[self.ops.len() - 1];
+    }
+    pub fn eval_interval(&self,x: Interval,y: Interval,z: Interval) -> Interval{
+        let mut r = [Interval::ANY;MAX_OPS];
+        for (i,op) in self.ops.iter().enumerate(){
+            let at = |j: u32| r[j as usize];
+            r[i] = match *op {
+                Op::CONST(v) => Interval::new(v,v),
+                Op::X => x,
+                Op::Y => y,
+                Op::Z => z,
+                Op::ADD(a,b) => Interval::new(at(a).lo + at(b).lo,at(a).hi + at(b).hi),
+                Op::SUB(a,b) => Interval::new(at(a).lo - at(b).hi,at(a).hi - at(b).lo),
+                Op::MUL(a,b) => at(a).mul(&at(b)),
+                Op::DIV(a,b) => {
+                    let d = at(b);
+                    if d.lo <= 0. && d.hi >= 0. { Interval::ANY } else { at(a).mul(&Interval::new(1./d.hi,1./d.lo)) }
+                },
+                Op::NEG(a) => Interval::new(-at(a).hi,-at(a).lo),
+                Op::ABS(a) => {
+                    let v = at(a);
+                    if v.lo >= 0. { v } else if v.hi <= 0. { Interval::new(-v.hi,-v.lo) } else { Interval::new(0.,(-v.lo).max(v.hi)) }
+                },
+                Op::SQR(a) => at(a).sqr(),
+                Op::SQRT(a) => Interval::new(at(a).lo.max(0.).sqrt(),at(a).hi.max(0.).sqrt()),
+                Op::SIN(a) => at(a).sin(),
+                Op::COS(a) => Interval::new(at(a).lo + PI/2.,at(a).hi + PI/2.).sin(),
+                Op::FLOOR(a) => Interval::new(at(a).lo.floor(),at(a).hi.floor()),
+                Op::MIN(a,b) => Interval::new(at(a).lo.min(at(b).lo),at(a).hi.min(at(b).hi)),
+                Op::MAX(a,b) => Interval::new(at(a).lo.max(at(b).lo),at(a).hi.max(at(b).hi)),
+                //Grows in a and b, shrinks as k grows
+                Op::SMIN(a,b,k) => Interval::new(smin(at(a).lo,at(b).lo,at(k).hi),smin(at(a).hi,at(b).hi,at(k).lo)),
+                Op::NOISE(..) => Interval::new(-1.,1.),
+            };
+        }
+        return r[self.ops.len() - 1];
+    }
+    //Where the expression can be <= 0. Boxes that can't are dropped level after level, until there's too many of them
+    pub fn bounds(&self) -> BoundingBox3D{
+        let mut boxes = vec!(BoundingBox3D::new(&Point3::new(-BOUNDS_SEARCH,-BOUNDS_SEARCH,-BOUNDS_SEARCH),&Point3::new(BOUNDS_SEARCH,BOUNDS_SEARCH,BOUNDS_SEARCH)));
+        let inside = |b: &BoundingBox3D| self.eval_interval(Interval::new(b.minp.x(),b.maxp.x()),Interval::new(b.minp.y(),b.maxp.y()),
+                                                            Interval::new(b.minp.z(),b.maxp.z())).lo <= 0.;
+        boxes.retain(|b| inside(b));
+        for _l in 0..BOUNDS_LEVELS{
+            let mut next = Vec::with_capacity(8*boxes.len());
+            for b in &boxes{
+                let mid = (b.minp + b.maxp)*0.5;
+                for i in 0..8{
+                    let pick = |bit: usize,a: usize| if (i >> bit) & 1 == 0 { (b.minp[a],mid[a]) } else { (mid[a],b.maxp[a]) };
+                    let (x,y,z) = (pick(0,0),pick(1,1),pick(2,2));
+                    let child = BoundingBox3D::new(&Point3::new(x.0,y.0,z.0),&Point3::new(x.1,y.1,z.1));
+                    if inside(&child) { next.push(child); }
+                }
+            }
+            if next.len() > MAX_BOUNDS_BOXES { break; }
+            boxes = next;
+        }
+        if boxes.is_empty() { return BoundingBox3D::new(&Point3::ZERO,&Point3::ZERO); }//Nothing
+        let bb = boxes.iter().skip(1).fold(boxes[0],|acc,b| acc.union(b));
+        if bb.minp.min_val() <= -BOUNDS_SEARCH || bb.maxp.max_val() >= BOUNDS_SEARCH { return BoundingBox3D::draw_always(); }
+        return bb;
+    }
+    //Statements are 'let NAME = EXPR;', the last one is the distance. Everything is scalars and 3d vectors:
+    //+ - * / work componentwise (scalars go to every component), v.x v.y v.z pick one, pi, p is the point.
+    //Functions: vec3(s) vec3(x,y,z) length dot abs sqrt sin cos floor min max clamp mix mod smin(a,b,k) smax(a,b,k) noise(v)
+    //and the primitives sphere(p,r) box(p,half_sizes) torus(p,major,minor) cylinder(p,r,half_height) plane(p,unit_normal,offset)
+    pub fn compile(src: &str) -> Result<Self,String>{
+        let mut c = Compiler{tokens: tokenize(src)?,pos: 0,tape: Tape::new(),vars: Vec::new()};
+        let px = c.tape.push(Op::X)?;
+        let py = c.tape.push(Op::Y)?;
+        let pz = c.tape.push(Op::Z)?;
+        c.vars.push(("p".to_string(),Value::VEC3([px,py,pz])));
+        let pi = c.tape.constant(PI)?;
+        c.vars.push(("pi".to_string(),Value::SCALAR(pi)));
+        while c.peek() == Some(&Token::IDENT("let".to_string())) {
+            c.pos += 1;
+            let name = match c.next() {
+                Some(Token::IDENT(n)) => n,
+                t => return Err(format!("Expected a name after let, got {}",describe(&t))),
+            };
+            c.expect('=')?;
+            let v = c.expr()?;
+            c.expect(';')?;
+            c.vars.push((name,v));
+        }
+        let result = match c.expr()? {
+            Value::SCALAR(r) => r,
+            Value::VEC3(_) => return Err("The distance has to be a scalar, not a vector".to_string()),
+        };
+        if c.peek() == Some(&Token::SYM(';')) { c.pos += 1; }
+        if c.pos < c.tokens.len() { return Err(format!("Unexpected {} after the distance",describe(&c.peek().cloned()))); }
+        return Ok(Self{ops: c.tape.finish(result)});
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[allow(non_camel_case_types)]
+enum Token {
+    NUM(f32),
+    IDENT(String),
+    SYM(char),
+}
+
+fn describe(t: &Option<Token>) -> String{
+    return match t {
+        Some(Token::NUM(v)) => format!("'{}'",v),
+        Some(Token::IDENT(s)) => format!("'{}'",s),
+        Some(Token::SYM(c)) => format!("'{}'",c),
+        None => "the end".to_string(),
+    };
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>,String>{
+    let chars: Vec<char> = src.chars().collect();
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < chars.len(){
+        let c = chars[i];
+        if c.is_whitespace() { i += 1; continue; }
+        if c.is_ascii_digit() || (c == '.' && chars.get(i+1).map_or(false,|d| d.is_ascii_digit())) {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') { i += 1; }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                i += 1;
+                if i < chars.len() && (chars[i] == '-' || chars[i] == '+') { i += 1; }
+                while i < chars.len() && chars[i].is_ascii_digit() { i += 1; }
+            }
+            let s: String = chars[start..i].iter().collect();
+            ret.push(Token::NUM(s.parse::<f32>().map_err(|_| format!("Bad number '{}'",s))?));
+        }
+        else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') { i += 1; }
+            ret.push(Token::IDENT(chars[start..i].iter().collect()));
+        }
+        else if "+-*/(),.;=".contains(c) {
+            ret.push(Token::SYM(c));
+            i += 1;
+        }
+        else {
+            return Err(format!("Unexpected character '{}'",c));
+        }
+    }
+    return Ok(ret);
+}
+
+//Instructions being compiled, deduplicated and folded as they come
+struct Tape {
+    ops: Vec<Op>,
+    seen: HashMap<(u8,[u32;3]),u32>,
+}
+
+impl Tape {
+    fn new() -> Self{
+        return Self{ops: Vec::new(),seen: HashMap::new()};
+    }
+    fn push(&mut self,op: Op) -> Result<u32,String>{
+        let args = op.args();
+        let op = if !args.is_empty() && args.iter().all(|a| matches!(self.ops[*a as usize],Op::CONST(_))) {
+            //Its constants followed by the instruction reading them
+            let mut folded: Vec<Op> = args.iter().map(|a| self.ops[*a as usize]).collect();
+            folded.push(op.map_args(&|a| args.iter().position(|b| *b == a).unwrap() as u32));
+            Op::CONST(SdfExpr{ops: folded}.eval(&Point3::ZERO))
+        } else { op };
+        let key = op.key();
+        if let Some(r) = self.seen.get(&key) { return Ok(*r); }
+        if self.ops.len() >= MAX_OPS { return Err(format!("Expression too long, over {} operations",MAX_OPS)); }
+        self.ops.push(op);
+        self.seen.insert(key,(self.ops.len() - 1) as u32);
+        return Ok((self.ops.len() - 1) as u32);
+    }
+    fn constant(&mut self,v: f32) -> Result<u32,String>{
+        return self.push(Op::CONST(v));
+    }
+    //Only what the result needs, in the same order
+    fn finish(self,result: u32) -> Vec<Op>{
+        let mut used = vec!(false;self.ops.len());
+        used[result as usize] = true;
+        for i in (0..=result as usize).rev(){
+            if used[i] { for a in self.ops[i].args() { used[a as usize] = true; } }
+        }
+        let mut remap = vec!(0u32;self.ops.len());
+        let mut ret = Vec::new();
+        for i in 0..=result as usize{
+            if !used[i] { continue; }
+            ret.push(self.ops[i].map_args(&|a| remap[a as usize]));
+            remap[i] = (ret.len() - 1) as u32;
+        }
+        return ret;
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[allow(non_camel_case_types)]
+enum Value {
+    SCALAR(u32),
+    VEC3([u32;3]),
+}
+
+struct Compiler {
+    tokens: Vec<Token>,
+    pos: usize,
+    tape: Tape,
+    vars: Vec<(String,Value)>,
+}
+
+impl Compiler {
+    fn peek(&self) -> Option<&Token>{
+        return self.tokens.get(self.pos);
+    }
+    fn next(&mut self) -> Option<Token>{
+        self.pos += 1;
+        return self.tokens.get(self.pos - 1).cloned();
+    }
+    fn expect(&mut self,c: char) -> Result<(),String>{
+        let t = self.next();
+        if t != Some(Token::SYM(c)) { return Err(format!("Expected '{}', got {}",c,describe(&t))); }
+        return Ok(());
+    }
+    //Scalars go to every component of the other side
+    fn map2(&mut self,a: Value,b: Value,f: &dyn Fn(u32,u32) -> Op) -> Result<Value,String>{
+        return match (a,b) {
+            (Value::SCALAR(x),Value::SCALAR(y)) => Ok(Value::SCALAR(self.tape.push(f(x,y))?)),
+            _ => {
+                let (va,vb) = (self.splat(a),self.splat(b));
+                Ok(Value::VEC3([self.tape.push(f(va[0],vb[0]))?,self.tape.push(f(va[1],vb[1]))?,self.tape.push(f(va[2],vb[2]))?]))
+            },
+        };
+    }
+    fn map1(&mut self,a: Value,f: &dyn Fn(u32) -> Op) -> Result<Value,String>{
+        return match a {
+            Value::SCALAR(x) => Ok(Value::SCALAR(self.tape.push(f(x))?)),
+            Value::VEC3(v) => Ok(Value::VEC3([self.tape.push(f(v[0]))?,self.tape.push(f(v[1]))?,self.tape.push(f(v[2]))?])),
+        };
+    }
+    fn splat(&self,a: Value) -> [u32;3]{
+        return match a {
+            Value::SCALAR(x) => [x,x,x],
+            Value::VEC3(v) => v,
+        };
+    }
+    fn mul(&mut self,a: Value,b: Value) -> Result<Value,String>{
+        return self.map2(a,b,&|x,y| if x == y { Op::SQR(x) } else { Op::MUL(x,y) });
+    }
+    fn scalar(&self,v: Value,what: &str) -> Result<u32,String>{
+        return match v {
+            Value::SCALAR(x) => Ok(x),
+            Value::VEC3(_) => Err(format!("{} takes a scalar there, not a vector",what)),
+        };
+    }
+    fn vector(&self,v: Value,what: &str) -> Result<[u32;3],String>{
+        return match v {
+            Value::VEC3(x) => Ok(x),
+            Value::SCALAR(_) => Err(format!("{} takes a vector there, not a scalar",what)),
+        };
+    }
+    fn length(&mut self,v: &[u32]) -> Result<u32,String>{
+        let mut sum = self.tape.push(Op::SQR(v[0]))?;
+        for c in &v[1..]{
+            let sq = self.tape.push(Op::SQR(*c))?;
+            sum = self.tape.push(Op::ADD(sum,sq))?;
+        }
+        return self.tape.push(Op::SQRT(sum));
+    }
+    fn dot(&mut self,a: [u32;3],b: [u32;3]) -> Result<u32,String>{
+        let mut sum = self.mul(Value::SCALAR(a[0]),Value::SCALAR(b[0]))?;
+        for i in 1..3{
+            let m = self.mul(Value::SCALAR(a[i]),Value::SCALAR(b[i]))?;
+            sum = self.map2(sum,m,&|x,y| Op::ADD(x,y))?;
+        }
+        return self.scalar(sum,"dot");
+    }
+    fn expr(&mut self) -> Result<Value,String>{
+        let mut v = self.term()?;
+        loop {
+            match self.peek() {
+                Some(Token::SYM('+')) => { self.pos += 1; let r = self.term()?; v = self.map2(v,r,&|x,y| Op::ADD(x,y))?; },
+                Some(Token::SYM('-')) => { self.pos += 1; let r = self.term()?; v = self.map2(v,r,&|x,y| Op::SUB(x,y))?; },
+                _ => return Ok(v),
+            }
+        }
+    }
+    fn term(&mut self) -> Result<Value,String>{
+        let mut v = self.unary()?;
+        loop {
+            match self.peek() {
+                Some(Token::SYM('*')) => { self.pos += 1; let r = self.unary()?; v = self.mul(v,r)?; },
+                Some(Token::SYM('/')) => { self.pos += 1; let r = self.unary()?; v = self.map2(v,r,&|x,y| Op::DIV(x,y))?; },
+                _ => return Ok(v),
+            }
+        }
+    }
+    fn unary(&mut self) -> Result<Value,String>{
+        if self.peek() == Some(&Token::SYM('-')) {
+            self.pos += 1;
+            let v = self.unary()?;
+            return self.map1(v,&|x| Op::NEG(x));
+        }
+        let mut v = self.primary()?;
+        while self.peek() == Some(&Token::SYM('.')) {
+            self.pos += 1;
+            let c = match self.next() {
+                Some(Token::IDENT(s)) if s == "x" => 0,
+                Some(Token::IDENT(s)) if s == "y" => 1,
+                Some(Token::IDENT(s)) if s == "z" => 2,
+                t => return Err(format!("Expected x, y or z after '.', got {}",describe(&t))),
+            };
+            v = Value::SCALAR(self.vector(v,"'.'")?[c]);
+        }
+        return Ok(v);
+    }
+    fn primary(&mut self) -> Result<Value,String>{
+        return match self.next() {
+            Some(Token::NUM(v)) => Ok(Value::SCALAR(self.tape.constant(v)?)),
+            Some(Token::SYM('(')) => {
+                let v = self.expr()?;
+                self.expect(')')?;
+                Ok(v)
+            },
+            Some(Token::IDENT(name)) => {
+                if self.peek() == Some(&Token::SYM('(')) {
+                    self.pos += 1;
+                    let mut args = Vec::new();
+                    if self.peek() != Some(&Token::SYM(')')) {
+                        loop {
+                            args.push(self.expr()?);
+                            if self.peek() != Some(&Token::SYM(',')) { break; }
+                            self.pos += 1;
+                        }
+                    }
+                    self.expect(')')?;
+                    return self.call(&name,&args);
+                }
+                match self.vars.iter().rev().find(|(n,_)| *n == name) {
+                    Some((_,v)) => Ok(*v),
+                    None => Err(format!("Unknown variable '{}'",name)),
+                }
+            },
+            t => Err(format!("Expected a value, got {}",describe(&t))),
+        };
+    }
+    fn call(&mut self,name: &str,args: &[Value]) -> Result<Value,String>{
+        let arity = match name {
+            "length" | "abs" | "sqrt" | "sin" | "cos" | "floor" | "noise" => 1,
+            "dot" | "min" | "max" | "mod" | "sphere" | "box" => 2,
+            "clamp" | "mix" | "smin" | "smax" | "torus" | "cylinder" | "plane" => 3,
+            "vec3" => if args.len() == 1 { 1 } else { 3 },
+            _ => return Err(format!("Unknown function '{}'",name)),
+        };
+        if args.len() != arity { return Err(format!("{} takes {} arguments, got {}",name,arity,args.len())); }
+        return match name {
+            "vec3" => {
+                if args.len() == 1 { return Ok(Value::VEC3([self.scalar(args[0],name)?;3])); }
+                Ok(Value::VEC3([self.scalar(args[0],name)?,self.scalar(args[1],name)?,self.scalar(args[2],name)?]))
+            },
+            "length" => match args[0] {
+                Value::SCALAR(x) => Ok(Value::SCALAR(self.tape.push(Op::ABS(x))?)),
+                Value::VEC3(v) => Ok(Value::SCALAR(self.length(&v)?)),
+            },
+            "dot" => {
+                let (a,b) = (self.vector(args[0],name)?,self.vector(args[1],name)?);
+                Ok(Value::SCALAR(self.dot(a,b)?))
+            },
+            "abs" => self.map1(args[0],&|x| Op::ABS(x)),
+            "sqrt" => self.map1(args[0],&|x| Op::SQRT(x)),
+            "sin" => self.map1(args[0],&|x| Op::SIN(x)),
+            "cos" => self.map1(args[0],&|x| Op::COS(x)),
+            "floor" => self.map1(args[0],&|x| Op::FLOOR(x)),
+            "min" => self.map2(args[0],args[1],&|x,y| Op::MIN(x,y)),
+            "max" => self.map2(args[0],args[1],&|x,y| Op::MAX(x,y)),
+            "clamp" => {
+                let lo = self.map2(args[0],args[1],&|x,y| Op::MAX(x,y))?;
+                self.map2(lo,args[2],&|x,y| Op::MIN(x,y))
+            },
+            "mix" => {
+                let d = self.map2(args[1],args[0],&|x,y| Op::SUB(x,y))?;
+                let d = self.mul(d,args[2])?;
+                self.map2(args[0],d,&|x,y| Op::ADD(x,y))
+            },
+            "mod" => {
+                let q = self.map2(args[0],args[1],&|x,y| Op::DIV(x,y))?;
+                let q = self.map1(q,&|x| Op::FLOOR(x))?;
+                let q = self.mul(args[1],q)?;
+                self.map2(args[0],q,&|x,y| Op::SUB(x,y))
+            },
+            "smin" | "smax" => {
+                let (mut a,mut b,k) = (self.scalar(args[0],name)?,self.scalar(args[1],name)?,self.scalar(args[2],name)?);
+                if name == "smax" {//-smin(-a,-b)
+                    a = self.tape.push(Op::NEG(a))?;
+                    b = self.tape.push(Op::NEG(b))?;
+                }
+                let r = self.tape.push(Op::SMIN(a,b,k))?;
+                Ok(Value::SCALAR(if name == "smax" { self.tape.push(Op::NEG(r))? } else { r }))
+            },
+            "noise" => {
+                let v = self.vector(args[0],name)?;
+                Ok(Value::SCALAR(self.tape.push(Op::NOISE(v[0],v[1],v[2]))?))
+            },
+            "sphere" => {
+                let (p,r) = (self.vector(args[0],name)?,self.scalar(args[1],name)?);
+                let l = self.length(&p)?;
+                Ok(Value::SCALAR(self.tape.push(Op::SUB(l,r))?))
+            },
+            "box" => {//length(max(q,0)) + min(max(q.x,q.y,q.z),0), q = abs(p) - half_sizes
+                let (p,b) = (self.vector(args[0],name)?,self.vector(args[1],name)?);
+                let zero = self.tape.constant(0.)?;
+                let mut q = [0;3];
+                let mut outside = [0;3];
+                for i in 0..3{
+                    let a = self.tape.push(Op::ABS(p[i]))?;
+                    q[i] = self.tape.push(Op::SUB(a,b[i]))?;
+                    outside[i] = self.tape.push(Op::MAX(q[i],zero))?;
+                }
+                let l = self.length(&outside)?;
+                let m = self.tape.push(Op::MAX(q[1],q[2]))?;
+                let m = self.tape.push(Op::MAX(q[0],m))?;
+                let inside = self.tape.push(Op::MIN(m,zero))?;
+                Ok(Value::SCALAR(self.tape.push(Op::ADD(l,inside))?))
+            },
+            "torus" => {//Around y
+                let (p,major,minor) = (self.vector(args[0],name)?,self.scalar(args[1],name)?,self.scalar(args[2],name)?);
+                let ring = self.length(&[p[0],p[2]])?;
+                let ring = self.tape.push(Op::SUB(ring,major))?;
+                let l = self.length(&[ring,p[1]])?;
+                Ok(Value::SCALAR(self.tape.push(Op::SUB(l,minor))?))
+            },
+            "cylinder" => {//Capped, along y
+                let (p,r,h) = (self.vector(args[0],name)?,self.scalar(args[1],name)?,self.scalar(args[2],name)?);
+                let zero = self.tape.constant(0.)?;
+                let ring = self.length(&[p[0],p[2]])?;
+                let dx = self.tape.push(Op::SUB(ring,r))?;
+                let ay = self.tape.push(Op::ABS(p[1]))?;
+                let dy = self.tape.push(Op::SUB(ay,h))?;
+                let m = self.tape.push(Op::MAX(dx,dy))?;
+                let inside = self.tape.push(Op::MIN(m,zero))?;
+                let ox = self.tape.push(Op::MAX(dx,zero))?;
+                let oy = self.tape.push(Op::MAX(dy,zero))?;
+                let l = self.length(&[ox,oy])?;
+                Ok(Value::SCALAR(self.tape.push(Op::ADD(inside,l))?))
+            },
+            _ => {//plane
+                let (p,n,h) = (self.vector(args[0],name)?,self.vector(args[1],name)?,self.scalar(args[2],name)?);
+                let d = self.dot(p,n)?;
+                Ok(Value::SCALAR(self.tape.push(Op::ADD(d,h))?))
+            },
+        };
+    }
+}
+
+//An expression as a marched object, in world space. Expressions that aren't exact distances (noise, warps) give how much
+//faster than 1 they can change as lipschitz, the distance is divided by it so marching doesn't step through them
+#[derive(Clone)]
+pub struct MarchedExpr {
+    pub expr: Arc<SdfExpr>,
+    pub material: Material,
+    pub lipschitz: f32,
+    bounds: BoundingBox3D,
+}
+
+impl MarchedExpr {
+    pub fn new(expr: &Arc<SdfExpr>,material: &Material,lipschitz: f32) -> Self{
+        assert!(lipschitz > 0. && lipschitz.is_finite(),"lipschitz must be positive and finite");
+        return Self{expr: expr.clone(),material: *material,lipschitz: lipschitz,bounds: expr.bounds()};
+    }
+}
+
+impl Marched for MarchedExpr {
+    fn local_sdf(&self,p: &Point3) -> f32 {
+        return self.expr.eval(p)/self.lipschitz;
+    }
+    fn material(&self) -> &Material{
+        return &self.material;
+    }
+    fn to_local(&self,p: &Vec4) -> Vec4{
+        return *p;
+    }
+    fn to_world(&self,p: &Vec4) -> Vec4{
+        return *p;
+    }
+    fn to_world_f(&self,f: f32) -> f32{
+        return f;
+    }
+}
+
+impl Bounded for MarchedExpr {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        return self.bounds;
+    }
+}
+
+//Blocks of 'shape MATERIAL [lipschitz L]', the expression (see SdfExpr::compile) and 'end'. # starts a comment.
+//MATERIAL is lambertian R G B, metal R G B FUZZ, dielectric IOR or light R G B [GROUP] (default 1)
+pub fn load_shapes(text: &str) -> Result<Vec<MarchedExpr>,String>{
+    let mut ret = Vec::new();
+    let mut lines = text.lines().enumerate().map(|(i,l)| (i+1,l.split('#').next().unwrap().trim())).filter(|(_,l)| !l.is_empty());
+    while let Some((line_idx,header)) = lines.next(){
+        let err = |msg: &str| format!("line {}: {}",line_idx,msg);
+        let tokens: Vec<&str> = header.split_whitespace().collect();
+        if tokens[0] != "shape" { return Err(err(&format!("expected 'shape', got '{}'",tokens[0]))); }
+        let nums = |from: usize,count: usize| -> Result<Vec<f32>,String> {
+            if tokens.len() < from + count { return Err(err(&format!("{} takes {} values",tokens[1],count))); }
+            return tokens[from..from+count].iter().map(|t| t.parse::<f32>().map_err(|_| err(&format!("bad value '{}'",t)))).collect();
+        };
+        let (material,used) = match tokens.get(1) {
+            Some(&"lambertian") => { let v = nums(2,3)?; (Material::new_lambertian(Color::new(v[0],v[1],v[2])),5) },
+            Some(&"metal")      => { let v = nums(2,4)?; (Material::new_metal_fuzz(Color::new(v[0],v[1],v[2]),v[3]),6) },
+            Some(&"dielectric") => { let v = nums(2,1)?; (Material::new_dielectric(v[0]),3) },
+            Some(&"light")      => {
+                let v = nums(2,3)?;
+                //Optional light group, 0 is the sky's
+                let (group,used) = match tokens.get(5).and_then(|t| t.parse::<u32>().ok()) {
+                    Some(g) => (g,6),
+                    None => (1,5),
+                };
+                if group == 0 || group as usize >= MAX_LIGHT_GROUPS { return Err(err(&format!("light group must be in [1;{})",MAX_LIGHT_GROUPS))); }
+                (Material::new_diffuse_light(Color::new(v[0],v[1],v[2]),group),used)
+            },
+            t => return Err(err(&format!("unknown material '{}'",t.unwrap_or(&"")))),
+        };
+        let lipschitz = match &tokens[used..] {
+            [] => 1.,
+            ["lipschitz",l] => match l.parse::<f32>() {
+                Ok(v) if v > 0. && v.is_finite() => v,
+                _ => return Err(err(&format!("lipschitz must be a positive number, got '{}'",l))),
+            },
+            rest => return Err(err(&format!("unexpected '{}'",rest.join(" ")))),
+        };
+        let mut src = String::new();
+        loop {
+            match lines.next() {
+                Some((_,"end")) => break,
+                Some((_,l)) => { src.push_str(l); src.push('\n'); },
+                None => return Err(err("shape without an 'end'")),
+            }
+        }
+        let expr = SdfExpr::compile(&src).map_err(|e| err(&e))?;
+        ret.push(MarchedExpr::new(&Arc::new(expr),&material,lipschitz));
+    }
+    return Ok(ret);
+}
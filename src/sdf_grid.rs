@@ -0,0 +1,296 @@
+use std::sync::Arc;
+use std::fs::File;
+use std::io::{BufWriter,Write};
+use crate::math::vec3::{Vec3,Point3};
+use crate::math::vec4::Vec4;
+use crate::materials::Material;
+use crate::bounding_box::*;
+use crate::marched::Marched;
+use crate::mesh::Mesh;
+
+//Voxels per brick side. Bricks keep their (BRICK+1)^3 corner samples, the ones on the border repeated in the neighbours
+const BRICK: usize = 8;
+const BRICK_SIDE: usize = BRICK + 1;
+const BRICK_SAMPLES: usize = BRICK_SIDE*BRICK_SIDE*BRICK_SIDE;
+const UNIFORM: u32 = u32::MAX;
+const MAGIC: &[u8;4] = b"SDFG";
+const VERSION: u32 = 1;
+
+//Signed distance of a closed triangle mesh sampled on a grid of bricks, negative inside and trilinearly interpolated.
+//Sparse grids only keep the samples of the bricks the surface could go through, the rest are a single value that
+//bounds the distance anywhere in them from below, enough to march through them
+pub struct SdfGrid {
+    pub minp: Point3,//Where the first sample is
+    pub voxel: f32,//Distance between samples
+    pub bricks: [usize;3],
+    brick_index: Vec<u32>,//Into samples in BRICK_SAMPLES units, UNIFORM if there's only brick_value
+    brick_value: Vec<f32>,
+    samples: Vec<f32>,
+}
+
+//Whether each sample is inside the mesh, by the parity of the crossings along the lines of samples. Each of the 3 axes
+//gets a vote so a line going right through an edge or a hole in the mesh doesn't flip a whole row.
+//The lines are nudged a bit off the samples, meshes tend to have their edges right where the samples are
+fn inside_samples(mesh: &Mesh,minp: &Point3,voxel: f32,n: [usize;3]) -> Vec<bool>{
+    let idx = |i: [usize;3]| (i[2]*n[1] + i[1])*n[0] + i[0];
+    let mut votes = vec!(0u8;n[0]*n[1]*n[2]);
+    let nudge = [0.7548776*1e-3*voxel,0.5698403*1e-3*voxel];
+    let cross2 = |a: (f32,f32),b: (f32,f32),q: (f32,f32)| (b.0 - a.0)*(q.1 - a.1) - (b.1 - a.1)*(q.0 - a.0);
+    for axis in 0..3{
+        let (u,v) = ((axis + 1) % 3,(axis + 2) % 3);
+        //Triangles binned by the lines their bounding box covers
+        let mut lines: Vec<Vec<u32>> = vec!(Vec::new();n[u]*n[v]);
+        for (t_idx,tri) in mesh.triangles.iter().enumerate(){
+            let p = mesh.triangle_points(tri);
+            let range = |a: usize,off: f32| {
+                let lo = p[0][a].min(p[1][a].min(p[2][a])) - minp[a] - off;
+                let hi = p[0][a].max(p[1][a].max(p[2][a])) - minp[a] - off;
+                ((lo/voxel).ceil().max(0.) as usize,((hi/voxel).floor().max(-1.) + 1.).min(n[a] as f32) as usize)
+            };
+            let ((u0,u1),(v0,v1)) = (range(u,nudge[0]),range(v,nudge[1]));
+            for iv in v0..v1{
+                for iu in u0..u1{
+                    lines[iv*n[u] + iu].push(t_idx as u32);
+                }
+            }
+        }
+        let mut crossings: Vec<f32> = Vec::new();
+        for iv in 0..n[v]{
+            for iu in 0..n[u]{
+                let q = (minp[u] + iu as f32*voxel + nudge[0],minp[v] + iv as f32*voxel + nudge[1]);
+                crossings.clear();
+                for t_idx in &lines[iv*n[u] + iu]{
+                    let p = mesh.triangle_points(&mesh.triangles[*t_idx as usize]);
+                    let (a,b,c) = ((p[0][u],p[0][v]),(p[1][u],p[1][v]),(p[2][u],p[2][v]));
+                    //Weights of each vertex, all of the same sign if the line goes through the triangle
+                    let (wa,wb,wc) = (cross2(b,c,q),cross2(c,a,q),cross2(a,b,q));
+                    let area = wa + wb + wc;
+                    if area == 0. { continue; }//Edge on
+                    if (wa < 0. || wb < 0. || wc < 0.) && (wa > 0. || wb > 0. || wc > 0.) { continue; }
+                    crossings.push((wa*p[0][axis] + wb*p[1][axis] + wc*p[2][axis])/area);
+                }
+                crossings.sort_by(|a,b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+                let mut crossed = 0;
+                for ia in 0..n[axis]{
+                    let x = minp[axis] + ia as f32*voxel;
+                    while crossed < crossings.len() && crossings[crossed] < x { crossed += 1; }
+                    let mut i = [0;3];
+                    i[axis] = ia;
+                    i[u] = iu;
+                    i[v] = iv;
+                    votes[idx(i)] += (crossed % 2) as u8;
+                }
+            }
+        }
+    }
+    return votes.into_iter().map(|v| v >= 2).collect();
+}
+
+impl SdfGrid {
+    //resolution is how many voxels go along the longest side of the mesh, with a couple of voxels of padding around it
+    pub fn bake(mesh: &Mesh,resolution: u32,sparse: bool) -> Self{
+        let bb = mesh.build_world_bounding_box();
+        let extent = bb.maxp - bb.minp;
+        let voxel = extent.max_val().max(1e-6)/(resolution.max(1) as f32);
+        let mut bricks = [0;3];
+        for a in 0..3{
+            bricks[a] = ((extent[a]/voxel + 4.)/BRICK as f32).ceil().max(1.) as usize;
+        }
+        let size = Vec3::new(bricks[0] as f32,bricks[1] as f32,bricks[2] as f32)*(BRICK as f32*voxel);
+        let minp = (bb.minp + bb.maxp)*0.5 - size*0.5;
+        let n = [bricks[0]*BRICK + 1,bricks[1]*BRICK + 1,bricks[2]*BRICK + 1];
+        let inside = inside_samples(mesh,&minp,voxel,n);
+        let sample_idx = |i: [usize;3]| (i[2]*n[1] + i[1])*n[0] + i[0];
+        let mut cache = vec!(f32::NAN;n[0]*n[1]*n[2]);//Samples are shared by up to 8 bricks
+        let mut sample = |i: [usize;3]| {
+            let j = sample_idx(i);
+            if cache[j].is_nan() {
+                let p = minp + voxel*Vec3::new(i[0] as f32,i[1] as f32,i[2] as f32);
+                cache[j] = if inside[j] { -mesh.distance(&p) } else { mesh.distance(&p) };
+            }
+            return cache[j];
+        };
+        let mut ret = Self{minp: minp,voxel: voxel,bricks: bricks,brick_index: Vec::new(),brick_value: Vec::new(),samples: Vec::new()};
+        let half_diagonal = 0.5*(3f32).sqrt()*BRICK as f32*voxel;
+        for bz in 0..bricks[2]{
+            for by in 0..bricks[1]{
+                for bx in 0..bricks[0]{
+                    let corner = [bx*BRICK,by*BRICK,bz*BRICK];
+                    let center = sample([corner[0] + BRICK/2,corner[1] + BRICK/2,corner[2] + BRICK/2]);
+                    //A voxel more than the surface could need, so the interpolation next to the brick stays smooth
+                    if sparse && center.abs() > half_diagonal + voxel {
+                        ret.brick_index.push(UNIFORM);
+                        ret.brick_value.push(center.signum()*(center.abs() - half_diagonal));
+                        continue;
+                    }
+                    ret.brick_index.push((ret.samples.len()/BRICK_SAMPLES) as u32);
+                    ret.brick_value.push(center);
+                    for z in 0..BRICK_SIDE{
+                        for y in 0..BRICK_SIDE{
+                            for x in 0..BRICK_SIDE{
+                                ret.samples.push(sample([corner[0] + x,corner[1] + y,corner[2] + z]));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        return ret;
+    }
+    pub fn size(&self) -> Vec3{
+        return Vec3::new(self.bricks[0] as f32,self.bricks[1] as f32,self.bricks[2] as f32)*(BRICK as f32*self.voxel);
+    }
+    //Trilinear inside the grid. Outside, what the closest point of the grid has on top of the way there, the surface is always inside
+    pub fn distance(&self,p: &Point3) -> f32{
+        let q = p.max(&self.minp).min(&(self.minp + self.size()));
+        let g = (q - self.minp)/self.voxel;
+        let mut brick = [0;3];
+        let mut local = [0.;3];
+        for a in 0..3{
+            brick[a] = ((g[a] as usize)/BRICK).min(self.bricks[a] - 1);
+            local[a] = (g[a] - (brick[a]*BRICK) as f32).max(0.).min(BRICK as f32);
+        }
+        let b = (brick[2]*self.bricks[1] + brick[1])*self.bricks[0] + brick[0];
+        let inner = if self.brick_index[b] == UNIFORM { self.brick_value[b] } else {
+            let base = self.brick_index[b] as usize*BRICK_SAMPLES;
+            let cell = [(local[0] as usize).min(BRICK - 1),(local[1] as usize).min(BRICK - 1),(local[2] as usize).min(BRICK - 1)];
+            let f = [local[0] - cell[0] as f32,local[1] - cell[1] as f32,local[2] - cell[2] as f32];
+            let at = |x: usize,y: usize,z: usize| self.samples[base + ((cell[2] + z)*BRICK_SIDE + cell[1] + y)*BRICK_SIDE + cell[0] + x];
+            let lerp = |a: f32,b: f32,t: f32| a + (b - a)*t;
+            let x00 = lerp(at(0,0,0),at(1,0,0),f[0]);
+            let x10 = lerp(at(0,1,0),at(1,1,0),f[0]);
+            let x01 = lerp(at(0,0,1),at(1,0,1),f[0]);
+            let x11 = lerp(at(0,1,1),at(1,1,1),f[0]);
+            lerp(lerp(x00,x10,f[1]),lerp(x01,x11,f[1]),f[2])
+        };
+        let outside = (*p - q).length();
+        if outside == 0. { return inner; }
+        //Going out of the box only takes us further from everything in it
+        return (outside*outside + inner.max(0.)*inner.max(0.)).sqrt();
+    }
+    //Little endian: SDFG, version, minp, voxel, bricks, then per brick its index (into the samples, or all 1s if
+    //uniform) and value, then the samples
+    pub fn save(&self,path: &str) -> std::io::Result<()>{
+        let mut f = BufWriter::new(File::create(path)?);
+        f.write_all(MAGIC)?;
+        f.write_all(&VERSION.to_le_bytes())?;
+        for v in [self.minp.x(),self.minp.y(),self.minp.z(),self.voxel]{
+            f.write_all(&v.to_le_bytes())?;
+        }
+        for b in self.bricks{
+            f.write_all(&(b as u32).to_le_bytes())?;
+        }
+        for (i,v) in self.brick_index.iter().zip(&self.brick_value){
+            f.write_all(&i.to_le_bytes())?;
+            f.write_all(&v.to_le_bytes())?;
+        }
+        for v in &self.samples{
+            f.write_all(&v.to_le_bytes())?;
+        }
+        return f.flush();
+    }
+    pub fn load(path: &str) -> Result<Self,String>{
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}",path,e))?;
+        let err = |msg: &str| format!("{}: {}",path,msg);
+        if data.len() < 36 || &data[0..4] != MAGIC { return Err(err("not a baked SDF")); }
+        let word = |i: usize| [data[4*i],data[4*i+1],data[4*i+2],data[4*i+3]];
+        let version = u32::from_le_bytes(word(1));
+        if version != VERSION { return Err(err(&format!("version {}, expected {}",version,VERSION))); }
+        let minp = Point3::new(f32::from_le_bytes(word(2)),f32::from_le_bytes(word(3)),f32::from_le_bytes(word(4)));
+        let voxel = f32::from_le_bytes(word(5));
+        let bricks = [u32::from_le_bytes(word(6)) as usize,u32::from_le_bytes(word(7)) as usize,u32::from_le_bytes(word(8)) as usize];
+        //Sizes from the header could be anything, they get checked against the file before anything is allocated
+        let count = bricks[0].checked_mul(bricks[1]).and_then(|c| c.checked_mul(bricks[2])).ok_or_else(|| err("too many bricks"))?;
+        if count == 0 || !(voxel > 0.) { return Err(err("empty grid")); }
+        let table_end = count.checked_mul(2).and_then(|c| c.checked_add(9)).and_then(|c| c.checked_mul(4)).ok_or_else(|| err("too many bricks"))?;
+        if data.len() < table_end { return Err(err("truncated brick table")); }
+        let mut brick_index = Vec::with_capacity(count);
+        let mut brick_value = Vec::with_capacity(count);
+        for b in 0..count{
+            brick_index.push(u32::from_le_bytes(word(9 + 2*b)));
+            brick_value.push(f32::from_le_bytes(word(10 + 2*b)));
+        }
+        let first = 9 + 2*count;
+        let stored = brick_index.iter().filter(|i| **i != UNIFORM).count();
+        if Some(data.len()) != stored.checked_mul(BRICK_SAMPLES).and_then(|c| c.checked_add(first)).and_then(|c| c.checked_mul(4)) {
+            return Err(err(&format!("expected {} bricks of samples",stored)));
+        }
+        if brick_index.iter().any(|i| *i != UNIFORM && *i as usize >= stored) { return Err(err("brick index out of range")); }
+        let samples = (0..stored*BRICK_SAMPLES).map(|i| f32::from_le_bytes(word(first + i))).collect();
+        return Ok(Self{minp: minp,voxel: voxel,bricks: bricks,brick_index: brick_index,brick_value: brick_value,samples: samples});
+    }
+    //FNV-1a of the mesh's geometry and the bake settings, so changing any of them doesn't load a stale bake
+    fn bake_key(mesh: &Mesh,resolution: u32,sparse: bool) -> u64{
+        let mut h: u64 = 0xcbf29ce484222325;
+        let mut add = |v: u32| for b in v.to_le_bytes() { h = (h ^ b as u64).wrapping_mul(0x100000001b3); };
+        add(resolution);
+        add(sparse as u32);
+        for p in &mesh.positions{
+            add(p.x().to_bits());
+            add(p.y().to_bits());
+            add(p.z().to_bits());
+        }
+        for t in &mesh.triangles{
+            add(t[0]);
+            add(t[1]);
+            add(t[2]);
+        }
+        return h;
+    }
+    //Loads NAME_KEY.sdf from cache_dir if it's there, otherwise bakes the mesh and saves it there for the next time.
+    //KEY hashes the mesh and the settings
+    pub fn load_or_bake(cache_dir: Option<&str>,name: &str,mesh: &Mesh,resolution: u32,sparse: bool) -> Self{
+        let path = cache_dir.map(|dir| format!("{}/{}_{:016x}.sdf",dir,name,Self::bake_key(mesh,resolution,sparse)));
+        let path = path.as_deref();
+        if let Some(p) = path {
+            if std::path::Path::new(p).exists() {
+                match Self::load(p) {
+                    Ok(grid) => return grid,
+                    Err(e) => eprintln!("Baking again, couldn't load {}",e),
+                }
+            }
+        }
+        let grid = Self::bake(mesh,resolution,sparse);
+        if let Some(p) = path {
+            if let Some(dir) = std::path::Path::new(p).parent() { let _ = std::fs::create_dir_all(dir); }
+            if let Err(e) = grid.save(p) { eprintln!("Couldn't save the baked SDF to {}: {}",p,e); }
+        }
+        return grid;
+    }
+}
+
+//A baked grid as a marched object, in the space the mesh was in. Place it with a MarchedInstance, they can share the grid
+pub struct MarchedSdfGrid {
+    pub grid: Arc<SdfGrid>,
+    pub material: Material,
+}
+
+impl MarchedSdfGrid {
+    pub fn new(grid: &Arc<SdfGrid>,material: &Material) -> Self{
+        return Self{grid: grid.clone(),material: *material};
+    }
+}
+
+impl Marched for MarchedSdfGrid {
+    fn local_sdf(&self,p: &Point3) -> f32 {
+        return self.grid.distance(p);
+    }
+    fn material(&self) -> &Material{
+        return &self.material;
+    }
+    fn to_local(&self,p: &Vec4) -> Vec4{
+        return *p;
+    }
+    fn to_world(&self,p: &Vec4) -> Vec4{
+        return *p;
+    }
+    fn to_world_f(&self,f: f32) -> f32{
+        return f;
+    }
+}
+
+impl Bounded for MarchedSdfGrid {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        return BoundingBox3D::new(&self.grid.minp,&(self.grid.minp + self.grid.size()));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::math::vec3::Color;
+use crate::hits::HitRecord;
+use std::sync::{Arc,Mutex};
+
+//Image sampled with wrapping uvs, v = 0 is the bottom row
+#[derive(Clone)]
+pub struct Texture {
+    pub width: u32,
+    pub height: u32,
+    pub channels: u32,
+    pub data: Vec<f32>,//Top row first, channels interleaved
+}
+
+impl Texture {
+    pub fn new(width: u32,height: u32,channels: u32,data: Vec<f32>) -> Self{
+        assert!(width > 0 && height > 0 && channels > 0 && data.len() == (width*height*channels) as usize);
+        return Self{width: width,height: height,channels: channels,data: data};
+    }
+    //Anything read_image understands. Normal maps are expected to be stored as 0.5*n + 0.5, like every tool exports them
+    #[allow(dead_code)]
+    pub fn load(path: &str) -> Result<Self,String>{
+        let (w,h,channels,data) = crate::image_io::read_image(path)?;
+        return Ok(Self::new(w,h,channels,data));
+    }
+    #[inline]
+    fn texel(&self,x: i64,y: i64) -> Color{
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        let i = (y*self.width as usize + x)*self.channels as usize;
+        if self.channels < 3 { return Color::new(self.data[i],self.data[i],self.data[i]); }
+        return Color::new(self.data[i],self.data[i+1],self.data[i+2]);
+    }
+    //Bilinear, repeats outside of [0;1]
+    pub fn sample(&self,u: f32,v: f32) -> Color{
+        let x = u*self.width as f32 - 0.5;
+        let y = (1. - v)*self.height as f32 - 0.5;
+        let (x0,y0) = (x.floor(),y.floor());
+        let (fx,fy) = (x - x0,y - y0);
+        let (x0,y0) = (x0 as i64,y0 as i64);
+        return (1.-fy)*((1.-fx)*self.texel(x0,y0) + fx*self.texel(x0+1,y0))
+             +     fy*((1.-fx)*self.texel(x0,y0+1) + fx*self.texel(x0+1,y0+1));
+    }
+}
+
+//Materials are Copy so they refer to textures by id, 0 is "no texture"
+static TEXTURES: Mutex<Vec<Texture>> = Mutex::new(Vec::new());
+
+pub fn register(t: Texture) -> u32{
+    let mut textures = TEXTURES.lock().unwrap();
+    textures.push(t);
+    return textures.len() as u32;
+}
+
+//Everything registered so far, for the render threads to index without locking. Textures registered later aren't in it
+pub fn freeze() -> Arc<[Texture]>{
+    return Arc::from(TEXTURES.lock().unwrap().as_slice());
+}
+
+#[inline]
+fn get(textures: &[Texture],id: u32) -> Option<&Texture>{
+    if id == 0 { return None; }
+    return textures.get((id - 1) as usize);
+}
+
+//Replaces the shading normal with the one from the material's normal map and/or bump map.
+//The tangent frame is built from the hit's dP/du, any direction on the surface if it has none
+pub fn perturb_normal(hr: &mut HitRecord,textures: &[Texture]){
+    let n = hr.normal;
+    let mut t = hr.tangent - n*n.dot(hr.tangent);
+    if t.length_squared() < 1e-12 { t = n.orthonormal_basis().0; }
+    let t = t.unit();
+    let b = n.cross(t);
+    let mut new_normal = n;
+    if let Some(tex) = get(textures,hr.material.normal_map) {
+        let c = 2.*tex.sample(hr.uv.0,hr.uv.1) - Color::new(1.,1.,1.);
+        new_normal = c.x()*t + c.y()*b + c.z()*n;
+    }
+    if let Some(tex) = get(textures,hr.material.bump_map) {
+        //Height slopes from one texel away, the bump bends the normal against them
+        let (du,dv) = (1./tex.width as f32,1./tex.height as f32);
+        let h = tex.sample(hr.uv.0,hr.uv.1).x();
+        let dhdu = (tex.sample(hr.uv.0 + du,hr.uv.1).x() - h)/du;
+        let dhdv = (tex.sample(hr.uv.0,hr.uv.1 + dv).x() - h)/dv;
+        new_normal = new_normal - hr.material.bump_scale*(dhdu*t + dhdv*b);
+    }
+    if new_normal.length_squared() < 1e-12 { return; }
+    //Never past the surface, it would shade as if lit from behind
+    let mut new_normal = new_normal.unit();
+    let g = if hr.geometric_normal.dot(n) < 0. { -hr.geometric_normal } else { hr.geometric_normal };
+    let below = new_normal.dot(g);
+    if below < 0.01 { new_normal = (new_normal + (0.01 - below)*g).unit(); }
+    hr.normal = new_normal;
+}
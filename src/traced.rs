@@ -4,11 +4,13 @@ use crate::math::vec3::{Vec3,UnitVec3,Point3};
 use crate::math::vec4::{Vec4};
 use crate::math::mat3x3::{Mat3x3};
 use crate::math::mat4x4::{Mat4x4};
-use crate::utils::{INF,get_id};
+use crate::math::roots::solve_quartic;
+use crate::utils::{INF,PI};
 use crate::ray::Ray;
 use crate::hits::HitRecord;
 use crate::materials::Material;
 use crate::bounding_box::*;
+use crate::motion::{AnimatedTransform,transforms_at};
 
 #[derive(Copy, Clone)]
 pub struct Sphere {
@@ -16,28 +18,41 @@ pub struct Sphere {
     pub m_word_to_local: Mat4x4,
     pub material: Material,
     bounding_box: BoundingBox,
+    pub motion: Option<AnimatedTransform>,//If set, the transforms above are the ones at the first keyframe
 }
 
 impl Sphere {
     pub fn new(m_local_to_world: &Mat4x4,mat: &Material) -> Self{
         Sphere{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),material: *mat
-              ,bounding_box: BoundingBox::draw_always()}
+              ,bounding_box: BoundingBox::draw_always(),motion: None}
     }
     pub fn new_with_radius(o: &Point3,r: f32,mat: &Material) -> Self {
         let m = Mat4x4::new_translate(&o)
         .dot_mat(&Mat4x4::new_scale(&Vec3::new(r,r,r)));
         Sphere{m_local_to_world: m,m_word_to_local: m.fast_homogenous_inverse(),material: *mat
-              ,bounding_box: BoundingBox::draw_always()}
+              ,bounding_box: BoundingBox::draw_always(),motion: None}
+    }
+    #[allow(dead_code)]
+    pub fn new_moving(motion: &AnimatedTransform,mat: &Material) -> Self{
+        let mut ret = Self::new(&motion.start,mat);
+        ret.motion = Some(*motion);
+        return ret;
     }
 }
 
 pub trait Traced: Bounded {
+    //obj_id of the hit is relative to the object, in [0;id_count()). Whoever holds the object adds where its ids start
     fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord>;
+    //How many object ids it takes, groups take one per object inside
+    fn id_count(&self) -> u64 {
+        return 1;
+    }
 }
 
 impl Traced for Sphere {
     fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
-        let new_r = r.transform(&self.m_word_to_local);
+        let (m_local_to_world,m_word_to_local) = transforms_at(&self.motion,&self.m_local_to_world,&self.m_word_to_local,r.time);
+        let new_r = r.transform(&m_word_to_local);
         let a =  new_r.dir.length_squared();
         let half_b = new_r.orig.dot(new_r.dir);
         //Assume in local coords its a 1 radii sphere
@@ -55,16 +70,19 @@ impl Traced for Sphere {
             }
         }
         let local_point = new_r.at(root);
-        let point = self.m_local_to_world.dot_p3(&local_point);
-        let outward_normal = self.m_local_to_world.dot_v3(&local_point).unit();
+        let point = m_local_to_world.dot_p3(&local_point);
+        let outward_normal = m_local_to_world.dot_v3(&local_point).unit();
         //Maybe its faster to send some sort of reference/pointer to material? Probably not, since its so small
-        return Some(HitRecord{t: root,point: point,normal: outward_normal,material: self.material,obj_id: get_id(self)});
+        return Some(HitRecord{t: root,point: point,normal: outward_normal,geometric_normal: outward_normal,material: self.material,obj_id: 0,
+                              uv: local_point.spherical_uv(),tangent: Vec3::ZERO,fiber: false});
     }
 }
 
 impl Bounded for Sphere {
     fn build_world_bounding_box(&self) -> BoundingBox3D {
-        let ret = BoundingBox3D::new(&Point3::new(-1.,-1.,-1.),&Point3::new(1.,1.,1.)).dot(&self.m_local_to_world);
+        let local = BoundingBox3D::new(&Point3::new(-1.,-1.,-1.),&Point3::new(1.,1.,1.));
+        if let Some(motion) = &self.motion { return motion.motion_bounds(&local); }
+        let ret = local.dot(&self.m_local_to_world);
         //println!("{:?}",ret);
         return ret;
     }
@@ -109,8 +127,11 @@ impl Traced for InfinitePlane {
             return None;
         }
         let outward_normal = normal_against_direction(&self.normal,normal_dot_dir);
+        let point = r.at(root);
+        let (tangent,bitangent) = self.normal.orthonormal_basis();
+        let uv = ((point - self.center).dot(tangent),(point - self.center).dot(bitangent));
         //Maybe its faster to send some sort of reference/pointer to material? Probably not, since its so small
-        return Some(HitRecord{t: root,point: r.at(root),normal: outward_normal,material: self.material,obj_id: get_id(self)});
+        return Some(HitRecord{t: root,point: point,normal: outward_normal,geometric_normal: outward_normal,material: self.material,obj_id: 0,uv: uv,tangent: Vec3::ZERO,fiber: false});
     }
 }
 impl Bounded for InfinitePlane {}
@@ -128,6 +149,7 @@ pub struct Barycentric<const BT: usize>{
     pub v_in_base: Vec3,//Vec2, Z is 0 @SPEED
     pub material: Material,
     pub bounding_box: BoundingBox,
+    pub vertex_normals: Option<[UnitVec3;4]>,//Origin, origin+u, origin+v, origin+u+v (parallelograms only). Smooth shading if set
 }
 
 //Current rust version doesn't suport direct const enum templates... so I use an usize...
@@ -142,7 +164,7 @@ impl <const BT: usize> Barycentric<BT> {
         let v_in_base = base_inv.dot(&v_unit);
         return Self{origin: *origin,material: *material,
             u: u_unit,u_length: u_length,v: v_unit,v_length: v_length,uxv: uxv,uxvxu: uxvxu,
-            base_inv: base_inv, v_in_base: v_in_base, bounding_box: BoundingBox::draw_always()
+            base_inv: base_inv, v_in_base: v_in_base, bounding_box: BoundingBox::draw_always(),vertex_normals: None
         };
     }
     pub fn new3points(origin: &Point3,upoint: &Point3,vpoint: &Point3,material: &Material) -> Self{
@@ -152,6 +174,20 @@ impl <const BT: usize> Barycentric<BT> {
         let v_length = v_rel.length();
         return Self::new(origin,&u_rel,&v_rel,u_length,v_length,material);
     }
+    //Normals in the same order as the points, the fourth one is only used by parallelograms
+    pub fn new3points_with_normals(origin: &Point3,upoint: &Point3,vpoint: &Point3,normals: &[UnitVec3;4],material: &Material) -> Self{
+        let mut ret = Self::new3points(origin,upoint,vpoint,material);
+        ret.vertex_normals = Some(normals.map(|n| n.unit()));
+        return ret;
+    }
+    //Per vertex normals interpolated with the same lambdas that found the hit
+    #[inline]
+    fn interpolate_normal(&self,normals: &[UnitVec3;4],l1: f32,l2: f32,l3: f32) -> Vec3{
+        if BT == 0 {//Bilinear
+            return (1.-l1)*(1.-l2)*normals[0] + l1*(1.-l2)*normals[1] + (1.-l1)*l2*normals[2] + l1*l2*normals[3];
+        }
+        return l3*normals[0] + l1*normals[1] + l2*normals[2];
+    }
     #[inline]
     fn calc_barycentric(&self,coords2d: &Point3) -> (f32,f32,f32){
         let rx = coords2d.x();
@@ -196,7 +232,14 @@ impl <const BT: usize> Barycentric<BT> {
             return None;
         }
         let outward_normal = normal_against_direction(&self.uxv,normal_dot_dir);
-        return Some(HitRecord{t: root,point: point,normal: outward_normal,material: self.material,obj_id: get_id(self)});
+        let mut normal = outward_normal;
+        if let Some(normals) = &self.vertex_normals {
+            //Flipped the same way as the face, the vertex normals may point to either side
+            let n = self.interpolate_normal(normals,lambda1,lambda2,lambda3).unit();
+            normal = if n.dot(outward_normal) < 0. { -n } else { n };
+        }
+        return Some(HitRecord{t: root,point: point,normal: normal,geometric_normal: outward_normal,material: self.material,obj_id: 0,
+                              uv: (lambda1,lambda2),tangent: self.u,fiber: false});
     }
 }
 
@@ -232,17 +275,25 @@ pub struct Cube {
     pub m_word_to_local: Mat4x4,
     pub material: Material,
     pub bounding_box: BoundingBox,
+    pub motion: Option<AnimatedTransform>,//If set, the transforms above are the ones at the first keyframe
 }
 impl Cube {
     #[allow(dead_code)]
     pub fn new(m_local_to_world: &Mat4x4,mat: &Material) -> Self{
-        Self{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),material: *mat,bounding_box: BoundingBox::draw_always()}
+        Self{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),material: *mat,bounding_box: BoundingBox::draw_always(),
+             motion: None}
+    }
+    #[allow(dead_code)]
+    pub fn new_moving(motion: &AnimatedTransform,mat: &Material) -> Self{
+        let mut ret = Self::new(&motion.start,mat);
+        ret.motion = Some(*motion);
+        return ret;
     }
     #[allow(dead_code)]
     pub fn new_with_length(o: &Point3,length: f32,mat: &Material) -> Self {
         let m = Mat4x4::new_translate(&o)
         .dot_mat(&Mat4x4::new_scale(&Vec3::new(length,length,length)));
-        Self{m_local_to_world: m,m_word_to_local: m.fast_homogenous_inverse(),material: *mat,bounding_box: BoundingBox::draw_always()}
+        Self{m_local_to_world: m,m_word_to_local: m.fast_homogenous_inverse(),material: *mat,bounding_box: BoundingBox::draw_always(),motion: None}
     }
 }
 /*
@@ -264,7 +315,8 @@ i = x,y,z
 
 impl Traced for Cube {
     fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
-        let new_r = r.transform(&self.m_word_to_local);
+        let (m_local_to_world,m_word_to_local) = transforms_at(&self.motion,&self.m_local_to_world,&self.m_word_to_local,r.time);
+        let new_r = r.transform(&m_word_to_local);
         let mut smallest_t = INF;
         let mut idx = usize::MAX;
         for i in 0..3{
@@ -292,18 +344,642 @@ impl Traced for Cube {
         //The sign of the inside (derivative of abs value) defines what face between the pair
         let local_outward_normal: Vec3 = [Vec3::new(1.,0.,0.),Vec3::new(0.,1.,0.),Vec3::new(0.,0.,1.)][idx]
                                   *(1. as f32).copysign(new_r.at(smallest_t)[idx]);
-        let point = self.m_local_to_world.dot_p3(&local_point);
-        let outward_normal = self.m_local_to_world.dot_v3(&local_outward_normal);
-        return Some(HitRecord{t: smallest_t,point: point,normal: outward_normal,material: self.material,obj_id: get_id(self)});
+        let point = m_local_to_world.dot_p3(&local_point);
+        let outward_normal = m_local_to_world.dot_v3(&local_outward_normal);
+        //The face is parametrized by the other 2 axes
+        let uv = (local_point[(idx+1)%3]+0.5,local_point[(idx+2)%3]+0.5);
+        return Some(HitRecord{t: smallest_t,point: point,normal: outward_normal,geometric_normal: outward_normal,material: self.material,obj_id: 0,uv: uv,tangent: Vec3::ZERO,fiber: false});
     }
 }
 
 impl Bounded for Cube {
     fn build_world_bounding_box(&self) -> BoundingBox3D {
-        return BoundingBox3D::new(&Point3::new(-1.,-1.,-1.),&Point3::new(1.,1.,1.)).dot(&self.m_local_to_world);
+        let local = BoundingBox3D::new(&Point3::new(-1.,-1.,-1.),&Point3::new(1.,1.,1.));
+        if let Some(motion) = &self.motion { return motion.motion_bounds(&local); }
+        return local.dot(&self.m_local_to_world);
     }
     fn hit_bounding_box(&self,dir: &Vec3) -> bool{ 
         self.bounding_box.hit(dir) 
     }
     fn get_bounding_box(&self) -> BoundingBox { self.bounding_box }
 }
+
+//Quadric primitives below are intersected in local space, where they are unit sized and their axis is Y
+//t is the same in both spaces since the ray is transformed without renormalizing its direction
+
+//What a primitive found in local space, turned into a HitRecord by finish_local_hit
+#[derive(Copy, Clone)]
+struct LocalHit {
+    t: f32,
+    normal: Vec3,//Local, not normalized
+    uv: (f32,f32),
+}
+
+#[inline]
+fn keep_closest(best: &mut Option<LocalHit>,t: f32,t_min: f32,t_max: f32,normal: Vec3,uv: (f32,f32)){
+    if t < t_min || t > t_max || !t.is_finite() { return; }
+    if let Some(b) = best {
+        if b.t <= t { return; }
+    }
+    *best = Some(LocalHit{t: t,normal: normal,uv: uv});
+}
+
+//Both roots of a t^2 + 2 half_b t + c, smallest first
+#[inline]
+fn quadratic_roots(a: f32,half_b: f32,c: f32) -> Option<(f32,f32)>{
+    if a.abs() < 1e-12 { return None; }
+    let discriminant = half_b*half_b - a*c;
+    if discriminant < 0. { return None; }
+    let sqrtd = discriminant.sqrt();
+    let t1 = (-half_b - sqrtd)/a;
+    let t2 = (-half_b + sqrtd)/a;
+    return Some((t1.min(t2),t1.max(t2)));
+}
+
+#[inline]
+fn azimuth_u(p: &Point3) -> f32{
+    return (p.z().atan2(p.x()) + PI)/(2.*PI);
+}
+
+//Hit of the local ray with the y = y0 plane inside a circle of radius. Caps face away from the body
+#[inline]
+fn cap_hit(best: &mut Option<LocalHit>,r: &Ray,y0: f32,radius: f32,t_min: f32,t_max: f32){
+    if r.dir.y().abs() < 0.000001 || radius <= 0. { return; }
+    let t = (y0 - r.orig.y())/r.dir.y();
+    let p = r.at(t);
+    if p.x()*p.x() + p.z()*p.z() > radius*radius { return; }
+    keep_closest(best,t,t_min,t_max,Vec3::new(0.,y0.signum(),0.),(0.5 + 0.5*p.x()/radius,0.5 + 0.5*p.z()/radius));
+}
+
+//Normals go through the inverse transpose so non uniform scaling doesn't bend them
+//Open surfaces are two sided, their normal faces the ray like with planes
+#[inline]
+fn finish_local_hit(hit: &LocalHit,r: &Ray,m_local_to_world: &Mat4x4,m_word_to_local: &Mat4x4,two_sided: bool,
+    material: &Material) -> HitRecord{
+    let local_point = r.transform(m_word_to_local).at(hit.t);
+    let point = m_local_to_world.dot_p3(&local_point);
+    let mut normal = m_word_to_local.transpose().dot_v3(&hit.normal).unit();
+    if two_sided {
+        normal = normal_against_direction(&normal,normal.dot(r.dir));
+    }
+    return HitRecord{t: hit.t,point: point,normal: normal,geometric_normal: normal,material: *material,obj_id: 0,uv: hit.uv,tangent: Vec3::ZERO,fiber: false};
+}
+
+//Local frame that takes the Y axis from p0 (y=-1) to p1 (y=1) with the given radius, for building primitives from their ends
+fn segment_frame(p0: &Point3,p1: &Point3,radius: f32,half_length: f32) -> Mat4x4{
+    let axis = (*p1 - *p0).unit();
+    let (tangent,bitangent) = axis.orthonormal_basis();
+    let center = (*p0 + *p1)*0.5;
+    return Mat4x4::new_4vec_vert(
+        &Vec4::new_v3(&(tangent*radius)),&Vec4::new_v3(&(axis*half_length)),&Vec4::new_v3(&(bitangent*radius)),&Vec4::new_p3(&center)
+    );
+}
+
+//Radius 1, from y=-1 to y=1
+#[derive(Copy, Clone)]
+pub struct Cylinder {
+    pub m_local_to_world: Mat4x4,
+    pub m_word_to_local: Mat4x4,
+    pub capped: bool,
+    pub material: Material,
+}
+
+impl Cylinder {
+    #[allow(dead_code)]
+    pub fn new(m_local_to_world: &Mat4x4,capped: bool,mat: &Material) -> Self{
+        Self{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),capped: capped,material: *mat}
+    }
+    #[allow(dead_code)]
+    pub fn new_with_ends(p0: &Point3,p1: &Point3,radius: f32,capped: bool,mat: &Material) -> Self{
+        return Self::new(&segment_frame(p0,p1,radius,(*p1 - *p0).length()/2.),capped,mat);
+    }
+}
+
+impl Traced for Cylinder {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let new_r = r.transform(&self.m_word_to_local);
+        let (o,d) = (new_r.orig,new_r.dir);
+        let mut best: Option<LocalHit> = None;
+        let a = d.x()*d.x() + d.z()*d.z();
+        let half_b = o.x()*d.x() + o.z()*d.z();
+        let c = o.x()*o.x() + o.z()*o.z() - 1.;
+        if let Some((t1,t2)) = quadratic_roots(a,half_b,c) {
+            for t in [t1,t2]{
+                let p = new_r.at(t);
+                if p.y().abs() > 1. { continue; }
+                keep_closest(&mut best,t,t_min,t_max,Vec3::new(p.x(),0.,p.z()),(azimuth_u(&p),0.5*(p.y() + 1.)));
+            }
+        }
+        if self.capped {
+            cap_hit(&mut best,&new_r,-1.,1.,t_min,t_max);
+            cap_hit(&mut best,&new_r, 1.,1.,t_min,t_max);
+        }
+        return best.map(|h| finish_local_hit(&h,r,&self.m_local_to_world,&self.m_word_to_local,!self.capped,&self.material));
+    }
+}
+
+impl Bounded for Cylinder {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        return BoundingBox3D::new(&Point3::new(-1.,-1.,-1.),&Point3::new(1.,1.,1.)).dot(&self.m_local_to_world);
+    }
+}
+
+//Radius 1 at y=-1 and top_radius at y=1. top_radius 0 is a full cone with its apex at y=1, anything else a frustum
+#[derive(Copy, Clone)]
+pub struct Cone {
+    pub m_local_to_world: Mat4x4,
+    pub m_word_to_local: Mat4x4,
+    pub top_radius: f32,
+    pub capped: bool,
+    pub material: Material,
+}
+
+impl Cone {
+    #[allow(dead_code)]
+    pub fn new(m_local_to_world: &Mat4x4,top_radius: f32,capped: bool,mat: &Material) -> Self{
+        Self{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),
+             top_radius: top_radius.max(0.),capped: capped,material: *mat}
+    }
+    //Radius r0 at p0 and r1 at p1. The frame is scaled by the bottom radius, so the wider end goes at the bottom
+    //(v = 0 is always there) and a pointed end is always the top
+    #[allow(dead_code)]
+    pub fn new_with_ends(p0: &Point3,r0: f32,p1: &Point3,r1: f32,capped: bool,mat: &Material) -> Self{
+        if r1 > r0 { return Self::new_with_ends(p1,r1,p0,r0,capped,mat); }
+        let r0 = r0.max(1e-6);//Both ends pointed, a line
+        return Self::new(&segment_frame(p0,p1,r0,(*p1 - *p0).length()/2.),r1.max(0.)/r0,capped,mat);
+    }
+}
+
+impl Traced for Cone {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let new_r = r.transform(&self.m_word_to_local);
+        let (o,d) = (new_r.orig,new_r.dir);
+        let mut best: Option<LocalHit> = None;
+        //radius(y) = r0 + k*y, side is x^2 + z^2 - radius(y)^2 = 0
+        let k  = (self.top_radius - 1.)/2.;
+        let r0 = (self.top_radius + 1.)/2.;
+        let ro = r0 + k*o.y();
+        let a = d.x()*d.x() + d.z()*d.z() - k*k*d.y()*d.y();
+        let half_b = o.x()*d.x() + o.z()*d.z() - k*d.y()*ro;
+        let c = o.x()*o.x() + o.z()*o.z() - ro*ro;
+        if let Some((t1,t2)) = quadratic_roots(a,half_b,c) {
+            for t in [t1,t2]{
+                let p = new_r.at(t);
+                if p.y().abs() > 1. { continue; }//Also throws away the mirrored cone past the apex
+                let radius = r0 + k*p.y();
+                keep_closest(&mut best,t,t_min,t_max,Vec3::new(p.x(),-k*radius,p.z()),(azimuth_u(&p),0.5*(p.y() + 1.)));
+            }
+        }
+        if self.capped {
+            cap_hit(&mut best,&new_r,-1.,1.,t_min,t_max);
+            cap_hit(&mut best,&new_r, 1.,self.top_radius,t_min,t_max);
+        }
+        return best.map(|h| finish_local_hit(&h,r,&self.m_local_to_world,&self.m_word_to_local,!self.capped,&self.material));
+    }
+}
+
+impl Bounded for Cone {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let rmax = self.top_radius.max(1.);
+        return BoundingBox3D::new(&Point3::new(-rmax,-1.,-rmax),&Point3::new(rmax,1.,rmax)).dot(&self.m_local_to_world);
+    }
+}
+
+//Radius 1 in the y=0 plane, facing +Y. With inner_radius > 0 it's an annulus
+#[derive(Copy, Clone)]
+pub struct Disk {
+    pub m_local_to_world: Mat4x4,
+    pub m_word_to_local: Mat4x4,
+    pub inner_radius: f32,
+    pub material: Material,
+}
+
+impl Disk {
+    #[allow(dead_code)]
+    pub fn new(m_local_to_world: &Mat4x4,mat: &Material) -> Self{
+        return Self::new_annulus(m_local_to_world,0.,mat);
+    }
+    #[allow(dead_code)]
+    pub fn new_annulus(m_local_to_world: &Mat4x4,inner_radius: f32,mat: &Material) -> Self{
+        Self{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),
+             inner_radius: inner_radius.max(0.).min(1.),material: *mat}
+    }
+    #[allow(dead_code)]
+    pub fn new_with_normal(center: &Point3,normal: &UnitVec3,radius: f32,inner_radius: f32,mat: &Material) -> Self{
+        let m = segment_frame(&(*center - *normal),&(*center + *normal),radius,1.);
+        return Self::new_annulus(&m,inner_radius/radius,mat);
+    }
+}
+
+impl Traced for Disk {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let new_r = r.transform(&self.m_word_to_local);
+        if new_r.dir.y().abs() < 0.000001 { return None; }
+        let t = -new_r.orig.y()/new_r.dir.y();
+        if t < t_min || t > t_max { return None; }
+        let p = new_r.at(t);
+        let r2 = p.x()*p.x() + p.z()*p.z();
+        if r2 > 1. || r2 < self.inner_radius*self.inner_radius { return None; }
+        //Polar uv, v goes from the inner to the outer edge
+        let uv = (azimuth_u(&p),(r2.sqrt() - self.inner_radius)/(1. - self.inner_radius).max(0.000001));
+        let hit = LocalHit{t: t,normal: Vec3::new(0.,1.,0.),uv: uv};
+        return Some(finish_local_hit(&hit,r,&self.m_local_to_world,&self.m_word_to_local,true,&self.material));
+    }
+}
+
+impl Bounded for Disk {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        return BoundingBox3D::new(&Point3::new(-1.,0.,-1.),&Point3::new(1.,0.,1.)).dot(&self.m_local_to_world);
+    }
+}
+
+//Radius 1 cylinder from y=-half_height to y=half_height with a radius 1 hemisphere on each end
+#[derive(Copy, Clone)]
+pub struct Capsule {
+    pub m_local_to_world: Mat4x4,
+    pub m_word_to_local: Mat4x4,
+    pub half_height: f32,
+    pub material: Material,
+}
+
+impl Capsule {
+    #[allow(dead_code)]
+    pub fn new(m_local_to_world: &Mat4x4,half_height: f32,mat: &Material) -> Self{
+        Self{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),
+             half_height: half_height.max(0.),material: *mat}
+    }
+    //p0 and p1 are the centers of the hemispheres
+    #[allow(dead_code)]
+    pub fn new_with_ends(p0: &Point3,p1: &Point3,radius: f32,mat: &Material) -> Self{
+        let m = segment_frame(p0,p1,radius,radius);
+        return Self::new(&m,(*p1 - *p0).length()/(2.*radius),mat);
+    }
+}
+
+impl Traced for Capsule {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let new_r = r.transform(&self.m_word_to_local);
+        let (o,d) = (new_r.orig,new_r.dir);
+        let h = self.half_height;
+        let total = 2.*(h + 1.);
+        let mut best: Option<LocalHit> = None;
+        let a = d.x()*d.x() + d.z()*d.z();
+        let half_b = o.x()*d.x() + o.z()*d.z();
+        let c = o.x()*o.x() + o.z()*o.z() - 1.;
+        if let Some((t1,t2)) = quadratic_roots(a,half_b,c) {
+            for t in [t1,t2]{
+                let p = new_r.at(t);
+                if p.y().abs() > h { continue; }
+                keep_closest(&mut best,t,t_min,t_max,Vec3::new(p.x(),0.,p.z()),(azimuth_u(&p),(p.y() + h + 1.)/total));
+            }
+        }
+        for side in [-1.,1.]{//Each hemisphere is the part of its sphere past the cylinder
+            let center = Point3::new(0.,side*h,0.);
+            let oc = o - center;
+            if let Some((t1,t2)) = quadratic_roots(d.length_squared(),oc.dot(d),oc.length_squared() - 1.) {
+                for t in [t1,t2]{
+                    let p = new_r.at(t);
+                    if (p.y() - center.y())*side < 0. { continue; }
+                    keep_closest(&mut best,t,t_min,t_max,p - center,(azimuth_u(&p),(p.y() + h + 1.)/total));
+                }
+            }
+        }
+        return best.map(|hit| finish_local_hit(&hit,r,&self.m_local_to_world,&self.m_word_to_local,false,&self.material));
+    }
+}
+
+impl Bounded for Capsule {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let y = self.half_height + 1.;
+        return BoundingBox3D::new(&Point3::new(-1.,-y,-1.),&Point3::new(1.,y,1.)).dot(&self.m_local_to_world);
+    }
+}
+
+//Ring around the Y axis in the y=0 plane, major radius 1 and the tube of radius minor_radius
+#[derive(Copy, Clone)]
+pub struct Torus {
+    pub m_local_to_world: Mat4x4,
+    pub m_word_to_local: Mat4x4,
+    pub minor_radius: f32,
+    pub material: Material,
+}
+
+impl Torus {
+    #[allow(dead_code)]
+    pub fn new(m_local_to_world: &Mat4x4,minor_radius: f32,mat: &Material) -> Self{
+        Self{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),
+             minor_radius: minor_radius,material: *mat}
+    }
+    //Same sizes as MarchedTorus: (major radius,minor radius)
+    #[allow(dead_code)]
+    pub fn new_with_radii(m_local_to_world: &Mat4x4,major_radius: f32,minor_radius: f32,mat: &Material) -> Self{
+        let m = m_local_to_world.dot_mat(&Mat4x4::new_scale(&Vec3::new(major_radius,major_radius,major_radius)));
+        return Self::new(&m,minor_radius/major_radius,mat);
+    }
+}
+
+impl Traced for Torus {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let new_r = r.transform(&self.m_word_to_local);
+        let rr = self.minor_radius as f64;
+        let dir_len = new_r.dir.length() as f64;
+        //Start from where the ray enters the bounding sphere, with a unit direction. The quartic is very sensitive
+        //to how far the origin is, this keeps its coefficients small
+        let bound = 1. + self.minor_radius;
+        let ts = match quadratic_roots(new_r.dir.length_squared(),new_r.orig.dot(new_r.dir),new_r.orig.length_squared() - bound*bound) {
+            Some(ts) => ts,
+            None => return None,
+        };
+        let t_start = ts.0.max(t_min);
+        if t_start > t_max || t_start > ts.1 { return None; }
+        let o = new_r.at(t_start);
+        let (ox,oy,oz) = (o.x() as f64,o.y() as f64,o.z() as f64);
+        let (dx,dy,dz) = (new_r.dir.x() as f64/dir_len,new_r.dir.y() as f64/dir_len,new_r.dir.z() as f64/dir_len);
+        //(|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2), R = 1
+        let od = ox*dx + oy*dy + oz*dz;
+        let k = ox*ox + oy*oy + oz*oz + 1. - rr*rr;
+        let roots = solve_quartic(
+            1.,
+            4.*od,
+            4.*od*od + 2.*k - 4.*(dx*dx + dz*dz),
+            4.*od*k - 8.*(ox*dx + oz*dz),
+            k*k - 4.*(ox*ox + oz*oz),
+        );
+        let mut best: Option<LocalHit> = None;
+        for s in roots{
+            if s < 0. { continue; }
+            let t = t_start + (s/dir_len) as f32;
+            let p = new_r.at(t);
+            let ring = Vec3::new(p.x(),0.,p.z()).unit();
+            let tube_angle = p.y().atan2(Vec3::new(p.x(),0.,p.z()).length() - 1.);
+            keep_closest(&mut best,t,t_min,t_max,p - ring,(azimuth_u(&p),(tube_angle + PI)/(2.*PI)));
+        }
+        return best.map(|h| finish_local_hit(&h,r,&self.m_local_to_world,&self.m_word_to_local,false,&self.material));
+    }
+}
+
+impl Bounded for Torus {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let s = 1. + self.minor_radius;
+        return BoundingBox3D::new(&Point3::new(-s,-self.minor_radius,-s),&Point3::new(s,self.minor_radius,s)).dot(&self.m_local_to_world);
+    }
+}
+
+//p^T q p = 0 for homogeneous local points p = (x,y,z,1), q symmetric. Only the part inside the local clip box is kept,
+//since most quadrics are infinite
+#[derive(Copy, Clone)]
+pub struct Quadric {
+    pub m_local_to_world: Mat4x4,
+    pub m_word_to_local: Mat4x4,
+    pub q: Mat4x4,
+    pub clip_min: Point3,
+    pub clip_max: Point3,
+    pub two_sided: bool,//Clipped surfaces are open, closed ones (ellipsoids) keep the gradient as the outward normal
+    pub material: Material,
+}
+
+impl Quadric {
+    #[allow(dead_code)]
+    pub fn new(m_local_to_world: &Mat4x4,q: &Mat4x4,clip_min: &Point3,clip_max: &Point3,two_sided: bool,mat: &Material) -> Self{
+        //Only the symmetric part matters for p^T q p, keeps the gradient right if someone passes an asymmetric one
+        let qs = Mat4x4::new_16f(
+            q.at(0,0),(q.at(0,1)+q.at(1,0))/2.,(q.at(0,2)+q.at(2,0))/2.,(q.at(0,3)+q.at(3,0))/2.,
+            (q.at(0,1)+q.at(1,0))/2.,q.at(1,1),(q.at(1,2)+q.at(2,1))/2.,(q.at(1,3)+q.at(3,1))/2.,
+            (q.at(0,2)+q.at(2,0))/2.,(q.at(1,2)+q.at(2,1))/2.,q.at(2,2),(q.at(2,3)+q.at(3,2))/2.,
+            (q.at(0,3)+q.at(3,0))/2.,(q.at(1,3)+q.at(3,1))/2.,(q.at(2,3)+q.at(3,2))/2.,q.at(3,3),
+        );
+        Self{m_local_to_world: *m_local_to_world,m_word_to_local: m_local_to_world.fast_homogenous_inverse(),q: qs,
+             clip_min: *clip_min,clip_max: *clip_max,two_sided: two_sided,material: *mat}
+    }
+    //x^2 + y^2 + z^2 = 1, an ellipsoid once m_local_to_world scales it
+    #[allow(dead_code)]
+    pub fn new_ellipsoid(m_local_to_world: &Mat4x4,mat: &Material) -> Self{
+        let q = Mat4x4::new_16f(1.,0.,0.,0., 0.,1.,0.,0., 0.,0.,1.,0., 0.,0.,0.,-1.);
+        return Self::new(m_local_to_world,&q,&Point3::new(-1.,-1.,-1.),&Point3::new(1.,1.,1.),false,mat);
+    }
+    //y = x^2 + z^2, from the tip at the origin up to y=1
+    #[allow(dead_code)]
+    pub fn new_paraboloid(m_local_to_world: &Mat4x4,mat: &Material) -> Self{
+        let q = Mat4x4::new_16f(1.,0.,0.,0., 0.,0.,0.,-0.5, 0.,0.,1.,0., 0.,-0.5,0.,0.);
+        return Self::new(m_local_to_world,&q,&Point3::new(-1.,0.,-1.),&Point3::new(1.,1.,1.),true,mat);
+    }
+    //One sheet: x^2 - y^2 + z^2 = 1, the waist is radius 1. Two sheets: -x^2 + y^2 - z^2 = 1, tips at y=+-1. y goes from -2 to 2
+    #[allow(dead_code)]
+    pub fn new_hyperboloid(m_local_to_world: &Mat4x4,one_sheet: bool,mat: &Material) -> Self{
+        let s = if one_sheet { 1. } else { -1. };
+        let q = Mat4x4::new_16f(s,0.,0.,0., 0.,-s,0.,0., 0.,0.,s,0., 0.,0.,0.,-1.);
+        let xz = if one_sheet { 5f32.sqrt() } else { 3f32.sqrt() };
+        return Self::new(m_local_to_world,&q,&Point3::new(-xz,-2.,-xz),&Point3::new(xz,2.,xz),true,mat);
+    }
+    #[inline]
+    fn inside_clip(&self,p: &Point3) -> bool{
+        const EPS: f32 = 0.00001;
+        return p.x() >= self.clip_min.x()-EPS && p.y() >= self.clip_min.y()-EPS && p.z() >= self.clip_min.z()-EPS
+            && p.x() <= self.clip_max.x()+EPS && p.y() <= self.clip_max.y()+EPS && p.z() <= self.clip_max.z()+EPS;
+    }
+}
+
+impl Traced for Quadric {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let new_r = r.transform(&self.m_word_to_local);
+        let o = Vec4::new_p3(&new_r.orig);
+        let d = Vec4::new_v3(&new_r.dir);
+        let qo = self.q.dot(&o);
+        let qd = self.q.dot(&d);
+        let a = d.dot(qd);
+        let half_b = d.dot(qo);
+        let c = o.dot(qo);
+        let ts = if a.abs() < 1e-9 {//Degenerates to linear, eg: a ray parallel to the axis of a paraboloid
+            if half_b.abs() < 1e-12 { return None; }
+            [-c/(2.*half_b),INF]
+        } else {
+            match quadratic_roots(a,half_b,c) {
+                Some((t1,t2)) => [t1,t2],
+                None => return None,
+            }
+        };
+        let mut best: Option<LocalHit> = None;
+        let height = (self.clip_max.y() - self.clip_min.y()).max(0.000001);
+        for t in ts{
+            let p = new_r.at(t);
+            if !self.inside_clip(&p) { continue; }
+            let gradient = self.q.dot(&Vec4::new_p3(&p)).xyz();
+            keep_closest(&mut best,t,t_min,t_max,gradient,(azimuth_u(&p),(p.y() - self.clip_min.y())/height));
+        }
+        return best.map(|h| finish_local_hit(&h,r,&self.m_local_to_world,&self.m_word_to_local,self.two_sided,&self.material));
+    }
+}
+
+impl Bounded for Quadric {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        return BoundingBox3D::new(&self.clip_min,&self.clip_max).dot(&self.m_local_to_world);
+    }
+}
+
+//Cubic Bézier with a width going linearly from width0 to width1, for hair, fur and grass
+//Intersected in ray space by recursive subdivision (Nakamaru & Ohno 2002, the way pbrt does it)
+//Cylinders fake their roundness through the normal, ribbons are flat strips twisting from one normal to the other
+#[derive(Copy, Clone)]
+pub struct Curve {
+    pub p: [Point3;4],
+    pub width0: f32,
+    pub width1: f32,
+    pub ribbon_normals: Option<(UnitVec3,UnitVec3)>,
+    pub material: Material,
+    //Levels of subdivision until each piece is close enough to a line
+    max_depth: u32,
+}
+
+#[inline]
+fn bezier_eval(cp: &[Point3;4],u: f32) -> (Point3,Vec3){
+    let s = 1. - u;
+    let p = s*s*s*cp[0] + 3.*s*s*u*cp[1] + 3.*s*u*u*cp[2] + u*u*u*cp[3];
+    let d = 3.*(s*s*(cp[1] - cp[0]) + 2.*s*u*(cp[2] - cp[1]) + u*u*(cp[3] - cp[2]));
+    return (p,d);
+}
+
+//de Casteljau at u=0.5
+#[inline]
+fn bezier_split(cp: &[Point3;4]) -> ([Point3;4],[Point3;4]){
+    let m01 = 0.5*(cp[0] + cp[1]);
+    let m12 = 0.5*(cp[1] + cp[2]);
+    let m23 = 0.5*(cp[2] + cp[3]);
+    let a = 0.5*(m01 + m12);
+    let b = 0.5*(m12 + m23);
+    let mid = 0.5*(a + b);
+    return ([cp[0],m01,a,mid],[mid,b,m23,cp[3]]);
+}
+
+#[inline]
+fn slerp(u: f32,a: &UnitVec3,b: &UnitVec3) -> UnitVec3{
+    let cos = a.dot(*b).max(-1.).min(1.);
+    if cos > 0.9995 { return ((1. - u)*(*a) + u*(*b)).unit(); }
+    let theta = cos.acos();
+    return ((((1. - u)*theta).sin()*(*a) + (u*theta).sin()*(*b))/theta.sin()).unit();
+}
+
+impl Curve {
+    #[allow(dead_code)]
+    pub fn new(p: &[Point3;4],width0: f32,width1: f32,mat: &Material) -> Self{
+        //Second differences bound how far the control polygon is from a line, pbrt's estimate of the depth
+        //needed for the pieces to be within 5% of the width of it
+        let mut l0: f32 = 0.;
+        for i in 0..2{
+            let dd = p[i] - 2.*p[i+1] + p[i+2];
+            l0 = l0.max(dd.x().abs()).max(dd.y().abs()).max(dd.z().abs());
+        }
+        let eps = 0.05*width0.max(width1).max(0.000001);
+        let depth = ((6.*std::f32::consts::SQRT_2*l0/(8.*eps)).log2()/2.).ceil();
+        let max_depth = if depth.is_finite() { (depth.max(0.) as u32).min(10) } else { 0 };
+        return Self{p: *p,width0: width0,width1: width1,ribbon_normals: None,material: *mat,max_depth: max_depth};
+    }
+    #[allow(dead_code)]
+    pub fn new_ribbon(p: &[Point3;4],width0: f32,width1: f32,n0: &UnitVec3,n1: &UnitVec3,mat: &Material) -> Self{
+        let mut ret = Self::new(p,width0,width1,mat);
+        ret.ribbon_normals = Some((n0.unit(),n1.unit()));
+        return ret;
+    }
+    //Catmull-Rom through the points, one Bézier per span, the width tapering from root to tip
+    #[allow(dead_code)]
+    pub fn new_strand(points: &[Point3],root_width: f32,tip_width: f32,mat: &Material) -> Vec<Self>{
+        let n = points.len();
+        let mut ret = Vec::with_capacity(n.saturating_sub(1));
+        for i in 0..n.saturating_sub(1){
+            let prev = points[if i == 0 { 0 } else { i-1 }];
+            let next = points[(i+2).min(n-1)];
+            let (p1,p2) = (points[i],points[i+1]);
+            let cp = [p1,p1 + (p2 - prev)/6.,p2 - (next - p1)/6.,p2];
+            let w = |j: usize| root_width + (tip_width - root_width)*(j as f32)/((n-1) as f32);
+            ret.push(Self::new(&cp,w(i),w(i+1),mat));
+        }
+        return ret;
+    }
+    #[inline]
+    fn width(&self,u: f32) -> f32{
+        return (1. - u)*self.width0 + u*self.width1;
+    }
+    //Width as seen by a ray, ribbons seen edge on vanish
+    #[inline]
+    fn hit_width(&self,u: f32,dir: &UnitVec3) -> f32{
+        return match &self.ribbon_normals {
+            Some((n0,n1)) => self.width(u)*slerp(u,n0,n1).dot(*dir).abs(),
+            None => self.width(u),
+        };
+    }
+    //cp are in ray space: the ray starts at the origin and goes along +z. Returns the z and u of the closest hit
+    fn recursive_hit(&self,cp: &[Point3;4],u0: f32,u1: f32,depth: u32,dir: &UnitVec3,z_min: f32,z_max: f32) -> Option<(f32,f32)>{
+        let half_w = 0.5*self.width(u0).max(self.width(u1));
+        let minp = cp[0].min(&cp[1]).min(&cp[2].min(&cp[3]));
+        let maxp = cp[0].max(&cp[1]).max(&cp[2].max(&cp[3]));
+        if minp.x() - half_w > 0. || maxp.x() + half_w < 0. || minp.y() - half_w > 0. || maxp.y() + half_w < 0.
+        || maxp.z() + half_w < z_min || minp.z() - half_w > z_max {
+            return None;
+        }
+        if depth > 0 {
+            let (a,b) = bezier_split(cp);
+            let um = 0.5*(u0 + u1);
+            let hit_a = self.recursive_hit(&a,u0,um,depth-1,dir,z_min,z_max);
+            return self.recursive_hit(&b,um,u1,depth-1,dir,z_min,hit_a.map_or(z_max,|h| h.0)).or(hit_a);
+        }
+        //Past the planes perpendicular to the tangent at the ends it's the neighbouring piece's hit
+        if (cp[1].y() - cp[0].y())*-cp[0].y() + cp[0].x()*(cp[0].x() - cp[1].x()) < 0. { return None; }
+        if (cp[2].y() - cp[3].y())*-cp[3].y() + cp[3].x()*(cp[3].x() - cp[2].x()) < 0. { return None; }
+        //Closest point of the piece, taken as a line, to the ray
+        let (sx,sy) = (cp[3].x() - cp[0].x(),cp[3].y() - cp[0].y());
+        let denom = sx*sx + sy*sy;
+        if denom == 0. { return None; }
+        let w = ((-cp[0].x()*sx - cp[0].y()*sy)/denom).max(0.).min(1.);
+        let u = (u0 + w*(u1 - u0)).max(u0).min(u1);
+        let hit_width = self.hit_width(u,dir);
+        let (pc,_dpcdw) = bezier_eval(cp,w);
+        let dist2 = pc.x()*pc.x() + pc.y()*pc.y();
+        if dist2 > 0.25*hit_width*hit_width { return None; }
+        if pc.z() < z_min || pc.z() > z_max { return None; }
+        //A ray that starts on the fiber's own surface would hit it again from the inside, it's too thin to matter
+        if dist2 + pc.z()*pc.z() < 0.25*1.1*self.width(u)*self.width(u) { return None; }
+        return Some((pc.z(),u));
+    }
+}
+
+impl Traced for Curve {
+    fn hit(&self,r: &Ray,t_min: f32,t_max: f32) -> Option<HitRecord> {
+        let (bx,by) = r.dir.orthonormal_basis();
+        let mut cp = [Point3::ZERO;4];
+        for i in 0..4{
+            let q = self.p[i] - r.orig;
+            cp[i] = Point3::new(q.dot(bx),q.dot(by),q.dot(r.dir));
+        }
+        let (z,u) = self.recursive_hit(&cp,0.,1.,self.max_depth,&r.dir,t_min,t_max)?;
+        let (center,dpdu) = bezier_eval(&self.p,u);
+        let tangent = if dpdu.length_squared() > 0. { dpdu.unit() } else { bx };
+        let half_w = 0.5*self.width(u);
+        let side = tangent.cross(r.dir);
+        if side.length_squared() < 1e-12 {//Looking right down the fiber
+            return Some(HitRecord{t: z,point: r.at(z),normal: -r.dir,geometric_normal: -r.dir,material: self.material,obj_id: 0,uv: (u,0.5),tangent: tangent,fiber: true});
+        }
+        let side = side.unit();
+        //Offset across the width in [-1;1], what the hair BSDF calls h
+        let h = ((r.at(z) - center).dot(side)/half_w).max(-1.).min(1.);
+        let (t,normal) = match &self.ribbon_normals {
+            Some((n0,n1)) => {
+                let n = slerp(u,n0,n1);
+                (z,normal_against_direction(&n,n.dot(r.dir)))
+            },
+            None => {//Moved out to where the cylinder's surface would be
+                let facing = (-r.dir - tangent*(-r.dir).dot(tangent)).unit();
+                let cos_gamma = (1. - h*h).sqrt();
+                ((z - half_w*cos_gamma).max(t_min),(cos_gamma*facing + h*side).unit())
+            },
+        };
+        return Some(HitRecord{t: t,point: r.at(t),normal: normal,geometric_normal: normal,material: self.material,obj_id: 0,uv: (u,0.5 + 0.5*h),tangent: tangent,fiber: true});
+    }
+}
+
+impl Bounded for Curve {
+    fn build_world_bounding_box(&self) -> BoundingBox3D {
+        let half_w = 0.5*self.width0.max(self.width1);
+        let minp = self.p[0].min(&self.p[1]).min(&self.p[2].min(&self.p[3]));
+        let maxp = self.p[0].max(&self.p[1]).max(&self.p[2].max(&self.p[3]));
+        return BoundingBox3D::new(&(minp - Vec3::new(half_w,half_w,half_w)),&(maxp + Vec3::new(half_w,half_w,half_w)));
+    }
+}
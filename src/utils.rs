@@ -9,20 +9,40 @@ pub fn clamp(x: f32,fmin: f32,fmax: f32) -> f32{
 pub fn lerp(t: f32,c1: Color,c2: Color) -> Color{
     return (1.0-t)*c1 + t*c2;
 }
+#[inline]
+pub fn srgb_oetf(x: f32) -> f32{
+    return if x <= 0.0031308 { 12.92*x } else { 1.055*x.powf(1./2.4) - 0.055 };
+}
+//sRGB encodes and clamps, for data views (depth, samples, AOVs). The render itself goes through display::DisplayTransform
 pub fn normalize_color(color: &Color) -> Color{
-    let r = clamp(color.x().sqrt(),0.,0.999);
-    let g = clamp(color.y().sqrt(),0.,0.999);
-    let b = clamp(color.z().sqrt(),0.,0.999);
+    let r = clamp(srgb_oetf(color.x().max(0.)),0.,0.999);
+    let g = clamp(srgb_oetf(color.y().max(0.)),0.,0.999);
+    let b = clamp(srgb_oetf(color.z().max(0.)),0.,0.999);
     return Color::new(r,g,b);
 }
 
-use rand::Rng;
+//Rec. 709 relative luminance
+#[inline]
+pub fn luminance(color: &Color) -> f32{
+    return 0.2126*color.x() + 0.7152*color.y() + 0.0722*color.z();
+}
+
+use rand::{Rng,SeedableRng};
+use rand::rngs::StdRng;
+use std::cell::RefCell;
+thread_local!{
+    //Per thread, from entropy unless seeded. Scenes are built after seeding so every frame of an animation gets the same one
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+pub fn seed_rng(seed: u64){
+    RNG.with(|r| *r.borrow_mut() = StdRng::seed_from_u64(seed));
+}
 pub trait MyRandom{
     fn rand() -> Self;
     fn rand_range(fmin: f32,fmax: f32) -> Self;
 }
 impl MyRandom for f32{
-    fn rand() -> Self{ rand::thread_rng().gen() }
+    fn rand() -> Self{ RNG.with(|r| r.borrow_mut().gen()) }
     fn rand_range(min: f32,max: f32) -> f32{ Self::rand()*(max-min) + min }
 }
 
@@ -107,7 +127,6 @@ impl BloomFilter {
     }
 }
 
-pub fn get_id<O: ?Sized>(obj: &O) -> u64 { (obj as *const O as *const ()) as u64 }
 
 
 
@@ -115,9 +134,14 @@ pub fn get_id<O: ?Sized>(obj: &O) -> u64 { (obj as *const O as *const ()) as u64
 pub struct VecIndexes<const SIZE: usize>{
     pub arr: [usize;SIZE],
     pub count: usize,
+    pub overflow: bool,//Tried to add more than SIZE indexes, the list is incomplete
 }
 impl <const SIZE: usize> VecIndexes<SIZE>{
     pub fn add(&mut self,x: usize) -> () {
+        if self.count == SIZE {
+            self.overflow = true;
+            return;
+        }
         self.arr[self.count] = x;
         self.count += 1;
     }
@@ -126,5 +150,6 @@ impl <const SIZE: usize> VecIndexes<SIZE>{
             self.arr[i] = 0;
         }
         self.count = 0;
+        self.overflow = false;
     }
 }
\ No newline at end of file